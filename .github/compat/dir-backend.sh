@@ -0,0 +1,24 @@
+#!/bin/sh
+# An `exec` backend that stores objects in $DIR_BACKEND_ROOT.
+set -eu
+
+object="${DIR_BACKEND_ROOT:?}/$2"
+
+case "$1" in
+get)
+  [ -f "$object" ] || exit 2
+  cat "$object"
+  ;;
+put)
+  mkdir -p "$(dirname "$object")"
+  cat > "$object.tmp"
+  mv "$object.tmp" "$object"
+  ;;
+exists)
+  [ -f "$object" ] || exit 2
+  wc -c < "$object" | tr -d ' '
+  ;;
+*)
+  exit 1
+  ;;
+esac
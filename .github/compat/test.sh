@@ -0,0 +1,47 @@
+#!/usr/bin/env bash
+# Check that the installed version of Nix can use magic-nix-cache as a
+# binary cache: upload a path with `nix copy`, then substitute it.
+set -euxo pipefail
+
+magic_nix_cache="$1"
+cache="http://127.0.0.1:3000"
+
+work="$(mktemp -d)"
+export DIR_BACKEND_ROOT="$work/objects"
+
+"$magic_nix_cache" \
+  --listen 127.0.0.1:3000 \
+  --backend "exec:$(dirname "$0")/dir-backend.sh" \
+  --use-gha-cache enabled \
+  --use-flakehub disabled \
+  --diagnostic-endpoint "" \
+  --nix-conf "$work/nix.conf" \
+  --startup-notification-file "$work/started" &
+
+for _ in $(seq 60); do
+  [ -e "$work/started" ] && break
+  sleep 1
+done
+
+curl -fsS "$cache/nix-cache-info" -D "$work/headers"
+grep -qi '^content-type: text/x-nix-cache-info' "$work/headers"
+
+echo "compat test $(nix --version) $RANDOM" > "$work/file"
+path="$(nix-store --add "$work/file")"
+hash="$(basename "$path" | cut -d- -f1)"
+
+# A missing path answers HEAD without an error.
+curl -sS -o /dev/null -w '%{http_code}' -I "$cache/$hash.narinfo" | grep -q '^404$'
+
+nix --extra-experimental-features nix-command copy --to "$cache" "$path"
+
+curl -fsS -I "$cache/$hash.narinfo" -D "$work/headers" -o /dev/null
+grep -qi '^content-type: text/x-nix-narinfo' "$work/headers"
+
+nix-store --delete "$path"
+nix-store --realise "$path" \
+  --option substituters "$cache" \
+  --option require-sigs false
+
+curl -fsS -X POST "$cache/api/workflow-finish"
+wait
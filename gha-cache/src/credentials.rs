@@ -0,0 +1,94 @@
+//! Credentials for the GitHub Actions Cache.
+//!
+//! Forgejo and Gitea Actions runners serve a cache with the same API, but
+//! only at `ACTIONS_CACHE_URL`: `ACTIONS_RESULTS_URL` points at the artifact
+//! service of the forge, and the REST API has no cache endpoints.
+
+use std::env;
+
+use crate::{Error, Result};
+
+/// Credentials for the cache service the runner talks to.
+///
+/// These are only available to processes started by the Actions runner.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    /// The base URL of the cache service.
+    pub cache_url: String,
+
+    /// The runtime token the runner issued for this job.
+    pub runtime_token: String,
+}
+
+impl Credentials {
+    /// Load the credentials from the environment of an Actions job.
+    pub fn load_from_env() -> Result<Self> {
+        let cache_url = if is_gitea_actions() {
+            env::var("ACTIONS_CACHE_URL")
+        } else {
+            env::var("ACTIONS_RESULTS_URL").or_else(|_| env::var("ACTIONS_CACHE_URL"))
+        }
+        .map_err(|_| Error::MissingEnv("ACTIONS_CACHE_URL"))?;
+
+        // Older Gitea runners only hand out the job's `GITEA_TOKEN`.
+        let runtime_token = env::var("ACTIONS_RUNTIME_TOKEN")
+            .or_else(|err| {
+                if is_gitea_actions() {
+                    env::var("GITEA_TOKEN")
+                } else {
+                    Err(err)
+                }
+            })
+            .map_err(|_| Error::MissingEnv("ACTIONS_RUNTIME_TOKEN"))?;
+
+        Ok(Credentials {
+            cache_url,
+            runtime_token,
+        })
+    }
+}
+
+/// Credentials for the GitHub REST API, used to list and delete cache entries.
+#[derive(Clone, Debug)]
+pub struct RestCredentials {
+    /// The base URL of the REST API, e.g. `https://api.github.com`.
+    pub api_url: String,
+
+    /// The repository owning the cache, as `owner/name`.
+    pub repository: String,
+
+    /// A token with the `actions: write` permission on the repository.
+    pub token: String,
+}
+
+impl RestCredentials {
+    /// Load the credentials from the environment of an Actions job.
+    ///
+    /// `GITHUB_TOKEN` isn't exported to steps by default, so it must be
+    /// passed in explicitly by the workflow.
+    pub fn load_from_env() -> Result<Self> {
+        if is_gitea_actions() {
+            return Err(Error::NoRestCredentials);
+        }
+
+        let api_url =
+            env::var("GITHUB_API_URL").unwrap_or_else(|_| "https://api.github.com".to_owned());
+        let repository =
+            env::var("GITHUB_REPOSITORY").map_err(|_| Error::MissingEnv("GITHUB_REPOSITORY"))?;
+        let token = env::var("GITHUB_TOKEN").map_err(|_| Error::MissingEnv("GITHUB_TOKEN"))?;
+
+        Ok(RestCredentials {
+            api_url,
+            repository,
+            token,
+        })
+    }
+}
+
+/// Whether this is a job on a Forgejo or Gitea Actions runner, which also
+/// claim to be GitHub Actions.
+pub fn is_gitea_actions() -> bool {
+    ["GITEA_ACTIONS", "FORGEJO_ACTIONS"]
+        .iter()
+        .any(|name| env::var(name).is_ok_and(|value| value == "true"))
+}
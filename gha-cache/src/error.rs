@@ -0,0 +1,20 @@
+//! Errors.
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cache API error: {0}")]
+    Cache(#[from] opendal::Error),
+
+    #[error("REST API error: {0}")]
+    Rest(#[from] reqwest::Error),
+
+    #[error("Environment variable {0} is not set")]
+    MissingEnv(&'static str),
+
+    #[error("Listing and deleting entries requires REST API credentials")]
+    NoRestCredentials,
+}
@@ -0,0 +1,339 @@
+//! A client for the GitHub Actions Cache.
+//!
+//! This crate lets any process running inside an Actions job store and
+//! retrieve arbitrary blobs in the repository's cache, the same storage used
+//! by `actions/cache`. The cache service is accessed through
+//! [opendal](https://docs.rs/opendal)'s `ghac` service, while listing and
+//! deleting entries goes through the GitHub REST API.
+//!
+//! ```no_run
+//! # async fn example() -> gha_cache::Result<()> {
+//! let api = gha_cache::Api::builder().version("my-tool-v1").build()?;
+//!
+//! api.put("greeting", "hello".as_bytes().to_vec()).await?;
+//!
+//! if let Some(greeting) = api.get("greeting").await? {
+//!     println!("{}", String::from_utf8_lossy(&greeting.to_vec()));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Entries are immutable: writing to an existing key fails, unless another
+//! writer created the same entry concurrently (see [`is_reserve_conflict`]).
+//! Entries written with one version string are invisible to clients using
+//! another.
+
+mod credentials;
+mod error;
+
+use opendal::{ErrorKind, Operator};
+use serde::Deserialize;
+
+pub use credentials::{is_gitea_actions, Credentials, RestCredentials};
+pub use error::{Error, Result};
+pub use opendal::{Buffer, Metadata};
+
+const USER_AGENT: &str = "gha-cache";
+
+/// Entries returned by the REST API per page when listing.
+const LIST_PAGE_SIZE: usize = 100;
+
+/// A handle to the GitHub Actions Cache.
+#[derive(Clone, Debug)]
+pub struct Api {
+    operator: Operator,
+
+    /// Prefix prepended to every key by the `ghac` service.
+    key_prefix: String,
+
+    rest: Option<RestCredentials>,
+
+    client: reqwest::Client,
+}
+
+/// Configuration for an [`Api`].
+#[derive(Clone, Debug, Default)]
+pub struct ApiBuilder {
+    version: Option<String>,
+    root: Option<String>,
+    credentials: Option<Credentials>,
+    rest: Option<RestCredentials>,
+}
+
+/// A cache entry, as reported by the REST API.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CacheEntry {
+    pub id: u64,
+
+    pub key: String,
+
+    /// The git ref the entry was written from, which determines its visibility.
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+
+    /// A hash of the version string the entry was written with.
+    pub version: String,
+
+    pub size_in_bytes: u64,
+
+    pub created_at: String,
+
+    pub last_accessed_at: String,
+}
+
+#[derive(Deserialize)]
+struct ListResponse {
+    total_count: usize,
+    actions_caches: Vec<CacheEntry>,
+}
+
+impl ApiBuilder {
+    /// Set the version string. Only entries with the same version are visible.
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Set a directory that all keys are relative to.
+    pub fn root(mut self, root: impl Into<String>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Use explicit cache service credentials instead of the job's environment.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Set the REST API credentials needed by [`Api::list`] and [`Api::delete`].
+    ///
+    /// If unset, they are loaded from the environment when available.
+    pub fn rest_credentials(mut self, rest: RestCredentials) -> Self {
+        self.rest = Some(rest);
+        self
+    }
+
+    pub fn build(self) -> Result<Api> {
+        let mut builder = opendal::services::Ghac::default();
+
+        if let Some(version) = &self.version {
+            builder = builder.version(version);
+        }
+
+        if let Some(root) = &self.root {
+            builder = builder.root(root);
+        }
+
+        // The `ghac` service would pick `ACTIONS_RESULTS_URL` on Forgejo and Gitea.
+        let credentials = match self.credentials {
+            None if is_gitea_actions() => Some(Credentials::load_from_env()?),
+            credentials => credentials,
+        };
+
+        if let Some(credentials) = &credentials {
+            builder = builder
+                .endpoint(&credentials.cache_url)
+                .runtime_token(&credentials.runtime_token);
+        }
+
+        let operator = Operator::new(builder)?.finish();
+
+        let key_prefix = match self.root.as_deref().map(|r| r.trim_matches('/')) {
+            None | Some("") => String::new(),
+            Some(root) => format!("{}/", root),
+        };
+
+        let rest = self.rest.or_else(|| RestCredentials::load_from_env().ok());
+
+        let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+
+        Ok(Api {
+            operator,
+            key_prefix,
+            rest,
+            client,
+        })
+    }
+}
+
+impl Api {
+    pub fn builder() -> ApiBuilder {
+        ApiBuilder::default()
+    }
+
+    /// The underlying opendal operator, for streaming reads and writes.
+    pub fn operator(&self) -> &Operator {
+        &self.operator
+    }
+
+    /// Read an entry, returning `None` if it doesn't exist.
+    pub async fn get(&self, key: &str) -> Result<Option<Buffer>> {
+        match self.operator.read(key).await {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Create an entry.
+    ///
+    /// If another writer reserved the key first, this succeeds as long as
+    /// the entry it created has the same size.
+    pub async fn put(&self, key: &str, content: impl Into<Buffer>) -> Result<()> {
+        let content = content.into();
+        let size = content.len() as u64;
+
+        match self.operator.write(key, content).await {
+            Ok(_) => Ok(()),
+            Err(err)
+                if is_reserve_conflict(&err)
+                    && existing_matches(&self.operator, key, Some(size)).await? =>
+            {
+                tracing::debug!("Entry '{}' was already created by another writer", key);
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the metadata of an entry, returning `None` if it doesn't exist.
+    pub async fn metadata(&self, key: &str) -> Result<Option<Metadata>> {
+        match self.operator.stat(key).await {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// List the entries whose key starts with `prefix`, across all versions.
+    ///
+    /// ```no_run
+    /// # async fn example(api: gha_cache::Api) -> gha_cache::Result<()> {
+    /// for entry in api.list("v1/").await? {
+    ///     println!("{} ({} bytes)", entry.key, entry.size_in_bytes);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn list(&self, prefix: &str) -> Result<Vec<CacheEntry>> {
+        let rest = self.rest.as_ref().ok_or(Error::NoRestCredentials)?;
+        let key = format!("{}{}", self.key_prefix, prefix);
+
+        let mut entries = Vec::new();
+
+        for page in 1.. {
+            let response: ListResponse = self
+                .client
+                .get(format!(
+                    "{}/repos/{}/actions/caches",
+                    rest.api_url, rest.repository
+                ))
+                .bearer_auth(&rest.token)
+                .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+                .query(&[
+                    ("key", key.as_str()),
+                    ("per_page", &LIST_PAGE_SIZE.to_string()),
+                    ("page", &page.to_string()),
+                ])
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            let count = response.actions_caches.len();
+            entries.extend(response.actions_caches);
+
+            if count < LIST_PAGE_SIZE || entries.len() >= response.total_count {
+                break;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Delete every entry with exactly this key, across all versions and refs.
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        let rest = self.rest.as_ref().ok_or(Error::NoRestCredentials)?;
+        let key = format!("{}{}", self.key_prefix, key);
+
+        let response = self
+            .client
+            .delete(format!(
+                "{}/repos/{}/actions/caches",
+                rest.api_url, rest.repository
+            ))
+            .bearer_auth(&rest.token)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .query(&[("key", key.as_str())])
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            tracing::debug!("No cache entry with key '{}' to delete", key);
+            return Ok(());
+        }
+
+        response.error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Delete a single entry by its ID, as returned by [`Api::list`].
+    pub async fn delete_by_id(&self, id: u64) -> Result<()> {
+        let rest = self.rest.as_ref().ok_or(Error::NoRestCredentials)?;
+
+        self.client
+            .delete(format!(
+                "{}/repos/{}/actions/caches/{}",
+                rest.api_url, rest.repository, id
+            ))
+            .bearer_auth(&rest.token)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Whether `err` means that another writer reserved the key first, e.g.
+/// because two jobs of a matrix raced to upload the same entry.
+pub fn is_reserve_conflict(err: &opendal::Error) -> bool {
+    match err.kind() {
+        ErrorKind::AlreadyExists | ErrorKind::ConditionNotMatch => true,
+        // The `ghac` service reports a 409 as an unexpected error, with the
+        // response in its context.
+        ErrorKind::Unexpected => err.to_string().contains("status: 409"),
+        _ => false,
+    }
+}
+
+/// After a reserve conflict, check that the entry under `key` exists and,
+/// if `size` is known, that it has that size.
+///
+/// Returns `false` if the entry isn't visible yet, e.g. because the other
+/// writer is still uploading it.
+pub async fn existing_matches(operator: &Operator, key: &str, size: Option<u64>) -> Result<bool> {
+    let metadata = match operator.stat(key).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+
+    match size {
+        Some(size) if metadata.content_length() != size => {
+            tracing::warn!(
+                "Entry '{}' created by another writer has {} bytes instead of {}",
+                key,
+                metadata.content_length(),
+                size
+            );
+            Ok(false)
+        }
+        _ => Ok(true),
+    }
+}
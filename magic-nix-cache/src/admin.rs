@@ -0,0 +1,31 @@
+//! Authentication of administrative endpoints.
+//!
+//! Endpoints that reveal what the cache holds, like the NAR listing, are
+//! only served if the daemon has an admin token from `--admin-token-file`,
+//! and only to requests that carry it as `Authorization: Bearer <token>`.
+
+use axum::http::{header, HeaderMap};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::State;
+
+/// Check that the request with `headers` carries the admin token.
+pub fn authorize(state: &State, headers: &HeaderMap) -> Result<()> {
+    let Some(admin_token) = &state.admin_token else {
+        return Err(Error::NotFound);
+    };
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+
+    // Comparing digests doesn't reveal how much of the token was right.
+    if Sha256::digest(token.trim()) != Sha256::digest(admin_token) {
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(())
+}
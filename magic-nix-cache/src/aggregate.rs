@@ -0,0 +1,244 @@
+//! Aggregating metrics across the jobs of a workflow run.
+//!
+//! At shutdown, each job writes its telemetry snapshot to the GitHub Actions
+//! Cache under the run ID. The `aggregate` subcommand then merges the
+//! snapshots of all jobs of a run into a single report, and the `trend`
+//! subcommand compares the runs whose snapshots are still in the cache.
+
+use serde_json::{Map, Value};
+
+use crate::error::{Error, Result};
+use crate::github_app;
+use crate::telemetry::TelemetryReport;
+
+/// Version string of the snapshot entries, which keeps them apart from the binary cache.
+const SNAPSHOT_VERSION: &str = "magic-nix-cache-metrics-v1";
+
+/// Directory the snapshots are stored in.
+const SNAPSHOT_ROOT: &str = "magic-nix-cache-metrics";
+
+/// Fields of a snapshot that identify the job rather than count anything.
+const IDENTITY_FIELDS: &[&str] = &["distinct_id", "version", "is_ci", "tripped_429"];
+
+fn api_builder() -> gha_cache::ApiBuilder {
+    gha_cache::Api::builder()
+        .version(SNAPSHOT_VERSION)
+        .root(SNAPSHOT_ROOT)
+}
+
+fn api() -> Result<gha_cache::Api> {
+    Ok(api_builder().build()?)
+}
+
+/// [`api`] for listing the snapshots, which uses the REST API.
+async fn rest_api() -> Result<gha_cache::Api> {
+    Ok(github_app::authenticate(api_builder()).await?.build()?)
+}
+
+fn run_id(run_id: Option<String>) -> Result<String> {
+    run_id
+        .or_else(|| std::env::var("GITHUB_RUN_ID").ok())
+        .ok_or_else(|| Error::Config("GITHUB_RUN_ID is not set".to_owned()))
+}
+
+/// Store the metrics of this job in the GitHub Actions Cache.
+pub async fn push_snapshot(metrics: &TelemetryReport) -> Result<()> {
+    let run_id = run_id(None)?;
+    let attempt = std::env::var("GITHUB_RUN_ATTEMPT").unwrap_or_else(|_| "1".to_owned());
+    let job = std::env::var("GITHUB_JOB").unwrap_or_else(|_| "job".to_owned());
+
+    // Matrix jobs share the job ID, so make the key unique.
+    let key = format!(
+        "{}/{}/{}-{}.json",
+        run_id,
+        attempt,
+        job,
+        uuid::Uuid::now_v7()
+    );
+
+    let snapshot = serde_json::to_vec(metrics)
+        .map_err(|e| Error::Internal(format!("Serializing metrics: {}", e)))?;
+
+    api()?.put(&key, snapshot).await?;
+
+    tracing::debug!("Stored the metrics snapshot as '{}'", key);
+
+    Ok(())
+}
+
+/// The counters of the snapshots of several jobs, summed up.
+#[derive(Default)]
+struct Totals {
+    metrics: Map<String, Value>,
+    jobs: u64,
+}
+
+impl Totals {
+    fn add(&mut self, snapshot: Map<String, Value>) {
+        for (field, value) in snapshot {
+            if IDENTITY_FIELDS.contains(&field.as_str()) {
+                continue;
+            }
+
+            if let Some(n) = value.as_u64() {
+                let total = self.metrics.entry(field).or_insert(Value::from(0u64));
+                *total = Value::from(total.as_u64().unwrap_or(0) + n);
+            }
+        }
+
+        self.jobs += 1;
+    }
+
+    fn get(&self, field: &str) -> u64 {
+        self.metrics.get(field).and_then(Value::as_u64).unwrap_or(0)
+    }
+
+    fn narinfo_hit_rate(&self) -> Option<f64> {
+        ratio(
+            self.get("narinfos_served"),
+            self.get("narinfos_sent_upstream"),
+        )
+    }
+
+    fn nar_hit_rate(&self) -> Option<f64> {
+        ratio(self.get("nars_served"), self.get("nars_sent_upstream"))
+    }
+
+    fn substitution_rate(&self) -> Option<f64> {
+        ratio(self.get("outputs_substituted"), self.get("outputs_built"))
+    }
+}
+
+/// Read the snapshot stored as `entry`, or `None` if it isn't readable.
+async fn read_snapshot(
+    api: &gha_cache::Api,
+    entry: &gha_cache::CacheEntry,
+) -> Result<Option<Map<String, Value>>> {
+    let key = entry
+        .key
+        .strip_prefix(&format!("{}/", SNAPSHOT_ROOT))
+        .unwrap_or(&entry.key);
+
+    // Entries written with another version aren't visible.
+    let Some(content) = api.get(key).await? else {
+        return Ok(None);
+    };
+
+    match serde_json::from_slice(&content.to_vec()) {
+        Ok(snapshot) => Ok(Some(snapshot)),
+        Err(err) => {
+            tracing::warn!("Ignoring unreadable metrics snapshot '{}': {}", key, err);
+            Ok(None)
+        }
+    }
+}
+
+/// Merge the snapshots of all jobs of a run into a combined report.
+pub async fn aggregate(run_id_arg: Option<String>) -> Result<Value> {
+    let run_id = run_id(run_id_arg)?;
+    let api = rest_api().await?;
+
+    let mut totals = Totals::default();
+
+    for entry in api.list(&format!("{}/", run_id)).await? {
+        if let Some(snapshot) = read_snapshot(&api, &entry).await? {
+            totals.add(snapshot);
+        }
+    }
+
+    Ok(serde_json::json!({
+        "run_id": run_id,
+        "jobs": totals.jobs,
+        "metrics": totals.metrics,
+        "efficiency": {
+            "narinfo_hit_rate": totals.narinfo_hit_rate(),
+            "nar_hit_rate": totals.nar_hit_rate(),
+            "substitution_rate": totals.substitution_rate(),
+        },
+    }))
+}
+
+/// Compare the hit rates and upload volume of the last `runs` runs whose
+/// snapshots are still in the cache, oldest first.
+pub async fn trend(runs: usize) -> Result<Value> {
+    let api = rest_api().await?;
+
+    let mut entries = api.list("").await?;
+    // The timestamps are all in the same format, so they sort as strings.
+    entries.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+    // Runs in the order of their first snapshot.
+    let mut by_run: Vec<(String, String, Totals)> = Vec::new();
+
+    for entry in &entries {
+        let key = entry
+            .key
+            .strip_prefix(&format!("{}/", SNAPSHOT_ROOT))
+            .unwrap_or(&entry.key);
+        let Some((run_id, _)) = key.split_once('/') else {
+            continue;
+        };
+
+        let Some(snapshot) = read_snapshot(&api, entry).await? else {
+            continue;
+        };
+
+        match by_run.iter_mut().find(|(id, _, _)| id == run_id) {
+            Some((_, _, totals)) => totals.add(snapshot),
+            None => {
+                let mut totals = Totals::default();
+                totals.add(snapshot);
+                by_run.push((run_id.to_owned(), entry.created_at.clone(), totals));
+            }
+        }
+    }
+
+    let by_run = &by_run[by_run.len().saturating_sub(runs)..];
+
+    let rows = by_run
+        .iter()
+        .map(|(run_id, started_at, totals)| {
+            serde_json::json!({
+                "run_id": run_id,
+                "started_at": started_at,
+                "jobs": totals.jobs,
+                "narinfo_hit_rate": totals.narinfo_hit_rate(),
+                "nar_hit_rate": totals.nar_hit_rate(),
+                "substitution_rate": totals.substitution_rate(),
+                "nar_bytes_served": totals.get("nar_bytes_served"),
+                "nar_bytes_uploaded": totals.get("nar_bytes_uploaded"),
+                "elapsed_seconds": totals.get("elapsed_seconds"),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // The older half of the runs against the newer half.
+    let (earlier, recent) = by_run.split_at(by_run.len() / 2);
+    let change = |value: fn(&Totals) -> Option<f64>| {
+        serde_json::json!({
+            "earlier": mean(earlier.iter().filter_map(|(_, _, totals)| value(totals))),
+            "recent": mean(recent.iter().filter_map(|(_, _, totals)| value(totals))),
+        })
+    };
+
+    Ok(serde_json::json!({
+        "runs": rows,
+        "trend": {
+            "narinfo_hit_rate": change(Totals::narinfo_hit_rate),
+            "nar_hit_rate": change(Totals::nar_hit_rate),
+            "substitution_rate": change(Totals::substitution_rate),
+            "nar_bytes_uploaded": change(|totals| Some(totals.get("nar_bytes_uploaded") as f64)),
+            "elapsed_seconds": change(|totals| Some(totals.get("elapsed_seconds") as f64)),
+        },
+    }))
+}
+
+/// The fraction of requests that were hits, if there were any requests.
+fn ratio(hits: u64, misses: u64) -> Option<f64> {
+    (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64)
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
@@ -0,0 +1,300 @@
+//! Webhook alerts when the cache misbehaves during a job.
+//!
+//! With `--alert-webhook`, the daemon posts to a Slack incoming webhook, or
+//! any other URL as generic JSON, when a threshold is crossed: the narinfo
+//! hit rate at the end of the job is below `--alert-min-hit-rate`, more than
+//! `--alert-max-quota` percent of `--max-put-size` or `--max-upload-size` was
+//! used, or more than `--alert-max-upload-failures` uploads failed. Each
+//! alert is sent at most once per job.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::Url;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::telemetry::TelemetryReport;
+use crate::util;
+
+/// How often the thresholds are checked while the job runs.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long sending an alert may take.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The host of Slack incoming webhooks, which expect a `text` field.
+const SLACK_HOST: &str = "hooks.slack.com";
+
+/// Environment variables that describe the job in alerts.
+const CONTEXT: &[(&str, &str)] = &[
+    ("repository", "GITHUB_REPOSITORY"),
+    ("workflow", "GITHUB_WORKFLOW"),
+    ("job", "GITHUB_JOB"),
+    ("run_id", "GITHUB_RUN_ID"),
+];
+
+/// Where to send alerts and when.
+#[derive(Clone, Debug, Default, Serialize, clap::Args)]
+pub struct AlertFlags {
+    /// Post alerts to this URL, a Slack incoming webhook or any endpoint
+    /// that accepts JSON.
+    #[arg(long = "alert-webhook", value_name = "URL")]
+    #[serde(skip)]
+    pub webhook: Option<Url>,
+
+    /// Alert when less than this percentage of narinfo lookups were hits
+    /// by the end of the job.
+    #[arg(
+        long = "alert-min-hit-rate",
+        value_name = "PERCENT",
+        value_parser = util::parse_percent,
+        requires = "webhook"
+    )]
+    pub min_hit_rate: Option<f64>,
+
+    /// Alert when more than this percentage of `--max-put-size` or
+    /// `--max-upload-size` was used.
+    #[arg(
+        long = "alert-max-quota",
+        value_name = "PERCENT",
+        value_parser = util::parse_percent,
+        requires = "webhook"
+    )]
+    pub max_quota: Option<f64>,
+
+    /// Alert when more than this many uploads failed.
+    #[arg(
+        long = "alert-max-upload-failures",
+        value_name = "N",
+        requires = "webhook"
+    )]
+    pub max_upload_failures: Option<u64>,
+}
+
+impl AlertFlags {
+    fn has_thresholds(&self) -> bool {
+        self.min_hit_rate.is_some()
+            || self.max_quota.is_some()
+            || self.max_upload_failures.is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Alert {
+    HitRate,
+    Quota,
+    UploadFailures,
+}
+
+impl Alert {
+    fn name(self) -> &'static str {
+        match self {
+            Alert::HitRate => "hit_rate",
+            Alert::Quota => "quota",
+            Alert::UploadFailures => "upload_failures",
+        }
+    }
+}
+
+pub struct Alerts {
+    client: reqwest::Client,
+    webhook: Url,
+    flags: AlertFlags,
+    metrics: Arc<TelemetryReport>,
+
+    /// The metrics that count against a limit, the option that sets it and
+    /// the limit.
+    quotas: Vec<(&'static str, &'static str, u64)>,
+
+    /// The alerts that were sent.
+    fired: Mutex<HashSet<Alert>>,
+}
+
+impl Alerts {
+    /// Start checking `metrics` against the thresholds in `flags`, with the
+    /// limits the quota is relative to.
+    pub fn spawn(
+        flags: AlertFlags,
+        metrics: Arc<TelemetryReport>,
+        max_put_size: Option<u64>,
+        max_upload_size: Option<u64>,
+    ) -> Result<Arc<Alerts>> {
+        let Some(webhook) = flags.webhook.clone() else {
+            return Err(Error::Config(
+                "Alert thresholds require an alert webhook".to_owned(),
+            ));
+        };
+
+        if !flags.has_thresholds() {
+            return Err(Error::Config(
+                "The alert webhook requires at least one alert threshold".to_owned(),
+            ));
+        }
+
+        let quotas = [
+            ("put_bytes_accepted", "--max-put-size", max_put_size),
+            ("nar_bytes_uploaded", "--max-upload-size", max_upload_size),
+        ]
+        .into_iter()
+        .filter_map(|(name, option, limit)| Some((name, option, limit.filter(|limit| *limit > 0)?)))
+        .collect::<Vec<_>>();
+
+        if flags.max_quota.is_some() && quotas.is_empty() {
+            return Err(Error::Config(
+                "The quota alert requires --max-put-size or --max-upload-size".to_owned(),
+            ));
+        }
+
+        let alerts = Arc::new(Alerts {
+            client: reqwest::Client::new(),
+            webhook,
+            flags,
+            metrics,
+            quotas,
+            fired: Mutex::new(HashSet::new()),
+        });
+
+        let alerts2 = alerts.clone();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+                alerts2.check(false).await;
+            }
+        });
+
+        Ok(alerts)
+    }
+
+    /// Send the alerts whose thresholds were crossed. The hit rate is only
+    /// checked at the end of the job, since it is meaningless before.
+    pub async fn check(&self, finished: bool) {
+        let counters = self
+            .metrics
+            .counters()
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let get = |name: &str| counters.get(name).copied().unwrap_or(0);
+
+        if let Some(min_hit_rate) = self.flags.min_hit_rate.filter(|_| finished) {
+            let hits = get("narinfos_served");
+            let lookups = hits + get("narinfos_sent_upstream");
+
+            if lookups > 0 {
+                let hit_rate = 100.0 * hits as f64 / lookups as f64;
+
+                if hit_rate < min_hit_rate {
+                    self.fire(
+                        Alert::HitRate,
+                        format!(
+                            "The narinfo hit rate was {:.1}% ({} of {} lookups), below {}%",
+                            hit_rate, hits, lookups, min_hit_rate
+                        ),
+                        hit_rate,
+                        min_hit_rate,
+                    )
+                    .await;
+                }
+            }
+        }
+
+        if let Some(max_quota) = self.flags.max_quota {
+            let used = self
+                .quotas
+                .iter()
+                .map(|(name, option, limit)| {
+                    (*option, *limit, 100.0 * get(name) as f64 / *limit as f64)
+                })
+                .max_by(|a, b| a.2.total_cmp(&b.2));
+
+            if let Some((option, limit, used)) = used.filter(|(_, _, used)| *used > max_quota) {
+                self.fire(
+                    Alert::Quota,
+                    format!(
+                        "{:.1}% of {}={} was used, above {}%",
+                        used, option, limit, max_quota
+                    ),
+                    used,
+                    max_quota,
+                )
+                .await;
+            }
+        }
+
+        if let Some(max_upload_failures) = self.flags.max_upload_failures {
+            let failures = get("uploads_failed");
+
+            if failures > max_upload_failures {
+                self.fire(
+                    Alert::UploadFailures,
+                    format!(
+                        "{} uploads failed, more than {}",
+                        failures, max_upload_failures
+                    ),
+                    failures as f64,
+                    max_upload_failures as f64,
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn fire(&self, alert: Alert, message: String, value: f64, threshold: f64) {
+        if !self.fired.lock().await.insert(alert) {
+            return;
+        }
+
+        tracing::warn!("Alert: {}", message);
+
+        let context = CONTEXT
+            .iter()
+            .filter_map(|(name, var)| Some((*name, std::env::var(var).ok()?)))
+            .collect::<Vec<_>>();
+
+        let body = if self.webhook.host_str() == Some(SLACK_HOST) {
+            let job = context
+                .iter()
+                .map(|(_, value)| value.as_str())
+                .collect::<Vec<_>>()
+                .join(" / ");
+
+            serde_json::json!({
+                "text": if job.is_empty() {
+                    format!("Magic Nix Cache: {}", message)
+                } else {
+                    format!("Magic Nix Cache ({}): {}", job, message)
+                },
+            })
+        } else {
+            let mut body = serde_json::json!({
+                "alert": alert.name(),
+                "message": message,
+                "value": value,
+                "threshold": threshold,
+            });
+            for (name, value) in context {
+                body[name] = value.into();
+            }
+            body
+        };
+
+        let result = self
+            .client
+            .post(self.webhook.clone())
+            .json(&body)
+            .timeout(SEND_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        if let Err(err) = result {
+            tracing::warn!(
+                "Sending the {} alert failed: {}",
+                alert.name(),
+                err.without_url()
+            );
+        }
+    }
+}
@@ -2,18 +2,42 @@
 //!
 //! This API is intended to be used by nix-installer-action.
 
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use attic::nix_store::StorePath;
-use axum::{extract::Extension, routing::post, Json, Router};
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
 use serde::{Deserialize, Serialize};
 
 use super::State;
+use crate::admin;
 use crate::error::{Error, Result};
+use crate::gha::ClosureFlags;
+use crate::lifecycle::Phase;
+use crate::scoped;
+use crate::signing;
+use crate::steps::Step;
 
 #[derive(Debug, Clone, Serialize)]
 struct WorkflowStartResponse {
     num_original_paths: Option<usize>,
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+struct WorkflowFinishRequest {
+    /// The job summary file of the step that finishes the workflow, which
+    /// the results of `--check-reproducibility` are added to.
+    #[serde(default)]
+    step_summary: Option<PathBuf>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct WorkflowFinishResponse {
     num_original_paths: Option<usize>,
@@ -26,6 +50,99 @@ pub fn get_router() -> Router {
         .route("/api/workflow-start", post(workflow_start))
         .route("/api/workflow-finish", post(workflow_finish))
         .route("/api/enqueue-paths", post(post_enqueue_paths))
+        .route("/api/scoped-tokens", post(post_scoped_token))
+        .route("/api/build-results", post(post_build_results))
+        .route("/api/step-start", post(post_step_start))
+        .route("/api/step-end", post(post_step_end))
+        .route("/api/stats", get(get_stats))
+        .route("/api/info", get(get_info))
+        .route("/api/netrc", get(get_netrc))
+        .route("/api/re-sign", post(post_re_sign))
+}
+
+/// Sign the narinfos in the cache with the signing key, replacing the
+/// signatures by the old keys.
+async fn post_re_sign(
+    Extension(state): Extension<State>,
+    headers: HeaderMap,
+) -> Result<Json<signing::ReSignReport>> {
+    admin::authorize(&state, &headers)?;
+
+    let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
+    let signing_keys = state.signing_keys.as_ref().ok_or(Error::NotFound)?;
+
+    let mut report = signing::ReSignReport::default();
+    for api in gha_cache.apis() {
+        signing::re_sign_all(api, signing_keys, state.audit_log.as_deref(), &mut report).await?;
+    }
+
+    tracing::info!(
+        "Re-signed {} of {} narinfos, {} failed",
+        report.re_signed,
+        report.narinfos,
+        report.failed
+    );
+
+    Ok(Json(report))
+}
+
+/// Render the netrc that Nix needs for the caches it substitutes from
+/// besides the daemon.
+async fn get_netrc(
+    Extension(state): Extension<State>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    admin::authorize(&state, &headers)?;
+
+    let mut netrc = String::new();
+
+    if let Some(flakehub_state) = &*state.flakehub_state.read().await {
+        netrc.push_str(&flakehub_state.netrc_entry().await?.to_string());
+    }
+
+    for entry in &state.upstream_netrc {
+        netrc.push_str(&entry.to_string());
+    }
+
+    Ok(([(header::CONTENT_TYPE, "text/plain")], netrc))
+}
+
+/// Describe the version, configuration and environment of this daemon.
+async fn get_info(Extension(state): Extension<State>) -> Json<serde_json::Value> {
+    let mut info = serde_json::json!(&state.info);
+    info["phase"] = serde_json::json!(state.lifecycle.phase());
+
+    Json(info)
+}
+
+/// Report the current metrics, the health of each backend, the metrics of
+/// each finished step and a summary of the narinfo index.
+async fn get_stats(Extension(state): Extension<State>) -> Json<serde_json::Value> {
+    let flakehub_state = state.flakehub_state.read().await;
+
+    let narinfo_index = match state
+        .gha_cache
+        .as_ref()
+        .and_then(|gha_cache| gha_cache.narinfo_index.as_ref())
+    {
+        Some(narinfo_index) => Some(narinfo_index.summary().await),
+        None => None,
+    };
+
+    Json(serde_json::json!({
+        "metrics": &*state.metrics,
+        "backends": {
+            "gha": state.gha_cache.as_ref().map(|gha_cache| &*gha_cache.health),
+            "flakehub": flakehub_state.as_ref().map(|flakehub_state| &*flakehub_state.health),
+        },
+        "steps": state.steps.finished().await,
+        "narinfo_index": narinfo_index,
+        "scan_rejections": state
+            .gha_cache
+            .as_ref()
+            .and_then(|gha_cache| gha_cache.scanner.as_ref())
+            .map(|scanner| scanner.rejections()),
+    }))
 }
 
 /// Record existing paths.
@@ -54,10 +171,20 @@ async fn workflow_start(Extension(state): Extension<State>) -> Result<Json<Workf
 /// Push new paths and shut down.
 async fn workflow_finish(
     Extension(state): Extension<State>,
+    req: Option<Json<WorkflowFinishRequest>>,
 ) -> Result<Json<WorkflowFinishResponse>> {
     tracing::info!("Workflow finished");
 
-    let response = if let Some(original_paths) = &state.original_paths {
+    let req = req.map(|Json(req)| req).unwrap_or_default();
+
+    // The last step may not have been ended.
+    let _ = state.steps.end(None, &state.metrics).await;
+
+    // An earlier request already uploaded the new paths.
+    let first = state.lifecycle.phase() < Phase::Draining;
+    let original_paths = state.original_paths.as_ref().filter(|_| first);
+
+    let response = if let Some(original_paths) = original_paths {
         let original_paths = original_paths.lock().await;
         let final_paths = crate::util::get_store_paths(&state.store).await?;
         let new_paths = final_paths
@@ -83,7 +210,7 @@ async fn workflow_finish(
         // NOTE(cole-h): If we're substituting from an upstream cache, those paths won't have the
         // post-build-hook run on it, so we diff the store to ensure we cache everything we can.
         tracing::info!("Diffing the store and uploading any new paths before we shut down");
-        enqueue_paths(&state, new_paths).await?;
+        enqueue_paths(&state, new_paths, false).await?;
 
         reply
     } else {
@@ -94,9 +221,103 @@ async fn workflow_finish(
         }
     };
 
+    // Rebuilding takes too long once the job is cancelled.
+    if let Some(repro_check) = state
+        .repro_check
+        .as_ref()
+        .filter(|_| first && !state.cancellation.is_cancelled())
+    {
+        repro_check
+            .run(&state.store, &state.metrics, req.step_summary.as_deref())
+            .await;
+    }
+
+    shutdown(&state).await?;
+
+    // NOTE(cole-h): see `init_logging`
+    if let Some(logfile) = &state.logfile {
+        let logfile_contents = std::fs::read_to_string(logfile)
+            .map_err(|e| crate::error::Error::Io(e, format!("Reading {}", logfile.display())))?;
+        println!("Every log line throughout the lifetime of the program:");
+        println!("\n{logfile_contents}\n");
+    }
+
+    Ok(Json(response))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepStartRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepStartResponse {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepEndRequest {
+    /// The step that ends, which must be the running one.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Attribute the cache traffic from now on to the step `name`.
+async fn post_step_start(
+    Extension(state): Extension<State>,
+    Json(req): Json<StepStartRequest>,
+) -> Json<StepStartResponse> {
+    state.steps.start(req.name, &state.metrics).await;
+
+    Json(StepStartResponse {})
+}
+
+/// End the running step, returning the metrics it changed.
+async fn post_step_end(
+    Extension(state): Extension<State>,
+    Json(req): Json<StepEndRequest>,
+) -> Result<Json<Step>> {
+    Ok(Json(
+        state.steps.end(req.name.as_deref(), &state.metrics).await?,
+    ))
+}
+
+/// Wait for pending uploads to finish, then tell the server to stop.
+///
+/// If the daemon is already shutting down, this waits for that to finish.
+pub(crate) async fn shutdown(state: &State) -> Result<()> {
+    if !state.lifecycle.advance(Phase::Draining) {
+        state.lifecycle.reached(Phase::Stopped).await;
+        return Ok(());
+    }
+
+    let result = drain(state).await;
+
+    state.lifecycle.advance(Phase::Stopped);
+
+    if let Some(sender) = state.shutdown_sender.lock().await.take() {
+        sender
+            .send(())
+            .map_err(|_| Error::Internal("Sending shutdown server message".to_owned()))?;
+    }
+
+    result
+}
+
+/// Wait for the uploads to every cache to finish.
+async fn drain(state: &State) -> Result<()> {
     if let Some(gha_cache) = &state.gha_cache {
         tracing::info!("Waiting for GitHub action cache uploads to finish");
         gha_cache.shutdown().await?;
+
+        // The archive holds the whole closure, which can't be uploaded in time.
+        if let Some(closure_archive) = state
+            .closure_archive
+            .as_ref()
+            .filter(|_| !state.cancellation.is_cancelled())
+        {
+            if let Err(err) = closure_archive.save(&gha_cache.api, &state.store).await {
+                tracing::error!("Storing the closure archive failed: {}", err);
+            }
+        }
     }
 
     if let Some(attic_state) = state.flakehub_state.write().await.take() {
@@ -110,26 +331,80 @@ async fn workflow_finish(
         tracing::info!("FlakeHub cache is not enabled, not uploading anything to it");
     }
 
-    if let Some(sender) = state.shutdown_sender.lock().await.take() {
-        sender
-            .send(())
-            .map_err(|_| Error::Internal("Sending shutdown server message".to_owned()))?;
-    }
+    Ok(())
+}
 
-    // NOTE(cole-h): see `init_logging`
-    if let Some(logfile) = &state.logfile {
-        let logfile_contents = std::fs::read_to_string(logfile)
-            .map_err(|e| crate::error::Error::Io(e, format!("Reading {}", logfile.display())))?;
-        println!("Every log line throughout the lifetime of the program:");
-        println!("\n{logfile_contents}\n");
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopedTokenRequest {
+    /// The store paths that the token may push.
+    pub store_paths: Vec<String>,
 
-    Ok(Json(response))
+    /// How long the token is valid, in seconds.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopedTokenResponse {
+    pub token: String,
+
+    /// The URL of the binary cache that accepts the token.
+    pub url: String,
+
+    /// How long the token is valid, in seconds.
+    pub expires_in: u64,
+}
+
+/// Mint a token for the scoped listener that may only push `store_paths`.
+async fn post_scoped_token(
+    Extension(state): Extension<State>,
+    Json(req): Json<ScopedTokenRequest>,
+) -> Result<Json<ScopedTokenResponse>> {
+    let scoped_tokens = state.scoped_tokens.as_ref().ok_or(Error::NotFound)?;
+
+    // The paths may not exist yet, e.g. if they are built in a container.
+    let store_path_hashes = req
+        .store_paths
+        .iter()
+        .map(|path| {
+            state
+                .store
+                .parse_store_path(path)
+                .map(|path| path.to_hash().to_string())
+                .map_err(Error::Attic)
+        })
+        .collect::<Result<_>>()?;
+
+    let ttl = req
+        .ttl
+        .map_or(scoped::DEFAULT_TTL, Duration::from_secs)
+        .min(scoped::MAX_TTL);
+
+    tracing::info!(
+        "Minting a scoped token for {:?}, valid for {:?}",
+        req.store_paths,
+        ttl
+    );
+
+    Ok(Json(ScopedTokenResponse {
+        token: scoped_tokens.mint(store_path_hashes, ttl),
+        url: scoped_tokens.url.clone(),
+        expires_in: ttl.as_secs(),
+    }))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnqueuePathsRequest {
     pub store_paths: Vec<String>,
+
+    /// Upload the paths even if the cache already has them.
+    #[serde(default)]
+    pub force: bool,
+
+    /// How to compute the closure of the paths, in addition to the flags
+    /// the daemon was started with.
+    #[serde(flatten)]
+    pub closure: ClosureFlags,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -141,7 +416,14 @@ async fn post_enqueue_paths(
     Extension(state): Extension<State>,
     Json(req): Json<EnqueuePathsRequest>,
 ) -> Result<Json<EnqueuePathsResponse>> {
-    tracing::info!("Enqueueing {:?}", req.store_paths);
+    if req.force {
+        tracing::info!(
+            "Enqueueing {:?}, replacing existing entries",
+            req.store_paths
+        );
+    } else {
+        tracing::info!("Enqueueing {:?}", req.store_paths);
+    }
 
     let store_paths = req
         .store_paths
@@ -149,15 +431,123 @@ async fn post_enqueue_paths(
         .map(|path| state.store.follow_store_path(path).map_err(Error::Attic))
         .collect::<Result<Vec<_>>>()?;
 
-    enqueue_paths(&state, store_paths).await?;
+    enqueue_paths_with(&state, store_paths, req.force, req.closure).await?;
 
     Ok(Json(EnqueuePathsResponse {}))
 }
 
-pub async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
+/// An entry of the output of `nix build --json`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildResult {
+    pub outputs: HashMap<String, String>,
+
+    /// When the build started. Missing or zero for derivations whose
+    /// outputs were substituted or already valid.
+    #[serde(default)]
+    pub start_time: Option<u64>,
+
+    /// The build status, reported by versions of Nix that include it.
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+impl BuildResult {
+    fn was_built(&self) -> bool {
+        match &self.status {
+            Some(status) => status == "Built",
+            None => self.start_time.unwrap_or(0) > 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildResultsResponse {
+    pub num_built: usize,
+    pub num_substituted: usize,
+}
+
+/// Record which outputs of a `nix build --json` were built and which were
+/// substituted. Built outputs are uploaded, and substituted ones are left out
+/// of uploads unless forced.
+#[tracing::instrument(skip_all)]
+async fn post_build_results(
+    Extension(state): Extension<State>,
+    Json(results): Json<Vec<BuildResult>>,
+) -> Result<Json<BuildResultsResponse>> {
+    let mut built = Vec::new();
+    let mut substituted = Vec::new();
+
+    for result in &results {
+        for output in result.outputs.values() {
+            let path = state
+                .store
+                .follow_store_path(output)
+                .map_err(Error::Attic)?;
+
+            if result.was_built() {
+                built.push(path);
+            } else {
+                substituted.push(path);
+            }
+        }
+    }
+
+    tracing::info!(
+        "{} outputs were built and {} were substituted",
+        built.len(),
+        substituted.len()
+    );
+
+    state.metrics.outputs_built.add(built.len());
+    state.metrics.outputs_substituted.add(substituted.len());
+
+    let response = BuildResultsResponse {
+        num_built: built.len(),
+        num_substituted: substituted.len(),
+    };
+
+    if let Some(gha_cache) = &state.gha_cache {
+        gha_cache.mark_substituted(substituted).await;
+    }
+
+    enqueue_paths(&state, built, false).await?;
+
+    Ok(Json(response))
+}
+
+/// Schedule `store_paths` for uploading to every enabled cache.
+///
+/// `force` only applies to the binary cache backend; the FlakeHub cache
+/// decides for itself which paths it needs.
+pub async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>, force: bool) -> Result<()> {
+    enqueue_paths_with(state, store_paths, force, ClosureFlags::default()).await
+}
+
+/// Schedule `store_paths` for uploading, computing their closure for the
+/// binary cache backend with `closure` as well as the daemon's flags.
+pub async fn enqueue_paths_with(
+    state: &State,
+    store_paths: Vec<StorePath>,
+    force: bool,
+    closure: ClosureFlags,
+) -> Result<()> {
+    if let Some(closure_archive) = &state.closure_archive {
+        closure_archive.record(&store_paths).await;
+    }
+
+    if let Some(repro_check) = &state.repro_check {
+        repro_check.record(&store_paths).await;
+    }
+
     if let Some(gha_cache) = &state.gha_cache {
         gha_cache
-            .enqueue_paths(state.store.clone(), store_paths.clone())
+            .enqueue_paths(
+                state.store.clone(),
+                store_paths.clone(),
+                force,
+                state.closure_flags.union(closure),
+            )
             .await?;
     }
 
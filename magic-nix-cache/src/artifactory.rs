@@ -0,0 +1,179 @@
+//! Artifactory-specific extensions to the WebDAV backend.
+//!
+//! Uploaded NARs are tagged with properties identifying the build that
+//! produced them, and blobs Artifactory already knows about are deployed by
+//! checksum instead of being uploaded again.
+
+use std::sync::Arc;
+
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::{Error, Result};
+use crate::request_id;
+use crate::scheduler::Scheduler;
+
+/// Properties set on uploaded objects, and the environment variables they're read from.
+const PROPERTIES: &[(&str, &str)] = &[
+    ("build.name", "GITHUB_REPOSITORY"),
+    ("build.number", "GITHUB_RUN_ID"),
+    ("vcs.revision", "GITHUB_SHA"),
+];
+
+pub struct Artifactory {
+    client: reqwest::Client,
+
+    /// The base URL of Artifactory, e.g. `https://example.jfrog.io/artifactory`.
+    base: Url,
+
+    /// The key of the repository the cache is stored in.
+    repository: String,
+
+    credentials: Option<(String, String)>,
+
+    properties: Vec<(&'static str, String)>,
+
+    /// The scheduler shared with the WebDAV operator, if any.
+    scheduler: Option<Arc<Scheduler>>,
+}
+
+impl Artifactory {
+    /// Create a client for the repository at `url`, which must be of the form
+    /// `<base>/<repository>`.
+    pub fn new(url: &Url, credentials: Option<(String, String)>) -> Result<Self> {
+        let path = url.path().trim_end_matches('/');
+        let (base_path, repository) = path
+            .rsplit_once('/')
+            .filter(|(_, repository)| !repository.is_empty())
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "Artifactory URL '{}' does not point to a repository",
+                    url
+                ))
+            })?;
+
+        let mut base = url.clone();
+        base.set_path(&format!("{}/", base_path));
+
+        let properties = PROPERTIES
+            .iter()
+            .filter_map(|(name, var)| Some((*name, std::env::var(var).ok()?)))
+            .collect();
+
+        Ok(Artifactory {
+            client: reqwest::Client::new(),
+            base,
+            repository: repository.to_owned(),
+            credentials,
+            properties,
+            scheduler: None,
+        })
+    }
+
+    /// Send requests through `scheduler`, so that they are rate-limited along
+    /// with those to the WebDAV backend.
+    pub fn with_scheduler(mut self, scheduler: Arc<Scheduler>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.acquire().await?;
+        }
+
+        let response = request.send().await?;
+
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.observe_response(&response);
+        }
+
+        Ok(response)
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> Result<reqwest::RequestBuilder> {
+        let url = self
+            .base
+            .join(path)
+            .map_err(|_| Error::BadUrl(self.base.clone()))?;
+
+        let mut request = self.client.request(method, url);
+
+        if let Some(request_id) = request_id::current() {
+            request = request.header(&request_id::HEADER, request_id);
+        }
+
+        Ok(match &self.credentials {
+            Some((login, password)) => request.basic_auth(login, Some(password)),
+            None => request,
+        })
+    }
+
+    /// Try to deploy `key` from a blob with the given SHA-256 that Artifactory
+    /// already stores. Returns `false` if the blob is unknown and must be uploaded.
+    pub async fn checksum_deploy(&self, key: &str, sha256: &str) -> Result<bool> {
+        let request = self
+            .request(
+                reqwest::Method::PUT,
+                &format!("{}/{}", self.repository, key),
+            )?
+            .header("X-Checksum-Deploy", "true")
+            .header("X-Checksum-Sha256", sha256);
+
+        let response = self.send(request).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+
+        response.error_for_status()?;
+
+        Ok(true)
+    }
+
+    /// Tag `key` with the properties of the current build.
+    pub async fn set_properties(&self, key: &str) -> Result<()> {
+        if self.properties.is_empty() {
+            return Ok(());
+        }
+
+        let properties = self
+            .properties
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let request = self
+            .request(
+                reqwest::Method::PUT,
+                &format!("api/storage/{}/{}", self.repository, key),
+            )?
+            .query(&[("properties", properties)]);
+
+        self.send(request).await?.error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Compute the SHA-256 and size of everything read from `reader`.
+pub async fn sha256(mut reader: impl AsyncRead + Unpin) -> Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
@@ -0,0 +1,140 @@
+//! An append-only log of the objects written to and deleted from the cache.
+//!
+//! Each line is a JSON object recording who made the change, when, on which
+//! backend, and what was changed, including the key, size and NAR hash. The
+//! log is flushed after every line, so it is complete even if the daemon is
+//! killed, and can be kept as a workflow artifact.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Upload,
+    ChecksumDeploy,
+    Delete,
+}
+
+/// An entry of the audit log, without the fields common to every entry.
+#[derive(Debug, Default, Serialize)]
+pub struct Entry<'a> {
+    pub key: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_path: Option<&'a Path>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nar_hash: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nar_size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Line<'a> {
+    timestamp: u64,
+    actor: &'a Actor,
+    backend: &'a str,
+    action: Action,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+
+    #[serde(flatten)]
+    entry: Entry<'a>,
+}
+
+/// Who is making changes: the user or workflow job.
+#[derive(Debug, Serialize)]
+struct Actor {
+    user: Option<String>,
+    repository: Option<String>,
+    workflow: Option<String>,
+    run_id: Option<String>,
+    job: Option<String>,
+    sha: Option<String>,
+}
+
+pub struct AuditLog {
+    file: Mutex<tokio::fs::File>,
+    actor: Actor,
+    backend: String,
+}
+
+impl AuditLog {
+    /// Append to the audit log at `path` for changes to `backend`.
+    pub async fn open(path: &Path, backend: String) -> Result<AuditLog> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| Error::Io(e, format!("Opening the audit log {}", path.display())))?;
+
+        let var = |name| std::env::var(name).ok();
+
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            actor: Actor {
+                user: var("GITHUB_ACTOR").or_else(|| var("USER")),
+                repository: var("GITHUB_REPOSITORY"),
+                workflow: var("GITHUB_WORKFLOW"),
+                run_id: var("GITHUB_RUN_ID"),
+                job: var("GITHUB_JOB"),
+                sha: var("GITHUB_SHA"),
+            },
+            backend,
+        })
+    }
+
+    /// Append an entry. Failures are logged, since they shouldn't fail the change itself.
+    pub async fn record(&self, action: Action, entry: Entry<'_>) {
+        let line = Line {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            actor: &self.actor,
+            backend: &self.backend,
+            action,
+            request_id: crate::request_id::current(),
+            entry,
+        };
+
+        let mut line = match serde_json::to_vec(&line) {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::error!("Failed to serialize an audit log entry: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        if let Err(err) = async {
+            file.write_all(&line).await?;
+            file.flush().await
+        }
+        .await
+        {
+            tracing::error!("Failed to write to the audit log: {}", err);
+        }
+    }
+}
+
+/// Record an entry in `audit_log`, if there is one.
+pub async fn record(audit_log: Option<&AuditLog>, action: Action, entry: Entry<'_>) {
+    if let Some(audit_log) = audit_log {
+        audit_log.record(action, entry).await;
+    }
+}
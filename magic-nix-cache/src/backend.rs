@@ -0,0 +1,310 @@
+//! Selection of the storage backend for the binary cache.
+//!
+//! Every job of a repository shares its GitHub Actions Cache, and the same
+//! 10 GB quota. With `--cache-namespace`, e.g. set to a project's directory in
+//! a monorepo, entries are stored under that namespace and with their own
+//! version, so that independent projects don't see or garbage-collect each
+//! other's entries, and each can have its own retention policy.
+//!
+//! `--cache-version` similarly keeps entries apart by version, e.g. one per
+//! branch, and `--restore-cache-version` makes the daemon look up the paths
+//! it doesn't have in other versions, e.g. that of the default branch,
+//! without writing to them, like the `restore-keys` of `actions/cache`.
+
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use opendal::{Operator, OperatorBuilder};
+use reqwest::Url;
+
+use crate::artifactory::Artifactory;
+use crate::buildkite::BuildkiteBackend;
+use crate::error::{Error, Result};
+use crate::exec::ExecBackend;
+use crate::github_app;
+use crate::gitlab::{self, GitLabBackend};
+use crate::http::HttpBackend;
+use crate::info;
+use crate::local::LocalBackend;
+use crate::s3::S3Config;
+use crate::secrets::SecretSource;
+use crate::service::ServiceConfig;
+
+/// The version of GitHub Actions Cache entries outside of any namespace.
+const GHA_VERSION: &str = "magic-nix-cache";
+
+static GHA_NAMESPACE: OnceLock<String> = OnceLock::new();
+
+static GHA_CACHE_VERSION: OnceLock<String> = OnceLock::new();
+
+/// Store GitHub Actions Cache entries in `namespace` from now on.
+pub fn init_gha_namespace(namespace: &str) -> Result<()> {
+    init_scope(
+        &GHA_NAMESPACE,
+        "cache namespace",
+        namespace.trim_matches('/'),
+    )
+}
+
+/// Write GitHub Actions Cache entries with `cache_version` from now on.
+pub fn init_gha_cache_version(cache_version: &str) -> Result<()> {
+    init_scope(&GHA_CACHE_VERSION, "cache version", cache_version)
+}
+
+fn init_scope(scope: &OnceLock<String>, what: &str, value: &str) -> Result<()> {
+    if value.is_empty()
+        || value
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+        || !value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+    {
+        return Err(Error::Config(format!(
+            "Invalid {} '{}': it must be a relative path of letters, digits, '-', '_' and '.'",
+            what, value
+        )));
+    }
+
+    if scope.get().is_some_and(|existing| existing != value) {
+        return Err(Error::Config(format!(
+            "A different {} is already in use",
+            what
+        )));
+    }
+
+    let _ = scope.set(value.to_owned());
+
+    Ok(())
+}
+
+/// The directory that the entries with `cache_version` are stored in, if
+/// there is a cache namespace or version. Versions get their own keys, since
+/// the REST API deletes entries by key across versions.
+fn gha_root(cache_version: Option<&str>) -> Option<String> {
+    let parts = GHA_NAMESPACE
+        .get()
+        .map(String::as_str)
+        .into_iter()
+        .chain(cache_version.filter(|version| !version.is_empty()))
+        .collect::<Vec<_>>();
+
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// The GitHub Actions Cache API for the entries of the binary cache, in the
+/// cache namespace and with the cache version if there are any.
+pub(crate) fn gha_api() -> gha_cache::ApiBuilder {
+    gha_api_for_version(GHA_CACHE_VERSION.get().map(String::as_str))
+}
+
+/// Like [`gha_api`], for the entries with `cache_version` instead of the
+/// ones that are written. `None` and `""` are the entries without a cache
+/// version.
+pub(crate) fn gha_api_for_version(cache_version: Option<&str>) -> gha_cache::ApiBuilder {
+    match gha_root(cache_version) {
+        Some(root) => gha_cache::Api::builder()
+            .version(format!("{}-{}", GHA_VERSION, root))
+            .root(root),
+        None => gha_cache::Api::builder().version(GHA_VERSION),
+    }
+}
+
+/// [`gha_api`] for the REST API operations, authenticated as the GitHub App
+/// if there is one.
+pub(crate) async fn gha_rest_api() -> Result<gha_cache::Api> {
+    Ok(github_app::authenticate(gha_api()).await?.build()?)
+}
+
+/// The key of an entry listed by the REST API, relative to the directory of
+/// the written entries, as the other operations expect it.
+pub(crate) fn gha_key(key: &str) -> String {
+    match gha_root(GHA_CACHE_VERSION.get().map(String::as_str)) {
+        Some(root) => key
+            .strip_prefix(&format!("{}/", root))
+            .unwrap_or(key)
+            .to_owned(),
+        None => key.to_owned(),
+    }
+}
+
+/// Where NARs and narinfos are stored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Backend {
+    /// The GitHub Actions Cache.
+    #[default]
+    Gha,
+
+    /// A WebDAV server, e.g. a generic Nexus or Artifactory repository.
+    Webdav(Url),
+
+    /// An Artifactory repository, accessed through WebDAV, with build
+    /// properties and checksum deploys.
+    Artifactory(Url),
+
+    /// An external helper program, see [`crate::exec`].
+    Exec(PathBuf),
+
+    /// A remote binary cache that accepts uploads over `PUT`, see [`crate::http`].
+    Http(Url),
+
+    /// An S3 bucket, at AWS or at one of the providers in [`crate::s3`].
+    S3(S3Config),
+
+    /// Any other service that opendal supports, see [`crate::service`].
+    Service(ServiceConfig),
+
+    /// A generic package in the registry of the GitLab project of the job,
+    /// see [`crate::gitlab`].
+    GitLab(String),
+
+    /// The artifacts of the builds of the Buildkite pipeline of the job, see
+    /// [`crate::buildkite`].
+    Buildkite,
+
+    /// A directory on the runner's disk, holding at most `max_size` bytes,
+    /// see [`crate::local`].
+    Local { dir: PathBuf, max_size: u64 },
+}
+
+impl Backend {
+    /// Create the operator for this backend, reading credentials from the
+    /// `netrc` contents when the backend needs them.
+    pub(crate) fn operator(&self, netrc: Option<&str>) -> Result<Operator> {
+        match self {
+            Backend::Gha => Ok(gha_api().build()?.operator().clone()),
+            Backend::Webdav(url) | Backend::Artifactory(url) => {
+                let mut builder = opendal::services::Webdav::default()
+                    .endpoint(&url.origin().ascii_serialization())
+                    .root(url.path());
+
+                if let Some((login, password)) = netrc_credentials(netrc, url)? {
+                    builder = builder.username(&login).password(&password);
+                }
+
+                Ok(Operator::new(builder)?.finish())
+            }
+            Backend::Exec(helper) => {
+                Ok(OperatorBuilder::new(ExecBackend::new(helper.clone())).finish())
+            }
+            Backend::Http(url) => Ok(OperatorBuilder::new(HttpBackend::new(
+                url,
+                netrc_credentials(netrc, url)?,
+            ))
+            .finish()),
+            Backend::S3(config) => config.operator(),
+            Backend::Service(config) => config.operator(),
+            Backend::GitLab(package) => {
+                Ok(OperatorBuilder::new(GitLabBackend::new(package)?).finish())
+            }
+            Backend::Buildkite => Ok(OperatorBuilder::new(BuildkiteBackend::new()?).finish()),
+            Backend::Local { dir, max_size } => {
+                Ok(OperatorBuilder::new(LocalBackend::new(dir, *max_size)?).finish())
+            }
+        }
+    }
+
+    /// Create the Artifactory client, if this is an Artifactory backend.
+    pub(crate) fn artifactory(&self, netrc: Option<&str>) -> Result<Option<Artifactory>> {
+        match self {
+            Backend::Artifactory(url) => {
+                Ok(Some(Artifactory::new(url, netrc_credentials(netrc, url)?)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// This backend with any credentials removed from its URL.
+    pub(crate) fn redacted(&self) -> Backend {
+        match self {
+            Backend::Webdav(url) => Backend::Webdav(info::redact_url(url)),
+            Backend::Artifactory(url) => Backend::Artifactory(info::redact_url(url)),
+            Backend::Http(url) => Backend::Http(info::redact_url(url)),
+            backend => backend.clone(),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "gha" => Ok(Backend::Gha),
+            Some(("webdav", url)) => Ok(Backend::Webdav(parse_url(url)?)),
+            Some(("artifactory", url)) => Ok(Backend::Artifactory(parse_url(url)?)),
+            Some(("exec", helper)) if !helper.is_empty() => Ok(Backend::Exec(helper.into())),
+            Some(("http" | "https", _)) => Ok(Backend::Http(parse_url(s)?)),
+            Some((provider @ ("s3" | "r2" | "b2"), spec)) => {
+                Ok(Backend::S3(S3Config::parse(provider, spec)?))
+            }
+            Some(("opendal", spec)) => Ok(Backend::Service(ServiceConfig::parse(spec)?)),
+            None if s == "gitlab" => Ok(Backend::GitLab(gitlab::DEFAULT_PACKAGE.to_owned())),
+            Some(("gitlab", package)) => Ok(Backend::GitLab(gitlab::parse_package(package)?)),
+            None if s == "buildkite" => Ok(Backend::Buildkite),
+            _ => Err(Error::Config(format!(
+                "unknown backend '{}', expected 'gha', 'webdav:<url>', 'artifactory:<url>', 'exec:<path>', 's3:<bucket>', 'r2:<account-id>/<bucket>', 'b2:<region>/<bucket>', 'opendal:<scheme>?<options>', 'gitlab[:<package>]', 'buildkite' or an HTTP(S) URL",
+                s
+            ))),
+        }
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Gha => write!(f, "GitHub Actions Cache"),
+            Backend::Webdav(url) => write!(f, "WebDAV server {}", url),
+            Backend::Artifactory(url) => write!(f, "Artifactory repository {}", url),
+            Backend::Exec(helper) => write!(f, "exec helper {}", helper.display()),
+            Backend::Http(url) => write!(f, "remote binary cache {}", url),
+            Backend::S3(config) => write!(f, "{}", config),
+            Backend::Service(config) => write!(f, "{}", config),
+            Backend::GitLab(package) => write!(f, "GitLab package {}", package),
+            Backend::Buildkite => write!(f, "Buildkite artifacts"),
+            Backend::Local { dir, .. } => write!(f, "local cache in {}", dir.display()),
+        }
+    }
+}
+
+fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).map_err(|e| Error::Config(format!("bad URL '{}': {}", url, e)))
+}
+
+/// Read the `netrc` file with the credentials for the backend from `source`,
+/// or from `~/.netrc` if it exists.
+pub(crate) async fn read_netrc(source: Option<&SecretSource>) -> Result<Option<String>> {
+    let source = match source {
+        Some(source) => source.clone(),
+        None => match std::env::var_os("HOME") {
+            Some(home) if Path::new(&home).join(".netrc").exists() => {
+                SecretSource::File(Path::new(&home).join(".netrc"))
+            }
+            _ => return Ok(None),
+        },
+    };
+
+    Ok(Some(source.resolve_string().await?))
+}
+
+/// Look up the login and password for the host of `url` in the `netrc` contents.
+pub(crate) fn netrc_credentials(
+    netrc: Option<&str>,
+    url: &Url,
+) -> Result<Option<(String, String)>> {
+    let Some(contents) = netrc else {
+        return Ok(None);
+    };
+
+    let netrc = netrc_rs::Netrc::parse(contents.to_owned(), false).map_err(Error::Netrc)?;
+
+    let host = url.host_str();
+
+    Ok(netrc
+        .machines
+        .into_iter()
+        .find(|machine| machine.name.as_deref() == host)
+        .and_then(|machine| Some((machine.login?, machine.password?))))
+}
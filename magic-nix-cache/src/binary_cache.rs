@@ -1,45 +1,139 @@
 //! Binary Cache API.
+//!
+//! Responses carry the content types used by other binary cache servers, and
+//! `HEAD` requests (which Nix uses to check whether a path exists before
+//! uploading it) are answered without reading the object. They also carry
+//! `Cache-Control` headers, so that a CDN or nginx cache can be put in front
+//! of a long-lived daemon.
+//!
+//! The cache is also served below `/system/<system>/`, where narinfos for
+//! other systems are hidden, so that runners probing a cache shared between
+//! systems don't download entries they can't use.
+//!
+//! `/nar/?list=1` lists the stored NARs with their sizes, for finding out
+//! what uses up the quota. It is an admin endpoint, see [`crate::admin`].
+//!
+//! `/public-keys` serves the public keys that the narinfos are signed with,
+//! as a `trusted-public-keys` line for the nix.conf of the machines that
+//! use the cache.
+
+use std::collections::HashMap;
 
 use axum::{
     body::Body,
     extract::{Extension, Path},
+    http::{header, HeaderMap, Uri},
     response::{IntoResponse, Redirect, Response},
-    routing::{get, put},
-    Router,
-};
-use futures::{AsyncWriteExt, StreamExt as _};
-use tokio::io::copy;
-use tokio_util::{
-    compat::{FuturesAsyncWriteCompatExt, TokioAsyncWriteCompatExt},
-    io::StreamReader,
+    routing::{get, head, put},
+    Json, Router,
 };
+use futures::StreamExt as _;
+use opendal::Operator;
+use serde::Serialize;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio_util::io::StreamReader;
 
 use super::State;
+use crate::audit::{self, Action};
 use crate::error::{Error, Result};
+use crate::lru::LruCache;
+use crate::upload::Upload;
+use crate::{admin, backend, gha, keys, mirror, routing, scoped};
 
 pub fn get_router() -> Router {
     Router::new()
         .route("/nix-cache-info", get(get_nix_cache_info))
+        .route("/public-keys", get(get_public_keys))
         // .narinfo
         .route("/:path", get(get_narinfo))
+        .route("/:path", head(head_narinfo))
         .route("/:path", put(put_narinfo))
         // .nar
+        .route("/nar/", get(list_nars))
         .route("/nar/:path", get(get_nar))
+        .route("/nar/:path", head(head_nar))
         .route("/nar/:path", put(put_nar))
+        // Filtered by system. `get` also answers `HEAD`.
+        .route("/system/:system/nix-cache-info", get(get_nix_cache_info))
+        .route("/system/:system/:path", get(get_system_narinfo))
+        .route("/system/:system/nar/:path", get(get_system_nar))
 }
 
-async fn get_nix_cache_info() -> &'static str {
+const NIX_CACHE_INFO_CONTENT_TYPE: &str = "text/x-nix-cache-info";
+const NARINFO_CONTENT_TYPE: &str = "text/x-nix-narinfo";
+pub(crate) const NAR_CONTENT_TYPE: &str = "application/x-nix-nar";
+
+/// NARs are named after their hash, so a CDN or proxy in front of the daemon
+/// can keep them forever.
+pub(crate) const NAR_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Narinfos can disappear, e.g. when their NAR turns out to be corrupt, so
+/// they are only cached for a while.
+const NARINFO_CACHE_CONTROL: &str = "public, max-age=3600";
+
+/// Narinfos are a few kilobytes, so uploads of bigger ones are refused.
+const MAX_NARINFO_SIZE: usize = 1 << 20;
+
+/// The most that is buffered in memory before it is sent to the backend,
+/// when uploading a NAR of known size.
+const MAX_UPLOAD_CHUNK: u64 = 32 << 20;
+
+async fn get_nix_cache_info() -> impl IntoResponse {
     // TODO: Make StoreDir configurable
-    r#"WantMassQuery: 1
+    (
+        [(header::CONTENT_TYPE, NIX_CACHE_INFO_CONTENT_TYPE)],
+        r#"WantMassQuery: 1
 StoreDir: /nix/store
 Priority: 41
-"#
+"#,
+    )
+}
+
+async fn get_public_keys(Extension(state): Extension<State>) -> Result<impl IntoResponse> {
+    let signing_keys = state.signing_keys.as_ref().ok_or(Error::NotFound)?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain")],
+        format!(
+            "trusted-public-keys = {}\n",
+            signing_keys.public_keys().join(" ")
+        ),
+    ))
+}
+
+/// Get the store path hash from the name of a narinfo, e.g. `<hash>.narinfo`.
+pub(crate) fn narinfo_hash(path: &str) -> Option<&str> {
+    path.strip_suffix(".narinfo")
+        .filter(|hash| !hash.is_empty() && !hash.contains('.'))
+}
+
+fn narinfo_response(content: axum::body::Bytes) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, NARINFO_CONTENT_TYPE),
+            (header::CACHE_CONTROL, NARINFO_CACHE_CONTROL),
+        ],
+        content,
+    )
+        .into_response()
 }
 
 async fn get_narinfo(
     Extension(state): Extension<State>,
     Path(path): Path<String>,
-) -> Result<impl IntoResponse> {
+) -> Result<Response> {
+    narinfo(&state, &path, None).await
+}
+
+async fn get_system_narinfo(
+    Extension(state): Extension<State>,
+    Path((system, path)): Path<(String, String)>,
+) -> Result<Response> {
+    narinfo(&state, &path, Some(&system)).await
+}
+
+/// Serve the narinfo at `path`, unless it is for another system than `system`.
+async fn narinfo(state: &State, path: &str, system: Option<&str>) -> Result<Response> {
     let components: Vec<&str> = path.splitn(2, '.').collect();
 
     if components.len() != 2 {
@@ -51,7 +145,22 @@ async fn get_narinfo(
     }
 
     let store_path_hash = components[0].to_string();
-    let key = format!("{}.narinfo", store_path_hash);
+
+    if let Some(content) = state
+        .written_narinfos
+        .read()
+        .await
+        .get(&store_path_hash)
+        .cloned()
+    {
+        state.metrics.narinfos_served.incr();
+        return serve_narinfo(content, system);
+    }
+
+    if let Some(content) = bundled_narinfo(state, &store_path_hash) {
+        state.metrics.narinfos_served.incr();
+        return serve_narinfo(content, system);
+    }
 
     if state
         .narinfo_negative_cache
@@ -61,22 +170,172 @@ async fn get_narinfo(
     {
         state.metrics.narinfos_sent_upstream.incr();
         state.metrics.narinfos_negative_cache_hits.incr();
-        return pull_through(&state, &path);
+        return upstream_narinfo(state, path, &store_path_hash, system).await;
     }
 
     if let Some(gha_cache) = &state.gha_cache {
-        if let Ok(content) = gha_cache.api.read(&key).await {
-            state.metrics.narinfos_served.incr();
-            return Ok(content.to_bytes().into_response());
+        if !gha_cache.health.is_available() {
+            if let Some(content) = stale_narinfo(state, &store_path_hash).await {
+                return serve_narinfo(content, system);
+            }
+        } else {
+            match gha_cache
+                .read_any(&keys::narinfo_candidates(&store_path_hash))
+                .await
+            {
+                Ok(content) => {
+                    let content = content.to_bytes();
+                    if let Some(narinfo_index) = &gha_cache.narinfo_index {
+                        narinfo_index.touch(&store_path_hash).await;
+                    }
+                    state
+                        .narinfo_cache
+                        .lock()
+                        .await
+                        .insert(store_path_hash, content.clone());
+                    state.metrics.narinfos_served.incr();
+                    return serve_narinfo(content, system);
+                }
+                Err(err) if err.kind() != opendal::ErrorKind::NotFound => {
+                    tracing::warn!("Reading '{}' failed: {}", path, err);
+                    if let Some(content) = stale_narinfo(state, &store_path_hash).await {
+                        return serve_narinfo(content, system);
+                    }
+                }
+                Err(_) => {}
+            }
         }
     }
 
-    let mut negative_cache = state.narinfo_negative_cache.write().await;
-    negative_cache.insert(store_path_hash);
+    state
+        .narinfo_negative_cache
+        .write()
+        .await
+        .insert(store_path_hash.clone());
 
     state.metrics.narinfos_sent_upstream.incr();
     state.metrics.narinfos_negative_cache_misses.incr();
-    pull_through(&state, &path)
+    upstream_narinfo(state, path, &store_path_hash, system).await
+}
+
+/// Fetch the narinfo at `path` from the upstream cache with pull-through
+/// caching or trusted keys, or redirect the client there.
+async fn upstream_narinfo(
+    state: &State,
+    path: &str,
+    store_path_hash: &str,
+    system: Option<&str>,
+) -> Result<Response> {
+    let Some(upstream) = &state.upstream_client else {
+        return pull_through(state, path);
+    };
+
+    match mirror::narinfo(state, upstream, store_path_hash).await {
+        Ok(Some(content)) => serve_narinfo(content, system),
+        Ok(None) => Err(Error::NotFound),
+        Err(err) => {
+            tracing::warn!(
+                "Fetching '{}' from the upstream cache failed: {}",
+                path,
+                err
+            );
+
+            // The client would get the narinfo without it being checked.
+            if state.trusted_keys.is_some() {
+                return Err(err);
+            }
+
+            pull_through(state, path)
+        }
+    }
+}
+
+/// The narinfo of a path in a bundle.
+fn bundled_narinfo(state: &State, store_path_hash: &str) -> Option<axum::body::Bytes> {
+    state
+        .gha_cache
+        .as_ref()?
+        .bundles
+        .as_ref()?
+        .narinfo(store_path_hash)
+}
+
+/// Respond with a narinfo, or a 404 if it is for another system than `system`.
+fn serve_narinfo(content: axum::body::Bytes, system: Option<&str>) -> Result<Response> {
+    if let Some(system) = system {
+        let narinfo_system = String::from_utf8_lossy(&content)
+            .lines()
+            .find_map(|line| line.strip_prefix("System: ").map(str::to_owned));
+
+        // Paths without a system, e.g. sources, are usable everywhere.
+        if narinfo_system.is_some_and(|narinfo_system| narinfo_system != system) {
+            return Err(Error::NotFound);
+        }
+    }
+
+    Ok(narinfo_response(content))
+}
+
+async fn head_narinfo(
+    Extension(state): Extension<State>,
+    Path(path): Path<String>,
+) -> Result<Response> {
+    let store_path_hash = narinfo_hash(&path).ok_or(Error::NotFound)?;
+
+    let written = state
+        .written_narinfos
+        .read()
+        .await
+        .get(store_path_hash)
+        .cloned();
+
+    if let Some(content) = written.or_else(|| bundled_narinfo(&state, store_path_hash)) {
+        return Ok([
+            (header::CONTENT_TYPE, NARINFO_CONTENT_TYPE.to_owned()),
+            (header::CACHE_CONTROL, NARINFO_CACHE_CONTROL.to_owned()),
+            (header::CONTENT_LENGTH, content.len().to_string()),
+        ]
+        .into_response());
+    }
+
+    if state
+        .narinfo_negative_cache
+        .read()
+        .await
+        .contains(store_path_hash)
+    {
+        return upstream_narinfo(&state, &path, store_path_hash, None).await;
+    }
+
+    if let Some(gha_cache) = &state.gha_cache {
+        if !gha_cache.health.is_available() {
+            if state
+                .narinfo_cache
+                .lock()
+                .await
+                .get(store_path_hash)
+                .is_some()
+            {
+                return Ok([
+                    (header::CONTENT_TYPE, NARINFO_CONTENT_TYPE),
+                    (header::CACHE_CONTROL, NARINFO_CACHE_CONTROL),
+                ]
+                .into_response());
+            }
+        } else if let Some((_, _, stat)) = gha_cache
+            .stat_any(&keys::narinfo_candidates(store_path_hash))
+            .await?
+        {
+            return Ok([
+                (header::CONTENT_TYPE, NARINFO_CONTENT_TYPE.to_owned()),
+                (header::CACHE_CONTROL, NARINFO_CACHE_CONTROL.to_owned()),
+                (header::CONTENT_LENGTH, stat.content_length().to_string()),
+            ]
+            .into_response());
+        }
+    }
+
+    upstream_narinfo(&state, &path, store_path_hash, None).await
 }
 
 async fn put_narinfo(
@@ -96,28 +355,57 @@ async fn put_narinfo(
 
     let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
 
-    let store_path_hash = components[0].to_string();
-    let key = format!("{}.narinfo", store_path_hash);
-
-    let body_stream = body.into_data_stream();
-    let mut stream = StreamReader::new(
-        body_stream
-            .map(|r| r.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))),
-    );
+    if !gha_cache.health.is_available() {
+        return Err(Error::BackendUnavailable);
+    }
 
-    let mut writer = gha_cache
-        .api
-        .writer(&key)
-        .await?
-        .into_futures_async_write()
-        .compat_write();
+    let store_path_hash = components[0].to_string();
+    let key = keys::narinfo(&store_path_hash);
 
-    copy(&mut stream, &mut writer).await?;
+    let content = axum::body::to_bytes(body, MAX_NARINFO_SIZE)
+        .await
+        .map_err(|_| Error::BadRequest)?;
+    let size = content.len() as u64;
+
+    let api = match routing::narinfo_name(&String::from_utf8_lossy(&content)) {
+        Some(name) => gha_cache.api_for(name),
+        None => &gha_cache.api,
+    };
+
+    // `nix copy` uploads the NAR before its narinfo, so the NAR went to the
+    // default backend, and follows the narinfo to the one it's routed to.
+    if !std::ptr::eq(api, &*gha_cache.api) {
+        if let Some(file_name) = nar_url(&content)
+            .as_deref()
+            .and_then(|url| url.strip_prefix("nar/"))
+        {
+            move_nar(&state, gha_cache, api, file_name).await?;
+        }
+    }
 
-    writer.compat_write().close().await?;
+    api.write(&key, content.clone()).await?;
 
     state.metrics.narinfos_uploaded.incr();
 
+    audit::record(
+        state.audit_log.as_deref(),
+        Action::Upload,
+        audit::Entry {
+            key: &key,
+            size: Some(size),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // The backend may not serve the narinfo right away, e.g. while the write
+    // propagates, but clients expect to read what they just wrote.
+    state
+        .written_narinfos
+        .write()
+        .await
+        .insert(store_path_hash.clone(), content);
+
     state
         .narinfo_negative_cache
         .write()
@@ -127,64 +415,490 @@ async fn put_narinfo(
     Ok(())
 }
 
-async fn get_nar(
+/// Move the NAR served as `nar/<file_name>` from the default backend to
+/// `api`, unless `api` already has it or the default backend doesn't.
+async fn move_nar(
+    state: &State,
+    gha_cache: &gha::GhaCache,
+    api: &Operator,
+    file_name: &str,
+) -> Result<()> {
+    let key = keys::nar(file_name);
+
+    if api.stat(&key).await.is_ok() {
+        return Ok(());
+    }
+
+    let reader = match gha_cache.api.reader(&key).await {
+        Ok(reader) => reader,
+        Err(err) if err.kind() == opendal::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut nar = reader.into_futures_async_read(..).await?.compat();
+
+    let mut upload = Upload::new(api.writer(&key).await?, &key);
+    let size = upload.copy(&mut nar).await?;
+    upload.close().await?;
+
+    audit::record(
+        state.audit_log.as_deref(),
+        Action::Upload,
+        audit::Entry {
+            key: &key,
+            size: Some(size),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // NARs are looked up in every backend, so narinfos in the default
+    // backend that share it still find it.
+    gha::remove_existing(&gha_cache.api, state.audit_log.as_deref(), &key).await?;
+
+    tracing::debug!("Moved '{}' to the backend its narinfo is routed to", key);
+
+    Ok(())
+}
+
+async fn get_system_nar(
+    state: Extension<State>,
+    Path((_system, path)): Path<(String, String)>,
+) -> Result<Response> {
+    get_nar(state, Path(path)).await
+}
+
+async fn get_nar(Extension(state): Extension<State>, Path(path): Path<String>) -> Result<Response> {
+    let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
+
+    if let Some(bundles) = &gha_cache.bundles {
+        match bundles.nar(&gha_cache.api, &path).await {
+            Ok(Some(nar)) => {
+                state.metrics.nars_served.incr();
+                state.metrics.nar_bytes_served.add(nar.len());
+                return Ok((
+                    [
+                        (header::CONTENT_TYPE, NAR_CONTENT_TYPE.to_owned()),
+                        (header::CACHE_CONTROL, NAR_CACHE_CONTROL.to_owned()),
+                        (header::CONTENT_LENGTH, nar.len().to_string()),
+                    ],
+                    nar,
+                )
+                    .into_response());
+            }
+            Ok(None) => {}
+            Err(err) => tracing::warn!("Reading the bundle of 'nar/{}' failed: {}", path, err),
+        }
+    }
+
+    let (store_path_hash, expected_size) = match narinfo_for_nar(&state, &path).await {
+        Some((store_path_hash, expected_size)) => (Some(store_path_hash), expected_size),
+        None => (None, None),
+    };
+
+    if gha_cache.health.is_available() {
+        if let Ok(Some((api, key, stat))) = gha_cache.stat_any(&keys::nar_candidates(&path)).await {
+            let size = stat.content_length();
+
+            // A NAR is never empty, even when compressed.
+            if size == 0 || expected_size.is_some_and(|expected_size| expected_size != size) {
+                discard_corrupt_nar(
+                    &state,
+                    api,
+                    &key,
+                    store_path_hash.clone(),
+                    size,
+                    expected_size,
+                )
+                .await;
+            } else {
+                let reader = api.reader(&key).await?;
+                state.metrics.nars_served.incr();
+                state.metrics.nar_bytes_served.add(size as usize);
+                return Ok((
+                    [
+                        (header::CONTENT_TYPE, NAR_CONTENT_TYPE.to_owned()),
+                        (header::CACHE_CONTROL, NAR_CACHE_CONTROL.to_owned()),
+                        (header::CONTENT_LENGTH, size.to_string()),
+                    ],
+                    Body::from_stream(reader.into_bytes_stream(..).await?),
+                )
+                    .into_response());
+            }
+        }
+    }
+
+    if let Some(upstream) = state
+        .upstream_client
+        .as_ref()
+        .filter(|_| state.pull_through && gha_cache.health.is_available())
+    {
+        match mirror::nar(&state, upstream, &path, store_path_hash.clone()).await {
+            Ok(Some(response)) => return Ok(response),
+            Ok(None) => return Err(Error::NotFound),
+            Err(err) => {
+                tracing::warn!(
+                    "Fetching 'nar/{}' from the upstream cache failed: {}",
+                    path,
+                    err
+                )
+            }
+        }
+    }
+
+    if let Some(upstream) = nar_upstream(&state, store_path_hash.as_deref()) {
+        state.metrics.nars_sent_upstream.incr();
+        Ok(Redirect::temporary(&format!("{}/nar/{}", upstream, path)).into_response())
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+/// The upstream cache to redirect requests for a NAR to, which is the one
+/// that served the narinfo of `store_path_hash` if there are several.
+fn nar_upstream(state: &State, store_path_hash: Option<&str>) -> Option<String> {
+    match &state.upstream_client {
+        Some(upstream) if upstream.is_chain() => Some(upstream.url_for(store_path_hash).to_owned()),
+        _ => state.upstream.clone(),
+    }
+}
+
+/// Recently served narinfos, keyed by store path hash, along with the NAR
+/// URL of each, so that NAR requests find their narinfo without parsing
+/// every one.
+pub struct NarinfoCache {
+    narinfos: LruCache<String, axum::body::Bytes>,
+
+    /// The store path hash of the narinfo with each URL, like `nar/<path>`.
+    nar_urls: HashMap<String, String>,
+}
+
+impl NarinfoCache {
+    pub fn new(capacity: usize) -> NarinfoCache {
+        NarinfoCache {
+            narinfos: LruCache::new(capacity),
+            nar_urls: HashMap::new(),
+        }
+    }
+
+    /// Return a copy of the narinfo of `store_path_hash`, marking it as recently used.
+    pub fn get(&mut self, store_path_hash: &str) -> Option<axum::body::Bytes> {
+        self.narinfos.get(store_path_hash)
+    }
+
+    /// Remember the narinfo of `store_path_hash`, forgetting the least
+    /// recently used one if the cache is full.
+    pub fn insert(&mut self, store_path_hash: String, content: axum::body::Bytes) {
+        let url = nar_url(&content);
+
+        if let Some((removed_hash, removed)) =
+            self.narinfos.insert(store_path_hash.clone(), content)
+        {
+            if let Some(removed_url) = nar_url(&removed) {
+                if self.nar_urls.get(&removed_url) == Some(&removed_hash) {
+                    self.nar_urls.remove(&removed_url);
+                }
+            }
+        }
+
+        if let Some(url) = url {
+            self.nar_urls.insert(url, store_path_hash);
+        }
+    }
+
+    /// The store path hash and content of the narinfo whose URL is `url`.
+    fn find_by_url(&mut self, url: &str) -> Option<(String, axum::body::Bytes)> {
+        let store_path_hash = self.nar_urls.get(url)?.clone();
+        let content = self.narinfos.get(&store_path_hash)?;
+        Some((store_path_hash, content))
+    }
+}
+
+/// The `URL` field of a narinfo.
+fn nar_url(content: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .find_map(|line| line.strip_prefix("URL: "))
+        .map(|url| url.trim().to_owned())
+}
+
+/// Find the recently served narinfo whose URL is `nar/<path>`, returning
+/// its store path hash and the size that the NAR should have, if the
+/// narinfo says so. Nix always fetches the narinfo before the NAR.
+async fn narinfo_for_nar(state: &State, path: &str) -> Option<(String, Option<u64>)> {
+    let (store_path_hash, content) = state
+        .narinfo_cache
+        .lock()
+        .await
+        .find_by_url(&format!("nar/{}", path))?;
+
+    let content = String::from_utf8_lossy(&content);
+    let field = |name| {
+        content
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(str::trim)
+    };
+
+    // `NarSize` is the size of the uncompressed NAR, so it is only the size
+    // of the object when there's no `FileSize` and the NAR isn't compressed.
+    let expected_size = match field("FileSize: ") {
+        Some(file_size) => file_size.parse().ok(),
+        None if field("Compression: ") == Some("none") => {
+            field("NarSize: ").and_then(|nar_size| nar_size.parse().ok())
+        }
+        None => None,
+    };
+
+    Some((store_path_hash, expected_size))
+}
+
+/// Get rid of the corrupt NAR under `key` in `api`, and of the narinfo of
+/// `store_path_hash` that refers to it, so that Nix fetches the path from
+/// upstream and it gets uploaded again.
+async fn discard_corrupt_nar(
+    state: &State,
+    api: &Operator,
+    key: &str,
+    store_path_hash: Option<String>,
+    size: u64,
+    expected_size: Option<u64>,
+) {
+    match expected_size {
+        Some(expected_size) => tracing::warn!(
+            "The NAR '{}' is {} bytes instead of {}, discarding it",
+            key,
+            size,
+            expected_size
+        ),
+        None => tracing::warn!("The NAR '{}' is empty, discarding it", key),
+    }
+    state.metrics.nars_corrupt.incr();
+
+    let audit_log = state.audit_log.as_deref();
+
+    if let Err(err) = gha::remove_existing(api, audit_log, key).await {
+        tracing::warn!("Deleting the corrupt NAR '{}' failed: {}", key, err);
+    }
+
+    if let Some(store_path_hash) = store_path_hash {
+        for key in keys::narinfo_candidates(&store_path_hash) {
+            if let Err(err) = gha::remove_existing(api, audit_log, &key).await {
+                tracing::warn!("Deleting the narinfo '{}' failed: {}", key, err);
+            }
+        }
+
+        // Backends that can't delete keep serving the narinfo otherwise.
+        state
+            .narinfo_negative_cache
+            .write()
+            .await
+            .insert(store_path_hash);
+    }
+}
+
+async fn head_nar(
     Extension(state): Extension<State>,
     Path(path): Path<String>,
-) -> Result<impl IntoResponse> {
-    if let Ok(reader) = state
-        .gha_cache
+) -> Result<Response> {
+    let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
+
+    if let Some(size) = gha_cache
+        .bundles
         .as_ref()
-        .ok_or(Error::GHADisabled)?
-        .api
-        .reader(&path)
-        .await
+        .and_then(|bundles| bundles.nar_size(&path))
     {
-        let stat = state.gha_cache.as_ref().unwrap().api.stat(&path).await?;
-        state.metrics.nars_served.incr();
-        return Ok((
-            [(axum::http::header::CONTENT_LENGTH, stat.content_length())],
-            Body::from_stream(reader.into_bytes_stream(..).await?),
-        )
+        return Ok([
+            (header::CONTENT_TYPE, NAR_CONTENT_TYPE.to_owned()),
+            (header::CACHE_CONTROL, NAR_CACHE_CONTROL.to_owned()),
+            (header::CONTENT_LENGTH, size.to_string()),
+        ]
+        .into_response());
+    }
+
+    if gha_cache.health.is_available() {
+        // `GET` discards empty NARs, so don't claim to have them.
+        if let Some((_, _, stat)) = gha_cache
+            .stat_any(&keys::nar_candidates(&path))
+            .await?
+            .filter(|(_, _, stat)| stat.content_length() > 0)
+        {
+            return Ok([
+                (header::CONTENT_TYPE, NAR_CONTENT_TYPE.to_owned()),
+                (header::CACHE_CONTROL, NAR_CACHE_CONTROL.to_owned()),
+                (header::CONTENT_LENGTH, stat.content_length().to_string()),
+            ]
             .into_response());
+        }
     }
 
-    if let Some(upstream) = &state.upstream {
-        state.metrics.nars_sent_upstream.incr();
+    let store_path_hash = narinfo_for_nar(&state, &path)
+        .await
+        .map(|(store_path_hash, _)| store_path_hash);
+
+    if let Some(upstream) = nar_upstream(&state, store_path_hash.as_deref()) {
         Ok(Redirect::temporary(&format!("{}/nar/{}", upstream, path)).into_response())
     } else {
         Err(Error::NotFound)
     }
 }
 
+/// A NAR in a backend, as listed by `/nar/?list=1`.
+#[derive(Serialize)]
+struct NarObject {
+    key: String,
+    size: u64,
+    last_modified: Option<String>,
+}
+
+async fn list_nars(
+    Extension(state): Extension<State>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Json<Vec<NarObject>>> {
+    if !uri
+        .query()
+        .is_some_and(|query| query.split('&').any(|param| param == "list=1"))
+    {
+        return Err(Error::NotFound);
+    }
+
+    admin::authorize(&state, &headers)?;
+
+    let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
+    let prefix = keys::nars();
+    let mut objects = Vec::new();
+
+    for api in gha_cache.apis() {
+        if api.info().scheme() == opendal::Scheme::Ghac {
+            // The cache service can't list entries, but the REST API can.
+            let rest = backend::gha_rest_api().await?;
+
+            objects.extend(
+                rest.list(&prefix)
+                    .await?
+                    .into_iter()
+                    .map(|entry| NarObject {
+                        key: backend::gha_key(&entry.key),
+                        size: entry.size_in_bytes,
+                        last_modified: Some(entry.created_at),
+                    }),
+            );
+        } else if api.info().full_capability().list {
+            objects.extend(
+                api.list(&prefix)
+                    .await?
+                    .into_iter()
+                    .filter(|entry| entry.metadata().is_file())
+                    .map(|entry| NarObject {
+                        key: entry.path().to_owned(),
+                        size: entry.metadata().content_length(),
+                        last_modified: entry
+                            .metadata()
+                            .last_modified()
+                            .map(|last_modified| last_modified.to_rfc3339()),
+                    }),
+            );
+        } else {
+            tracing::debug!(
+                "Not listing the NARs in the {} backend, which can't list entries",
+                api.info().scheme()
+            );
+        }
+    }
+
+    Ok(Json(objects))
+}
+
 async fn put_nar(
     Extension(state): Extension<State>,
+    scoped: Option<Extension<scoped::Scoped>>,
     Path(path): Path<String>,
+    headers: HeaderMap,
     body: axum::body::Body,
 ) -> Result<()> {
     let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
 
+    if !gha_cache.health.is_available() {
+        return Err(Error::BackendUnavailable);
+    }
+
+    let key = keys::nar(&path);
+
+    // Any scoped token may push NARs, so it mustn't be able to replace the
+    // NAR of a path that others substitute.
+    if scoped.is_some() && gha_cache.api.exists(&key).await? {
+        tracing::warn!(
+            "Rejecting NAR '{}' pushed with a scoped token, which is already in the cache",
+            path
+        );
+        return Err(Error::Conflict);
+    }
+
     let body_stream = body.into_data_stream();
     let mut stream = StreamReader::new(
         body_stream
             .map(|r| r.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))),
     );
 
-    let mut writer = gha_cache
-        .api
-        .writer(&path)
-        .await?
-        .into_futures_async_write()
-        .compat_write();
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
 
-    copy(&mut stream, &mut writer).await?;
+    // With the size known up front, backends can upload small NARs in a
+    // single request instead of in parts.
+    let mut writer = gha_cache.api.writer_with(&key);
+    if let Some(content_length) = content_length {
+        writer = writer.chunk(content_length.clamp(1, MAX_UPLOAD_CHUNK) as usize);
+    }
+
+    let mut upload = Upload::new(writer.await?, &key);
+
+    let size = upload.copy(&mut stream).await?;
+
+    // Dropping the upload without closing it aborts it.
+    if content_length.is_some_and(|content_length| content_length != size) {
+        tracing::warn!(
+            "Rejecting NAR '{}' of {} bytes, whose Content-Length was {:?}",
+            path,
+            size,
+            content_length
+        );
+        return Err(Error::BadRequest);
+    }
 
-    writer.compat_write().close().await?;
+    upload.close().await?;
 
     state.metrics.nars_uploaded.incr();
+    state.metrics.nar_bytes_uploaded.add(size as usize);
+
+    audit::record(
+        state.audit_log.as_deref(),
+        Action::Upload,
+        audit::Entry {
+            key: &key,
+            size: Some(size),
+            ..Default::default()
+        },
+    )
+    .await;
 
     Ok(())
 }
 
+/// A previously fetched copy of a narinfo, for when the backend can't be read.
+async fn stale_narinfo(state: &State, store_path_hash: &str) -> Option<axum::body::Bytes> {
+    let content = state.narinfo_cache.lock().await.get(store_path_hash)?;
+
+    tracing::warn!(
+        "Serving a stale copy of '{}.narinfo' from the local cache",
+        store_path_hash
+    );
+    state.metrics.narinfos_served_stale.incr();
+
+    Some(content)
+}
+
 fn pull_through(state: &State, path: &str) -> Result<Response> {
     if let Some(upstream) = &state.upstream {
         Ok(Redirect::temporary(&format!("{}/{}", upstream, path)).into_response())
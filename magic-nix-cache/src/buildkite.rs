@@ -0,0 +1,430 @@
+//! A storage backend in the artifacts of Buildkite builds.
+//!
+//! Objects are uploaded as artifacts of the running build with
+//! `buildkite-agent artifact upload`, under their keys as paths, so they end
+//! up wherever the agent stores artifacts: at Buildkite, or in the agent's
+//! own bucket if `BUILDKITE_ARTIFACT_UPLOAD_DESTINATION` is set. Artifacts of
+//! the last finished builds of the same pipeline are found through the REST
+//! API, which needs a token with the `read_builds` and `read_artifacts`
+//! scopes in `BUILDKITE_API_TOKEN`, and downloaded with
+//! `buildkite-agent artifact download --build`. Without the token, only the
+//! objects uploaded by the running build are found.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use opendal::raw::{oio, Access, AccessorInfo, OpRead, OpStat, OpWrite, RpRead, RpStat, RpWrite};
+use opendal::{Buffer, Capability, EntryMode, ErrorKind, Metadata, Scheme};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::error::{Error, Result};
+use crate::local::FileReader;
+use crate::temp;
+
+const API_URL: &str = "https://api.buildkite.com/v2";
+
+/// How many of the last finished builds of the pipeline are searched.
+const PRIOR_BUILDS: usize = 10;
+
+/// The page size of REST API listings, which is at most 100.
+const PAGE_SIZE: usize = 100;
+
+#[derive(Debug)]
+pub struct BuildkiteBackend {
+    client: reqwest::Client,
+    organization: String,
+    pipeline: String,
+    build_id: String,
+    api_token: Option<String>,
+    info: Arc<AccessorInfo>,
+
+    /// The artifacts of prior builds, by path, loaded on first use.
+    prior: OnceCell<HashMap<String, Artifact>>,
+
+    /// The artifacts uploaded by this build, by path.
+    uploaded: Arc<Mutex<HashMap<String, u64>>>,
+
+    /// The number of the next temporary directory for an operation.
+    next_dir: AtomicU64,
+}
+
+#[derive(Clone, Debug)]
+struct Artifact {
+    build_id: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct ApiBuild {
+    id: String,
+    number: u64,
+}
+
+#[derive(Deserialize)]
+struct ApiArtifact {
+    path: String,
+    file_size: u64,
+    state: String,
+}
+
+impl BuildkiteBackend {
+    pub fn new() -> Result<Self> {
+        let info = AccessorInfo::default();
+        info.set_scheme(Scheme::Custom("buildkite"))
+            .set_root("/")
+            .set_native_capability(Capability {
+                stat: true,
+                read: true,
+                write: true,
+                ..Default::default()
+            });
+
+        let api_token = std::env::var("BUILDKITE_API_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+
+        if api_token.is_none() {
+            tracing::info!(
+                "BUILDKITE_API_TOKEN isn't set, so only the artifacts of this build are used as a cache"
+            );
+        }
+
+        Ok(BuildkiteBackend {
+            client: reqwest::Client::new(),
+            organization: build_variable("BUILDKITE_ORGANIZATION_SLUG")?,
+            pipeline: build_variable("BUILDKITE_PIPELINE_SLUG")?,
+            build_id: build_variable("BUILDKITE_BUILD_ID")?,
+            api_token,
+            info: Arc::new(info),
+            prior: OnceCell::new(),
+            uploaded: Arc::new(Mutex::new(HashMap::new())),
+            next_dir: AtomicU64::new(0),
+        })
+    }
+
+    /// Find the artifact stored at `path`, in this build or a prior one.
+    async fn find(&self, path: &str) -> opendal::Result<Artifact> {
+        let key = path.trim_start_matches('/');
+
+        if let Some(size) = self.uploaded.lock().await.get(key) {
+            return Ok(Artifact {
+                build_id: self.build_id.clone(),
+                size: *size,
+            });
+        }
+
+        let prior = self
+            .prior
+            .get_or_init(|| async {
+                match self.index_prior_builds().await {
+                    Ok(prior) => prior,
+                    Err(err) => {
+                        tracing::warn!(
+                            "Listing the artifacts of prior Buildkite builds failed: {}",
+                            err
+                        );
+                        HashMap::new()
+                    }
+                }
+            })
+            .await;
+
+        prior.get(key).cloned().ok_or_else(|| {
+            opendal::Error::new(
+                ErrorKind::NotFound,
+                format!("no Buildkite build has the artifact '{}'", key),
+            )
+        })
+    }
+
+    /// List the artifacts of the last finished builds of the pipeline,
+    /// keeping the newest one for each path.
+    async fn index_prior_builds(&self) -> Result<HashMap<String, Artifact>> {
+        let Some(token) = &self.api_token else {
+            return Ok(HashMap::new());
+        };
+
+        let pipeline_url = format!(
+            "{}/organizations/{}/pipelines/{}",
+            API_URL, self.organization, self.pipeline
+        );
+
+        let builds: Vec<ApiBuild> = self
+            .get(
+                token,
+                &format!("{}/builds", pipeline_url),
+                &[
+                    ("state", "finished".to_owned()),
+                    ("per_page", (PRIOR_BUILDS + 1).to_string()),
+                ],
+            )
+            .await?;
+
+        let mut index = HashMap::new();
+
+        for build in builds
+            .into_iter()
+            .filter(|build| build.id != self.build_id)
+            .take(PRIOR_BUILDS)
+        {
+            for page in 1.. {
+                let artifacts: Vec<ApiArtifact> = self
+                    .get(
+                        token,
+                        &format!("{}/builds/{}/artifacts", pipeline_url, build.number),
+                        &[
+                            ("page", page.to_string()),
+                            ("per_page", PAGE_SIZE.to_string()),
+                        ],
+                    )
+                    .await?;
+
+                let last = artifacts.len() < PAGE_SIZE;
+
+                for artifact in artifacts {
+                    if artifact.state == "finished" {
+                        index.entry(artifact.path).or_insert_with(|| Artifact {
+                            build_id: build.id.clone(),
+                            size: artifact.file_size,
+                        });
+                    }
+                }
+
+                if last {
+                    break;
+                }
+            }
+        }
+
+        tracing::debug!(
+            "Found {} artifacts in prior builds of the Buildkite pipeline {}",
+            index.len(),
+            self.pipeline
+        );
+
+        Ok(index)
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        token: &str,
+        url: &str,
+        query: &[(&str, String)],
+    ) -> Result<T> {
+        let response = self
+            .client
+            .get(url)
+            .query(query)
+            .bearer_auth(token)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| Error::Internal(format!("Buildkite API request failed: {}", e)))?;
+
+        response
+            .json()
+            .await
+            .map_err(|e| Error::Internal(format!("bad Buildkite API response: {}", e)))
+    }
+
+    /// A new temporary directory, to download to or upload from.
+    async fn temp_dir(&self) -> opendal::Result<PathBuf> {
+        let dir = temp::dir()
+            .join("buildkite")
+            .join(self.next_dir.fetch_add(1, Ordering::Relaxed).to_string());
+
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| io_error("creating", &dir, e))?;
+
+        Ok(dir)
+    }
+}
+
+impl Access for BuildkiteBackend {
+    type Reader = FileReader;
+    type Writer = BuildkiteWriter;
+    type Lister = ();
+    type Deleter = ();
+    type BlockingReader = ();
+    type BlockingWriter = ();
+    type BlockingLister = ();
+    type BlockingDeleter = ();
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        self.info.clone()
+    }
+
+    async fn stat(&self, path: &str, _args: OpStat) -> opendal::Result<RpStat> {
+        let artifact = self.find(path).await?;
+
+        Ok(RpStat::new(
+            Metadata::new(EntryMode::FILE).with_content_length(artifact.size),
+        ))
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        let key = path.trim_start_matches('/');
+        let artifact = self.find(path).await?;
+        let dir = self.temp_dir().await?;
+
+        agent(
+            Command::new("buildkite-agent")
+                .args(["artifact", "download", "--build", &artifact.build_id, key])
+                .arg(&dir),
+            key,
+        )
+        .await?;
+
+        let file_path = dir.join(key);
+        let file = tokio::fs::File::open(&file_path)
+            .await
+            .map_err(|e| io_error("opening", &file_path, e))?;
+
+        // The open file stays readable.
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+
+        Ok((RpRead::new(), FileReader::new(file, args.range()).await?))
+    }
+
+    async fn write(&self, path: &str, _args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        let key = path.trim_start_matches('/').to_owned();
+        let dir = self.temp_dir().await?;
+
+        let file_path = dir.join(&key);
+        if let Some(parent) = file_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| io_error("creating", parent, e))?;
+        }
+
+        let file = tokio::fs::File::create(&file_path)
+            .await
+            .map_err(|e| io_error("creating", &file_path, e))?;
+
+        Ok((
+            RpWrite::new(),
+            BuildkiteWriter {
+                key,
+                dir,
+                file: Some(file),
+                size: 0,
+                uploaded: self.uploaded.clone(),
+            },
+        ))
+    }
+}
+
+/// Writes an object to a temporary file, which is uploaded as an artifact
+/// when it is complete.
+pub struct BuildkiteWriter {
+    key: String,
+    dir: PathBuf,
+    file: Option<tokio::fs::File>,
+    size: u64,
+    uploaded: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl oio::Write for BuildkiteWriter {
+    async fn write(&mut self, bs: Buffer) -> opendal::Result<()> {
+        let file = self.file.as_mut().ok_or_else(|| {
+            opendal::Error::new(
+                ErrorKind::Unexpected,
+                "writing to a closed Buildkite writer",
+            )
+        })?;
+
+        file.write_all(&bs.to_bytes())
+            .await
+            .map_err(|e| io_error("writing", &self.dir.join(&self.key), e))?;
+
+        self.size += bs.len() as u64;
+
+        Ok(())
+    }
+
+    async fn close(&mut self) -> opendal::Result<Metadata> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()
+                .await
+                .map_err(|e| io_error("writing", &self.dir.join(&self.key), e))?;
+        }
+
+        // The artifact path is relative to the working directory.
+        let result = agent(
+            Command::new("buildkite-agent")
+                .args(["artifact", "upload", &self.key])
+                .current_dir(&self.dir),
+            &self.key,
+        )
+        .await;
+
+        let _ = tokio::fs::remove_dir_all(&self.dir).await;
+        result?;
+
+        self.uploaded
+            .lock()
+            .await
+            .insert(self.key.clone(), self.size);
+
+        Ok(Metadata::new(EntryMode::FILE).with_content_length(self.size))
+    }
+
+    async fn abort(&mut self) -> opendal::Result<()> {
+        drop(self.file.take());
+
+        let _ = tokio::fs::remove_dir_all(&self.dir).await;
+
+        Ok(())
+    }
+}
+
+/// Run a `buildkite-agent` command for the artifact `key`.
+async fn agent(command: &mut Command, key: &str) -> opendal::Result<()> {
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .map_err(|e| {
+            opendal::Error::new(
+                ErrorKind::Unexpected,
+                format!("running buildkite-agent for '{}'", key),
+            )
+            .set_source(e)
+        })?;
+
+    if !status.success() {
+        return Err(opendal::Error::new(
+            ErrorKind::Unexpected,
+            format!("buildkite-agent failed on '{}': {}", key, status),
+        ));
+    }
+
+    Ok(())
+}
+
+fn build_variable(name: &str) -> Result<String> {
+    std::env::var(name).map_err(|_| {
+        Error::Config(format!(
+            "the Buildkite backend needs {}, which the Buildkite agent sets in jobs",
+            name
+        ))
+    })
+}
+
+fn io_error(action: &str, path: &Path, err: std::io::Error) -> opendal::Error {
+    opendal::Error::new(
+        ErrorKind::Unexpected,
+        format!("{} {}", action, path.display()),
+    )
+    .set_source(err)
+}
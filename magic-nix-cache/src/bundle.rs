@@ -0,0 +1,409 @@
+//! Bundling small store paths into shared cache entries.
+//!
+//! Every path normally takes two entries, its narinfo and its NAR, so a
+//! closure with thousands of tiny paths, like setup hooks, wrappers and
+//! patches, takes thousands of requests to upload and to substitute, and runs
+//! into the rate limits of the GitHub Actions Cache. With `--bundle-below`,
+//! the worker instead collects the compressed NARs of the paths whose NAR is
+//! smaller into bundles of up to [`MAX_BUNDLE_SIZE`], which are stored as a
+//! single entry each. The narinfos of the bundled paths, and where each NAR
+//! is in which bundle, are kept in the bundle catalog of the repository,
+//! which is read on startup and merged back on shutdown, like the upload
+//! manifest. Narinfos of bundled paths are then served from memory, and their
+//! NARs from the bundle, which is downloaded once for all of them.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::body::Bytes;
+use opendal::{ErrorKind, Operator};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::AuditLog;
+use crate::error::{Error, Result};
+use crate::lru::LruCache;
+use crate::{gha, keys};
+
+/// How large a bundle gets before it is written.
+const MAX_BUNDLE_SIZE: usize = 16 << 20;
+
+/// How many downloaded bundles are kept in memory.
+const CACHED_BUNDLES: usize = 8;
+
+/// How long a bundle is trusted. Like the entries of the upload manifest, it
+/// may have been evicted after a week.
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A bundled path in the catalog.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    store_path_hash: String,
+
+    /// The file name the NAR is served under in `/nar/`.
+    nar: String,
+    narinfo: String,
+
+    /// The ID of the bundle, and where the NAR is in it.
+    bundle: String,
+    offset: u64,
+    size: u64,
+
+    created: u64,
+}
+
+struct Inner {
+    /// The bundled paths by store path hash.
+    paths: HashMap<String, Entry>,
+
+    /// The store path hashes of the bundled NARs by file name.
+    nars: HashMap<String, String>,
+
+    /// The ID and content of the bundle that is being filled.
+    pending_id: String,
+    pending: Vec<u8>,
+
+    /// The bundles whose paths aren't in the cache yet.
+    unwritten: HashSet<String>,
+
+    /// Whether paths were bundled since the catalog was loaded.
+    dirty: bool,
+}
+
+pub struct Bundles {
+    /// The paths whose NAR is smaller than this are bundled.
+    threshold: u64,
+
+    catalog_key: String,
+
+    inner: Mutex<Inner>,
+
+    /// Recently used bundles, including the ones that are being written.
+    cache: Mutex<LruCache<String, Bytes>>,
+}
+
+impl Bundles {
+    /// Bundle the paths whose NAR is smaller than `threshold` bytes.
+    pub fn new(threshold: u64) -> Bundles {
+        let repository = std::env::var("GITHUB_REPOSITORY").unwrap_or_else(|_| "local".to_owned());
+        let pending_id = new_id();
+
+        Bundles {
+            threshold,
+            catalog_key: keys::bundle_catalog(&repository),
+            inner: Mutex::new(Inner {
+                paths: HashMap::new(),
+                nars: HashMap::new(),
+                unwritten: HashSet::from([pending_id.clone()]),
+                pending_id,
+                pending: Vec::new(),
+                dirty: false,
+            }),
+            cache: Mutex::new(LruCache::new(CACHED_BUNDLES)),
+        }
+    }
+
+    /// Read the catalog of the repository. Paths that were bundled in the
+    /// meantime are kept.
+    pub async fn load(&self, api: &Operator) {
+        let entries = match read(api, &self.catalog_key).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to read the bundle catalog '{}': {}",
+                    self.catalog_key,
+                    err
+                );
+                return;
+            }
+        };
+
+        tracing::debug!("Bundle catalog lists {} paths", entries.len());
+
+        let mut inner = self.inner.lock().expect("bundles lock");
+        for entry in entries {
+            if !inner.paths.contains_key(&entry.store_path_hash) {
+                inner.insert(entry);
+            }
+        }
+    }
+
+    /// Whether a path with a NAR of `nar_size` bytes is bundled.
+    pub fn accepts(&self, nar_size: u64) -> bool {
+        nar_size < self.threshold
+    }
+
+    /// The key of the bundle that has the path with `store_path_hash`.
+    pub fn bundle_key(&self, store_path_hash: &str) -> Option<String> {
+        self.inner
+            .lock()
+            .expect("bundles lock")
+            .paths
+            .get(store_path_hash)
+            .map(|entry| keys::bundle(&entry.bundle))
+    }
+
+    /// Add the compressed `nar` of the path with `store_path_hash` and its
+    /// `narinfo` to the pending bundle, and write the bundle if it is full.
+    pub async fn add(
+        &self,
+        api: &Operator,
+        store_path_hash: &str,
+        nar_file: &str,
+        narinfo: String,
+        nar: Vec<u8>,
+    ) -> Result<()> {
+        let full = {
+            let mut inner = self.inner.lock().expect("bundles lock");
+
+            let entry = Entry {
+                store_path_hash: store_path_hash.to_owned(),
+                nar: nar_file.to_owned(),
+                narinfo,
+                bundle: inner.pending_id.clone(),
+                offset: inner.pending.len() as u64,
+                size: nar.len() as u64,
+                created: now(),
+            };
+
+            inner.pending.extend_from_slice(&nar);
+            inner.insert(entry);
+
+            inner.pending.len() >= MAX_BUNDLE_SIZE
+        };
+
+        if full {
+            self.flush(api).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the pending bundle, if it has any paths.
+    pub async fn flush(&self, api: &Operator) -> Result<()> {
+        let (id, content) = {
+            let mut inner = self.inner.lock().expect("bundles lock");
+
+            if inner.pending.is_empty() {
+                return Ok(());
+            }
+
+            let id = std::mem::replace(&mut inner.pending_id, new_id());
+            let pending_id = inner.pending_id.clone();
+            inner.unwritten.insert(pending_id);
+
+            (id, Bytes::from(std::mem::take(&mut inner.pending)))
+        };
+
+        // The paths are still served while the bundle is written.
+        self.cache
+            .lock()
+            .expect("bundle cache lock")
+            .insert(id.clone(), content.clone());
+
+        let size = content.len();
+        let result = api.write(&keys::bundle(&id), content).await;
+
+        let mut inner = self.inner.lock().expect("bundles lock");
+        inner.unwritten.remove(&id);
+
+        if let Err(err) = result {
+            // The paths aren't in the cache after all.
+            let hashes = inner
+                .paths
+                .values()
+                .filter(|entry| entry.bundle == id)
+                .map(|entry| entry.store_path_hash.clone())
+                .collect::<Vec<_>>();
+
+            for hash in &hashes {
+                if let Some(entry) = inner.paths.remove(hash) {
+                    inner.nars.remove(&entry.nar);
+                }
+            }
+
+            return Err(Error::Internal(format!(
+                "Writing the bundle of {} paths failed: {}",
+                hashes.len(),
+                err
+            )));
+        }
+
+        inner.dirty = true;
+
+        tracing::debug!("Stored a bundle of {} bytes", size);
+
+        Ok(())
+    }
+
+    /// The narinfo of the bundled path with `store_path_hash`.
+    pub fn narinfo(&self, store_path_hash: &str) -> Option<Bytes> {
+        self.inner
+            .lock()
+            .expect("bundles lock")
+            .paths
+            .get(store_path_hash)
+            .map(|entry| Bytes::from(entry.narinfo.clone()))
+    }
+
+    /// The size of the bundled NAR served as `nar_file`.
+    pub fn nar_size(&self, nar_file: &str) -> Option<u64> {
+        let inner = self.inner.lock().expect("bundles lock");
+
+        inner.entry_for_nar(nar_file).map(|entry| entry.size)
+    }
+
+    /// The bundled NAR served as `nar_file`, reading its bundle if it isn't
+    /// in memory.
+    pub async fn nar(&self, api: &Operator, nar_file: &str) -> Result<Option<Bytes>> {
+        let entry = {
+            let inner = self.inner.lock().expect("bundles lock");
+
+            let Some(entry) = inner.entry_for_nar(nar_file) else {
+                return Ok(None);
+            };
+
+            if entry.bundle == inner.pending_id {
+                let range = entry.offset as usize..(entry.offset + entry.size) as usize;
+                return Ok(inner.pending.get(range).map(Bytes::copy_from_slice));
+            }
+
+            entry.clone()
+        };
+
+        let cached = self
+            .cache
+            .lock()
+            .expect("bundle cache lock")
+            .get(&entry.bundle);
+
+        let bundle = match cached {
+            Some(bundle) => bundle,
+            None => {
+                let bundle = match api.read(&keys::bundle(&entry.bundle)).await {
+                    Ok(bundle) => bundle.to_bytes(),
+                    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(err.into()),
+                };
+
+                self.cache
+                    .lock()
+                    .expect("bundle cache lock")
+                    .insert(entry.bundle.clone(), bundle.clone());
+
+                bundle
+            }
+        };
+
+        let range = entry.offset as usize..(entry.offset + entry.size) as usize;
+
+        Ok((range.end <= bundle.len()).then(|| bundle.slice(range)))
+    }
+
+    /// Write the catalog back to the cache, merging in the paths that other
+    /// jobs bundled in the meantime.
+    pub async fn save(&self, api: &Operator, audit_log: Option<&AuditLog>) {
+        if !self.inner.lock().expect("bundles lock").dirty {
+            return;
+        }
+
+        let existing = match read(api, &self.catalog_key).await {
+            Ok(existing) => existing,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to read the bundle catalog '{}': {}",
+                    self.catalog_key,
+                    err
+                );
+                Vec::new()
+            }
+        };
+
+        let content = {
+            let mut inner = self.inner.lock().expect("bundles lock");
+
+            for entry in &existing {
+                if !inner.paths.contains_key(&entry.store_path_hash) {
+                    inner.insert(entry.clone());
+                }
+            }
+
+            let entries = inner
+                .paths
+                .values()
+                .filter(|entry| !inner.unwritten.contains(&entry.bundle))
+                .collect::<Vec<_>>();
+
+            tracing::debug!("Storing a bundle catalog of {} paths", entries.len());
+
+            match serde_json::to_vec(&entries) {
+                Ok(content) => content,
+                Err(err) => {
+                    tracing::warn!("Failed to serialize the bundle catalog: {}", err);
+                    return;
+                }
+            }
+        };
+
+        match gha::replace(
+            api,
+            audit_log,
+            &self.catalog_key,
+            !existing.is_empty(),
+            content,
+        )
+        .await
+        {
+            Ok(_) => self.inner.lock().expect("bundles lock").dirty = false,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to store the bundle catalog '{}': {}",
+                    self.catalog_key,
+                    err
+                );
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn insert(&mut self, entry: Entry) {
+        self.nars
+            .insert(entry.nar.clone(), entry.store_path_hash.clone());
+        self.paths.insert(entry.store_path_hash.clone(), entry);
+    }
+
+    fn entry_for_nar(&self, nar_file: &str) -> Option<&Entry> {
+        self.paths.get(self.nars.get(nar_file)?)
+    }
+}
+
+/// Read the unexpired entries of the catalog stored under `key`.
+async fn read(api: &Operator, key: &str) -> Result<Vec<Entry>> {
+    let content = match api.read(key).await {
+        Ok(content) => content.to_vec(),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let entries = serde_json::from_slice::<Vec<Entry>>(&content)
+        .map_err(|e| Error::Internal(format!("bad bundle catalog: {}", e)))?;
+
+    let oldest = now().saturating_sub(MAX_AGE.as_secs());
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| entry.created >= oldest)
+        .collect())
+}
+
+fn new_id() -> String {
+    uuid::Uuid::now_v7().simple().to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,84 @@
+//! Handling of job cancellation.
+//!
+//! When a job is cancelled, the runner signals its processes and kills them
+//! a few seconds later, so a multi-gigabyte upload that is in progress won't
+//! finish. On `SIGTERM` or `SIGINT`, the daemon switches to its shutdown
+//! budget instead: uploads of large NARs are abandoned, only small queued
+//! paths are uploaded, so that as many narinfos as possible make it into
+//! the cache, and then the daemon shuts down. A second signal exits right
+//! away.
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::{api, State};
+
+/// The largest compressed NAR, as estimated by [`crate::estimate`], that is
+/// still uploaded after the job was cancelled.
+pub const MAX_NAR_SIZE: u64 = 16 << 20;
+
+pub struct Cancellation {
+    cancelled: watch::Sender<bool>,
+}
+
+impl Default for Cancellation {
+    fn default() -> Cancellation {
+        Cancellation {
+            cancelled: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Cancellation {
+    /// Whether the job was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Wait until the job is cancelled.
+    pub async fn cancelled(&self) {
+        let _ = self
+            .cancelled
+            .subscribe()
+            .wait_for(|cancelled| *cancelled)
+            .await;
+    }
+
+    fn cancel(&self) {
+        self.cancelled.send_replace(true);
+    }
+}
+
+/// Cancel and shut down the daemon on the first `SIGTERM` or `SIGINT`.
+pub fn spawn(state: State) {
+    let (mut sigterm, mut sigint) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        (Err(err), _) | (_, Err(err)) => {
+            tracing::warn!("Can't handle job cancellation: {}", err);
+            return;
+        }
+    };
+
+    tokio::task::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = sigint.recv() => {}
+        }
+
+        tracing::warn!("The job was cancelled, uploading only small paths before shutting down");
+        state.cancellation.cancel();
+
+        tokio::select! {
+            result = api::shutdown(&state) => {
+                if let Err(err) = result {
+                    tracing::error!("Shutting down failed: {}", err);
+                }
+            }
+            _ = sigterm.recv() => std::process::exit(1),
+            _ = sigint.recv() => std::process::exit(1),
+        }
+    });
+}
@@ -0,0 +1,327 @@
+//! Storing the whole closure of a job as a single archive.
+//!
+//! Fetching thousands of paths one by one takes thousands of API calls. In
+//! closure archive mode, the daemon additionally exports everything that was
+//! enqueued during the job into a single `nix-store --export` archive, keyed
+//! by the hash of `flake.lock` and the system. When the next run has the same
+//! key, the archive is imported in one streamed download before any builds
+//! start.
+//!
+//! Each path is compressed separately, and an index records where each one
+//! is stored. Paths that are already valid in the local store are skipped,
+//! so a warm store only downloads the parts of the archive it is missing.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use attic::nix_store::{NixStore, StorePath};
+use opendal::{ErrorKind, Operator};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{copy, AsyncReadExt, AsyncWriteExt as _, BufReader};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+use crate::error::{Error, Result};
+use crate::upload::Upload;
+use crate::{gc_roots, keys, temp};
+
+/// The end of a `nix-store --export` stream.
+///
+/// An export of several paths is the concatenation of the exports of each
+/// path, followed by this terminator. Segments are stored without it, so
+/// that any subset of them can be imported at once.
+const EXPORT_END: [u8; 8] = [0; 8];
+
+/// Where each path is stored in the archive.
+#[derive(Debug, Serialize, Deserialize)]
+struct Index {
+    /// The paths in topological order, with offsets into the archive.
+    paths: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    path: PathBuf,
+
+    /// Offset of the compressed segment.
+    offset: u64,
+
+    /// Size of the compressed segment.
+    size: u64,
+}
+
+pub struct ClosureArchive {
+    key: String,
+    index_key: String,
+
+    /// Whether the archive already exists in the cache.
+    exists: AtomicBool,
+
+    /// The paths enqueued during this job, whose closure goes into the archive.
+    roots: Mutex<HashSet<StorePath>>,
+}
+
+impl ClosureArchive {
+    /// Set up the archive for the `flake.lock` of the workspace, if there is one.
+    pub async fn new() -> Option<ClosureArchive> {
+        let lock_file = std::env::var_os("GITHUB_WORKSPACE")
+            .map(PathBuf::from)
+            .unwrap_or_default()
+            .join("flake.lock");
+
+        let lock = match tokio::fs::read(&lock_file).await {
+            Ok(lock) => lock,
+            Err(err) => {
+                tracing::warn!(
+                    "Not using a closure archive because {} can't be read: {}",
+                    lock_file.display(),
+                    err
+                );
+                return None;
+            }
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.update(&lock);
+        hasher.update(b"\0");
+        hasher.update(system());
+
+        let id = format!("{:x}", hasher.finalize());
+
+        Some(ClosureArchive {
+            key: keys::closure_archive(&id),
+            index_key: keys::closure_archive_index(&id),
+            exists: AtomicBool::new(false),
+            roots: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Import the paths in the archive that aren't valid in the local store.
+    pub async fn restore(&self, api: &Operator, store: &NixStore) -> Result<()> {
+        let index = match api.read(&self.index_key).await {
+            Ok(index) => index.to_vec(),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                tracing::info!("No closure archive for this flake.lock yet");
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        self.exists.store(true, Ordering::Relaxed);
+
+        let index: Index = serde_json::from_slice(&index)
+            .map_err(|e| Error::Internal(format!("Parsing the closure archive index: {}", e)))?;
+
+        let mut missing = Vec::new();
+        for entry in &index.paths {
+            if !is_valid(store, &entry.path).await {
+                missing.push(entry);
+            }
+        }
+
+        if missing.is_empty() {
+            tracing::info!(
+                "All {} paths in the closure archive are already valid",
+                index.paths.len()
+            );
+            return Ok(());
+        }
+
+        tracing::info!(
+            "Restoring {} of {} paths from the closure archive '{}'",
+            missing.len(),
+            index.paths.len(),
+            self.key
+        );
+
+        let mut child = Command::new("nix-store")
+            .arg("--import")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Error::Io(e, "Running nix-store --import".to_owned()))?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+
+        // Read adjacent segments with a single request.
+        for run in missing.chunk_by(|a, b| a.offset + a.size == b.offset) {
+            let start = run[0].offset;
+            let end = run[run.len() - 1].offset + run[run.len() - 1].size;
+
+            let reader = api
+                .reader(&self.key)
+                .await?
+                .into_futures_async_read(start..end)
+                .await?;
+            let mut segments = ZstdDecoder::new(BufReader::new(reader.compat()));
+            segments.multiple_members(true);
+
+            copy(&mut segments, &mut stdin).await?;
+        }
+
+        stdin.write_all(&EXPORT_END).await?;
+        drop(stdin);
+
+        let status = child
+            .wait()
+            .await
+            .map_err(|e| Error::Io(e, "Waiting for nix-store --import".to_owned()))?;
+
+        if !status.success() {
+            return Err(Error::Internal(format!(
+                "nix-store --import failed with {}",
+                status
+            )));
+        }
+
+        tracing::info!("Restored the closure archive");
+
+        Ok(())
+    }
+
+    /// Include `store_paths` and their closures in the archive.
+    pub async fn record(&self, store_paths: &[StorePath]) {
+        self.roots.lock().await.extend(store_paths.iter().cloned());
+    }
+
+    /// Export the closure of the recorded paths into the archive, unless
+    /// the archive already exists.
+    pub async fn save(&self, api: &Operator, store: &NixStore) -> Result<()> {
+        if self.exists.load(Ordering::Relaxed) {
+            tracing::debug!("Not replacing the existing closure archive");
+            return Ok(());
+        }
+
+        let roots = self.roots.lock().await.drain().collect::<Vec<_>>();
+        if roots.is_empty() {
+            return Ok(());
+        }
+
+        // Keep the disk guard from deleting paths in the middle of the export.
+        let _gc_lock = gc_roots::hold_off_gc().await;
+
+        let closure = requisites(store, &roots).await?;
+
+        tracing::info!(
+            "Storing {} paths in the closure archive '{}'",
+            closure.len(),
+            self.key
+        );
+
+        let mut upload = Upload::new(api.writer(&self.key).await?, &self.key);
+
+        let spool_path = temp::dir().join("closure-archive-segment");
+        let mut index = Index { paths: Vec::new() };
+        let mut offset = 0;
+
+        for path in closure {
+            let size = export_segment(&path, &spool_path, &mut upload).await?;
+
+            index.paths.push(IndexEntry { path, offset, size });
+            offset += size;
+        }
+
+        if let Err(err) = tokio::fs::remove_file(&spool_path).await {
+            tracing::debug!("Failed to remove {}: {}", spool_path.display(), err);
+        }
+
+        upload.close().await?;
+
+        // The index is written last, so that a missing index means there is no archive.
+        let index = serde_json::to_vec(&index).map_err(|e| {
+            Error::Internal(format!("Serializing the closure archive index: {}", e))
+        })?;
+        api.write(&self.index_key, index).await?;
+
+        self.exists.store(true, Ordering::Relaxed);
+
+        tracing::info!("Stored the closure archive ({} bytes)", offset);
+
+        Ok(())
+    }
+}
+
+/// Append the zstd-compressed export of `path` to `writer`, returning its
+/// compressed size. The export is spooled to `spool_path` first, so that its
+/// terminator can be left out.
+async fn export_segment(path: &Path, spool_path: &Path, upload: &mut Upload) -> Result<u64> {
+    let spool = tokio::fs::File::create(spool_path)
+        .await
+        .map_err(|e| Error::Io(e, format!("Creating {}", spool_path.display())))?;
+
+    let status = Command::new("nix-store")
+        .arg("--export")
+        .arg(path)
+        .stdout(spool.into_std().await)
+        .status()
+        .await
+        .map_err(|e| Error::Io(e, format!("Exporting {}", path.display())))?;
+
+    if !status.success() {
+        return Err(Error::Internal(format!(
+            "nix-store --export {} failed with {}",
+            path.display(),
+            status
+        )));
+    }
+
+    let spool = tokio::fs::File::open(spool_path)
+        .await
+        .map_err(|e| Error::Io(e, format!("Opening {}", spool_path.display())))?;
+    let len = spool
+        .metadata()
+        .await
+        .map_err(|e| Error::Io(e, format!("Reading {}", spool_path.display())))?
+        .len();
+
+    let segment = spool.take(len.saturating_sub(EXPORT_END.len() as u64));
+    let mut compressor = ZstdEncoder::new(BufReader::new(segment));
+
+    upload.copy(&mut compressor).await
+}
+
+/// The closure of `roots`, in topological order.
+async fn requisites(store: &NixStore, roots: &[StorePath]) -> Result<Vec<PathBuf>> {
+    let output = Command::new("nix-store")
+        .arg("--query")
+        .arg("--requisites")
+        .args(roots.iter().map(|path| store.get_full_path(path)))
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix-store --query --requisites".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Internal(format!(
+            "nix-store --query --requisites failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(PathBuf::from)
+        .collect())
+}
+
+async fn is_valid(store: &NixStore, path: &Path) -> bool {
+    match store.follow_store_path(path) {
+        Ok(path) => store.query_path_info(path).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// The Nix system type of this machine, e.g. `x86_64-linux`.
+fn system() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+
+    format!("{}-{}", std::env::consts::ARCH, os)
+}
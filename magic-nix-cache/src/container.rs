@@ -0,0 +1,195 @@
+//! Serving the binary cache to containers.
+//!
+//! The daemon listens on the loopback interface, which containers can't
+//! reach. With `--container-listen`, it also listens on the address of the
+//! Docker bridge, at the same port, and only serves the binary cache there.
+//! Containers reach that address directly, or as `host.docker.internal`
+//! when started with `--add-host=host.docker.internal:host-gateway`, which
+//! Docker Desktop sets up by itself. Every container on the host can reach
+//! the bridge, so only the pushes that carry a scoped token are accepted
+//! there, as on the `--scoped-listen` listener. The substituter to configure
+//! in the containers is logged, and exported to later steps of a GitHub
+//! Actions job as `MAGIC_NIX_CACHE_CONTAINER_SUBSTITUTER`. The `inject`
+//! subcommand writes it to the Nix configuration of a running container.
+
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::process::Stdio;
+
+use reqwest::Url;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+
+/// The name of the Docker bridge interface on Linux.
+const BRIDGE_INTERFACE: &str = "docker0";
+
+/// The environment variable that later steps find the substituter in.
+const SUBSTITUTER_VAR: &str = "MAGIC_NIX_CACHE_CONTAINER_SUBSTITUTER";
+
+/// The netrc file with the token to push with, in containers.
+const CONTAINER_NETRC: &str = "/etc/nix/magic-nix-cache-netrc";
+
+/// The address of the Docker bridge on this host.
+pub async fn bridge_address() -> Result<IpAddr> {
+    // The gateway of the default network, which also covers a bridge that
+    // isn't called docker0.
+    let gateway = output(Command::new("docker").args([
+        "network",
+        "inspect",
+        "bridge",
+        "--format",
+        "{{range .IPAM.Config}}{{.Gateway}} {{end}}",
+    ]))
+    .await
+    .and_then(|output| {
+        output
+            .split_whitespace()
+            .find_map(|gateway| gateway.parse::<IpAddr>().ok())
+    });
+
+    if let Some(gateway) = gateway {
+        return Ok(gateway);
+    }
+
+    output(Command::new("ip").args(["-4", "-o", "addr", "show", "dev", BRIDGE_INTERFACE]))
+        .await
+        .and_then(|output| {
+            let mut words = output.split_whitespace();
+            words.find(|word| *word == "inet")?;
+            words.next()?.split('/').next()?.parse().ok()
+        })
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "Can't find the address of the Docker bridge: `docker network inspect bridge` failed and there is no {} interface",
+                BRIDGE_INTERFACE
+            ))
+        })
+}
+
+/// Tell the user, and the later steps of the job, which substituter the
+/// containers should use to reach the cache on `addr`.
+pub fn announce(addr: SocketAddr) {
+    let substituter = format!("http://{}?trusted=1&priority=1", addr);
+
+    tracing::info!(
+        "Containers can use the cache with NIX_CONFIG=\"extra-substituters = {}\", or http://host.docker.internal:{} when started with --add-host=host.docker.internal:host-gateway",
+        substituter,
+        addr.port()
+    );
+
+    let Some(github_env) = std::env::var_os("GITHUB_ENV") else {
+        return;
+    };
+
+    let result = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&github_env)
+        .and_then(|mut file| writeln!(file, "{}={}", SUBSTITUTER_VAR, substituter));
+
+    if let Err(err) = result {
+        tracing::warn!("Exporting {} failed: {}", SUBSTITUTER_VAR, err);
+    }
+}
+
+/// Configure Nix in the running `container` to use `substituter`, or the one
+/// exported by the daemon, and to push to it with the scoped `token` if there
+/// is one, which the container listener and the scoped listener check.
+/// The configuration is appended to `/etc/nix/nix.conf`, so it applies to
+/// Nix commands that start afterwards.
+pub async fn inject(container: &str, substituter: Option<&str>, token: Option<&str>) -> Result<()> {
+    let substituter = match substituter {
+        Some(substituter) => substituter.to_owned(),
+        None => std::env::var(SUBSTITUTER_VAR).map_err(|_| {
+            Error::Config(format!(
+                "Pass --substituter, or start the daemon with --container-listen to export {}",
+                SUBSTITUTER_VAR
+            ))
+        })?,
+    };
+
+    let url = Url::parse(&substituter)
+        .map_err(|e| Error::Config(format!("Invalid substituter '{}': {}", substituter, e)))?;
+
+    let mut nix_conf = format!(
+        "extra-substituters = {}\nextra-trusted-substituters = {}\n",
+        substituter, substituter
+    );
+
+    if let Some(token) = token {
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::Config(format!("The substituter '{}' has no host", url)))?;
+
+        exec(
+            container,
+            &format!(
+                "umask 077 && mkdir -p /etc/nix && cat > {}",
+                CONTAINER_NETRC
+            ),
+            format!("machine {} password {}\n", host, token),
+        )
+        .await?;
+
+        nix_conf.push_str(&format!("netrc-file = {}\n", CONTAINER_NETRC));
+    }
+
+    exec(
+        container,
+        "mkdir -p /etc/nix && cat >> /etc/nix/nix.conf",
+        nix_conf,
+    )
+    .await?;
+
+    tracing::info!("Configured container {} to use {}", container, substituter);
+
+    Ok(())
+}
+
+/// Run `script` as root in `container`, with `input` on its standard input.
+async fn exec(container: &str, script: &str, input: String) -> Result<()> {
+    let mut child = Command::new("docker")
+        .args(["exec", "-i", "-u", "0", container, "sh", "-c", script])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| Error::Io(e, "Running docker exec".to_owned()))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin
+        .write_all(input.as_bytes())
+        .await
+        .map_err(|e| Error::Io(e, format!("Writing to container {}", container)))?;
+    drop(stdin);
+
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| Error::Io(e, "Running docker exec".to_owned()))?;
+
+    if !status.success() {
+        return Err(Error::Internal(format!(
+            "docker exec in container {} failed: {}",
+            container, status
+        )));
+    }
+
+    Ok(())
+}
+
+/// The standard output of `command`, if it succeeds.
+async fn output(command: &mut Command) -> Option<String> {
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
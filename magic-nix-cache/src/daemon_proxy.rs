@@ -0,0 +1,417 @@
+//! An experimental proxy for the Nix daemon socket.
+//!
+//! Clients that connect to the proxy (e.g. with
+//! `NIX_REMOTE=unix:///path/to/socket`) are forwarded to the real daemon.
+//! Meanwhile, the proxy decodes the operations the client sends, and when
+//! the connection ends, it enqueues the outputs of the derivations that were
+//! built and the paths that were added to the store. This learns which paths
+//! are new without a post-build hook or diffing the store.
+//!
+//! Only the client's side of the connection is decoded, and only as far as
+//! the proxy understands it. When it encounters an operation it can't skip
+//! over, it stops observing the connection but keeps forwarding it.
+
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{copy, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::Command;
+use tokio::sync::oneshot;
+
+use crate::error::{Error, Result};
+use crate::State;
+
+const WORKER_MAGIC_1: u64 = 0x6e697863;
+
+/// Size of the buffer between the forwarded stream and the decoder.
+const OBSERVER_BUFFER: usize = 64 * 1024;
+
+/// Longest string the decoder accepts before deciding it is out of sync.
+const MAX_STRING_LEN: u64 = 1024 * 1024;
+
+/// Longest list the decoder accepts before deciding it is out of sync.
+const MAX_LIST_LEN: u64 = 1024 * 1024;
+
+mod op {
+    pub const IS_VALID_PATH: u64 = 1;
+    pub const HAS_SUBSTITUTES: u64 = 3;
+    pub const QUERY_PATH_HASH: u64 = 4;
+    pub const QUERY_REFERENCES: u64 = 5;
+    pub const QUERY_REFERRERS: u64 = 6;
+    pub const ADD_TEXT_TO_STORE: u64 = 8;
+    pub const BUILD_PATHS: u64 = 9;
+    pub const ENSURE_PATH: u64 = 10;
+    pub const ADD_TEMP_ROOT: u64 = 11;
+    pub const ADD_INDIRECT_ROOT: u64 = 12;
+    pub const SYNC_WITH_GC: u64 = 13;
+    pub const FIND_ROOTS: u64 = 14;
+    pub const QUERY_DERIVER: u64 = 18;
+    pub const SET_OPTIONS: u64 = 19;
+    pub const QUERY_SUBSTITUTABLE_PATH_INFO: u64 = 21;
+    pub const QUERY_DERIVATION_OUTPUTS: u64 = 22;
+    pub const QUERY_ALL_VALID_PATHS: u64 = 23;
+    pub const QUERY_PATH_INFO: u64 = 26;
+    pub const QUERY_DERIVATION_OUTPUT_NAMES: u64 = 28;
+    pub const QUERY_PATH_FROM_HASH_PART: u64 = 29;
+    pub const QUERY_VALID_PATHS: u64 = 31;
+    pub const QUERY_SUBSTITUTABLE_PATHS: u64 = 32;
+    pub const QUERY_VALID_DERIVERS: u64 = 33;
+    pub const OPTIMISE_STORE: u64 = 34;
+    pub const VERIFY_STORE: u64 = 35;
+    pub const ADD_SIGNATURES: u64 = 37;
+    pub const NAR_FROM_PATH: u64 = 38;
+    pub const ADD_TO_STORE_NAR: u64 = 39;
+    pub const QUERY_MISSING: u64 = 40;
+    pub const QUERY_DERIVATION_OUTPUT_MAP: u64 = 41;
+    pub const QUERY_REALISATION: u64 = 43;
+    pub const BUILD_PATHS_WITH_RESULTS: u64 = 46;
+}
+
+/// Listen on `socket`, forwarding connections to the daemon at `upstream`.
+pub async fn spawn(socket: PathBuf, upstream: PathBuf, state: State) -> Result<()> {
+    // Remove the socket of a previous run, which would make binding fail.
+    let _ = tokio::fs::remove_file(&socket).await;
+
+    let listener = UnixListener::bind(&socket)
+        .map_err(|e| Error::Io(e, format!("Listening on {}", socket.display())))?;
+
+    // Like the daemon socket, anyone may connect; the daemon does the access control.
+    tokio::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o666))
+        .await
+        .map_err(|e| Error::Io(e, format!("Setting permissions on {}", socket.display())))?;
+
+    tracing::info!(
+        "Proxying the Nix daemon at {} on {}",
+        upstream.display(),
+        socket.display()
+    );
+
+    tokio::task::spawn(async move {
+        loop {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(err) => {
+                    tracing::warn!("Accepting a Nix daemon connection failed: {}", err);
+                    continue;
+                }
+            };
+
+            let upstream = upstream.clone();
+            let state = state.clone();
+
+            tokio::task::spawn(async move {
+                if let Err(err) = proxy(client, &upstream, &state).await {
+                    tracing::debug!("Nix daemon connection failed: {}", err);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn proxy(client: UnixStream, upstream: &Path, state: &State) -> Result<()> {
+    let daemon = UnixStream::connect(upstream)
+        .await
+        .map_err(|e| Error::Io(e, format!("Connecting to {}", upstream.display())))?;
+
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut daemon_read, mut daemon_write) = daemon.into_split();
+
+    let (observer_write, observer_read) = tokio::io::duplex(OBSERVER_BUFFER);
+    let (version_tx, version_rx) = oneshot::channel();
+
+    let to_daemon = async {
+        tee(&mut client_read, &mut daemon_write, observer_write).await?;
+        daemon_write.shutdown().await
+    };
+
+    let to_client = async {
+        // The daemon's magic number and protocol version.
+        let mut hello = [0; 16];
+        daemon_read.read_exact(&mut hello).await?;
+        let _ = version_tx.send(u64::from_le_bytes(hello[8..].try_into().unwrap()));
+        client_write.write_all(&hello).await?;
+
+        copy(&mut daemon_read, &mut client_write).await?;
+        client_write.shutdown().await
+    };
+
+    let (to_daemon, to_client, observed) =
+        tokio::join!(to_daemon, to_client, observe(observer_read, version_rx));
+
+    enqueue_observed(state, observed).await;
+
+    to_daemon?;
+    to_client?;
+
+    Ok(())
+}
+
+/// Copy `reader` to `writer`, and to `observer` for as long as it keeps reading.
+async fn tee(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    observer: DuplexStream,
+) -> std::io::Result<()> {
+    let mut observer = Some(observer);
+    let mut buf = vec![0; OBSERVER_BUFFER];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+
+        writer.write_all(&buf[..n]).await?;
+
+        if let Some(o) = &mut observer {
+            if o.write_all(&buf[..n]).await.is_err() {
+                observer = None;
+            }
+        }
+    }
+}
+
+/// Decode the client's operations, returning the paths it built or added.
+async fn observe(mut reader: DuplexStream, daemon_version: oneshot::Receiver<u64>) -> Vec<String> {
+    let mut observed = Vec::new();
+
+    if let Err(err) = decode(&mut reader, daemon_version, &mut observed).await {
+        tracing::debug!("Stopped observing a Nix daemon connection: {}", err);
+    }
+
+    observed
+}
+
+async fn decode(
+    r: &mut DuplexStream,
+    daemon_version: oneshot::Receiver<u64>,
+    observed: &mut Vec<String>,
+) -> std::io::Result<()> {
+    if read_u64(r).await? != WORKER_MAGIC_1 {
+        return Err(out_of_sync("bad magic number"));
+    }
+
+    let client_version = read_u64(r).await?;
+    let daemon_version = daemon_version
+        .await
+        .map_err(|_| out_of_sync("no daemon version"))?;
+    let minor = client_version.min(daemon_version) & 0xff;
+
+    if minor >= 38 {
+        // Supported features.
+        read_strings(r).await?;
+    }
+
+    if minor >= 14 && read_u64(r).await? != 0 {
+        // CPU affinity.
+        read_u64(r).await?;
+    }
+
+    if minor >= 11 {
+        // Reserve space.
+        read_u64(r).await?;
+    }
+
+    loop {
+        let op = match read_u64(r).await {
+            Ok(op) => op,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        match op {
+            op::IS_VALID_PATH
+            | op::HAS_SUBSTITUTES
+            | op::QUERY_PATH_HASH
+            | op::QUERY_REFERENCES
+            | op::QUERY_REFERRERS
+            | op::ENSURE_PATH
+            | op::ADD_TEMP_ROOT
+            | op::ADD_INDIRECT_ROOT
+            | op::QUERY_DERIVER
+            | op::QUERY_SUBSTITUTABLE_PATH_INFO
+            | op::QUERY_DERIVATION_OUTPUTS
+            | op::QUERY_PATH_INFO
+            | op::QUERY_DERIVATION_OUTPUT_NAMES
+            | op::QUERY_PATH_FROM_HASH_PART
+            | op::QUERY_VALID_DERIVERS
+            | op::NAR_FROM_PATH
+            | op::QUERY_DERIVATION_OUTPUT_MAP
+            | op::QUERY_REALISATION => {
+                read_string(r).await?;
+            }
+            op::SYNC_WITH_GC | op::FIND_ROOTS | op::QUERY_ALL_VALID_PATHS | op::OPTIMISE_STORE => {}
+            op::SET_OPTIONS => {
+                for _ in 0..12 {
+                    read_u64(r).await?;
+                }
+
+                if minor >= 12 {
+                    let overrides = read_len(r, MAX_LIST_LEN).await?;
+                    for _ in 0..overrides {
+                        read_string(r).await?;
+                        read_string(r).await?;
+                    }
+                }
+            }
+            op::ADD_TEXT_TO_STORE => {
+                read_string(r).await?;
+                read_string(r).await?;
+                read_strings(r).await?;
+            }
+            op::BUILD_PATHS | op::BUILD_PATHS_WITH_RESULTS => {
+                observed.extend(read_strings(r).await?);
+
+                if minor >= 15 {
+                    // Build mode.
+                    read_u64(r).await?;
+                }
+            }
+            op::QUERY_VALID_PATHS => {
+                read_strings(r).await?;
+
+                if minor >= 27 {
+                    // Whether to substitute.
+                    read_u64(r).await?;
+                }
+            }
+            op::QUERY_SUBSTITUTABLE_PATHS | op::QUERY_MISSING => {
+                read_strings(r).await?;
+            }
+            op::VERIFY_STORE => {
+                read_u64(r).await?;
+                read_u64(r).await?;
+            }
+            op::ADD_SIGNATURES => {
+                read_string(r).await?;
+                read_strings(r).await?;
+            }
+            op::ADD_TO_STORE_NAR => {
+                observed.push(read_string(r).await?);
+
+                // The NAR follows, which can't be skipped without decoding it.
+                return Err(out_of_sync("can't skip over AddToStoreNar"));
+            }
+            op => {
+                return Err(out_of_sync(&format!("unsupported operation {}", op)));
+            }
+        }
+    }
+}
+
+async fn read_u64(r: &mut (impl AsyncRead + Unpin)) -> std::io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf).await?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+async fn read_len(r: &mut (impl AsyncRead + Unpin), max: u64) -> std::io::Result<u64> {
+    let len = read_u64(r).await?;
+
+    if len > max {
+        return Err(out_of_sync(&format!("implausible length {}", len)));
+    }
+
+    Ok(len)
+}
+
+async fn read_string(r: &mut (impl AsyncRead + Unpin)) -> std::io::Result<String> {
+    let len = read_len(r, MAX_STRING_LEN).await? as usize;
+
+    // Strings are padded to a multiple of 8 bytes.
+    let mut buf = vec![0; len.next_multiple_of(8)];
+    r.read_exact(&mut buf).await?;
+    buf.truncate(len);
+
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+async fn read_strings(r: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Vec<String>> {
+    let len = read_len(r, MAX_LIST_LEN).await?;
+
+    let mut strings = Vec::new();
+    for _ in 0..len {
+        strings.push(read_string(r).await?);
+    }
+
+    Ok(strings)
+}
+
+fn out_of_sync(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+/// Enqueue the valid paths among `observed`, which are store paths or
+/// derived paths like `/nix/store/...drv^out`.
+async fn enqueue_observed(state: &State, observed: Vec<String>) {
+    let mut store_paths = Vec::new();
+
+    for path in observed {
+        let paths = match path.split_once(['!', '^']) {
+            Some((drv, _outputs)) => derivation_outputs(drv).await,
+            None => vec![path],
+        };
+
+        for path in paths {
+            let Ok(store_path) = state.store.follow_store_path(&path) else {
+                continue;
+            };
+
+            if state
+                .store
+                .query_path_info(store_path.clone())
+                .await
+                .is_ok()
+            {
+                store_paths.push(store_path);
+            }
+        }
+    }
+
+    if store_paths.is_empty() {
+        return;
+    }
+
+    tracing::debug!(
+        "Enqueueing {} paths seen on a Nix daemon connection",
+        store_paths.len()
+    );
+
+    if let Err(err) = crate::api::enqueue_paths(state, store_paths, false).await {
+        tracing::error!(
+            "Enqueueing paths seen on a Nix daemon connection failed: {}",
+            err
+        );
+    }
+}
+
+/// The output paths of the derivation `drv`.
+async fn derivation_outputs(drv: &str) -> Vec<String> {
+    let output = match Command::new("nix-store")
+        .args(["--query", "--outputs", drv])
+        .output()
+        .await
+    {
+        Ok(output) if output.status.success() => output.stdout,
+        Ok(output) => {
+            tracing::debug!(
+                "Querying the outputs of {} failed: {}",
+                drv,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Vec::new();
+        }
+        Err(err) => {
+            tracing::debug!("Querying the outputs of {} failed: {}", drv, err);
+            return Vec::new();
+        }
+    };
+
+    String::from_utf8_lossy(&output)
+        .lines()
+        .map(str::to_owned)
+        .collect()
+}
@@ -0,0 +1,86 @@
+//! Deferral of low-priority uploads to scheduled maintenance runs.
+//!
+//! Store paths whose name matches a `--defer-until-idle` pattern, e.g.
+//! documentation or `-source` trees, aren't uploaded by ordinary jobs, which
+//! keeps pull request jobs fast. They are appended to a pending file
+//! instead, which a scheduled keep-warm workflow can pass to `enqueue
+//! --pending-file` after building the same outputs. Scheduled runs don't
+//! defer anything, and paths that an uploaded path refers to are uploaded
+//! anyway, so that the cache never advertises incomplete closures.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use regex::Regex;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+
+pub struct Deferral {
+    patterns: Vec<Regex>,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl Deferral {
+    /// Defer the paths matching `patterns`, appending them to the pending file at `path`.
+    pub async fn open(path: &Path, patterns: Vec<Regex>) -> Result<Deferral> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| Error::Io(e, format!("Opening the pending file {}", path.display())))?;
+
+        Ok(Deferral {
+            patterns,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Whether the upload of the store path named `name` is deferred.
+    pub fn defers(&self, name: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(name))
+    }
+
+    /// Add the store path at `full_path` to the pending file. Failures are
+    /// logged, since the path is uploaded by the next job that builds it anyway.
+    pub async fn record(&self, full_path: &Path) {
+        let mut line = full_path.as_os_str().as_encoded_bytes().to_vec();
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        if let Err(err) = async {
+            file.write_all(&line).await?;
+            file.flush().await
+        }
+        .await
+        {
+            tracing::error!("Failed to write to the pending file: {}", err);
+        }
+    }
+}
+
+/// Whether this is a scheduled run, which uploads deferred paths.
+pub fn is_maintenance_run() -> bool {
+    std::env::var("GITHUB_EVENT_NAME").is_ok_and(|event| event == "schedule")
+        || std::env::var("CI_PIPELINE_SOURCE").is_ok_and(|source| source == "schedule")
+        || std::env::var("BUILD_REASON").is_ok_and(|reason| reason == "Schedule")
+        || std::env::var("BUILDKITE_SOURCE").is_ok_and(|source| source == "schedule")
+}
+
+/// Read the distinct store paths in the pending file at `path`.
+pub async fn read_pending(path: &Path) -> Result<Vec<String>> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| Error::Io(e, format!("Reading the pending file {}", path.display())))?;
+
+    let mut seen = HashSet::new();
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && seen.insert(*line))
+        .map(str::to_owned)
+        .collect())
+}
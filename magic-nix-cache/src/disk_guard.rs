@@ -0,0 +1,98 @@
+//! Garbage-collecting the store when the runner runs low on disk space.
+//!
+//! Paths that are still waiting to be uploaded are protected by
+//! [`GcRoots`](crate::gc_roots::GcRoots), so a collection only deletes paths
+//! that are already cached or that were never going to be uploaded.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+use crate::gc_roots;
+
+/// How often free disk space is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Spawn a task that runs `nix store gc` whenever less than `min_free` bytes
+/// are available on the file system containing `store_dir`.
+pub fn spawn(store_dir: PathBuf, min_free: u64) {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+
+            if let Err(err) = check(&store_dir, min_free).await {
+                tracing::warn!("Disk space guard failed: {}", err);
+            }
+        }
+    });
+}
+
+async fn check(store_dir: &Path, min_free: u64) -> Result<()> {
+    let free = available_space(store_dir).await?;
+
+    if free >= min_free {
+        return Ok(());
+    }
+
+    // Free a quarter more than strictly needed, so that we don't collect
+    // again as soon as the next path is built.
+    let to_free = min_free - free + min_free / 4;
+
+    tracing::info!(
+        "Only {} bytes free in {}, collecting up to {} bytes of garbage",
+        free,
+        store_dir.display(),
+        to_free
+    );
+
+    // Don't collect while paths are being enqueued and haven't got their GC roots yet.
+    let _gc_lock = gc_roots::exclusive().await;
+
+    let status = Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command"])
+        .args(["store", "gc", "--max"])
+        .arg(to_free.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix store gc".to_owned()))?;
+
+    if !status.success() {
+        return Err(Error::Internal(format!("nix store gc failed: {}", status)));
+    }
+
+    tracing::info!(
+        "Garbage collection finished, {} bytes free",
+        available_space(store_dir).await?
+    );
+
+    Ok(())
+}
+
+/// The number of bytes available to unprivileged users on the file system containing `path`.
+async fn available_space(path: &Path) -> Result<u64> {
+    // `-P` gives the portable output format, which is the same on Linux and macOS.
+    let output = Command::new("df")
+        .arg("-Pk")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running df".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Internal(format!("df failed: {}", output.status)));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|available| available.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| Error::Internal("Could not parse the output of df".to_owned()))
+}
@@ -0,0 +1,87 @@
+//! Election of one uploader among the jobs of a workflow run.
+//!
+//! When several matrix jobs build the same closure, they would all upload
+//! the common paths. With `--elect-uploader`, a daemon first claims each
+//! path by creating a sentinel key for the run, and skips the paths that
+//! another job of the same run claimed first. Backends that can't create
+//! keys exclusively are handled by reading the claim back, so a race only
+//! costs a duplicate upload. Claims are never released, so a path claimed
+//! by a job that failed before uploading it is left for the next run.
+
+use opendal::Operator;
+use uuid::Uuid;
+
+use crate::keys;
+
+pub struct Election {
+    /// The workflow run whose jobs coordinate.
+    run: String,
+
+    /// What this daemon writes into its claims.
+    id: String,
+}
+
+impl Election {
+    /// Coordinate with the other jobs of the current GitHub Actions
+    /// workflow run or GitLab pipeline, if there is one.
+    pub fn from_env() -> Option<Election> {
+        let var = |name| std::env::var(name).ok();
+
+        let run = match (var("GITHUB_RUN_ID"), var("CI_PIPELINE_ID")) {
+            (Some(run_id), _) => format!(
+                "{}-{}",
+                run_id,
+                var("GITHUB_RUN_ATTEMPT").unwrap_or_else(|| "1".to_owned())
+            ),
+            (None, Some(pipeline_id)) => format!("gitlab-{}", pipeline_id),
+            (None, None) => return None,
+        };
+
+        Some(Election {
+            run,
+            id: Uuid::now_v7().to_string(),
+        })
+    }
+
+    /// The key of the claim on the store path with `store_path_hash`.
+    pub fn key(&self, store_path_hash: &str) -> String {
+        keys::claim(&self.run, store_path_hash)
+    }
+
+    /// Claim the upload of the store path with `store_path_hash`, returning
+    /// whether this job should upload it. Errors are logged and count as
+    /// winning, so that a backend hiccup doesn't skip uploads.
+    pub async fn claim(&self, api: &Operator, store_path_hash: &str) -> bool {
+        let key = self.key(store_path_hash);
+
+        let exclusive = api.info().full_capability().write_with_if_not_exists;
+
+        match api
+            .write_with(&key, self.id.clone())
+            .if_not_exists(exclusive)
+            .await
+        {
+            Ok(_) => {}
+            Err(err) if gha_cache::is_reserve_conflict(&err) => return false,
+            Err(err) => {
+                tracing::warn!("Claiming '{}' failed: {}", key, err);
+                return true;
+            }
+        }
+
+        if exclusive {
+            return true;
+        }
+
+        // Another job may have overwritten the claim since.
+        match api.read(&key).await {
+            Ok(content) => content.to_vec() == self.id.as_bytes(),
+            Err(err) => {
+                if err.kind() != opendal::ErrorKind::NotFound {
+                    tracing::warn!("Reading the claim '{}' failed: {}", key, err);
+                }
+                true
+            }
+        }
+    }
+}
@@ -3,12 +3,20 @@ use std::fmt::{self, Display};
 #[derive(Clone, Copy)]
 pub enum Environment {
     GitHubActions,
+    /// Forgejo or Gitea Actions, whose runners also set `GITHUB_ACTIONS`.
+    GiteaActions,
     GitLabCI,
+    AzurePipelines,
+    Buildkite,
     Other,
 }
 
 impl Environment {
     pub fn determine() -> Self {
+        if gha_cache::is_gitea_actions() {
+            return Environment::GiteaActions;
+        }
+
         if env_var_is_true("GITHUB_ACTIONS") {
             return Environment::GitHubActions;
         }
@@ -17,6 +25,15 @@ impl Environment {
             return Environment::GitLabCI;
         }
 
+        // Azure Pipelines capitalizes it as `True`.
+        if std::env::var("TF_BUILD").is_ok_and(|v| v.eq_ignore_ascii_case("true")) {
+            return Environment::AzurePipelines;
+        }
+
+        if env_var_is_true("BUILDKITE") {
+            return Environment::Buildkite;
+        }
+
         Environment::Other
     }
 
@@ -24,9 +41,18 @@ impl Environment {
         matches!(self, Self::GitHubActions)
     }
 
+    /// Whether the runner provides an Actions cache, for the `gha` backend.
+    pub fn has_actions_cache(&self) -> bool {
+        matches!(self, Self::GitHubActions | Self::GiteaActions)
+    }
+
     pub fn is_gitlab_ci(&self) -> bool {
         matches!(self, Self::GitLabCI)
     }
+
+    pub fn is_buildkite(&self) -> bool {
+        matches!(self, Self::Buildkite)
+    }
 }
 
 impl Display for Environment {
@@ -38,7 +64,10 @@ impl Display for Environment {
             "{}",
             match self {
                 GitHubActions => "GitHub Actions",
+                GiteaActions => "Forgejo or Gitea Actions",
                 GitLabCI => "GitLab CI",
+                AzurePipelines => "Azure Pipelines",
+                Buildkite => "Buildkite",
                 Other => "an unspecified environment",
             }
         )
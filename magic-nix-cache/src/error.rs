@@ -16,18 +16,48 @@ pub enum Error {
     #[error("GitHub API error: {0}")]
     Api(#[from] opendal::Error),
 
+    #[error("GitHub Actions Cache error: {0}")]
+    GhaCache(#[from] gha_cache::Error),
+
     #[error("Not Found")]
     NotFound,
 
     #[error("Bad Request")]
     BadRequest,
 
+    #[error("Unauthorized")]
+    Unauthorized,
+
+    #[error("Forbidden")]
+    Forbidden,
+
+    #[error("Conflict")]
+    Conflict,
+
     #[error("I/O error: {0}. Context: {1}")]
     Io(std::io::Error, String),
 
+    #[error("Store path {} is no longer valid", .0.display())]
+    PathNotValid(std::path::PathBuf),
+
     #[error("GHA cache is disabled")]
     GHADisabled,
 
+    #[error("Backend is disabled after failing health probes")]
+    BackendUnavailable,
+
+    #[error("Timed out after {0:?}")]
+    Timeout(std::time::Duration),
+
+    #[error("Upload quota of {0} bytes exceeded")]
+    PayloadTooLarge(u64),
+
+    #[error("The job was cancelled")]
+    Cancelled,
+
+    #[error("{} references of the store path must be uploaded first", .0.len())]
+    ReferencesPending(Vec<attic::nix_store::StorePath>),
+
     #[error("FlakeHub cache error: {0}")]
     FlakeHub(#[from] anyhow::Error),
 
@@ -67,9 +97,20 @@ impl IntoResponse for Error {
             Self::Api(_) => StatusCode::IM_A_TEAPOT,
             Self::NotFound => StatusCode::NOT_FOUND,
             Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::BackendUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        (code, format!("{}", self)).into_response()
+        match crate::request_id::current() {
+            Some(request_id) => {
+                (code, format!("{} (request ID {})", self, request_id)).into_response()
+            }
+            None => (code, format!("{}", self)).into_response(),
+        }
     }
 }
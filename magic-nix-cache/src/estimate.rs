@@ -0,0 +1,109 @@
+//! Estimates of the compressed size of NARs.
+//!
+//! How well a NAR compresses mostly depends on what kind of path it is, so
+//! the worker keeps the compression ratio of the paths it uploaded by their
+//! name without the version, e.g. `hello` for `hello-2.12.1`, and of all
+//! paths for names it hasn't seen. With the estimates, the worker knows
+//! whether a path still fits in `--max-upload-size`, or in the time that is
+//! left after the job was cancelled, before compressing it, and uploads the
+//! smallest queued paths first while either budget applies, so that as many
+//! paths as possible fit.
+
+use std::collections::HashMap;
+
+/// The compression ratio of paths before any upload, about what zstd
+/// achieves on a typical NAR.
+const DEFAULT_RATIO: f64 = 0.35;
+
+#[derive(Debug, Default)]
+pub struct CompressionEstimates {
+    names: HashMap<String, Observed>,
+    all: Observed,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Observed {
+    nar_size: u64,
+    compressed_size: u64,
+}
+
+impl Observed {
+    fn ratio(&self) -> Option<f64> {
+        (self.nar_size > 0).then(|| self.compressed_size as f64 / self.nar_size as f64)
+    }
+
+    fn add(&mut self, nar_size: u64, compressed_size: u64) {
+        self.nar_size += nar_size;
+        self.compressed_size += compressed_size;
+    }
+}
+
+impl CompressionEstimates {
+    /// The estimated compressed size of the NAR of the path named `name`,
+    /// which is `nar_size` bytes uncompressed.
+    pub fn estimate(&self, name: &str, nar_size: u64) -> u64 {
+        let ratio = self
+            .names
+            .get(pname(name))
+            .and_then(Observed::ratio)
+            .or_else(|| self.all.ratio())
+            .unwrap_or(DEFAULT_RATIO);
+
+        (nar_size as f64 * ratio).ceil() as u64
+    }
+
+    /// Record that the NAR of the path named `name` was compressed from
+    /// `nar_size` to `compressed_size` bytes.
+    pub fn record(&mut self, name: &str, nar_size: u64, compressed_size: u64) {
+        self.names
+            .entry(pname(name).to_owned())
+            .or_default()
+            .add(nar_size, compressed_size);
+        self.all.add(nar_size, compressed_size);
+    }
+}
+
+/// What is left of the compressed bytes that the worker may upload, if they
+/// are limited by `--max-upload-size`.
+#[derive(Clone, Copy, Debug)]
+pub struct UploadBudget(Option<u64>);
+
+impl UploadBudget {
+    pub fn new(max_upload_size: Option<u64>) -> UploadBudget {
+        UploadBudget(max_upload_size)
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Whether `size` more bytes fit.
+    pub fn fits(&self, size: u64) -> bool {
+        match self.0 {
+            Some(remaining) => size <= remaining,
+            None => true,
+        }
+    }
+
+    /// Account for `size` bytes that were uploaded.
+    pub fn spend(&mut self, size: u64) {
+        if let Some(remaining) = &mut self.0 {
+            *remaining = remaining.saturating_sub(size);
+        }
+    }
+}
+
+/// The name of a store path without its version, like
+/// `builtins.parseDrvName`: the version starts after the first dash that
+/// isn't followed by a letter.
+fn pname(name: &str) -> &str {
+    name.char_indices()
+        .find(|&(i, c)| {
+            c == '-'
+                && name[i + 1..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| !c.is_alphabetic())
+        })
+        .map_or(name, |(i, _)| &name[..i])
+}
@@ -0,0 +1,275 @@
+//! A storage backend that delegates to an external helper program.
+//!
+//! The helper is run once per operation, with the operation and the key as
+//! arguments:
+//!
+//! * `helper get <key>` writes the object to stdout.
+//! * `helper put <key>` reads the object from stdin and stores it.
+//! * `helper exists <key>` prints the size of the object in bytes.
+//!
+//! The helper exits with status 0 on success, and with status 2 if the object
+//! doesn't exist. Any other status is treated as an error.
+//!
+//! Ranged reads are cut out of the whole object, which the helper always
+//! writes.
+
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+
+use opendal::raw::{oio, Access, AccessorInfo, OpRead, OpStat, OpWrite, RpRead, RpStat, RpWrite};
+use opendal::{Buffer, Capability, EntryMode, ErrorKind, Metadata, Scheme};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+/// Exit status used by the helper to report a missing object.
+const EXIT_NOT_FOUND: i32 = 2;
+
+/// Size of the chunks read from the helper's stdout.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct ExecBackend {
+    helper: PathBuf,
+    info: Arc<AccessorInfo>,
+}
+
+impl ExecBackend {
+    pub fn new(helper: PathBuf) -> Self {
+        let info = AccessorInfo::default();
+        info.set_scheme(Scheme::Custom("exec"))
+            .set_root("/")
+            .set_native_capability(Capability {
+                stat: true,
+                read: true,
+                write: true,
+                ..Default::default()
+            });
+
+        ExecBackend {
+            helper,
+            info: Arc::new(info),
+        }
+    }
+
+    fn command(&self, op: &str, path: &str) -> Command {
+        let mut command = Command::new(&self.helper);
+        command.arg(op).arg(path).kill_on_drop(true);
+        command
+    }
+}
+
+impl Access for ExecBackend {
+    type Reader = ExecReader;
+    type Writer = ExecWriter;
+    type Lister = ();
+    type Deleter = ();
+    type BlockingReader = ();
+    type BlockingWriter = ();
+    type BlockingLister = ();
+    type BlockingDeleter = ();
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        self.info.clone()
+    }
+
+    async fn stat(&self, path: &str, _args: OpStat) -> opendal::Result<RpStat> {
+        let output = self
+            .command("exists", path)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .await
+            .map_err(|e| helper_error("running", path, e))?;
+
+        check_status(output.status, path)?;
+
+        let size = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| {
+                opendal::Error::new(
+                    ErrorKind::Unexpected,
+                    format!("exec helper printed an invalid size for '{}': {}", path, e),
+                )
+            })?;
+
+        Ok(RpStat::new(
+            Metadata::new(EntryMode::FILE).with_content_length(size),
+        ))
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        let mut child = self
+            .command("get", path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| helper_error("spawning", path, e))?;
+
+        let stdout = child.stdout.take().expect("helper stdout is piped");
+
+        Ok((
+            RpRead::new(),
+            ExecReader {
+                path: path.to_owned(),
+                child,
+                stdout,
+                skip: args.range().offset(),
+                remaining: args.range().size(),
+                done: false,
+            },
+        ))
+    }
+
+    async fn write(&self, path: &str, _args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        let mut child = self
+            .command("put", path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| helper_error("spawning", path, e))?;
+
+        let stdin = child.stdin.take();
+
+        Ok((
+            RpWrite::new(),
+            ExecWriter {
+                path: path.to_owned(),
+                child,
+                stdin,
+            },
+        ))
+    }
+}
+
+pub struct ExecReader {
+    path: String,
+    child: Child,
+    stdout: ChildStdout,
+
+    /// How many bytes before the requested range are still to be skipped.
+    skip: u64,
+
+    /// How many bytes of the requested range are still to be read, if it
+    /// is bounded.
+    remaining: Option<u64>,
+
+    done: bool,
+}
+
+impl oio::Read for ExecReader {
+    async fn read(&mut self) -> opendal::Result<Buffer> {
+        loop {
+            if self.done {
+                return Ok(Buffer::new());
+            }
+
+            if self.remaining == Some(0) {
+                // The rest of the object isn't needed.
+                self.done = true;
+                let _ = self.child.start_kill();
+                return Ok(Buffer::new());
+            }
+
+            let mut buf = vec![0; READ_CHUNK_SIZE];
+            let n = self
+                .stdout
+                .read(&mut buf)
+                .await
+                .map_err(|e| helper_error("reading from", &self.path, e))?;
+
+            if n == 0 {
+                self.done = true;
+                let status = self
+                    .child
+                    .wait()
+                    .await
+                    .map_err(|e| helper_error("waiting for", &self.path, e))?;
+                check_status(status, &self.path)?;
+                return Ok(Buffer::new());
+            }
+
+            buf.truncate(n);
+
+            let skipped = self.skip.min(n as u64);
+            self.skip -= skipped;
+            let mut chunk = buf.split_off(skipped as usize);
+
+            if let Some(remaining) = &mut self.remaining {
+                chunk.truncate((*remaining).min(chunk.len() as u64) as usize);
+                *remaining -= chunk.len() as u64;
+            }
+
+            if !chunk.is_empty() {
+                return Ok(Buffer::from(chunk));
+            }
+        }
+    }
+}
+
+pub struct ExecWriter {
+    path: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl oio::Write for ExecWriter {
+    async fn write(&mut self, bs: Buffer) -> opendal::Result<()> {
+        let stdin = self.stdin.as_mut().ok_or_else(|| {
+            opendal::Error::new(ErrorKind::Unexpected, "writing to a closed exec writer")
+        })?;
+
+        stdin
+            .write_all(&bs.to_bytes())
+            .await
+            .map_err(|e| helper_error("writing to", &self.path, e))
+    }
+
+    async fn close(&mut self) -> opendal::Result<Metadata> {
+        // Closing stdin tells the helper that the object is complete.
+        drop(self.stdin.take());
+
+        let status = self
+            .child
+            .wait()
+            .await
+            .map_err(|e| helper_error("waiting for", &self.path, e))?;
+        check_status(status, &self.path)?;
+
+        Ok(Metadata::new(EntryMode::FILE))
+    }
+
+    async fn abort(&mut self) -> opendal::Result<()> {
+        drop(self.stdin.take());
+
+        self.child
+            .kill()
+            .await
+            .map_err(|e| helper_error("killing", &self.path, e))
+    }
+}
+
+fn check_status(status: ExitStatus, path: &str) -> opendal::Result<()> {
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(EXIT_NOT_FOUND) => Err(opendal::Error::new(
+            ErrorKind::NotFound,
+            format!("exec helper did not find '{}'", path),
+        )),
+        _ => Err(opendal::Error::new(
+            ErrorKind::Unexpected,
+            format!("exec helper failed on '{}': {}", path, status),
+        )),
+    }
+}
+
+fn helper_error(action: &str, path: &str, err: std::io::Error) -> opendal::Error {
+    opendal::Error::new(
+        ErrorKind::Unexpected,
+        format!("{} the exec helper for '{}'", action, path),
+    )
+    .set_source(err)
+}
@@ -0,0 +1,168 @@
+//! Uploads to more than one backend.
+//!
+//! With `--extra-backend`, every NAR and narinfo that the worker uploads is
+//! also written to each extra backend, e.g. to keep a copy of the GitHub
+//! Actions Cache in an S3 bucket that outlives its evictions. The compressed
+//! NAR is streamed to all backends at once, so it is only compressed once.
+//! An extra backend that fails doesn't fail the upload, and the uploads to
+//! each one are counted in the `extra_backends` telemetry. Extra backends are
+//! only written to, never read from.
+
+use std::fmt::Display;
+use std::sync::Arc;
+
+use futures::future::join_all;
+use opendal::Operator;
+
+use crate::backend::Backend;
+use crate::error::Result;
+use crate::scheduler::{Scheduler, SchedulerLayer};
+use crate::telemetry::TelemetryReport;
+use crate::upload::Upload;
+
+pub struct ExtraBackend {
+    /// The backend, without credentials, for logs and telemetry.
+    name: String,
+    api: Operator,
+}
+
+#[derive(Default)]
+pub struct ExtraBackends(Vec<ExtraBackend>);
+
+/// The upload of a NAR to the extra backends.
+pub struct ExtraNar<'a> {
+    key: String,
+
+    /// The backends that have the NAR.
+    stored: Vec<&'a ExtraBackend>,
+
+    /// The backends that the NAR is being uploaded to, with their uploads
+    /// until they are taken.
+    uploading: Vec<&'a ExtraBackend>,
+    uploads: Vec<Upload>,
+}
+
+impl ExtraBackends {
+    /// Create the operators for `backends`.
+    pub fn new(
+        backends: &[Backend],
+        netrc: Option<&str>,
+        metrics: &Arc<TelemetryReport>,
+    ) -> Result<ExtraBackends> {
+        backends
+            .iter()
+            .map(|backend| {
+                // Each backend has its own limits, so it gets its own scheduler.
+                let scheduler = Arc::new(Scheduler::new(None, metrics.clone()));
+
+                Ok(ExtraBackend {
+                    name: backend.redacted().to_string(),
+                    api: backend.operator(netrc)?.layer(SchedulerLayer(scheduler)),
+                })
+            })
+            .collect::<Result<_>>()
+            .map(ExtraBackends)
+    }
+
+    /// Start uploading the NAR under `key` to the backends that don't have it.
+    pub async fn open_nar(&self, key: &str, metrics: &TelemetryReport) -> ExtraNar<'_> {
+        let mut nar = ExtraNar {
+            key: key.to_owned(),
+            stored: Vec::new(),
+            uploading: Vec::new(),
+            uploads: Vec::new(),
+        };
+
+        let writers = join_all(self.0.iter().map(|extra| extra.api.writer(key))).await;
+
+        for (extra, writer) in self.0.iter().zip(writers) {
+            match writer {
+                Ok(writer) => {
+                    nar.uploading.push(extra);
+                    nar.uploads.push(Upload::new(writer, key));
+                }
+                // NARs are keyed by their hash, so an existing one is the same.
+                Err(err)
+                    if gha_cache::is_reserve_conflict(&err)
+                        && gha_cache::existing_matches(&extra.api, key, None)
+                            .await
+                            .unwrap_or(false) =>
+                {
+                    nar.stored.push(extra);
+                }
+                Err(err) => extra.failed(key, &err, metrics),
+            }
+        }
+
+        nar
+    }
+
+    /// Write the narinfo under `key` to the backends that stored its NAR.
+    pub async fn write_narinfo(
+        &self,
+        nar: ExtraNar<'_>,
+        key: &str,
+        narinfo: &str,
+        metrics: &TelemetryReport,
+    ) {
+        let size = narinfo.len() as u64;
+
+        let results = join_all(
+            nar.stored
+                .iter()
+                .map(|extra| extra.api.write(key, narinfo.to_owned())),
+        )
+        .await;
+
+        for (extra, result) in nar.stored.into_iter().zip(results) {
+            match result {
+                Ok(_) => metrics.record_extra(&extra.name, |uploads| {
+                    uploads.narinfos_uploaded += 1;
+                }),
+                Err(err)
+                    if gha_cache::is_reserve_conflict(&err)
+                        && gha_cache::existing_matches(&extra.api, key, Some(size))
+                            .await
+                            .unwrap_or(false) => {}
+                Err(err) => extra.failed(key, &err, metrics),
+            }
+        }
+    }
+}
+
+impl ExtraNar<'_> {
+    /// Whether the NAR still has to be uploaded to some backends.
+    pub fn is_pending(&self) -> bool {
+        !self.uploads.is_empty()
+    }
+
+    /// Take the uploads, to write the NAR to them.
+    pub fn take_uploads(&mut self) -> Vec<Upload> {
+        std::mem::take(&mut self.uploads)
+    }
+
+    /// Record the `results` of the uploads that were taken, which wrote
+    /// `size` bytes.
+    pub fn finish(&mut self, results: Vec<Result<()>>, size: u64, metrics: &TelemetryReport) {
+        for (extra, result) in std::mem::take(&mut self.uploading).into_iter().zip(results) {
+            match result {
+                Ok(()) => {
+                    metrics.record_extra(&extra.name, |uploads| {
+                        uploads.nars_uploaded += 1;
+                        uploads.nar_bytes_uploaded += size;
+                    });
+                    self.stored.push(extra);
+                }
+                Err(err) => extra.failed(&self.key, &err, metrics),
+            }
+        }
+    }
+}
+
+impl ExtraBackend {
+    fn failed(&self, key: &str, err: &dyn Display, metrics: &TelemetryReport) {
+        tracing::warn!("Uploading '{}' to the {} failed: {}", key, self.name, err);
+
+        metrics.record_extra(&self.name, |uploads| uploads.uploads_failed += 1);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::env::Environment;
 use crate::error::{Error, Result};
+use crate::health::{self, BackendHealth};
+use crate::netrc;
 use crate::DETERMINATE_NETRC_PATH;
 use anyhow::Context;
 use attic::cache::CacheName;
@@ -25,10 +27,17 @@ use uuid::Uuid;
 const USER_AGENT: &str = "magic-nix-cache";
 
 pub struct State {
-    #[allow(dead_code)]
     pub substituter: Url,
 
+    api_server: Url,
+
+    /// The netrc with the FlakeHub credentials, which is kept up to date.
+    netrc_path: PathBuf,
+
     pub push_session: PushSession,
+
+    /// Health of the FlakeHub cache, as determined by periodic probes.
+    pub health: Arc<BackendHealth>,
 }
 
 pub async fn init_cache(
@@ -182,6 +191,16 @@ pub async fn init_cache(
 
     let cache_config = api.read().await.get_cache_config(&cache).await?;
 
+    let health = Arc::new(BackendHealth::default());
+
+    let probe_api = api.clone();
+    let probe_cache = cache.clone();
+    health::spawn_probe("FlakeHub cache".to_owned(), health.clone(), move || {
+        let api = probe_api.clone();
+        let cache = probe_cache.clone();
+        async move { api.read().await.get_cache_config(&cache).await.map(|_| ()) }
+    });
+
     let push_config = PushConfig {
         num_workers: 5, // FIXME: use number of CPUs?
         force_preamble: false,
@@ -204,12 +223,29 @@ pub async fn init_cache(
 
     let state = State {
         substituter: flakehub_cache_server.to_owned(),
+        api_server: flakehub_api_server.to_owned(),
+        netrc_path,
         push_session,
+        health,
     };
 
     Ok(state)
 }
 
+impl State {
+    /// The netrc entry for the FlakeHub cache server, with the current token.
+    pub async fn netrc_entry(&self) -> Result<netrc::Entry> {
+        let info =
+            extract_info_from_netrc(&self.netrc_path, &self.api_server, &self.substituter).await?;
+
+        Ok(netrc::Entry {
+            host: info.flakehub_cache_server_hostname,
+            login: info.flakehub_login,
+            password: info.flakehub_password,
+        })
+    }
+}
+
 #[derive(Debug)]
 struct NetrcInfo {
     netrc: netrc_rs::Netrc,
@@ -277,6 +313,11 @@ async fn extract_info_from_netrc(
 }
 
 pub async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
+    if !state.health.is_available() {
+        tracing::warn!("Not enqueueing paths because the FlakeHub cache is unhealthy");
+        return Ok(());
+    }
+
     state.push_session.queue_many(store_paths)?;
 
     Ok(())
@@ -0,0 +1,100 @@
+//! Temporary GC roots for paths waiting to be uploaded.
+//!
+//! A job may run `nix-collect-garbage` to free disk space while uploads are
+//! still queued. Enqueued paths are registered as indirect GC roots, which
+//! are released when the last upload request holding them is dropped.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::process::Command;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::error::{Error, Result};
+use crate::temp;
+
+/// Maximum number of paths passed to a single `nix-store` invocation.
+const MAX_PATHS_PER_INVOCATION: usize = 1000;
+
+static NEXT_BATCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Held shared while paths are being enqueued, and exclusively while
+/// collecting garbage, so that a collection can't race with adding roots.
+static GC_LOCK: RwLock<()> = RwLock::const_new(());
+
+/// Prevent garbage collection by [`crate::disk_guard`] until the guard is dropped.
+pub async fn hold_off_gc() -> RwLockReadGuard<'static, ()> {
+    GC_LOCK.read().await
+}
+
+/// Wait until no paths are being enqueued, and block enqueueing until the guard is dropped.
+pub async fn exclusive() -> RwLockWriteGuard<'static, ()> {
+    GC_LOCK.write().await
+}
+
+/// GC roots for a batch of store paths, removed on drop.
+#[derive(Debug)]
+pub struct GcRoots {
+    dir: PathBuf,
+}
+
+impl GcRoots {
+    /// Register GC roots for `store_paths`, which must be full paths.
+    pub async fn add(store_paths: &[PathBuf]) -> Result<GcRoots> {
+        let dir = temp::dir()
+            .join("gcroots")
+            .join(NEXT_BATCH.fetch_add(1, Ordering::Relaxed).to_string());
+
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| Error::Io(e, format!("Creating {}", dir.display())))?;
+
+        // Constructed before the roots are added so that a partial batch is
+        // cleaned up on error.
+        let roots = GcRoots { dir };
+
+        for (i, chunk) in store_paths.chunks(MAX_PATHS_PER_INVOCATION).enumerate() {
+            roots
+                .add_chunk(&roots.dir.join(format!("root-{}", i)), chunk)
+                .await?;
+        }
+
+        Ok(roots)
+    }
+
+    async fn add_chunk(&self, root: &Path, store_paths: &[PathBuf]) -> Result<()> {
+        let status = Command::new("nix-store")
+            .arg("--realise")
+            .arg("--indirect")
+            .arg("--add-root")
+            .arg(root)
+            .args(store_paths)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .await
+            .map_err(|e| Error::Io(e, "Running nix-store --add-root".to_owned()))?;
+
+        if !status.success() {
+            return Err(Error::Internal(format!(
+                "nix-store --add-root failed: {}",
+                status
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for GcRoots {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            tracing::debug!(
+                "Failed to remove GC roots in {}: {}",
+                self.dir.display(),
+                err
+            );
+        }
+    }
+}
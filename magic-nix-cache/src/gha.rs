@@ -1,36 +1,433 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use crate::artifactory::{self, Artifactory};
+use crate::audit::{self, Action, AuditLog};
+use crate::backend::Backend;
+use crate::bundle::Bundles;
+use crate::cancellation::{self, Cancellation};
+use crate::deferral::Deferral;
+use crate::election::Election;
 use crate::error::{Error, Result};
+use crate::estimate::{CompressionEstimates, UploadBudget};
+use crate::fanout::ExtraBackends;
+use crate::gc_roots::{self, GcRoots};
+use crate::health::{self, BackendHealth};
+use crate::index::{self, Index};
+use crate::keys;
+use crate::manifest::Manifest;
+use crate::policy::{self, Policy};
+use crate::queue_file::QueueFile;
+use crate::retention::RetentionIndex;
+use crate::routing::Routes;
+use crate::scan::Scanner;
+use crate::scheduler::{self, Scheduler, SchedulerLayer};
 use crate::telemetry;
+use crate::upload::{self, Upload};
+use crate::upstream::Upstream;
 use async_compression::tokio::bufread::ZstdEncoder;
 use attic::nix_store::{NixStore, StorePath, ValidPathInfo};
+use attic::signing::NixKeypair;
 use attic_server::narinfo::{Compression, NarInfo};
 use futures::stream::TryStreamExt;
-use futures::AsyncWriteExt;
-use opendal::Operator;
-use tokio::io::copy;
+use opendal::{Buffer, Metadata, Operator};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     RwLock,
 };
-use tokio_util::compat::{
-    FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt, TokioAsyncWriteCompatExt,
-};
+use tokio::task::JoinSet;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
 
 pub struct GhaCache {
     /// The GitHub Actions Cache API.
     pub api: Arc<Operator>,
 
+    /// Health of the GitHub Actions Cache API, as determined by periodic probes.
+    pub health: Arc<BackendHealth>,
+
+    /// The backends that some store paths are routed to instead of `api`.
+    routes: Arc<Routes>,
+
+    /// Other versions of the GitHub Actions Cache, which are read from after
+    /// `api` and the routes, but never written to.
+    restore: Vec<Operator>,
+
     /// The future from the completion of the worker.
     worker_result: RwLock<Option<tokio::task::JoinHandle<Result<()>>>>,
 
     channel_tx: UnboundedSender<Request>,
+
+    /// Paths that were substituted rather than built, which aren't uploaded.
+    substituted: Arc<RwLock<HashSet<StorePath>>>,
+
+    /// Paths at the depth of a depth-limited closure, whose references aren't
+    /// uploaded.
+    shallow: Arc<RwLock<HashSet<StorePath>>>,
+
+    upload_order: UploadOrder,
+
+    /// Whether only the paths that were built or added locally are uploaded.
+    built_only: bool,
+
+    /// The bundles of small paths, if they are bundled.
+    pub bundles: Option<Arc<Bundles>>,
+
+    /// The program that paths are scanned with before they are uploaded.
+    pub scanner: Option<Arc<Scanner>>,
+
+    /// The index of the paths in the cache, if one is kept.
+    pub narinfo_index: Option<Arc<Index>>,
+
+    /// The file the queued paths are kept in, if they are.
+    queue_file: Option<Arc<QueueFile>>,
+
+    name_filter: NameFilter,
+
+    metrics: Arc<telemetry::TelemetryReport>,
+}
+
+/// The backends and components that a [`GhaCache`] is made of.
+pub struct GhaCacheConfig<'a> {
+    pub backend: &'a Backend,
+    pub backend_netrc: Option<&'a str>,
+    pub rate_limit: Option<u32>,
+    pub circuit_breaker: Option<(usize, Duration)>,
+    pub routes: &'a [(Regex, Backend)],
+    pub extra_backends: &'a [Backend],
+    pub restore_versions: &'a [String],
+    pub audit_log: Option<Arc<AuditLog>>,
+    pub deferral: Option<Arc<Deferral>>,
+    pub election: Option<Arc<Election>>,
+    pub upstream: Option<Upstream>,
+    pub signing_key: Option<Arc<NixKeypair>>,
+    pub cancellation: Arc<Cancellation>,
+    pub uploads: UploadConfig,
+}
+
+/// Which paths the worker uploads, and how.
+#[derive(Clone)]
+pub struct UploadConfig {
+    pub max_upload_size: Option<u64>,
+    pub max_nar_size: Option<u64>,
+    pub include_fixed_output: bool,
+    pub scan_command: Option<PathBuf>,
+    pub upload_policy: Option<Arc<Policy>>,
+    pub narinfo_index: Option<Arc<Index>>,
+    pub queue_file: Option<Arc<QueueFile>>,
+    pub bundle_below: Option<u64>,
+    pub max_concurrent_uploads: usize,
+    pub upload_retries: u32,
+    pub upload_order: UploadOrder,
+    pub built_only: bool,
+    pub name_filter: NameFilter,
+}
+
+/// How the closure of enqueued paths is computed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, clap::Args)]
+#[serde(default)]
+pub struct ClosureFlags {
+    /// Upload the paths that refer to the enqueued paths, instead of the
+    /// paths they refer to.
+    #[arg(long = "closure-flip-direction")]
+    pub flip_direction: bool,
+
+    /// Also upload the outputs of the derivations in the closure, e.g. the
+    /// build-time closure of the derivers for remote builders.
+    #[arg(long = "closure-include-outputs")]
+    pub include_outputs: bool,
+
+    /// Also upload the derivations that produced the paths in the closure.
+    #[arg(long = "closure-include-derivers")]
+    pub include_derivers: bool,
+
+    /// Only upload the paths that are at most `N` references away from the
+    /// enqueued paths, leaving the rest of the closure to other caches.
+    #[arg(
+        long = "closure-depth",
+        value_name = "N",
+        conflicts_with_all = ["flip_direction", "include_outputs", "include_derivers"]
+    )]
+    pub depth: Option<usize>,
+}
+
+impl ClosureFlags {
+    /// The flags that are set in either `self` or `other`, with the depth of
+    /// `other` taking precedence.
+    pub fn union(self, other: ClosureFlags) -> ClosureFlags {
+        ClosureFlags {
+            flip_direction: self.flip_direction || other.flip_direction,
+            include_outputs: self.include_outputs || other.include_outputs,
+            include_derivers: self.include_derivers || other.include_derivers,
+            depth: other.depth.or(self.depth),
+        }
+    }
+}
+
+/// Which paths of enqueued closures are uploaded, by name.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct NameFilter {
+    /// Only upload the paths whose name matches this regex, e.g.
+    /// `-(bin|lib)$`. Can be given several times, matching any suffices.
+    #[arg(long = "include-regex", value_name = "REGEX")]
+    pub include: Vec<Regex>,
+
+    /// Don't upload the paths whose name matches this regex, e.g. `-source$`
+    /// or `\.tar\.gz$`. Can be given several times, and wins over
+    /// `--include-regex`.
+    #[arg(long = "exclude-regex", value_name = "REGEX")]
+    pub exclude: Vec<Regex>,
 }
 
+impl NameFilter {
+    /// Whether the store path named `name` is uploaded.
+    pub fn allows(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| pattern.is_match(name)))
+            && !self.exclude.iter().any(|pattern| pattern.is_match(name))
+    }
+}
+
+/// The order in which the worker uploads the paths that were enqueued.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadOrder {
+    /// The order in which the paths were enqueued, with their closures in
+    /// no particular order.
+    Fifo,
+
+    /// The smallest estimated NAR first, so that as many paths as possible
+    /// are uploaded before the job ends.
+    Smallest,
+
+    /// Each path after the paths in its closure that it refers to.
+    #[default]
+    Toposort,
+}
+
+/// NARs that are estimated to be at most this large when compressed are
+/// compressed into memory while the previous path uploads.
+const MAX_PREPARED_SIZE: u64 = 64 << 20;
+
+/// How many paths are passed to one `nix path-info`.
+const PATH_INFO_BATCH: usize = 500;
+
+/// How long to wait before retrying a failed upload the first time. The
+/// delay doubles with every retry, up to [`MAX_RETRY_DELAY`].
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How often the worker checks whether an unavailable backend is back, when
+/// it has nothing else to do but the uploads it holds for it.
+const HELD_RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Key that is read to probe the health of the cache. It does not need to exist.
+const HEALTH_PROBE_KEY: &str = "magic-nix-cache-health-probe";
+
 #[derive(Debug)]
 enum Request {
     Shutdown,
-    Upload(StorePath),
+
+    /// Upload a path, keeping it alive with the given GC roots until the
+    /// request is dropped. Forced uploads replace existing entries.
+    Upload(StorePath, Option<Arc<GcRoots>>, bool),
+}
+
+/// The uploads waiting in the worker while a budget applies or with
+/// [`UploadOrder::Smallest`], with the smallest estimated NAR first.
+#[derive(Default)]
+struct UploadQueue {
+    queued: BinaryHeap<Reverse<Queued>>,
+    count: u64,
+    shutdown: bool,
+
+    /// The request that [`UploadQueue::peek`] took out of the queue.
+    peeked: Option<Request>,
+}
+
+struct Queued {
+    estimate: u64,
+    /// Keeps paths with the same estimate in the order they were enqueued.
+    seq: u64,
+    request: Request,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.estimate, self.seq).cmp(&(other.estimate, other.seq))
+    }
+}
+
+impl UploadQueue {
+    /// The path that the next request uploads, if one is waiting.
+    async fn peek(
+        &mut self,
+        channel_rx: &mut UnboundedReceiver<Request>,
+        store: &NixStore,
+        progress: &Mutex<Progress>,
+        ordered: bool,
+    ) -> Option<&StorePath> {
+        if self.peeked.is_none() {
+            self.peeked = self.try_next(channel_rx, store, progress, ordered).await;
+        }
+
+        match &self.peeked {
+            Some(Request::Upload(path, _, _)) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The next request if one is waiting, taking the smallest of all the
+    /// uploads that are waiting if `ordered` is set.
+    async fn try_next(
+        &mut self,
+        channel_rx: &mut UnboundedReceiver<Request>,
+        store: &NixStore,
+        progress: &Mutex<Progress>,
+        ordered: bool,
+    ) -> Option<Request> {
+        if let Some(req) = self.peeked.take() {
+            return Some(req);
+        }
+
+        if !ordered && self.queued.is_empty() {
+            return channel_rx.try_recv().ok();
+        }
+
+        while !self.shutdown {
+            match channel_rx.try_recv() {
+                Ok(Request::Upload(path, gc_roots, force)) => {
+                    // Paths that are no longer valid fail when they are uploaded.
+                    let nar_size = match store.query_path_info(path.clone()).await {
+                        Ok(path_info) => path_info.nar_size,
+                        Err(_) => 0,
+                    };
+
+                    let estimate = progress
+                        .lock()
+                        .expect("progress lock")
+                        .estimates
+                        .estimate(&path.name(), nar_size);
+
+                    self.count += 1;
+                    self.queued.push(Reverse(Queued {
+                        estimate,
+                        seq: self.count,
+                        request: Request::Upload(path, gc_roots, force),
+                    }));
+                }
+                // Everything that was enqueued before is uploaded first.
+                Ok(Request::Shutdown) => self.shutdown = true,
+                Err(_) => break,
+            }
+        }
+
+        match self.queued.pop() {
+            Some(Reverse(queued)) => Some(queued.request),
+            None if self.shutdown => Some(Request::Shutdown),
+            None => None,
+        }
+    }
+}
+
+/// What the uploads of the worker keep track of together.
+struct Progress {
+    manifest: Manifest,
+    retention: RetentionIndex,
+    estimates: CompressionEstimates,
+    budget: UploadBudget,
+
+    /// Hashes of the paths that the worker is done with, or that it found in
+    /// the cache, whose references aren't enqueued again.
+    settled: HashSet<String>,
+}
+
+/// What the worker and the uploads it runs share.
+struct Uploader {
+    store: Arc<NixStore>,
+    metrics: Arc<telemetry::TelemetryReport>,
+    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+    health: Arc<BackendHealth>,
+    artifactory: Option<Arc<Artifactory>>,
+    substituted: Arc<RwLock<HashSet<StorePath>>>,
+    shallow: Arc<RwLock<HashSet<StorePath>>>,
+    audit_log: Option<Arc<AuditLog>>,
+    routes: Arc<Routes>,
+    extras: ExtraBackends,
+    deferral: Option<Arc<Deferral>>,
+    election: Option<Arc<Election>>,
+    upstream: Option<Upstream>,
+    signing_key: Option<Arc<NixKeypair>>,
+    cancellation: Arc<Cancellation>,
+    scanner: Option<Arc<Scanner>>,
+    bundles: Option<Arc<Bundles>>,
+    config: UploadConfig,
+}
+
+/// The backend that a path is uploaded to, which is the default one unless
+/// the path is routed elsewhere.
+struct Target<'a> {
+    api: &'a Operator,
+    artifactory: Option<&'a Artifactory>,
+    bundles: Option<&'a Bundles>,
+}
+
+/// The NAR of a path that is compressed into memory ahead of its upload.
+struct Prepared {
+    path: StorePath,
+    nar: tokio::task::JoinHandle<Option<Vec<u8>>>,
+}
+
+impl Prepared {
+    fn spawn(store: Arc<NixStore>, path: StorePath) -> Prepared {
+        let nar = tokio::task::spawn({
+            let path = path.clone();
+            async move {
+                let mut nar = Vec::new();
+                let mut reader = compressed_nar(&store, &path).take(MAX_PREPARED_SIZE + 1);
+
+                match reader.read_to_end(&mut nar).await {
+                    Ok(size) if size as u64 <= MAX_PREPARED_SIZE => Some(nar),
+                    _ => None,
+                }
+            }
+        });
+
+        Prepared { path, nar }
+    }
+
+    /// The compressed NAR, or `None` if it turned out too large or failed,
+    /// in which case it is compressed again while uploading.
+    async fn nar(&mut self) -> Option<Vec<u8>> {
+        (&mut self.nar).await.ok().flatten()
+    }
+}
+
+impl Drop for Prepared {
+    fn drop(&mut self) {
+        self.nar.abort();
+    }
 }
 
 impl GhaCache {
@@ -38,35 +435,179 @@ impl GhaCache {
         store: Arc<NixStore>,
         metrics: Arc<telemetry::TelemetryReport>,
         narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+        config: GhaCacheConfig<'_>,
     ) -> Result<GhaCache> {
+        let GhaCacheConfig {
+            backend,
+            backend_netrc,
+            rate_limit,
+            circuit_breaker,
+            routes,
+            extra_backends,
+            restore_versions,
+            audit_log,
+            deferral,
+            election,
+            upstream,
+            signing_key,
+            cancellation,
+            uploads: config,
+        } = config;
         let cb_metrics = metrics.clone();
-        let builder = opendal::services::Ghac::default().version("magic-nix-cache");
-        let api = opendal::Operator::new(builder)?.finish();
+
+        let health = Arc::new(match circuit_breaker {
+            Some((threshold, open_for)) => BackendHealth::with_circuit_breaker(threshold, open_for),
+            None => BackendHealth::default(),
+        });
+
+        // Serving and uploading share the scheduler, so that both back off together.
+        let scheduler =
+            Arc::new(Scheduler::new(rate_limit, metrics.clone()).with_health(health.clone()));
+
+        let api = backend
+            .operator(backend_netrc)?
+            .layer(SchedulerLayer(scheduler.clone()));
+        let restore = restore_versions
+            .iter()
+            .map(|version| {
+                Ok(crate::backend::gha_api_for_version(Some(version))
+                    .build()?
+                    .operator()
+                    .clone()
+                    .layer(SchedulerLayer(scheduler.clone())))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let artifactory = backend
+            .artifactory(backend_netrc)?
+            .map(|artifactory| Arc::new(artifactory.with_scheduler(scheduler)));
+
+        let policy_backends = config
+            .upload_policy
+            .as_ref()
+            .map_or(&[][..], |policy| policy.backends());
+        let routes = Arc::new(Routes::new(
+            routes,
+            policy_backends,
+            backend_netrc,
+            &metrics,
+        )?);
+        let extras = ExtraBackends::new(extra_backends, backend_netrc, &metrics)?;
 
         let (channel_tx, channel_rx) = unbounded_channel();
 
         let api = Arc::new(api);
 
-        let api2 = api.clone();
+        let probe_api = api.clone();
+        health::spawn_probe(backend.to_string(), health.clone(), move || {
+            let api = probe_api.clone();
+            async move {
+                match api.stat(HEALTH_PROBE_KEY).await {
+                    Ok(_) => Ok(()),
+                    Err(err) if err.kind() == opendal::ErrorKind::NotFound => Ok(()),
+                    Err(err) => Err(err),
+                }
+            }
+        });
 
-        let worker_result = tokio::task::spawn(async move {
-            worker(
-                &api2,
-                store,
-                channel_rx,
-                metrics,
-                narinfo_negative_cache.clone(),
-            )
-            .await
+        let substituted = Arc::new(RwLock::new(HashSet::new()));
+        let shallow = Arc::new(RwLock::new(HashSet::new()));
+        let bundles = config
+            .bundle_below
+            .map(|threshold| Arc::new(Bundles::new(threshold)));
+        let scanner = config
+            .scan_command
+            .clone()
+            .map(|program| Arc::new(Scanner::new(program)));
+
+        let uploader = Arc::new(Uploader {
+            store,
+            metrics,
+            narinfo_negative_cache,
+            health: health.clone(),
+            artifactory,
+            substituted: substituted.clone(),
+            shallow: shallow.clone(),
+            audit_log,
+            routes: routes.clone(),
+            extras,
+            deferral,
+            election,
+            upstream,
+            signing_key,
+            cancellation,
+            scanner: scanner.clone(),
+            bundles: bundles.clone(),
+            config: config.clone(),
         });
 
+        let api2 = api.clone();
+        let worker_result =
+            tokio::task::spawn(async move { worker(&api2, channel_rx, uploader).await });
+
         Ok(GhaCache {
             api,
+            health,
+            routes,
+            restore,
             worker_result: RwLock::new(Some(worker_result)),
             channel_tx,
+            substituted,
+            shallow,
+            upload_order: config.upload_order,
+            built_only: config.built_only,
+            bundles,
+            scanner,
+            narinfo_index: config.narinfo_index,
+            queue_file: config.queue_file,
+            name_filter: config.name_filter,
+            metrics: cb_metrics,
         })
     }
 
+    /// The backend that the store path named `name` is stored in.
+    pub fn api_for(&self, name: &str) -> &Operator {
+        self.routes
+            .select(name)
+            .map_or(&*self.api, |route| &*route.api)
+    }
+
+    /// The default backend, followed by the backends of the routes.
+    pub fn apis(&self) -> impl Iterator<Item = &Operator> {
+        std::iter::once(&*self.api).chain(self.routes.apis())
+    }
+
+    /// Read the first of `keys` that exists, looking in the default backend
+    /// first and in the restored versions last.
+    pub async fn read_any(&self, keys: &[String]) -> opendal::Result<Buffer> {
+        let mut result = keys::read_any(&self.api, keys).await;
+
+        for api in self.routes.apis().chain(&self.restore) {
+            if !matches!(&result, Err(err) if err.kind() == opendal::ErrorKind::NotFound) {
+                break;
+            }
+
+            result = keys::read_any(api, keys).await;
+        }
+
+        result
+    }
+
+    /// Find the first of `keys` that exists, looking in the default backend
+    /// first and in the restored versions last, returning it along with its
+    /// backend and metadata.
+    pub async fn stat_any(
+        &self,
+        keys: &[String],
+    ) -> opendal::Result<Option<(&Operator, String, Metadata)>> {
+        for api in self.apis().chain(&self.restore) {
+            if let Some((key, metadata)) = keys::stat_any(api, keys).await? {
+                return Ok(Some((api, key, metadata)));
+            }
+        }
+
+        Ok(None)
+    }
+
     pub async fn shutdown(&self) -> Result<()> {
         if let Some(worker_result) = self.worker_result.write().await.take() {
             self.channel_tx
@@ -80,25 +621,107 @@ impl GhaCache {
         }
     }
 
+    /// Don't upload `store_paths` unless they are forced, because they were
+    /// substituted from another cache.
+    pub async fn mark_substituted(&self, store_paths: impl IntoIterator<Item = StorePath>) {
+        self.substituted.write().await.extend(store_paths);
+    }
+
+    /// Upload `store_paths` and their closures, as computed with `closure`.
+    /// If `force` is set, `store_paths` themselves are uploaded even if the
+    /// cache already has them, e.g. to replace corrupted entries.
     pub async fn enqueue_paths(
         &self,
         store: Arc<NixStore>,
         store_paths: Vec<StorePath>,
+        force: bool,
+        closure: ClosureFlags,
     ) -> Result<()> {
-        // FIXME: make sending the closure optional. We might want to
-        // only send the paths that have been built by the user, under
-        // the assumption that everything else is already in a binary
-        // cache.
-        // FIXME: compute_fs_closure_multi doesn't return a
-        // toposort, though it doesn't really matter for the GHA
-        // cache.
-        let closure = store
-            .compute_fs_closure_multi(store_paths, false, false, false)
-            .await?;
+        // Don't let the disk guard collect these paths before they have GC roots.
+        let _gc_lock = gc_roots::hold_off_gc().await;
+
+        let forced = if force {
+            store_paths.iter().cloned().collect()
+        } else {
+            HashSet::new()
+        };
+
+        let (closure, shallow) = match closure.depth {
+            Some(_)
+                if closure.flip_direction
+                    || closure.include_outputs
+                    || closure.include_derivers =>
+            {
+                return Err(Error::Config(
+                    "the closure depth can't be combined with the other closure flags".to_owned(),
+                ));
+            }
+            Some(depth) => bounded_closure(&store, store_paths, depth).await?,
+            None => {
+                let closure = store
+                    .compute_fs_closure_multi(
+                        store_paths,
+                        closure.flip_direction,
+                        closure.include_outputs,
+                        closure.include_derivers,
+                    )
+                    .await?;
+
+                (closure, Vec::new())
+            }
+        };
+
+        self.shallow.write().await.extend(shallow);
+
+        let total = closure.len();
+        let closure = closure
+            .into_iter()
+            .filter(|path| forced.contains(path) || self.name_filter.allows(&path.name()))
+            .collect::<Vec<_>>();
+        self.metrics.uploads_excluded.add(total - closure.len());
+
+        // The rest of the closure is assumed to be in the cache it was
+        // substituted from.
+        let closure = if self.built_only {
+            let total = closure.len();
+            let built = locally_built(&store, closure, &forced).await?;
+            self.metrics
+                .uploads_skipped_not_built
+                .add(total - built.len());
+            built
+        } else {
+            closure
+        };
+
+        let closure = match self.upload_order {
+            UploadOrder::Toposort => toposort(&store, closure).await,
+            UploadOrder::Fifo | UploadOrder::Smallest => closure,
+        };
+
+        let full_paths = closure
+            .iter()
+            .map(|p| store.get_full_path(p))
+            .collect::<Vec<_>>();
+
+        let gc_roots = match GcRoots::add(&full_paths).await {
+            Ok(gc_roots) => Some(Arc::new(gc_roots)),
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to add GC roots for enqueued paths, they may be garbage-collected before being uploaded: {}",
+                    err
+                );
+                None
+            }
+        };
+
+        if let Some(queue_file) = &self.queue_file {
+            queue_file.add(full_paths);
+        }
 
         for p in closure {
+            let force = forced.contains(&p);
             self.channel_tx
-                .send(Request::Upload(p))
+                .send(Request::Upload(p, gc_roots.clone(), force))
                 .map_err(|_| Error::Internal("Cannot send upload message".to_owned()))?;
         }
 
@@ -108,116 +731,1623 @@ impl GhaCache {
 
 async fn worker(
     api: &Operator,
-    store: Arc<NixStore>,
     mut channel_rx: UnboundedReceiver<Request>,
-    metrics: Arc<telemetry::TelemetryReport>,
-    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+    uploader: Arc<Uploader>,
 ) -> Result<()> {
-    let mut done = HashSet::new();
+    let Uploader {
+        store,
+        metrics,
+        health,
+        substituted,
+        deferral,
+        cancellation,
+        bundles,
+        config,
+        ..
+    } = &*uploader;
+    let mut backlog = Backlog::default();
+
+    if let Some(bundles) = bundles {
+        bundles.load(api).await;
+    }
+
+    if let Some(narinfo_index) = &config.narinfo_index {
+        narinfo_index.load(api).await;
+    }
+
+    let progress = Arc::new(Mutex::new(Progress {
+        manifest: Manifest::load(api).await,
+        retention: RetentionIndex::new(),
+        estimates: CompressionEstimates::default(),
+        budget: UploadBudget::new(config.max_upload_size),
+        settled: HashSet::new(),
+    }));
+    let mut queue = UploadQueue::default();
+
+    // The NAR of the path that is likely to be uploaded next.
+    let mut prepared: Option<Prepared> = None;
+
+    // The uploads that are running, which yield the path and its references.
+    let mut uploads = JoinSet::new();
+
+    // Whether the worker stops once the running uploads and their missing
+    // references are done.
+    let mut shutting_down = false;
+
+    loop {
+        if uploads.len() >= config.max_concurrent_uploads {
+            if let Some(finished) = uploads.join_next().await {
+                backlog.finish(&uploader, &progress, finished);
+            }
+            continue;
+        }
+
+        // Not everything may fit, so make room for as many paths as possible.
+        let ordered = config.upload_order == UploadOrder::Smallest || {
+            let progress = progress.lock().expect("progress lock");
+            progress.budget.is_limited() || cancellation.is_cancelled()
+        };
+
+        // Missing references are needed by a path that was uploaded, so they
+        // can't be deferred. The uploads held while the backend was
+        // unavailable go first once it is back, and weren't deferred before.
+        let next = health
+            .is_available()
+            .then(|| backlog.held.pop_front())
+            .flatten()
+            .or_else(|| {
+                backlog
+                    .missing_references
+                    .pop_front()
+                    .map(|path| Request::Upload(path, None, false))
+            });
+
+        let (req, required) = match next {
+            Some(req) => (req, true),
+            None if shutting_down => match uploads.join_next().await {
+                Some(finished) => {
+                    backlog.finish(&uploader, &progress, finished);
+                    continue;
+                }
+                None => break,
+            },
+            None => {
+                let req = match queue
+                    .try_next(&mut channel_rx, store, &progress, ordered)
+                    .await
+                {
+                    Some(req) => Some(req),
+                    None => tokio::select! {
+                        Some(finished) = uploads.join_next(), if !uploads.is_empty() => {
+                            backlog.finish(&uploader, &progress, finished);
+                            continue;
+                        }
+                        _ = tokio::time::sleep(HELD_RECHECK_INTERVAL), if !backlog.held.is_empty() => {
+                            continue;
+                        }
+                        req = channel_rx.recv() => req,
+                    },
+                };
+
+                match req {
+                    Some(req) => (req, false),
+                    None => {
+                        shutting_down = true;
+                        continue;
+                    }
+                }
+            }
+        };
 
-    while let Some(req) = channel_rx.recv().await {
         match req {
             Request::Shutdown => {
-                break;
+                shutting_down = true;
             }
-            Request::Upload(path) => {
-                // if api.circuit_breaker_tripped() {
-                //     tracing::trace!("GitHub Actions gave us a 429, so we're done.",);
-                //     continue;
-                // }
+            Request::Upload(path, gc_roots, force) => {
+                // The path leaves the queue file once the worker is done
+                // with it, unless the next daemon should retry it.
+                let dequeue = config
+                    .queue_file
+                    .as_ref()
+                    .map(|queue_file| queue_file.dequeue_on_drop(store.get_full_path(&path)));
+
+                let path_prepared = match prepared.take() {
+                    Some(p) if p.path == path => Some(p),
+                    other => {
+                        prepared = other;
+                        None
+                    }
+                };
 
-                if !done.insert(path.clone()) {
+                if !backlog.done.insert(path.clone()) && !force {
                     continue;
                 }
 
-                if let Err(err) = upload_path(
-                    api,
-                    store.clone(),
-                    &path,
-                    metrics.clone(),
-                    narinfo_negative_cache.clone(),
-                )
-                .await
+                if !force && substituted.read().await.contains(&path) {
+                    tracing::debug!(
+                        "Not uploading '{}' because it was substituted",
+                        store.get_full_path(&path).display()
+                    );
+                    metrics.uploads_skipped_substituted.incr();
+                    continue;
+                }
+
+                if let Some(deferral) = deferral
+                    .as_deref()
+                    .filter(|deferral| !force && !required && deferral.defers(&path.name()))
                 {
-                    tracing::error!(
-                        "Upload of path '{}' failed: {}",
-                        store.get_full_path(&path).display(),
-                        err
+                    let full_path = store.get_full_path(&path);
+                    tracing::debug!(
+                        "Deferring the upload of '{}' to a scheduled run",
+                        full_path.display()
+                    );
+                    deferral.record(&full_path).await;
+                    metrics.uploads_deferred.incr();
+
+                    // Upload it after all if another path turns out to need it.
+                    backlog.done.remove(&path);
+                    continue;
+                }
+
+                // Hold it until the backend is available again, e.g. once the
+                // circuit closes. It stays in the queue file meanwhile.
+                if !health.is_available() {
+                    tracing::warn!(
+                        "Holding the upload of '{}' until the cache backend is healthy again",
+                        store.get_full_path(&path).display()
                     );
+                    metrics.uploads_skipped_unhealthy.incr();
+                    if let Some(dequeue) = dequeue {
+                        dequeue.keep();
+                    }
+                    backlog.done.remove(&path);
+                    backlog
+                        .held
+                        .push_back(Request::Upload(path, gc_roots, force));
+                    continue;
+                }
+
+                // Overlap compressing the next path with uploading this one.
+                if prepared.is_none() {
+                    let next = match backlog.missing_references.front() {
+                        Some(next) => Some(next.clone()),
+                        None => queue
+                            .peek(&mut channel_rx, store, &progress, ordered)
+                            .await
+                            .cloned(),
+                    };
+
+                    if let Some(next) =
+                        next.filter(|next| *next != path && !backlog.done.contains(next))
+                    {
+                        let fits = match store.query_path_info(next.clone()).await {
+                            Ok(path_info) => {
+                                let progress = progress.lock().expect("progress lock");
+                                progress
+                                    .estimates
+                                    .estimate(&next.name(), path_info.nar_size)
+                                    <= MAX_PREPARED_SIZE
+                            }
+                            Err(_) => false,
+                        };
+
+                        if fits {
+                            prepared = Some(Prepared::spawn(store.clone(), next));
+                        }
+                    }
                 }
+
+                let uploader = uploader.clone();
+                let progress = progress.clone();
+                let api = api.clone();
+
+                backlog.running.insert(path.clone());
+                uploads.spawn(async move {
+                    let Uploader {
+                        store,
+                        metrics,
+                        shallow,
+                        routes,
+                        cancellation,
+                        config,
+                        ..
+                    } = &*uploader;
+
+                    // The GC roots keep the path alive until it is uploaded.
+                    let _gc_roots = gc_roots;
+
+                    // The policy looks at the path info, so it is applied here
+                    // rather than holding up the worker.
+                    let action = match &config.upload_policy {
+                        Some(upload_policy) => policy_action(upload_policy, store, &path).await,
+                        None => None,
+                    };
+
+                    let route = match action {
+                        Some(policy::Action::Route(index)) => routes.policy_backend(index).cloned(),
+                        _ => routes.select(&path.name()).map(|route| route.api.clone()),
+                    };
+
+                    // Checksum deploys and bundles only work on the default backend.
+                    let (path_api, path_artifactory, path_bundles) = match route {
+                        Some(route_api) => ((*route_api).clone(), None, None),
+                        None => (
+                            api,
+                            uploader.artifactory.clone(),
+                            uploader.bundles.clone(),
+                        ),
+                    };
+
+                    let mut path_prepared = path_prepared;
+                    let mut attempt = 0;
+
+                    let result = loop {
+                        let target = Target {
+                            api: &path_api,
+                            artifactory: path_artifactory.as_deref(),
+                            bundles: path_bundles.as_deref(),
+                        };
+                        let result = upload_path(
+                            &uploader,
+                            target,
+                            &path,
+                            &progress,
+                            action,
+                            path_prepared.take(),
+                            force,
+                        )
+                        .await;
+
+                        match result {
+                            Err(err)
+                                if attempt < config.upload_retries
+                                    && is_retryable(&err)
+                                    && !cancellation.is_cancelled() =>
+                            {
+                                let delay = retry_delay(attempt);
+                                attempt += 1;
+                                metrics.uploads_retried.incr();
+
+                                tracing::warn!(
+                                    "Upload of path '{}' failed, retrying in {:?} ({} of {}): {}",
+                                    store.get_full_path(&path).display(),
+                                    delay,
+                                    attempt,
+                                    config.upload_retries,
+                                    err
+                                );
+
+                                tokio::time::sleep(delay).await;
+                            }
+                            result => break result,
+                        }
+                    };
+
+                    if let (Err(err), Some(dequeue)) = (&result, dequeue) {
+                        if is_retryable(err) {
+                            dequeue.keep();
+                        }
+                    }
+
+                    let result = match result {
+                        Ok(uploaded) if shallow.read().await.contains(&path) => {
+                            tracing::debug!(
+                                "Not enqueueing the references of '{}', which is at the closure depth",
+                                store.get_full_path(&path).display()
+                            );
+                            Ok(uploaded.without_references())
+                        }
+                        result => result,
+                    };
+
+                    (path, result)
+                });
             }
         }
     }
 
+    if !backlog.held.is_empty() {
+        tracing::warn!(
+            "Not uploading {} paths because the cache backend was still unhealthy at shutdown",
+            backlog.held.len()
+        );
+    }
+
+    // The uploads are all done, so nothing else holds the progress.
+    let Progress {
+        manifest,
+        retention,
+        ..
+    } = Arc::into_inner(progress)
+        .expect("the uploads are done")
+        .into_inner()
+        .expect("progress lock");
+
+    manifest.save(api, uploader.audit_log.as_deref()).await;
+
+    if let Some(bundles) = bundles {
+        if let Err(err) = bundles.flush(api).await {
+            tracing::warn!("{}", err);
+        }
+
+        bundles.save(api, uploader.audit_log.as_deref()).await;
+    }
+
+    retention.save(api).await;
+
+    if let Some(narinfo_index) = &config.narinfo_index {
+        narinfo_index.save(api, uploader.audit_log.as_deref()).await;
+    }
+
+    if let Some(queue_file) = &config.queue_file {
+        queue_file.flush().await;
+    }
+
     Ok(())
 }
 
-async fn upload_path(
-    api: &Operator,
-    store: Arc<NixStore>,
-    path: &StorePath,
-    metrics: Arc<telemetry::TelemetryReport>,
-    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
-) -> Result<()> {
-    let path_info = store.query_path_info(path.clone()).await?;
+/// The paths that the worker took on, and the ones it still has to get to
+/// besides the enqueued ones.
+#[derive(Default)]
+struct Backlog {
+    /// Paths that aren't taken on again unless they are forced.
+    done: HashSet<StorePath>,
 
-    // Upload the NAR.
-    let nar_path = format!("{}.nar.zstd", path_info.nar_hash.to_base32());
+    /// Paths whose uploads are running.
+    running: HashSet<StorePath>,
 
-    let nar_stream = store.nar_from_path(path.clone());
+    /// References discovered during uploads that still need to be uploaded.
+    missing_references: VecDeque<StorePath>,
 
-    let nar_reader = nar_stream
-        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
-        .into_async_read();
+    /// Paths that wait for their references to be uploaded before their
+    /// narinfos are, with the references that haven't settled yet.
+    waiting: HashMap<StorePath, HashSet<StorePath>>,
 
-    let mut nar_compressor = ZstdEncoder::new(nar_reader.compat());
+    /// Paths that were woken once their references settled, which fail if
+    /// they still miss some.
+    woken: HashSet<StorePath>,
 
-    let mut writer = api
-        .writer(&nar_path)
-        .await?
-        .into_futures_async_write()
-        .compat_write();
+    /// Uploads held until the backend is available again.
+    held: VecDeque<Request>,
+}
 
-    let compressed_nar_size = copy(&mut nar_compressor, &mut writer).await?;
+impl Backlog {
+    /// Handle the result of a finished upload.
+    fn finish(
+        &mut self,
+        uploader: &Uploader,
+        progress: &Mutex<Progress>,
+        finished: std::result::Result<(StorePath, Result<Uploaded>), tokio::task::JoinError>,
+    ) {
+        let store = &uploader.store;
+        let metrics = &uploader.metrics;
+        let queue_file = uploader.config.queue_file.as_deref();
 
-    writer.compat_write().close().await?;
+        let (path, result) = match finished {
+            Ok(finished) => finished,
+            Err(err) => {
+                metrics.uploads_failed.incr();
+                tracing::error!("An upload task failed: {}", err);
+                return;
+            }
+        };
 
-    // let compressed_nar_size = api.upload_file(nar_allocation, nar_compressor).await?;
-    metrics.nars_uploaded.incr();
+        self.running.remove(&path);
 
-    tracing::debug!(
-        "Uploaded '{}' (size {} -> {})",
-        nar_path,
-        path_info.nar_size,
-        compressed_nar_size
-    );
+        if !matches!(result, Err(Error::ReferencesPending(_))) {
+            self.woken.remove(&path);
+        }
 
-    // Upload the narinfo.
-    let narinfo_path = format!("{}.narinfo", path.to_hash().as_str());
+        match result {
+            Ok(uploaded) => {
+                // A skipped path isn't in the cache, so neither may be the
+                // paths that refer to it.
+                let (settled, references) = match uploaded {
+                    Uploaded::Settled(references) => (true, references),
+                    Uploaded::Skipped(references) => (false, references),
+                };
 
-    let narinfo = path_info_to_nar_info(store.clone(), &path_info, format!("nar/{}", nar_path))
-        .to_string()
-        .expect("failed to convert path into to nar info");
+                {
+                    let mut progress = progress.lock().expect("progress lock");
+                    if settled {
+                        progress.settled.insert(path.to_hash().to_string());
+                    }
 
-    tracing::debug!("Uploading '{}'", narinfo_path);
+                    // Make sure we never advertise a path whose references
+                    // are missing, e.g. when it wasn't enqueued with its closure.
+                    for reference in references {
+                        if !self.done.contains(&reference)
+                            && !self.missing_references.contains(&reference)
+                            && !progress.settled.contains(&reference.to_hash().to_string())
+                        {
+                            tracing::debug!(
+                                "Enqueueing reference '{}' of '{}'",
+                                store.get_full_path(&reference).display(),
+                                store.get_full_path(&path).display()
+                            );
+                            if let Some(queue_file) = queue_file {
+                                queue_file.add([store.get_full_path(&reference)]);
+                            }
+                            self.missing_references.push_back(reference);
+                        }
+                    }
+                }
 
-    api.write(&narinfo_path, narinfo).await?;
+                if settled {
+                    self.wake(store, &path);
+                } else {
+                    self.abandon(uploader, &path);
+                }
+            }
+            Err(Error::ReferencesPending(references)) => {
+                // A woken path's references were all uploaded, so any that
+                // are still missing failed. So did a reference that the
+                // worker is done with, unless it waits for references itself.
+                let failed = self.woken.remove(&path)
+                    || references.iter().any(|reference| {
+                        self.done.contains(reference)
+                            && !self.running.contains(reference)
+                            && !self.waiting.contains_key(reference)
+                    });
 
-    metrics.narinfos_uploaded.incr();
+                if failed {
+                    metrics.uploads_failed.incr();
 
-    narinfo_negative_cache
-        .write()
-        .await
-        .remove(&path.to_hash().to_string());
+                    tracing::error!(
+                        "Not uploading '{}' because some of its references couldn't be uploaded",
+                        store.get_full_path(&path).display()
+                    );
+                    self.abandon(uploader, &path);
+                    return;
+                }
+
+                for reference in &references {
+                    if !self.done.contains(reference)
+                        && !self.missing_references.contains(reference)
+                    {
+                        tracing::debug!(
+                            "Enqueueing reference '{}' of '{}'",
+                            store.get_full_path(reference).display(),
+                            store.get_full_path(&path).display()
+                        );
+                        if let Some(queue_file) = queue_file {
+                            queue_file.add([store.get_full_path(reference)]);
+                        }
+                        self.missing_references.push_front(reference.clone());
+                    }
+                }
+
+                tracing::debug!(
+                    "Uploading '{}' once its {} missing references are",
+                    store.get_full_path(&path).display(),
+                    references.len()
+                );
+
+                // The upload took it out of the queue file. The paths that
+                // wait for it keep waiting until it settles.
+                if let Some(queue_file) = queue_file {
+                    queue_file.add([store.get_full_path(&path)]);
+                }
+                self.waiting.insert(path, references.into_iter().collect());
+            }
+            Err(Error::Cancelled) => {
+                tracing::warn!(
+                    "Abandoned the upload of '{}' because the job was cancelled",
+                    store.get_full_path(&path).display()
+                );
+                self.abandon(uploader, &path);
+            }
+            Err(Error::PathNotValid(full_path)) => {
+                tracing::warn!(
+                    "Not uploading '{}' because it is no longer valid, e.g. it was garbage-collected",
+                    full_path.display()
+                );
+                self.abandon(uploader, &path);
+            }
+            Err(err) => {
+                metrics.uploads_failed.incr();
+
+                tracing::error!(
+                    "Upload of path '{}' failed: {}",
+                    store.get_full_path(&path).display(),
+                    err
+                );
+                self.abandon(uploader, &path);
+            }
+        }
+    }
+
+    /// Upload the paths that waited for `path`, which settled, if it was the
+    /// last of their references that hadn't.
+    fn wake(&mut self, store: &NixStore, path: &StorePath) {
+        let mut ready = Vec::new();
+        for (referrer, pending) in &mut self.waiting {
+            if pending.remove(path) && pending.is_empty() {
+                ready.push(referrer.clone());
+            }
+        }
+
+        for referrer in ready {
+            tracing::debug!(
+                "Uploading '{}' now that its references are",
+                store.get_full_path(&referrer).display()
+            );
+            self.waiting.remove(&referrer);
+            self.woken.insert(referrer.clone());
+            self.done.remove(&referrer);
+            self.missing_references.push_front(referrer);
+        }
+    }
+
+    /// Give up on the paths that wait for `path`, which won't be in the
+    /// cache, and on the paths that wait for those.
+    fn abandon(&mut self, uploader: &Uploader, path: &StorePath) {
+        let store = &uploader.store;
+        let mut abandoned = vec![path.clone()];
+
+        while let Some(reference) = abandoned.pop() {
+            let referrers = self
+                .waiting
+                .iter()
+                .filter(|(_, pending)| pending.contains(&reference))
+                .map(|(referrer, _)| referrer.clone())
+                .collect::<Vec<_>>();
+
+            for referrer in referrers {
+                self.waiting.remove(&referrer);
+                uploader.metrics.uploads_failed.incr();
+
+                tracing::error!(
+                    "Not uploading '{}' because its reference '{}' couldn't be uploaded",
+                    store.get_full_path(&referrer).display(),
+                    store.get_full_path(&reference).display()
+                );
+
+                // It went back into the queue file when it started waiting.
+                if let Some(queue_file) = &uploader.config.queue_file {
+                    drop(queue_file.dequeue_on_drop(store.get_full_path(&referrer)));
+                }
+
+                abandoned.push(referrer);
+            }
+        }
+    }
+}
+
+/// What became of a path that the worker took on, with the references of
+/// the path that still need to be uploaded.
+enum Uploaded {
+    /// The path is in the cache, or another job of the run uploads it.
+    Settled(Vec<StorePath>),
+
+    /// The path was deliberately not uploaded, so the paths that refer to it
+    /// can't be either.
+    Skipped(Vec<StorePath>),
+}
+
+impl Uploaded {
+    /// The same outcome, for a path whose references are left to other caches.
+    fn without_references(self) -> Uploaded {
+        match self {
+            Uploaded::Settled(_) => Uploaded::Settled(Vec::new()),
+            Uploaded::Skipped(_) => Uploaded::Skipped(Vec::new()),
+        }
+    }
+}
+
+async fn upload_path(
+    uploader: &Uploader,
+    target: Target<'_>,
+    path: &StorePath,
+    progress: &Mutex<Progress>,
+    action: Option<policy::Action>,
+    prepared: Option<Prepared>,
+    force: bool,
+) -> Result<Uploaded> {
+    let Target {
+        api,
+        artifactory,
+        bundles,
+    } = target;
+    let Uploader {
+        store,
+        metrics,
+        narinfo_negative_cache,
+        extras,
+        cancellation,
+        config,
+        ..
+    } = uploader;
+    let audit_log = uploader.audit_log.as_deref();
+    let election = uploader.election.as_deref();
+    let upstream = uploader.upstream.as_ref();
+    let signing_key = uploader.signing_key.as_deref();
+    let scanner = uploader.scanner.as_deref();
+    let narinfo_index = config.narinfo_index.as_deref();
+
+    let path_info = query_valid_path(store, path).await?;
+
+    if action == Some(policy::Action::Skip) && !force {
+        tracing::debug!(
+            "Not uploading '{}' because the upload policy skips it",
+            store.get_full_path(path).display()
+        );
+        metrics.uploads_skipped_policy.incr();
+
+        return Ok(Uploaded::Skipped(references(store, &path_info)?));
+    }
+
+    // A rule that uploads the path wins over the flags.
+    let chosen = matches!(
+        action,
+        Some(policy::Action::Upload | policy::Action::Route(_))
+    );
+
+    if let Some(max_nar_size) = config.max_nar_size.filter(|_| !force && !chosen) {
+        if path_info.nar_size > max_nar_size {
+            tracing::info!(
+                "Not uploading '{}' because its NAR of {} bytes is larger than --max-nar-size",
+                store.get_full_path(path).display(),
+                path_info.nar_size
+            );
+            metrics.uploads_skipped_too_large.incr();
+
+            return Ok(Uploaded::Skipped(Vec::new()));
+        }
+    }
+
+    // Fixed-output paths, like the sources fetched by `fetchurl`, are cheap
+    // to download again from where they came from.
+    if !config.include_fixed_output
+        && !force
+        && !chosen
+        && is_fixed_output(&store.get_full_path(path), &path_info).await
+    {
+        tracing::debug!(
+            "Not uploading '{}' because it is the output of a fixed-output derivation",
+            store.get_full_path(path).display()
+        );
+        metrics.uploads_skipped_fixed_output.incr();
+
+        return Ok(Uploaded::Skipped(references(store, &path_info)?));
+    }
+
+    let store_path_hash = path.to_hash().to_string();
+    let nar_hash = path_info.nar_hash.to_base32();
+
+    let nar_path = format!("{}.nar.zstd", nar_hash);
+    let nar_key = keys::nar(&nar_path);
+    let narinfo_path = keys::narinfo(&store_path_hash);
+
+    let already_uploaded = {
+        let mut progress = progress.lock().expect("progress lock");
+
+        // Entries this run relies on are kept as long as the run is.
+        progress.retention.record(nar_key.clone());
+        progress.retention.record(narinfo_path.clone());
+
+        let bundle_key = bundles.and_then(|bundles| bundles.bundle_key(&store_path_hash));
+        let bundled = bundle_key.is_some();
+        if let Some(bundle_key) = bundle_key {
+            progress.retention.record(bundle_key);
+        }
+
+        bundled || progress.manifest.contains(&store_path_hash, &nar_hash)
+    };
+
+    let backend = index::backend_label(api);
+    let already_uploaded = already_uploaded
+        || match narinfo_index {
+            Some(narinfo_index) => {
+                narinfo_index
+                    .has_path(&store_path_hash, &nar_hash, &backend)
+                    .await
+            }
+            None => false,
+        };
+
+    if !force && already_uploaded {
+        tracing::debug!(
+            "Not uploading '{}' because an earlier run uploaded it",
+            store.get_full_path(path).display()
+        );
+        metrics.uploads_skipped_manifest.incr();
+
+        narinfo_negative_cache
+            .write()
+            .await
+            .remove(&store_path_hash);
+
+        return Ok(Uploaded::Settled(references(store, &path_info)?));
+    }
+
+    if let Some(upstream) = upstream.filter(|_| !force) {
+        let has = if upstream.checks_hashes() {
+            let upstream_hashes = upstream.nar_hashes(&store_path_hash).await;
+            let mismatches = upstream_hashes
+                .iter()
+                .filter(|(_, upstream_hash)| *upstream_hash != nar_hash)
+                .collect::<Vec<_>>();
+
+            for (url, upstream_hash) in &mismatches {
+                tracing::warn!(
+                    "'{}' has the NAR hash {} here, but {} in the upstream cache {}, so it wasn't built reproducibly, or one of them was tampered with",
+                    store.get_full_path(path).display(),
+                    nar_hash,
+                    upstream_hash,
+                    url
+                );
+            }
+
+            if !mismatches.is_empty() {
+                metrics.upstream_hash_mismatches.incr();
+            }
+
+            !upstream_hashes.is_empty()
+        } else {
+            upstream.has(&store_path_hash).await
+        };
+
+        if has {
+            tracing::debug!(
+                "Not uploading '{}' because the upstream cache has it",
+                store.get_full_path(path).display()
+            );
+            metrics.uploads_skipped_upstream.incr();
+
+            // The upstream cache has its references too.
+            return Ok(Uploaded::Settled(Vec::new()));
+        }
+    }
+
+    // Nix may fetch the narinfo as soon as it is written, so the paths it
+    // refers to are uploaded first. The references of the paths at the
+    // closure depth are left to other caches.
+    if !uploader.shallow.read().await.contains(path) {
+        let pending =
+            pending_references(uploader, api, bundles, progress, path, &path_info).await?;
+        if !pending.is_empty() {
+            return Err(Error::ReferencesPending(pending));
+        }
+    }
+
+    let (estimate, fits) = {
+        let progress = progress.lock().expect("progress lock");
+        let estimate = progress
+            .estimates
+            .estimate(&path.name(), path_info.nar_size);
+        (estimate, progress.budget.fits(estimate))
+    };
+
+    // The job is killed soon, so only small paths can still make it.
+    let large = estimate > cancellation::MAX_NAR_SIZE;
+    if large && cancellation.is_cancelled() {
+        tracing::debug!(
+            "Not uploading '{}' because the job was cancelled",
+            store.get_full_path(path).display()
+        );
+        metrics.uploads_skipped_cancelled.incr();
+
+        return Ok(Uploaded::Skipped(Vec::new()));
+    }
+
+    if !fits {
+        tracing::debug!(
+            "Not uploading '{}' because its NAR of about {} bytes doesn't fit in the upload budget",
+            store.get_full_path(path).display(),
+            estimate
+        );
+        metrics.uploads_skipped_budget.incr();
+
+        return Ok(Uploaded::Skipped(Vec::new()));
+    }
+
+    // Even forced uploads are scanned.
+    if let Some(scanner) = scanner {
+        if let Some(reason) = scanner.scan(&store.get_full_path(path)).await {
+            tracing::warn!(
+                "Not uploading '{}' because the scan rejected it: {}",
+                store.get_full_path(path).display(),
+                reason
+            );
+            metrics.uploads_skipped_scan.incr();
+
+            return Ok(Uploaded::Skipped(references(store, &path_info)?));
+        }
+    }
+
+    // A forced upload replaces the entry, whoever claimed it.
+    if let Some(election) = election.filter(|_| !force) {
+        progress
+            .lock()
+            .expect("progress lock")
+            .retention
+            .record(election.key(&store_path_hash));
+
+        if !election.claim(api, &store_path_hash).await {
+            tracing::debug!(
+                "Not uploading '{}' because another job of this run claimed it",
+                store.get_full_path(path).display()
+            );
+            metrics.uploads_skipped_claimed.incr();
+
+            return Ok(Uploaded::Settled(references(store, &path_info)?));
+        }
+    }
+
+    // Upload the NAR.
+
+    // It may have been compressed while the previous path was uploading.
+    let prepared_nar = match prepared {
+        Some(mut prepared) => prepared.nar().await,
+        None => None,
+    };
+    let nar_reader = || -> Box<dyn AsyncRead + Unpin + Send + '_> {
+        match &prepared_nar {
+            Some(nar) => Box::new(nar.as_slice()),
+            None => Box::new(compressed_nar(store, path)),
+        }
+    };
+
+    // Small paths go into a bundle instead of entries of their own.
+    if let Some(bundles) = bundles.filter(|bundles| !force && bundles.accepts(path_info.nar_size)) {
+        let mut nar = Vec::new();
+        nar_reader().read_to_end(&mut nar).await?;
+        let size = nar.len() as u64;
+
+        let full_path = store.get_full_path(path);
+        let narinfo = render_narinfo(store, &path_info, &nar_path, &full_path, signing_key).await;
+
+        bundles
+            .add(api, &store_path_hash, &nar_path, narinfo, nar)
+            .await?;
+        metrics.paths_bundled.incr();
+
+        {
+            let mut progress = progress.lock().expect("progress lock");
+            progress
+                .estimates
+                .record(&path.name(), path_info.nar_size, size);
+            progress.budget.spend(size);
+
+            if let Some(bundle_key) = bundles.bundle_key(&store_path_hash) {
+                progress.retention.record(bundle_key);
+            }
+        }
+
+        narinfo_negative_cache
+            .write()
+            .await
+            .remove(&store_path_hash);
+
+        tracing::debug!(
+            "Bundled '{}' (size {} -> {})",
+            full_path.display(),
+            path_info.nar_size,
+            size
+        );
+
+        return Ok(Uploaded::Settled(references(store, &path_info)?));
+    }
+
+    // NARs are keyed by their hash, so paths with the same contents, and
+    // paths that an earlier job uploaded but its manifest doesn't list, share
+    // one. A forced upload must not reuse a possibly corrupted NAR.
+    let present = !force
+        && (match narinfo_index {
+            Some(narinfo_index) => narinfo_index.has_nar(&nar_hash, &backend).await,
+            None => false,
+        } || has_entry(api, &nar_key).await);
+
+    // Artifactory can deploy blobs it already stores by checksum, which
+    // saves uploading NARs that another repository already has.
+    let deployed = match artifactory {
+        Some(artifactory) if !force && !present => {
+            let (sha256, _) = artifactory::sha256(nar_reader()).await?;
+            artifactory.checksum_deploy(&nar_key, &sha256).await?
+        }
+        _ => false,
+    };
+
+    if force {
+        remove_existing(api, audit_log, &nar_key).await?;
+    }
+
+    let full_path = store.get_full_path(path);
+    let nar_entry = |size| audit::Entry {
+        key: &nar_key,
+        size,
+        store_path: Some(&full_path),
+        nar_hash: Some(&nar_hash),
+        nar_size: Some(path_info.nar_size),
+    };
+
+    let mut extra_nar = extras.open_nar(&nar_key, metrics).await;
+
+    if present {
+        metrics.nar_dedup_hits.incr();
+
+        tracing::debug!("Not uploading '{}' because the cache has it", nar_path);
+    } else if deployed {
+        metrics.nars_checksum_deployed.incr();
+
+        audit::record(audit_log, Action::ChecksumDeploy, nar_entry(None)).await;
+
+        tracing::debug!("Deployed '{}' by checksum", nar_path);
+    } else {
+        match api.writer(&nar_key).await {
+            Ok(writer) => {
+                let mut nar_compressor = nar_reader();
+
+                let mut uploads = vec![Upload::new(writer, &nar_key)];
+                uploads.extend(extra_nar.take_uploads());
+
+                // Dropping the uploads aborts them.
+                let (compressed_nar_size, results) = tokio::select! {
+                    result = upload::copy_all(uploads, &mut nar_compressor) => result?,
+                    _ = cancellation.cancelled(), if large => {
+                        metrics.uploads_skipped_cancelled.incr();
+                        return Err(Error::Cancelled);
+                    }
+                };
+
+                let mut results = results.into_iter();
+                results
+                    .next()
+                    .expect("the default backend is uploaded to")?;
+                extra_nar.finish(results.collect(), compressed_nar_size, metrics);
+
+                // let compressed_nar_size = api.upload_file(nar_allocation, nar_compressor).await?;
+                metrics.nars_uploaded.incr();
+                metrics.nar_bytes_uploaded.add(compressed_nar_size as usize);
+
+                {
+                    let mut progress = progress.lock().expect("progress lock");
+                    progress.estimates.record(
+                        &path.name(),
+                        path_info.nar_size,
+                        compressed_nar_size,
+                    );
+                    progress.budget.spend(compressed_nar_size);
+                }
+
+                audit::record(
+                    audit_log,
+                    Action::Upload,
+                    nar_entry(Some(compressed_nar_size)),
+                )
+                .await;
+
+                tracing::debug!(
+                    "Uploaded '{}' (size {} -> {})",
+                    nar_path,
+                    path_info.nar_size,
+                    compressed_nar_size
+                );
+            }
+            // The compressed size isn't known yet, but NARs are keyed by their hash.
+            Err(err) if lost_race(api, &nar_key, None, &err).await? => {
+                metrics.uploads_deduplicated.incr();
+
+                tracing::debug!("Another job uploaded '{}' first", nar_path);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    // The default backend already had the NAR, but the extra ones don't.
+    if extra_nar.is_pending() {
+        let (size, results) = upload::copy_all(extra_nar.take_uploads(), &mut nar_reader()).await?;
+        extra_nar.finish(results, size, metrics);
+    }
+
+    if let Some(artifactory) = artifactory {
+        if let Err(err) = artifactory.set_properties(&nar_key).await {
+            tracing::warn!("Setting properties on '{}' failed: {}", nar_path, err);
+        }
+    }
+
+    // Upload the narinfo.
+
+    let narinfo = render_narinfo(store, &path_info, &nar_path, &full_path, signing_key).await;
+
+    tracing::debug!("Uploading '{}'", narinfo_path);
+
+    if force {
+        remove_existing(api, audit_log, &narinfo_path).await?;
+    }
+
+    let narinfo_size = narinfo.len() as u64;
+    match api.write(&narinfo_path, narinfo.clone()).await {
+        Ok(_) => {
+            metrics.narinfos_uploaded.incr();
+
+            audit::record(
+                audit_log,
+                Action::Upload,
+                audit::Entry {
+                    key: &narinfo_path,
+                    size: Some(narinfo_size),
+                    store_path: Some(&full_path),
+                    nar_hash: Some(&nar_hash),
+                    nar_size: Some(path_info.nar_size),
+                },
+            )
+            .await;
+        }
+        Err(err) if lost_race(api, &narinfo_path, Some(narinfo_size), &err).await? => {
+            metrics.uploads_deduplicated.incr();
+
+            tracing::debug!("Another job uploaded '{}' first", narinfo_path);
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    extras
+        .write_narinfo(extra_nar, &narinfo_path, &narinfo, metrics)
+        .await;
+
+    narinfo_negative_cache
+        .write()
+        .await
+        .remove(&store_path_hash);
+
+    if let Some(narinfo_index) = narinfo_index {
+        narinfo_index
+            .record(index::Entry {
+                store_path_hash: &store_path_hash,
+                name: &path.name(),
+                nar_hash: &nar_hash,
+                nar_size: path_info.nar_size,
+                backend: &backend,
+            })
+            .await;
+    }
+
+    progress
+        .lock()
+        .expect("progress lock")
+        .manifest
+        .insert(store_path_hash, nar_hash);
 
     tracing::info!(
         "Uploaded '{}' to the GitHub Action Cache",
         store.get_full_path(path).display()
     );
 
+    Ok(Uploaded::Settled(references(store, &path_info)?))
+}
+
+/// The references of the path with `path_info` that neither the worker nor
+/// the cache has, which must be uploaded before its narinfo.
+async fn pending_references(
+    uploader: &Uploader,
+    api: &Operator,
+    bundles: Option<&Bundles>,
+    progress: &Mutex<Progress>,
+    path: &StorePath,
+    path_info: &ValidPathInfo,
+) -> Result<Vec<StorePath>> {
+    let mut pending = Vec::new();
+
+    for reference in references(&uploader.store, path_info)? {
+        let hash = reference.to_hash().to_string();
+
+        if reference == *path || uploader.substituted.read().await.contains(&reference) {
+            continue;
+        }
+
+        let known = {
+            let progress = progress.lock().expect("progress lock");
+            progress.settled.contains(&hash) || progress.manifest.has_path(&hash)
+        } || bundles.is_some_and(|bundles| bundles.bundle_key(&hash).is_some());
+
+        if known {
+            continue;
+        }
+
+        // Another job may have uploaded it.
+        if has_entry(api, &keys::narinfo(&hash)).await {
+            progress.lock().expect("progress lock").settled.insert(hash);
+        } else {
+            pending.push(reference);
+        }
+    }
+
+    Ok(pending)
+}
+
+/// The narinfo of the path with `path_info`, whose NAR is served as `nar_path`.
+async fn render_narinfo(
+    store: &Arc<NixStore>,
+    path_info: &ValidPathInfo,
+    nar_path: &str,
+    full_path: &Path,
+    signing_key: Option<&NixKeypair>,
+) -> String {
+    let mut narinfo = path_info_to_nar_info(store.clone(), path_info, format!("nar/{}", nar_path));
+    narinfo.system = derivation_system(full_path).await;
+
+    if let Some(signing_key) = signing_key {
+        narinfo.sign(signing_key);
+    }
+
+    narinfo
+        .to_string()
+        .expect("failed to convert path into to nar info")
+}
+
+/// Whether an upload that failed with `err` may succeed when it is retried:
+/// the backend rate-limited it, failed with a server error or timed out.
+/// Other errors, like a request the backend rejects, fail again.
+fn is_retryable(err: &Error) -> bool {
+    match err {
+        Error::Api(err) | Error::GhaCache(gha_cache::Error::Cache(err)) => {
+            scheduler::is_overloaded(err)
+        }
+        Error::Timeout(_) => true,
+        _ => false,
+    }
+}
+
+/// How long to wait before retry number `attempt`, counting from zero. The
+/// delay is jittered between half and all of the exponential backoff, so
+/// that uploads that failed together don't retry together.
+fn retry_delay(attempt: u32) -> Duration {
+    let backoff = INITIAL_RETRY_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_RETRY_DELAY);
+    let jitter = (uuid::Uuid::new_v4().as_u128() % 1000) as u32;
+
+    backoff / 2 + backoff / 2 * jitter / 1000
+}
+
+/// Whether the failed write of `key` lost a race with another job creating
+/// the same entry, which is treated as a successful upload if the entry
+/// exists with `size` bytes.
+async fn lost_race(
+    api: &Operator,
+    key: &str,
+    size: Option<u64>,
+    err: &opendal::Error,
+) -> Result<bool> {
+    Ok(gha_cache::is_reserve_conflict(err) && gha_cache::existing_matches(api, key, size).await?)
+}
+
+/// Whether the backend has a non-empty entry under `key`. Errors count as a
+/// missing entry, so that the upload is attempted anyway.
+async fn has_entry(api: &Operator, key: &str) -> bool {
+    match api.stat(key).await {
+        Ok(metadata) => metadata.content_length() > 0,
+        Err(err) => {
+            if err.kind() != opendal::ErrorKind::NotFound {
+                tracing::debug!("Checking whether '{}' exists failed: {}", key, err);
+            }
+            false
+        }
+    }
+}
+
+/// Delete the entry under `key`, for backends that can't overwrite entries,
+/// or to get rid of a corrupt entry. Does nothing on backends that can't delete.
+pub(crate) async fn remove_existing(
+    api: &Operator,
+    audit_log: Option<&AuditLog>,
+    key: &str,
+) -> Result<()> {
+    if api.info().full_capability().delete {
+        match api.delete(key).await {
+            Ok(()) => {
+                audit::record(
+                    audit_log,
+                    Action::Delete,
+                    audit::Entry {
+                        key,
+                        ..Default::default()
+                    },
+                )
+                .await;
+            }
+            Err(err) if err.kind() == opendal::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
     Ok(())
 }
 
+/// Write `content` under `key` in place of the entry that is already there
+/// if `existing`, e.g. a manifest that was merged with the stored one.
+pub(crate) async fn replace(
+    api: &Operator,
+    audit_log: Option<&AuditLog>,
+    key: &str,
+    existing: bool,
+    content: impl Into<Buffer>,
+) -> Result<()> {
+    // Some backends, like the GitHub Actions Cache, can't overwrite entries.
+    if existing {
+        if let Err(err) = remove_existing(api, audit_log, key).await {
+            tracing::debug!("Failed to delete the old '{}': {}", key, err);
+        }
+    }
+
+    api.write(key, content).await?;
+
+    Ok(())
+}
+
+/// The paths that are at most `depth` references away from `roots`, along
+/// with the ones at exactly `depth`, whose references are left out.
+async fn bounded_closure(
+    store: &NixStore,
+    roots: Vec<StorePath>,
+    depth: usize,
+) -> Result<(Vec<StorePath>, Vec<StorePath>)> {
+    let mut closure = roots.iter().cloned().collect::<HashSet<_>>();
+    let mut level = roots;
+
+    for _ in 0..depth {
+        let mut next = Vec::new();
+
+        for path in level {
+            let path_info = store.query_path_info(path).await?;
+
+            for reference in references(store, &path_info)? {
+                if closure.insert(reference.clone()) {
+                    next.push(reference);
+                }
+            }
+        }
+
+        level = next;
+    }
+
+    Ok((closure.into_iter().collect(), level))
+}
+
+/// Order `paths` so that each comes after the ones among them that it refers
+/// to. Uploading them in this order leaves closures that can be substituted
+/// behind if the job stops halfway, instead of paths with missing references.
+async fn toposort(store: &NixStore, paths: Vec<StorePath>) -> Vec<StorePath> {
+    let index = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| (path.clone(), i))
+        .collect::<HashMap<_, _>>();
+
+    let mut edges = Vec::with_capacity(paths.len());
+    for (i, path) in paths.iter().enumerate() {
+        // Paths that are no longer valid fail when they are uploaded.
+        let path_references = match store.query_path_info(path.clone()).await {
+            Ok(path_info) => references(store, &path_info).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+
+        edges.push(
+            path_references
+                .iter()
+                .filter_map(|reference| index.get(reference).copied())
+                .filter(|&j| j != i)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    // A depth-first search that emits each path once its references are.
+    let mut visited = vec![false; paths.len()];
+    let mut order = Vec::with_capacity(paths.len());
+
+    for root in 0..paths.len() {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+
+        let mut stack = vec![(root, 0)];
+        while let Some(&(node, next)) = stack.last() {
+            match edges[node].get(next) {
+                Some(&reference) => {
+                    stack.last_mut().expect("the stack isn't empty").1 += 1;
+                    if !visited[reference] {
+                        visited[reference] = true;
+                        stack.push((reference, 0));
+                    }
+                }
+                None => {
+                    order.push(node);
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    let mut paths = paths.into_iter().map(Some).collect::<Vec<_>>();
+    order
+        .into_iter()
+        .map(|i| paths[i].take().expect("each path is ordered once"))
+        .collect()
+}
+
+/// The store paths that `path_info` refers to.
+fn references(store: &NixStore, path_info: &ValidPathInfo) -> Result<Vec<StorePath>> {
+    Ok(path_info
+        .references
+        .iter()
+        .map(|r| store.parse_store_path(r))
+        .collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+/// Query the info of `path`, making sure it is still in the store.
+///
+/// A path may be garbage-collected between being enqueued and being
+/// uploaded, which would otherwise surface as an obscure I/O error in the
+/// middle of the NAR stream.
+async fn query_valid_path(store: &NixStore, path: &StorePath) -> Result<ValidPathInfo> {
+    let full_path = store.get_full_path(path);
+
+    if let Ok(path_info) = store.query_path_info(path.clone()).await {
+        if tokio::fs::symlink_metadata(&full_path).await.is_ok() {
+            return Ok(path_info);
+        }
+    }
+
+    // Ask again, in case the path was being deleted and re-added concurrently.
+    let path_info = store.query_path_info(path.clone()).await;
+
+    if tokio::fs::symlink_metadata(&full_path).await.is_err() {
+        return Err(Error::PathNotValid(full_path));
+    }
+
+    Ok(path_info?)
+}
+
+/// The paths among `paths` that were built or added locally rather than
+/// substituted, which Nix records as ultimately trusted, and those in `forced`.
+async fn locally_built(
+    store: &NixStore,
+    paths: Vec<StorePath>,
+    forced: &HashSet<StorePath>,
+) -> Result<Vec<StorePath>> {
+    let mut ultimate = HashSet::new();
+
+    for batch in paths.chunks(PATH_INFO_BATCH) {
+        let output = tokio::process::Command::new("nix")
+            .args(["--extra-experimental-features", "nix-command"])
+            .args(["path-info", "--json"])
+            .args(batch.iter().map(|path| store.get_full_path(path)))
+            .stdin(std::process::Stdio::null())
+            .output()
+            .await
+            .map_err(|e| Error::Io(e, "Running nix path-info".to_owned()))?;
+
+        if !output.status.success() {
+            return Err(Error::Internal(format!(
+                "nix path-info failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        let infos = serde_json::from_slice::<serde_json::Value>(&output.stdout)
+            .map_err(|e| Error::Internal(format!("bad output of nix path-info: {}", e)))?;
+
+        // Nix 2.19 and later map the paths to their infos, older versions
+        // list the infos with their path.
+        let infos = match &infos {
+            serde_json::Value::Object(infos) => infos
+                .iter()
+                .map(|(path, info)| (path.as_str(), info))
+                .collect::<Vec<_>>(),
+            serde_json::Value::Array(infos) => infos
+                .iter()
+                .filter_map(|info| Some((info.get("path")?.as_str()?, info)))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        for (path, info) in infos {
+            if info.get("ultimate").and_then(serde_json::Value::as_bool) == Some(true) {
+                ultimate.insert(PathBuf::from(path));
+            }
+        }
+    }
+
+    Ok(paths
+        .into_iter()
+        .filter(|path| forced.contains(path) || ultimate.contains(&store.get_full_path(path)))
+        .collect())
+}
+
+/// The action of the upload policy for `path`, if one of its rules matches.
+async fn policy_action(
+    upload_policy: &Policy,
+    store: &NixStore,
+    path: &StorePath,
+) -> Option<policy::Action> {
+    let path_info = store.query_path_info(path.clone()).await.ok()?;
+
+    let deriver = if upload_policy.needs_derivation() {
+        query_deriver(&store.get_full_path(path)).await
+    } else {
+        None
+    };
+    let system = match &deriver {
+        Some(deriver) => deriver_system(deriver).await,
+        None => None,
+    };
+
+    upload_policy.decide(&policy::Subject {
+        name: &path.name(),
+        nar_size: path_info.nar_size,
+        system: system.as_deref(),
+        deriver: deriver.as_deref(),
+    })
+}
+
+/// The system of the derivation that produced `full_path`, if it is known.
+async fn derivation_system(full_path: &Path) -> Option<String> {
+    deriver_system(&query_deriver(full_path).await?).await
+}
+
+/// The derivation that produced `full_path`, if it is known.
+async fn query_deriver(full_path: &Path) -> Option<String> {
+    let output = tokio::process::Command::new("nix-store")
+        .arg("--query")
+        .arg("--deriver")
+        .arg(full_path)
+        .output()
+        .await
+        .ok()?;
+
+    // Prints `unknown-deriver` for paths without one.
+    let deriver = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if !output.status.success() || !deriver.ends_with(".drv") {
+        return None;
+    }
+
+    Some(deriver)
+}
+
+/// The system of the derivation at `deriver`.
+async fn deriver_system(deriver: &str) -> Option<String> {
+    // The deriver is often not in the store, e.g. for substituted paths.
+    let drv = tokio::fs::read_to_string(deriver).await.ok()?;
+
+    parse_derivation_system(&drv)
+}
+
+/// Extract the system from a derivation in ATerm format, which is the fourth
+/// field of `Derive(outputs, input drvs, input sources, system, ...)`.
+fn parse_derivation_system(drv: &str) -> Option<String> {
+    let fields = drv.strip_prefix("Derive(")?;
+
+    let mut depth = 0;
+    let mut field = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in fields.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' if depth == 0 && field == 3 => {
+                let system = &fields[i + 1..];
+                return Some(system[..system.find('"')?].to_owned());
+            }
+            '"' => in_string = true,
+            '[' | '(' => depth += 1,
+            ']' | ')' if depth == 0 => return None,
+            ']' | ')' => depth -= 1,
+            ',' if depth == 0 => field += 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// The zstd-compressed NAR serialisation of `path`.
+fn compressed_nar(store: &NixStore, path: &StorePath) -> impl AsyncRead + Unpin {
+    let nar_reader = store
+        .nar_from_path(path.clone())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+        .into_async_read();
+
+    ZstdEncoder::new(nar_reader.compat())
+}
+
+/// Whether the path at `full_path` with `path_info` is the output of a
+/// fixed-output derivation. Paths added with `nix-store --add` or
+/// `builtins.path` and the outputs of floating content-addressed derivations
+/// have the same kind of content address, so the derivation decides: only
+/// its fixed outputs have their hash in it. Paths whose derivation isn't in
+/// the store don't count.
+async fn is_fixed_output(full_path: &Path, path_info: &ValidPathInfo) -> bool {
+    if !path_info
+        .ca
+        .as_deref()
+        .is_some_and(|ca| ca.starts_with("fixed:"))
+    {
+        return false;
+    }
+
+    let Some(deriver) = query_deriver(full_path).await else {
+        return false;
+    };
+
+    let Ok(drv) = tokio::fs::read_to_string(&deriver).await else {
+        return false;
+    };
+
+    let full_path = full_path.to_string_lossy();
+    parse_derivation_outputs(&drv).is_some_and(|outputs| {
+        outputs
+            .iter()
+            .any(|output| output.len() == 4 && output[1] == full_path && !output[3].is_empty())
+    })
+}
+
+/// Extract the outputs from a derivation in ATerm format, which are the
+/// first field of `Derive([(name, path, hash algorithm, hash), ...], ...)`.
+fn parse_derivation_outputs(drv: &str) -> Option<Vec<Vec<String>>> {
+    let mut rest = drv.strip_prefix("Derive([")?;
+    let mut outputs = Vec::new();
+
+    while let Some(tuple) = rest.strip_prefix('(') {
+        let mut fields = Vec::new();
+        rest = tuple;
+
+        loop {
+            let (field, after) = parse_aterm_string(rest)?;
+            fields.push(field);
+
+            match after.chars().next()? {
+                ',' => rest = &after[1..],
+                ')' => {
+                    rest = &after[1..];
+                    break;
+                }
+                _ => return None,
+            }
+        }
+
+        outputs.push(fields);
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+    }
+
+    rest.starts_with(']').then_some(outputs)
+}
+
+/// Parse the ATerm string at the start of `s`, returning it and the rest.
+fn parse_aterm_string(s: &str) -> Option<(String, &str)> {
+    let s = s.strip_prefix('"')?;
+    let mut string = String::new();
+    let mut chars = s.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &s[i + 1..])),
+            '\\' => match chars.next()?.1 {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                'r' => string.push('\r'),
+                c => string.push(c),
+            },
+            c => string.push(c),
+        }
+    }
+
+    None
+}
+
 // FIXME: move to attic.
 fn path_info_to_nar_info(store: Arc<NixStore>, path_info: &ValidPathInfo, url: String) -> NarInfo {
     NarInfo {
@@ -0,0 +1,288 @@
+//! Authentication to the GitHub REST API as a GitHub App installation.
+//!
+//! Listing and deleting GitHub Actions Cache entries, for `gc`, `aggregate`,
+//! `trend` and `/nar/?list=1`, goes through the REST API, which by default
+//! needs a `GITHUB_TOKEN` with the `actions` permission. Workflows can't
+//! always grant it, e.g. to scheduled cleanups in organizations that
+//! restrict the permissions of `GITHUB_TOKEN`, or to pull requests from
+//! forks. With `--github-app-id` and `--github-app-private-key-file`, the REST
+//! API is instead used with an installation token of a GitHub App, e.g. one
+//! installed on all repositories of the organization: a JWT signed with the
+//! app's private key is exchanged for a token of the installation on the
+//! repository, limited to the repository and the `actions: write`
+//! permission, which is renewed before it expires.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::RsaPrivateKey;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::secrets::SecretSource;
+
+/// How long requests to the REST API may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How far back JWTs are dated, to allow for clock drift.
+const JWT_BACKDATE: u64 = 60;
+
+/// How long JWTs are valid after they are issued. GitHub accepts at most 10
+/// minutes in total.
+const JWT_LIFETIME: u64 = 9 * 60;
+
+/// How long installation tokens are valid.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Installation tokens that expire within this long are renewed.
+const TOKEN_RENEWAL: Duration = Duration::from_secs(5 * 60);
+
+static APP: OnceLock<GitHubApp> = OnceLock::new();
+
+/// The GitHub App to authenticate to the REST API as.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct GitHubAppFlags {
+    /// Use the GitHub REST API with an installation token of the GitHub App
+    /// with this ID instead of `GITHUB_TOKEN`. The app needs the
+    /// `actions: write` permission on the repository.
+    #[arg(
+        long = "github-app-id",
+        value_name = "ID",
+        requires = "private_key_file"
+    )]
+    pub app_id: Option<u64>,
+
+    /// A file with the private key of the GitHub App, in PEM. Also accepts
+    /// secret manager references like `--backend-netrc`.
+    #[arg(
+        long = "github-app-private-key-file",
+        value_name = "FILE",
+        requires = "app_id"
+    )]
+    pub private_key_file: Option<SecretSource>,
+
+    /// The installation of the GitHub App to use. Defaults to the one on the
+    /// repository.
+    #[arg(
+        long = "github-app-installation-id",
+        value_name = "ID",
+        requires = "app_id"
+    )]
+    pub installation_id: Option<u64>,
+}
+
+impl GitHubAppFlags {
+    /// Read the private key and authenticate as the app, if there is one.
+    pub async fn load(&self) -> Result<Option<GitHubApp>> {
+        let (Some(app_id), Some(source)) = (self.app_id, &self.private_key_file) else {
+            return Ok(None);
+        };
+
+        let private_key = source.resolve_string().await?;
+
+        GitHubApp::new(app_id, &private_key, self.installation_id).map(Some)
+    }
+}
+
+pub struct GitHubApp {
+    client: reqwest::Client,
+    app_id: u64,
+    installation_id: Option<u64>,
+    key: SigningKey<Sha256>,
+
+    /// The last installation token, and when it expires.
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Deserialize)]
+struct Installation {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    token: String,
+}
+
+impl GitHubApp {
+    /// Authenticate as the app `app_id` with `private_key`, a PKCS #1 or
+    /// PKCS #8 key in PEM, as GitHub issues them.
+    pub fn new(app_id: u64, private_key: &str, installation_id: Option<u64>) -> Result<GitHubApp> {
+        let key = RsaPrivateKey::from_pkcs1_pem(private_key.trim())
+            .or_else(|_| RsaPrivateKey::from_pkcs8_pem(private_key.trim()))
+            .map_err(|e| {
+                Error::Config(format!(
+                    "The private key of GitHub App {} isn't a PEM RSA key: {}",
+                    app_id, e
+                ))
+            })?;
+
+        Ok(GitHubApp {
+            client: reqwest::Client::new(),
+            app_id,
+            installation_id,
+            key: SigningKey::new(key),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// The REST API credentials for the repository of the job, with an
+    /// installation token.
+    pub async fn rest_credentials(&self) -> Result<gha_cache::RestCredentials> {
+        let api_url =
+            std::env::var("GITHUB_API_URL").unwrap_or_else(|_| "https://api.github.com".to_owned());
+        let repository = std::env::var("GITHUB_REPOSITORY").map_err(|_| {
+            Error::Config("Authenticating as a GitHub App requires GITHUB_REPOSITORY".to_owned())
+        })?;
+
+        let token = self.token(&api_url, &repository).await?;
+
+        Ok(gha_cache::RestCredentials {
+            api_url,
+            repository,
+            token,
+        })
+    }
+
+    async fn token(&self, api_url: &str, repository: &str) -> Result<String> {
+        let mut cached = self.token.lock().await;
+
+        if let Some((token, expires)) = &*cached {
+            if expires.saturating_duration_since(Instant::now()) > TOKEN_RENEWAL {
+                return Ok(token.clone());
+            }
+        }
+
+        let jwt = self.jwt();
+
+        let installation_id = match self.installation_id {
+            Some(id) => id,
+            None => {
+                self.request::<Installation>(
+                    self.client
+                        .get(format!("{}/repos/{}/installation", api_url, repository)),
+                    &jwt,
+                )
+                .await?
+                .id
+            }
+        };
+
+        let name = repository.rsplit('/').next().unwrap_or(repository);
+        let requested = Instant::now();
+
+        let token = self
+            .request::<AccessToken>(
+                self.client
+                    .post(format!(
+                        "{}/app/installations/{}/access_tokens",
+                        api_url, installation_id
+                    ))
+                    .json(&serde_json::json!({
+                        "repositories": [name],
+                        "permissions": { "actions": "write" },
+                    })),
+                &jwt,
+            )
+            .await?
+            .token;
+
+        tracing::debug!(
+            "Minted a token for installation {} of GitHub App {}",
+            installation_id,
+            self.app_id
+        );
+
+        *cached = Some((token.clone(), requested + TOKEN_LIFETIME));
+
+        Ok(token)
+    }
+
+    /// A JWT that authenticates as the app itself.
+    fn jwt(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(
+            serde_json::json!({
+                "iat": now - JWT_BACKDATE,
+                "exp": now + JWT_LIFETIME,
+                "iss": self.app_id.to_string(),
+            })
+            .to_string(),
+        );
+
+        let message = format!("{}.{}", header, claims);
+        let signature = self.key.sign(message.as_bytes());
+
+        format!(
+            "{}.{}",
+            message,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+        jwt: &str,
+    ) -> Result<T> {
+        let response = request
+            .bearer_auth(jwt)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .header(reqwest::header::USER_AGENT, "magic-nix-cache")
+            .timeout(TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "Authenticating as GitHub App {} failed: {}",
+                    self.app_id, e
+                ))
+            })?;
+
+        response
+            .json()
+            .await
+            .map_err(|e| Error::Internal(format!("bad GitHub API response: {}", e)))
+    }
+}
+
+/// Use the REST API as `app` from now on.
+pub fn init(app: GitHubApp) -> Result<()> {
+    if let Some(existing) = APP.get() {
+        if (existing.app_id, existing.installation_id) != (app.app_id, app.installation_id) {
+            return Err(Error::Config(
+                "A different GitHub App is already in use".to_owned(),
+            ));
+        }
+
+        return Ok(());
+    }
+
+    let _ = APP.set(app);
+
+    Ok(())
+}
+
+/// Authenticate the REST API operations of `api` as the GitHub App, if
+/// there is one.
+pub async fn authenticate(api: gha_cache::ApiBuilder) -> Result<gha_cache::ApiBuilder> {
+    match APP.get() {
+        Some(app) => Ok(api.rest_credentials(app.rest_credentials().await?)),
+        None => Ok(api),
+    }
+}
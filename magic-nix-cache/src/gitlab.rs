@@ -0,0 +1,144 @@
+//! A storage backend in the generic package registry of a GitLab project.
+//!
+//! In GitLab CI, the binary cache is stored as the files of a generic
+//! package of the project that runs the job, `magic-nix-cache` unless given
+//! as `gitlab:<package>`. The registry is found through `CI_API_V4_URL` and
+//! `CI_PROJECT_ID`, and requests are authenticated with the job's
+//! `CI_JOB_TOKEN`.
+//!
+//! File names can't contain slashes, so the directory of each key becomes the
+//! version of the package that holds the file, e.g. `v1/nar/<file>` is the
+//! file `<file>` of version `v1-nar`.
+
+use std::sync::Arc;
+
+use opendal::raw::{Access, AccessorInfo, OpRead, OpStat, OpWrite, RpRead, RpStat, RpWrite};
+use opendal::{Capability, ErrorKind, Scheme};
+use reqwest::{Method, Url};
+
+use crate::error::{Error, Result};
+use crate::http::{self, HttpReader, HttpWriter};
+
+/// The package that stores the cache unless another one is given.
+pub const DEFAULT_PACKAGE: &str = "magic-nix-cache";
+
+/// The version of the package that holds keys without a directory.
+const ROOT_VERSION: &str = "0";
+
+#[derive(Debug)]
+pub struct GitLabBackend {
+    client: reqwest::Client,
+    base: Url,
+    token: String,
+    info: Arc<AccessorInfo>,
+}
+
+impl GitLabBackend {
+    pub fn new(package: &str) -> Result<Self> {
+        let api_url = ci_variable("CI_API_V4_URL")?;
+        let project_id = ci_variable("CI_PROJECT_ID")?;
+
+        let base = Url::parse(&format!(
+            "{}/projects/{}/packages/generic/{}/",
+            api_url.trim_end_matches('/'),
+            project_id,
+            package
+        ))
+        .map_err(|e| Error::Config(format!("bad CI_API_V4_URL '{}': {}", api_url, e)))?;
+
+        let info = AccessorInfo::default();
+        info.set_scheme(Scheme::Custom("gitlab"))
+            .set_root("/")
+            .set_native_capability(Capability {
+                stat: true,
+                read: true,
+                write: true,
+                ..Default::default()
+            });
+
+        Ok(GitLabBackend {
+            client: reqwest::Client::new(),
+            base,
+            token: ci_variable("CI_JOB_TOKEN")?,
+            info: Arc::new(info),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> opendal::Result<reqwest::RequestBuilder> {
+        let key = path.trim_start_matches('/');
+
+        let (version, file_name) = match key.rsplit_once('/') {
+            Some((dir, file_name)) => (dir.replace('/', "-"), file_name),
+            None => (ROOT_VERSION.to_owned(), key),
+        };
+
+        let url = self
+            .base
+            .join(&format!("{}/{}", version, file_name))
+            .map_err(|e| {
+                opendal::Error::new(
+                    ErrorKind::Unexpected,
+                    format!("invalid path '{}' for {}", path, self.base),
+                )
+                .set_source(e)
+            })?;
+
+        Ok(self
+            .client
+            .request(method, url)
+            .header("JOB-TOKEN", &self.token))
+    }
+}
+
+impl Access for GitLabBackend {
+    type Reader = HttpReader;
+    type Writer = HttpWriter;
+    type Lister = ();
+    type Deleter = ();
+    type BlockingReader = ();
+    type BlockingWriter = ();
+    type BlockingLister = ();
+    type BlockingDeleter = ();
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        self.info.clone()
+    }
+
+    async fn stat(&self, path: &str, _args: OpStat) -> opendal::Result<RpStat> {
+        http::stat(self.request(Method::HEAD, path)?, path).await
+    }
+
+    async fn read(&self, path: &str, _args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        http::read(self.request(Method::GET, path)?, path).await
+    }
+
+    async fn write(&self, path: &str, _args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        Ok(http::write(self.request(Method::PUT, path)?, path))
+    }
+}
+
+/// Check the name of a package, which GitLab restricts to letters, digits,
+/// dots, dashes and underscores.
+pub fn parse_package(package: &str) -> Result<String> {
+    if package.is_empty()
+        || !package
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    {
+        return Err(Error::Config(format!(
+            "bad GitLab package name '{}', expected letters, digits, '.', '-' and '_'",
+            package
+        )));
+    }
+
+    Ok(package.to_owned())
+}
+
+fn ci_variable(name: &str) -> Result<String> {
+    std::env::var(name).map_err(|_| {
+        Error::Config(format!(
+            "the GitLab backend needs {}, which GitLab CI sets in jobs",
+            name
+        ))
+    })
+}
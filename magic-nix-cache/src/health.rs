@@ -0,0 +1,180 @@
+//! Backend health tracking.
+//!
+//! Each backend is probed periodically with a small read. A backend that
+//! fails several probes in a row is disabled for reads and writes until a
+//! later probe succeeds again.
+//!
+//! With `--circuit-breaker-threshold`, the requests themselves are watched
+//! too: after that many in a row failed because the backend is overloaded,
+//! i.e. with a 429 or a 5xx, the circuit opens, and requests fail right away
+//! for `--circuit-breaker-duration` instead of adding to the load. After
+//! that, requests are sent again, and a single failure opens the circuit
+//! again until one succeeds.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often backends are probed.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of consecutive failed probes after which a backend is disabled.
+const FAILURE_THRESHOLD: usize = 3;
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct BackendHealth {
+    disabled: AtomicBool,
+    consecutive_failures: AtomicUsize,
+    probes_failed: AtomicUsize,
+
+    /// Whether requests fail fast because the backend is overloaded.
+    circuit_open: AtomicBool,
+    circuits_opened: AtomicUsize,
+
+    #[serde(skip)]
+    circuit: Mutex<Circuit>,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    /// The number of requests failing in a row after which the circuit
+    /// opens, or `None` if it never does.
+    threshold: Option<usize>,
+
+    /// How long the circuit stays open.
+    open_for: Duration,
+
+    /// The number of requests that failed in a row.
+    failures: usize,
+
+    /// Until when the circuit is open.
+    open_until: Option<Instant>,
+}
+
+impl BackendHealth {
+    /// Track a backend whose circuit opens for `open_for` once `threshold`
+    /// requests in a row failed because it is overloaded.
+    pub fn with_circuit_breaker(threshold: usize, open_for: Duration) -> BackendHealth {
+        BackendHealth {
+            circuit: Mutex::new(Circuit {
+                threshold: Some(threshold),
+                open_for,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the backend should currently be used.
+    pub fn is_available(&self) -> bool {
+        !self.disabled.load(Ordering::Relaxed) && !self.is_circuit_open()
+    }
+
+    /// Whether requests should fail fast, closing the circuit if it has been
+    /// open for long enough.
+    pub fn is_circuit_open(&self) -> bool {
+        if !self.circuit_open.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let mut circuit = self.circuit.lock().expect("circuit lock");
+
+        if circuit
+            .open_until
+            .is_some_and(|until| until > Instant::now())
+        {
+            return true;
+        }
+
+        // Half-open: the next failure opens the circuit again.
+        circuit.open_until = None;
+        circuit.failures = circuit.threshold.unwrap_or(1).saturating_sub(1);
+        self.circuit_open.store(false, Ordering::Relaxed);
+
+        tracing::info!("Sending requests to the backend again");
+
+        false
+    }
+
+    /// Record that a request succeeded, or failed because the backend is
+    /// overloaded, returning whether this opened the circuit.
+    pub fn record_request(&self, overloaded: bool) -> bool {
+        let mut circuit = self.circuit.lock().expect("circuit lock");
+
+        let Some(threshold) = circuit.threshold else {
+            return false;
+        };
+
+        if !overloaded {
+            circuit.failures = 0;
+            return false;
+        }
+
+        circuit.failures += 1;
+
+        if circuit.failures < threshold || circuit.open_until.is_some() {
+            return false;
+        }
+
+        circuit.open_until = Some(Instant::now() + circuit.open_for);
+        self.circuit_open.store(true, Ordering::Relaxed);
+        self.circuits_opened.fetch_add(1, Ordering::Relaxed);
+
+        tracing::warn!(
+            "{} requests in a row failed because the backend is overloaded, failing requests for {:?}",
+            circuit.failures,
+            circuit.open_for
+        );
+
+        true
+    }
+
+    fn record_success(&self, backend: &str) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+
+        if self.disabled.swap(false, Ordering::Relaxed) {
+            tracing::info!("{} is reachable again, re-enabling it", backend);
+        }
+    }
+
+    fn record_failure(&self, backend: &str, err: &dyn Display) {
+        self.probes_failed.fetch_add(1, Ordering::Relaxed);
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        tracing::warn!("Health probe of {} failed: {}", backend, err);
+
+        if failures >= FAILURE_THRESHOLD && !self.disabled.swap(true, Ordering::Relaxed) {
+            tracing::error!(
+                "{} failed {} health probes in a row, disabling it until it recovers",
+                backend,
+                failures
+            );
+        }
+    }
+}
+
+/// Spawn a task that periodically runs `probe` and updates `health`.
+pub fn spawn_probe<F, Fut, E>(backend: String, health: Arc<BackendHealth>, probe: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = std::result::Result<(), E>> + Send,
+    E: Display,
+{
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+
+            // The probe would fail fast too.
+            if health.is_circuit_open() {
+                continue;
+            }
+
+            match probe().await {
+                Ok(()) => health.record_success(&backend),
+                Err(err) => health.record_failure(&backend, &err),
+            }
+        }
+    });
+}
@@ -0,0 +1,281 @@
+//! A storage backend that pushes to a remote HTTP binary cache.
+//!
+//! Any server that serves a Nix binary cache over `GET` and accepts uploads
+//! over `PUT` works, e.g. another magic-nix-cache or nginx with WebDAV. This
+//! allows chaining a runner-local daemon to a shared cache in a datacenter.
+//!
+//! Objects are stored under their binary cache paths (`<hash>.narinfo` and
+//! `nar/<file>`) rather than under versioned keys, so that the remote can be
+//! used as a substituter directly.
+
+use std::sync::Arc;
+
+use futures::StreamExt;
+use opendal::raw::{oio, Access, AccessorInfo, OpRead, OpStat, OpWrite, RpRead, RpStat, RpWrite};
+use opendal::{Buffer, Capability, EntryMode, ErrorKind, Metadata, Scheme};
+use reqwest::{Method, StatusCode, Url};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{keys, request_id};
+
+/// Number of chunks buffered between the writer and the upload request.
+const WRITE_QUEUE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub struct HttpBackend {
+    client: reqwest::Client,
+    base: Url,
+    credentials: Option<(String, String)>,
+    info: Arc<AccessorInfo>,
+}
+
+impl HttpBackend {
+    pub fn new(url: &Url, credentials: Option<(String, String)>) -> Self {
+        let info = AccessorInfo::default();
+        info.set_scheme(Scheme::Http)
+            .set_root(url.path())
+            .set_native_capability(Capability {
+                stat: true,
+                read: true,
+                write: true,
+                ..Default::default()
+            });
+
+        let mut base = url.clone();
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+
+        HttpBackend {
+            client: reqwest::Client::new(),
+            base,
+            credentials,
+            info: Arc::new(info),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> opendal::Result<reqwest::RequestBuilder> {
+        let path = binary_cache_path(path.trim_start_matches('/'));
+
+        let url = self.base.join(path).map_err(|e| {
+            opendal::Error::new(
+                ErrorKind::Unexpected,
+                format!("invalid path '{}' for {}", path, self.base),
+            )
+            .set_source(e)
+        })?;
+
+        let mut request = self.client.request(method, url);
+
+        if let Some(request_id) = request_id::current() {
+            request = request.header(&request_id::HEADER, request_id);
+        }
+
+        Ok(match &self.credentials {
+            Some((login, password)) => request.basic_auth(login, Some(password)),
+            None => request,
+        })
+    }
+}
+
+impl Access for HttpBackend {
+    type Reader = HttpReader;
+    type Writer = HttpWriter;
+    type Lister = ();
+    type Deleter = ();
+    type BlockingReader = ();
+    type BlockingWriter = ();
+    type BlockingLister = ();
+    type BlockingDeleter = ();
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        self.info.clone()
+    }
+
+    async fn stat(&self, path: &str, _args: OpStat) -> opendal::Result<RpStat> {
+        stat(self.request(Method::HEAD, path)?, path).await
+    }
+
+    async fn read(&self, path: &str, _args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        read(self.request(Method::GET, path)?, path).await
+    }
+
+    async fn write(&self, path: &str, _args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        Ok(write(self.request(Method::PUT, path)?, path))
+    }
+}
+
+/// Stat the object at `path` with `request`, a `HEAD` request. Shared with
+/// the other backends that store objects over plain HTTP.
+pub(crate) async fn stat(request: reqwest::RequestBuilder, path: &str) -> opendal::Result<RpStat> {
+    let response = request.send().await.map_err(|e| request_error(path, e))?;
+    let response = check_status(response, path)?;
+
+    let mut metadata = Metadata::new(EntryMode::FILE);
+    if let Some(size) = response.content_length() {
+        metadata.set_content_length(size);
+    }
+
+    Ok(RpStat::new(metadata))
+}
+
+/// Read the object at `path` with `request`, a `GET` request.
+pub(crate) async fn read(
+    request: reqwest::RequestBuilder,
+    path: &str,
+) -> opendal::Result<(RpRead, HttpReader)> {
+    let response = request.send().await.map_err(|e| request_error(path, e))?;
+    let response = check_status(response, path)?;
+
+    Ok((
+        RpRead::new(),
+        HttpReader {
+            path: path.to_owned(),
+            response,
+        },
+    ))
+}
+
+/// Write the object at `path` with `request`, a `PUT` request without a body.
+pub(crate) fn write(request: reqwest::RequestBuilder, path: &str) -> (RpWrite, HttpWriter) {
+    let (tx, rx) = mpsc::channel::<Buffer>(WRITE_QUEUE_DEPTH);
+
+    let body = reqwest::Body::wrap_stream(
+        ReceiverStream::new(rx).map(|bs| Ok::<_, std::io::Error>(bs.to_bytes())),
+    );
+
+    let request = request.body(body);
+    let upload = tokio::task::spawn(async move { request.send().await });
+
+    (
+        RpWrite::new(),
+        HttpWriter {
+            path: path.to_owned(),
+            tx: Some(tx),
+            upload,
+        },
+    )
+}
+
+pub struct HttpReader {
+    path: String,
+    response: reqwest::Response,
+}
+
+impl oio::Read for HttpReader {
+    async fn read(&mut self) -> opendal::Result<Buffer> {
+        match self.response.chunk().await {
+            Ok(Some(chunk)) => Ok(Buffer::from(chunk)),
+            Ok(None) => Ok(Buffer::new()),
+            Err(e) => Err(request_error(&self.path, e)),
+        }
+    }
+}
+
+pub struct HttpWriter {
+    path: String,
+    tx: Option<mpsc::Sender<Buffer>>,
+    upload: JoinHandle<reqwest::Result<reqwest::Response>>,
+}
+
+impl HttpWriter {
+    /// Wait for the upload request to complete.
+    async fn finish(&mut self) -> opendal::Result<()> {
+        drop(self.tx.take());
+
+        let response = (&mut self.upload)
+            .await
+            .map_err(|e| {
+                opendal::Error::new(
+                    ErrorKind::Unexpected,
+                    format!("upload of '{}' panicked or was cancelled", self.path),
+                )
+                .set_source(e)
+            })?
+            .map_err(|e| request_error(&self.path, e))?;
+
+        check_status(response, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl oio::Write for HttpWriter {
+    async fn write(&mut self, bs: Buffer) -> opendal::Result<()> {
+        let tx = self.tx.as_ref().ok_or_else(|| {
+            opendal::Error::new(ErrorKind::Unexpected, "writing to a closed HTTP writer")
+        })?;
+
+        if tx.send(bs).await.is_err() {
+            // The request ended before the body was complete, so it has
+            // an error to report.
+            self.finish().await?;
+
+            return Err(opendal::Error::new(
+                ErrorKind::Unexpected,
+                format!("upload of '{}' ended early", self.path),
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn close(&mut self) -> opendal::Result<Metadata> {
+        self.finish().await?;
+
+        Ok(Metadata::new(EntryMode::FILE))
+    }
+
+    async fn abort(&mut self) -> opendal::Result<()> {
+        drop(self.tx.take());
+        self.upload.abort();
+
+        Ok(())
+    }
+}
+
+/// The path of the object stored under `key` in a binary cache.
+fn binary_cache_path(key: &str) -> &str {
+    key.strip_prefix(&format!("v{}/", keys::SCHEMA_VERSION))
+        .unwrap_or(key)
+}
+
+fn check_status(response: reqwest::Response, path: &str) -> opendal::Result<reqwest::Response> {
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let kind = match status {
+        StatusCode::NOT_FOUND => ErrorKind::NotFound,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::PermissionDenied,
+        StatusCode::TOO_MANY_REQUESTS => ErrorKind::RateLimited,
+        _ => ErrorKind::Unexpected,
+    };
+
+    let mut err = opendal::Error::new(
+        kind,
+        format!("remote cache returned {} for '{}'", status, path),
+    );
+
+    // Picked up by the scheduler, see `crate::scheduler`.
+    if let Some(retry_after) = response.headers().get(reqwest::header::RETRY_AFTER) {
+        err = err.with_context(
+            "retry-after",
+            String::from_utf8_lossy(retry_after.as_bytes()),
+        );
+    }
+
+    Err(err)
+}
+
+fn request_error(path: &str, err: reqwest::Error) -> opendal::Error {
+    opendal::Error::new(
+        ErrorKind::Unexpected,
+        format!("request to the remote cache for '{}' failed", path),
+    )
+    .set_source(err)
+}
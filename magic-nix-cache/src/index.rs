@@ -0,0 +1,348 @@
+//! An index of the paths in the cache, as a SQLite database.
+//!
+//! With `--narinfo-index <file>`, the daemon keeps a row for every path
+//! that a job of the repository uploaded: its hash, name, NAR hash and size,
+//! the backend it is stored in, and when it was uploaded and last served.
+//! The database is stored in the cache under a fixed key, merged into the
+//! local one when the daemon starts and written back, merged with the rows
+//! that other jobs added in the meantime, when it shuts down.
+//!
+//! The worker uses it to skip paths and NARs that the backend already has
+//! without asking it, and `/api/stats` summarizes it. The local file can be
+//! queried with `sqlite3`, e.g. to find the largest paths or the ones that
+//! haven't been served for a while, and kept as a workflow artifact.
+//!
+//! Rows expire after [`MAX_AGE`] without being served, like the entries of
+//! the GitHub Actions Cache itself.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opendal::{ErrorKind, Operator};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+
+use crate::audit::AuditLog;
+use crate::error::{Error, Result};
+use crate::{gha, keys, temp};
+
+/// How long a row is trusted without the path being served.
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS paths (
+    store_path_hash TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    nar_hash TEXT NOT NULL,
+    nar_size INTEGER NOT NULL,
+    backend TEXT NOT NULL,
+    uploaded_at INTEGER NOT NULL,
+    last_access INTEGER NOT NULL
+)";
+
+/// A path that was uploaded.
+pub struct Entry<'a> {
+    pub store_path_hash: &'a str,
+    pub name: &'a str,
+    pub nar_hash: &'a str,
+    pub nar_size: u64,
+    pub backend: &'a str,
+}
+
+pub struct Index {
+    /// Only one connection, so that attached databases stay attached.
+    pool: SqlitePool,
+
+    path: PathBuf,
+
+    key: String,
+
+    /// Whether rows were added or served since the index was loaded.
+    dirty: AtomicBool,
+}
+
+impl Index {
+    /// Open the index at `path`, creating it if it doesn't exist.
+    pub async fn open(path: &Path) -> Result<Index> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .map_err(|e| database_error(path, e))?;
+
+        sqlx::query(SCHEMA)
+            .execute(&pool)
+            .await
+            .map_err(|e| database_error(path, e))?;
+
+        let repository = std::env::var("GITHUB_REPOSITORY").unwrap_or_else(|_| "local".to_owned());
+
+        Ok(Index {
+            pool,
+            path: path.to_owned(),
+            key: keys::narinfo_index(&repository),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Merge in the index stored in the cache, and drop the expired rows.
+    pub async fn load(&self, api: &Operator) {
+        if let Err(err) = self.merge_stored(api).await {
+            tracing::warn!("Failed to read the narinfo index '{}': {}", self.key, err);
+        }
+
+        let result = sqlx::query("DELETE FROM paths WHERE last_access < ?")
+            .bind(oldest())
+            .execute(&self.pool)
+            .await;
+
+        match result {
+            Ok(result) if result.rows_affected() > 0 => {
+                tracing::debug!(
+                    "Dropped {} expired paths from the narinfo index",
+                    result.rows_affected()
+                );
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!("{}", database_error(&self.path, err)),
+        }
+    }
+
+    /// Record that a path was uploaded.
+    pub async fn record(&self, entry: Entry<'_>) {
+        let now = now();
+
+        let result = sqlx::query(
+            "INSERT INTO paths VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (store_path_hash) DO UPDATE SET
+                 nar_hash = excluded.nar_hash,
+                 nar_size = excluded.nar_size,
+                 backend = excluded.backend,
+                 uploaded_at = excluded.uploaded_at,
+                 last_access = excluded.last_access",
+        )
+        .bind(entry.store_path_hash)
+        .bind(entry.name)
+        .bind(entry.nar_hash)
+        .bind(entry.nar_size as i64)
+        .bind(entry.backend)
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await;
+
+        match result {
+            Ok(_) => self.dirty.store(true, Ordering::Relaxed),
+            Err(err) => tracing::warn!("{}", database_error(&self.path, err)),
+        }
+    }
+
+    /// Record that the path with `store_path_hash` was served.
+    pub async fn touch(&self, store_path_hash: &str) {
+        let result = sqlx::query("UPDATE paths SET last_access = ? WHERE store_path_hash = ?")
+            .bind(now())
+            .bind(store_path_hash)
+            .execute(&self.pool)
+            .await;
+
+        match result {
+            Ok(result) if result.rows_affected() > 0 => self.dirty.store(true, Ordering::Relaxed),
+            Ok(_) => {}
+            Err(err) => tracing::debug!("{}", database_error(&self.path, err)),
+        }
+    }
+
+    /// Whether the path with `store_path_hash` is in `backend` with the NAR
+    /// with `nar_hash`.
+    pub async fn has_path(&self, store_path_hash: &str, nar_hash: &str, backend: &str) -> bool {
+        self.exists(
+            "SELECT 1 FROM paths
+             WHERE store_path_hash = ? AND nar_hash = ? AND backend = ? AND last_access >= ?",
+            &[store_path_hash, nar_hash, backend],
+        )
+        .await
+    }
+
+    /// Whether a NAR with `nar_hash` is in `backend`, whichever path it is of.
+    pub async fn has_nar(&self, nar_hash: &str, backend: &str) -> bool {
+        self.exists(
+            "SELECT 1 FROM paths WHERE nar_hash = ? AND backend = ? AND last_access >= ? LIMIT 1",
+            &[nar_hash, backend],
+        )
+        .await
+    }
+
+    /// Whether `query` returns a row, given `values` and the oldest time of
+    /// access that is still trusted.
+    async fn exists(&self, query: &str, values: &[&str]) -> bool {
+        let mut query = sqlx::query(query);
+        for value in values {
+            query = query.bind(*value);
+        }
+
+        match query.bind(oldest()).fetch_optional(&self.pool).await {
+            Ok(row) => row.is_some(),
+            Err(err) => {
+                tracing::debug!("{}", database_error(&self.path, err));
+                false
+            }
+        }
+    }
+
+    /// The number of paths and the size of their NARs in each backend.
+    pub async fn summary(&self) -> serde_json::Value {
+        let rows = sqlx::query_as::<_, (String, i64, i64)>(
+            "SELECT backend, COUNT(*), SUM(nar_size) FROM paths GROUP BY backend",
+        )
+        .fetch_all(&self.pool)
+        .await;
+
+        match rows {
+            Ok(rows) => rows
+                .into_iter()
+                .map(|(backend, paths, nar_size)| {
+                    (
+                        backend,
+                        serde_json::json!({ "paths": paths, "nar_size": nar_size }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            Err(err) => {
+                tracing::debug!("{}", database_error(&self.path, err));
+                serde_json::Value::Null
+            }
+        }
+    }
+
+    /// Write the index back to the cache, merging in the rows that other
+    /// jobs added in the meantime.
+    pub async fn save(&self, api: &Operator, audit_log: Option<&AuditLog>) {
+        if !self.dirty.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let existing = match self.merge_stored(api).await {
+            Ok(existing) => existing,
+            Err(err) => {
+                tracing::warn!("Failed to read the narinfo index '{}': {}", self.key, err);
+                false
+            }
+        };
+
+        let snapshot = temp::dir().join(format!("index-{}.sqlite", uuid::Uuid::new_v4()));
+
+        let content = sqlx::query("VACUUM INTO ?")
+            .bind(snapshot.to_string_lossy().into_owned())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| database_error(&self.path, e))
+            .and_then(|_| {
+                std::fs::read(&snapshot)
+                    .map_err(|e| Error::Io(e, format!("Reading {}", snapshot.display())))
+            });
+        let _ = std::fs::remove_file(&snapshot);
+
+        let content = match content {
+            Ok(content) => content,
+            Err(err) => {
+                tracing::warn!("Failed to snapshot the narinfo index: {}", err);
+                return;
+            }
+        };
+
+        match gha::replace(api, audit_log, &self.key, existing, content).await {
+            Ok(_) => {
+                tracing::debug!("Stored the narinfo index");
+                self.dirty.store(false, Ordering::Relaxed);
+            }
+            Err(err) => {
+                tracing::warn!("Failed to store the narinfo index '{}': {}", self.key, err);
+            }
+        }
+    }
+
+    /// Merge the unexpired rows of the index stored in the cache into the
+    /// local one, returning whether there was one. Rows that are in both
+    /// keep the latest time of access.
+    async fn merge_stored(&self, api: &Operator) -> Result<bool> {
+        let content = match api.read(&self.key).await {
+            Ok(content) => content.to_vec(),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+
+        let stored = temp::dir().join(format!("index-{}.sqlite", uuid::Uuid::new_v4()));
+        std::fs::write(&stored, content)
+            .map_err(|e| Error::Io(e, format!("Writing {}", stored.display())))?;
+
+        let result = self.merge(&stored).await;
+        let _ = std::fs::remove_file(&stored);
+
+        result.map(|()| true)
+    }
+
+    async fn merge(&self, stored: &Path) -> Result<()> {
+        let mut connection = self
+            .pool
+            .acquire()
+            .await
+            .map_err(|e| database_error(&self.path, e))?;
+
+        sqlx::query("ATTACH DATABASE ? AS stored")
+            .bind(stored.to_string_lossy().into_owned())
+            .execute(&mut *connection)
+            .await
+            .map_err(|e| database_error(stored, e))?;
+
+        let merged = sqlx::query(
+            "INSERT INTO paths
+             SELECT store_path_hash, name, nar_hash, nar_size, backend, uploaded_at, last_access
+             FROM stored.paths WHERE last_access >= ?
+             ON CONFLICT (store_path_hash) DO UPDATE SET
+                 last_access = max(paths.last_access, excluded.last_access)",
+        )
+        .bind(oldest())
+        .execute(&mut *connection)
+        .await;
+
+        let detached = sqlx::query("DETACH DATABASE stored")
+            .execute(&mut *connection)
+            .await;
+
+        let merged = merged.map_err(|e| database_error(stored, e))?;
+        detached.map_err(|e| database_error(stored, e))?;
+
+        tracing::debug!(
+            "Merged {} paths into the narinfo index",
+            merged.rows_affected()
+        );
+
+        Ok(())
+    }
+}
+
+/// The label of the backend of `api` in the index.
+pub fn backend_label(api: &Operator) -> String {
+    let info = api.info();
+    format!("{}:{}", info.scheme(), info.name())
+}
+
+fn database_error(path: &Path, err: sqlx::Error) -> Error {
+    Error::Internal(format!("narinfo index {}: {}", path.display(), err))
+}
+
+/// The oldest time of access that is still trusted.
+fn oldest() -> i64 {
+    now() - MAX_AGE.as_secs() as i64
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,168 @@
+//! Description of the running daemon, served at `/api/info`.
+//!
+//! This lets the wrapper action and bug reports capture the exact runtime
+//! state. Credentials are never included: URLs are stripped of their user
+//! info, and only the presence of `netrc` files is reported.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::alerts::AlertFlags;
+use crate::gha::{ClosureFlags, UploadOrder};
+
+#[derive(Debug, Serialize)]
+pub struct Info {
+    pub version: &'static str,
+    pub backends: Backends,
+    pub config: Config,
+    pub features: Vec<&'static str>,
+    pub environment: Environment,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Backends {
+    /// The binary cache backend, if enabled.
+    pub gha: Option<String>,
+
+    /// The FlakeHub cache server, if enabled.
+    pub flakehub: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub scoped_listen: Option<SocketAddr>,
+    pub container_listen: bool,
+    pub upstreams: Vec<String>,
+    pub upload_upstream_paths: bool,
+    pub check_upstream_hashes: bool,
+    pub pull_through: bool,
+    pub trusted_public_keys: Vec<String>,
+    pub backend: String,
+    pub backend_netrc: bool,
+    pub backend_rate_limit: Option<u32>,
+    pub circuit_breaker_threshold: Option<usize>,
+    pub circuit_breaker_duration: Option<u64>,
+    pub routes: Vec<String>,
+    pub extra_backends: Vec<String>,
+    pub cache_namespace: Option<String>,
+    pub cache_version: Option<String>,
+    pub restore_cache_versions: Vec<String>,
+    pub key_secret: bool,
+    pub admin_token: bool,
+    pub github_app_id: Option<u64>,
+    pub diff_store: bool,
+    pub closure: ClosureFlags,
+    pub min_free_space: Option<u64>,
+    pub max_put_size: Option<u64>,
+    pub max_upload_size: Option<u64>,
+    pub max_nar_size: Option<u64>,
+    pub include_fixed_output: bool,
+    pub scan_command: Option<String>,
+    pub upload_policy: Option<String>,
+    pub narinfo_index: Option<String>,
+    pub queue_file: Option<String>,
+    pub resume: bool,
+    pub bundle_below: Option<u64>,
+    pub max_concurrent_uploads: usize,
+    pub upload_retries: u32,
+    pub upload_order: UploadOrder,
+    pub upload_built_only: bool,
+    pub include_regex: Vec<String>,
+    pub exclude_regex: Vec<String>,
+    pub temp_dir: PathBuf,
+    pub statsd_addr: Option<String>,
+    pub alerts: Option<AlertFlags>,
+    pub strict: bool,
+    pub closure_archive: bool,
+    pub check_reproducibility: Option<usize>,
+    pub audit_log: bool,
+    pub defer_until_idle: Vec<String>,
+    pub elect_uploader: bool,
+    pub signing_key: Option<String>,
+    pub old_public_keys: Vec<String>,
+    pub flakehub: Option<FlakeHubConfig>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FlakeHubConfig {
+    pub api_server: String,
+    pub cache_server: String,
+    pub flake_name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Environment {
+    /// The CI system, as detected from the environment.
+    pub ci: String,
+
+    /// The runner OS reported by GitHub Actions, e.g. `Linux`.
+    pub runner_os: Option<String>,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub nix_version: Option<String>,
+}
+
+impl Environment {
+    pub async fn detect(ci: crate::env::Environment) -> Self {
+        Environment {
+            ci: ci.to_string(),
+            runner_os: std::env::var("RUNNER_OS").ok(),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            nix_version: nix_version().await,
+        }
+    }
+}
+
+/// Features compiled into or enabled in this daemon.
+pub fn features(statsd: bool, min_free_space: bool) -> Vec<&'static str> {
+    let mut features = vec!["source-cache", "request-id"];
+
+    if statsd {
+        features.push("statsd");
+    }
+
+    if min_free_space {
+        features.push("disk-guard");
+    }
+
+    if cfg!(debug_assertions) {
+        features.push("debug");
+    }
+
+    features
+}
+
+async fn nix_version() -> Option<String> {
+    let output = tokio::process::Command::new("nix")
+        .arg("--version")
+        .output()
+        .await
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Remove any credentials from `url`.
+pub fn redact_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    // These only fail for URLs that can't have credentials anyway.
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url
+}
+
+/// Remove any credentials from `s` if it is a URL.
+pub fn redact(s: &str) -> String {
+    match Url::parse(s) {
+        Ok(url) => redact_url(&url).to_string(),
+        Err(_) => s.to_owned(),
+    }
+}
@@ -0,0 +1,198 @@
+//! Layout of the keys under which objects are stored in the cache.
+//!
+//! Keys are prefixed with a schema version, so that changing how objects are
+//! laid out or compressed doesn't silently orphan existing entries. Objects
+//! are always written using the current schema, and looked up using the
+//! current schema first, then the older ones.
+//!
+//! With a key secret, the names in keys are replaced by their HMAC-SHA256, so
+//! that entry names don't reveal store path hashes, NAR hashes or repository
+//! names, e.g. in the GitHub Actions Cache UI. Lookups stay deterministic as
+//! long as every daemon sharing the cache uses the same secret.
+
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use hmac::{Hmac, Mac};
+use opendal::{Buffer, ErrorKind, Metadata, Operator};
+use sha2::Sha256;
+
+use crate::error::{Error, Result};
+
+/// The current key schema version. Version 0 is the original, unprefixed layout.
+pub const SCHEMA_VERSION: u32 = 1;
+
+static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Hash the names in keys with `secret` from now on.
+pub fn init_secret(secret: Vec<u8>) -> Result<()> {
+    if secret.is_empty() {
+        return Err(Error::Config("The key secret is empty".to_owned()));
+    }
+
+    if SECRET.get().is_some_and(|existing| *existing != secret) {
+        return Err(Error::Config(
+            "A different key secret is already in use".to_owned(),
+        ));
+    }
+
+    let _ = SECRET.set(secret);
+
+    Ok(())
+}
+
+/// Whether the names in keys are hashed.
+pub fn is_obfuscated() -> bool {
+    SECRET.get().is_some()
+}
+
+/// `name` as it appears in keys, which is its hex HMAC-SHA256 if there is a key secret.
+fn obfuscate(name: &str) -> Cow<'_, str> {
+    match SECRET.get() {
+        Some(secret) => {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+            mac.update(name.as_bytes());
+            Cow::Owned(format!("{:x}", mac.finalize().into_bytes()))
+        }
+        None => Cow::Borrowed(name),
+    }
+}
+
+/// The key of the narinfo for a store path hash.
+pub fn narinfo(store_path_hash: &str) -> String {
+    format!("v{}/{}.narinfo", SCHEMA_VERSION, obfuscate(store_path_hash))
+}
+
+/// The prefix of the keys of all narinfos, which other keys share.
+pub fn narinfos() -> String {
+    format!("v{}/", SCHEMA_VERSION)
+}
+
+/// The key of a NAR, given the file name it's served under in `/nar/`.
+pub fn nar(file_name: &str) -> String {
+    format!("v{}/nar/{}", SCHEMA_VERSION, obfuscate(file_name))
+}
+
+/// The prefix of the keys of all NARs.
+pub fn nars() -> String {
+    format!("v{}/nar/", SCHEMA_VERSION)
+}
+
+/// The key of a compressed source tree NAR, given its base-32 NAR hash.
+pub fn source(nar_hash: &str) -> String {
+    format!(
+        "v{}/source/{}.nar.zstd",
+        SCHEMA_VERSION,
+        obfuscate(nar_hash)
+    )
+}
+
+/// The key of a closure archive, given its ID.
+pub fn closure_archive(id: &str) -> String {
+    format!("v{}/closure/{}.export.zstd", SCHEMA_VERSION, id)
+}
+
+/// The key of the index of a closure archive, given its ID.
+pub fn closure_archive_index(id: &str) -> String {
+    format!("v{}/closure/{}.index.json", SCHEMA_VERSION, id)
+}
+
+/// The key of a bundle of small NARs, given its ID.
+pub fn bundle(id: &str) -> String {
+    format!("v{}/bundle/{}.zstd", SCHEMA_VERSION, id)
+}
+
+/// The key of the catalog of the paths a repository bundled.
+pub fn bundle_catalog(repository: &str) -> String {
+    format!("{}{}", bundle_catalogs(), obfuscate(repository))
+}
+
+/// The prefix of the keys of all bundle catalogs.
+pub fn bundle_catalogs() -> String {
+    format!("v{}/bundles/", SCHEMA_VERSION)
+}
+
+/// The key of the claim on uploading a store path during a workflow run.
+pub fn claim(run: &str, store_path_hash: &str) -> String {
+    format!(
+        "v{}/claim/{}/{}",
+        SCHEMA_VERSION,
+        run,
+        obfuscate(store_path_hash)
+    )
+}
+
+/// The key of a retention index, given a unique ID.
+pub fn retention_index(id: &str) -> String {
+    format!("{}{}.json", retention_indexes(), id)
+}
+
+/// The prefix of the keys of all retention indexes.
+pub fn retention_indexes() -> String {
+    format!("v{}/retention/", SCHEMA_VERSION)
+}
+
+/// The key of the manifest of paths uploaded by a repository.
+pub fn manifest(repository: &str) -> String {
+    format!("{}{}", manifests(), obfuscate(repository))
+}
+
+/// The prefix of the keys of all manifests.
+pub fn manifests() -> String {
+    format!("v{}/manifest/", SCHEMA_VERSION)
+}
+
+/// The key of the narinfo index of a repository.
+pub fn narinfo_index(repository: &str) -> String {
+    format!("{}{}.sqlite", narinfo_indexes(), obfuscate(repository))
+}
+
+/// The prefix of the keys of all narinfo indexes.
+pub fn narinfo_indexes() -> String {
+    format!("v{}/index/", SCHEMA_VERSION)
+}
+
+/// The keys a narinfo may be stored under, newest schema first.
+pub fn narinfo_candidates(store_path_hash: &str) -> [String; 2] {
+    [
+        narinfo(store_path_hash),
+        format!("{}.narinfo", store_path_hash),
+    ]
+}
+
+/// The keys a NAR may be stored under, newest schema first.
+pub fn nar_candidates(file_name: &str) -> [String; 2] {
+    [nar(file_name), file_name.to_owned()]
+}
+
+/// Read the first of `keys` that exists.
+pub async fn read_any(api: &Operator, keys: &[String]) -> opendal::Result<Buffer> {
+    let mut result = Err(opendal::Error::new(ErrorKind::NotFound, "no keys to read"));
+
+    for key in keys {
+        result = api.read(key).await;
+
+        if !matches!(&result, Err(err) if err.kind() == ErrorKind::NotFound) {
+            break;
+        }
+    }
+
+    result
+}
+
+/// Find the first of `keys` that exists, returning it along with its metadata.
+pub async fn stat_any(
+    api: &Operator,
+    keys: &[String],
+) -> opendal::Result<Option<(String, Metadata)>> {
+    for key in keys {
+        match api.stat(key).await {
+            Ok(metadata) => return Ok(Some((key.clone(), metadata))),
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(None)
+}
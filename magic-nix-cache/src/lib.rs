@@ -0,0 +1,1470 @@
+//! GitHub Actions-powered Nix binary cache.
+//!
+//! Besides the `magic-nix-cache` binary, the cache can be embedded in other
+//! programs using [`Server`].
+
+#![deny(
+    asm_sub_register,
+    deprecated,
+    missing_abi,
+    unused_macros,
+    unused_must_use,
+    unused_unsafe
+)]
+#![deny(clippy::from_over_into, clippy::needless_question_mark)]
+#![cfg_attr(
+    not(debug_assertions),
+    deny(unused_imports, unused_mut, unused_variables,)
+)]
+
+mod admin;
+mod aggregate;
+mod alerts;
+mod api;
+mod artifactory;
+mod audit;
+mod backend;
+mod binary_cache;
+mod buildkite;
+mod bundle;
+mod cancellation;
+mod closure_archive;
+mod container;
+mod daemon_proxy;
+mod deferral;
+mod disk_guard;
+mod election;
+mod env;
+mod error;
+mod estimate;
+mod exec;
+mod fanout;
+mod flakehub;
+mod gc_roots;
+mod gha;
+mod github_app;
+mod gitlab;
+mod health;
+mod http;
+mod index;
+mod info;
+mod keys;
+mod lifecycle;
+mod listener;
+mod local;
+mod lru;
+mod manifest;
+mod mirror;
+mod netrc;
+mod nix_conf;
+mod pbh;
+mod policy;
+mod queue_file;
+mod quota;
+mod repro;
+mod request_id;
+mod retention;
+mod routing;
+mod s3;
+mod scan;
+mod scheduler;
+mod scoped;
+mod secrets;
+mod server;
+mod service;
+mod signing;
+mod source;
+mod statsd;
+mod steps;
+mod telemetry;
+mod temp;
+mod timeout;
+mod upload;
+mod upstream;
+mod util;
+
+pub use backend::Backend;
+pub use gha::ClosureFlags;
+pub use secrets::SecretSource;
+pub use server::{Server, ServerBuilder};
+pub use timeout::{RouteClass, Timeouts};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::create_dir_all;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ::attic::nix_store::NixStore;
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+const DETERMINATE_STATE_DIR: &str = "/nix/var/determinate";
+const DETERMINATE_NIXD_SOCKET_NAME: &str = "determinate-nixd.socket";
+const DETERMINATE_NETRC_PATH: &str = "/nix/var/determinate/netrc";
+
+/// Number of narinfos kept in memory to be served if the backend fails.
+const NARINFO_CACHE_CAPACITY: usize = 4096;
+
+// TODO(colemickens): refactor, move with other UDS stuff (or all PBH stuff) to new file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "c", rename_all = "kebab-case")]
+pub(crate) struct BuiltPathResponseEventV1 {
+    pub drv: PathBuf,
+    pub outputs: Vec<PathBuf>,
+}
+
+type State = Arc<StateInner>;
+
+/// GitHub Actions-powered Nix binary cache
+#[derive(Parser, Debug)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Address to listen on.
+    ///
+    /// FIXME: IPv6
+    #[arg(short = 'l', long, default_value = "127.0.0.1:3000")]
+    listen: SocketAddr,
+
+    /// Also listen on this address, e.g. that of the Docker bridge, for
+    /// sub-processes that push paths with the scoped tokens minted at
+    /// `/api/scoped-tokens`. Only the binary cache is served there, and only
+    /// to requests with a token.
+    #[arg(long, value_name = "ADDR")]
+    scoped_listen: Option<SocketAddr>,
+
+    /// Also serve the binary cache on the address of the Docker bridge, at
+    /// the same port, so that builds in containers can use it, and log the
+    /// substituter to configure in them. Only pushes with a scoped token
+    /// from `/api/scoped-tokens` are accepted there.
+    #[arg(long, default_value_t = false)]
+    container_listen: bool,
+
+    /// The cache version.
+    ///
+    /// Only caches with the same version string are visible.
+    /// Using another version string allows you to "bust" the cache. Entries
+    /// of other versions aren't read, unless given with
+    /// `--restore-cache-version`. `gc` must be given the same version.
+    #[arg(long)]
+    cache_version: Option<String>,
+
+    /// Also look up the paths that the cache doesn't have in the entries of
+    /// this cache version, without writing to it, e.g. to restore from the
+    /// default branch's version on other branches. Can be given more than
+    /// once, and `''` is the version of entries written without
+    /// `--cache-version`.
+    #[arg(long, value_name = "VERSION")]
+    restore_cache_version: Vec<String>,
+
+    /// The upstream cache.
+    ///
+    /// Requests for unknown NARs are redirected to this cache
+    /// instead. Can be given more than once, in which case the caches
+    /// are tried in order.
+    #[arg(long)]
+    upstream: Vec<String>,
+
+    /// Also upload the paths that the upstream cache has. By default, the
+    /// upstream cache is asked for each path first, and the paths it has
+    /// are skipped.
+    #[arg(long, default_value_t = false)]
+    upload_upstream_paths: bool,
+
+    /// Compare the NAR hash of each path that the upstream cache has with
+    /// its narinfo, and warn when they differ, which means that the path
+    /// wasn't built reproducibly, or that one of them was tampered with.
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "upstream",
+        conflicts_with = "upload_upstream_paths"
+    )]
+    check_upstream_hashes: bool,
+
+    /// Fetch the paths that the cache doesn't have from the upstream cache,
+    /// and store them in the cache, instead of redirecting to it.
+    #[arg(long, default_value_t = false, requires = "upstream")]
+    pull_through: bool,
+
+    /// Only serve narinfos from the upstream cache that are signed by one of
+    /// these keys, like `cache.nixos.org-1:<base64>`. The daemon fetches them
+    /// itself to check, instead of redirecting to the upstream cache.
+    #[arg(long, value_delimiter = ' ', requires = "upstream")]
+    trusted_public_keys: Vec<String>,
+
+    /// Diagnostic endpoint to send diagnostics and performance data.
+    ///
+    /// An HTTP(S) URL, or `file:<path>` to write them to a local JSON file
+    /// instead. Set it to an empty string to disable reporting.
+    /// See the README for details.
+    #[arg(
+        long,
+        default_value = "https://install.determinate.systems/magic-nix-cache/perf"
+    )]
+    diagnostic_endpoint: telemetry::DiagnosticSink,
+
+    /// The FlakeHub API server.
+    #[arg(long, default_value = "https://api.flakehub.com")]
+    flakehub_api_server: reqwest::Url,
+
+    /// The path of the `netrc` file that contains the FlakeHub JWT token.
+    #[arg(long)]
+    flakehub_api_server_netrc: Option<PathBuf>,
+
+    /// The FlakeHub binary cache server.
+    #[arg(long, default_value = "https://cache.flakehub.com")]
+    flakehub_cache_server: reqwest::Url,
+
+    #[arg(long)]
+    flakehub_flake_name: Option<String>,
+
+    /// The location of `nix.conf`.
+    #[arg(long, default_value_os_t = default_nix_conf())]
+    nix_conf: PathBuf,
+
+    /// Append the settings for the cache to `nix.conf` again when another
+    /// step removes them. By default, the daemon only warns.
+    #[arg(long, default_value_t = false)]
+    reapply_nix_conf: bool,
+
+    /// Whether to use the GHA cache.
+    #[arg(long)]
+    use_gha_cache: Option<Option<CacheTrinary>>,
+
+    /// Where the binary cache stores its objects.
+    ///
+    /// `gha` uses the GitHub Actions Cache. `webdav:<url>` uses a WebDAV
+    /// server. `artifactory:<url>` uses an Artifactory repository, tagging
+    /// uploads with build properties. `exec:<path>` runs the given helper
+    /// program for each operation; see the README for its protocol. An
+    /// `http://` or `https://` URL pushes to a remote binary cache that
+    /// accepts `PUT` requests. `s3:<bucket>` uses an S3 bucket, and
+    /// `r2:<account-id>/<bucket>` and `b2:<region>/<bucket>` one at Cloudflare
+    /// R2 or Backblaze B2. `opendal:<scheme>?<options>` uses any other service
+    /// that opendal supports. `gitlab[:<package>]` uses the generic package
+    /// registry of the GitLab project, and `buildkite` the artifacts of the
+    /// builds of the Buildkite pipeline.
+    ///
+    /// Defaults to `gitlab` in GitLab CI and to `buildkite` on Buildkite
+    /// agents, both without FlakeHub, and to `gha` otherwise.
+    #[arg(long)]
+    backend: Option<Backend>,
+
+    /// Store the binary cache in this directory instead, e.g. on the
+    /// persistent disk of a self-hosted runner.
+    #[arg(long, conflicts_with = "backend")]
+    local_cache_dir: Option<PathBuf>,
+
+    /// Remove the least recently used objects from `--local-cache-dir` when
+    /// it holds more than this, e.g. `50G`.
+    #[arg(
+        long,
+        value_parser = util::parse_size,
+        default_value = "10G",
+        requires = "local_cache_dir"
+    )]
+    local_cache_size: u64,
+
+    /// The `netrc` file with the credentials for the backend, or a reference
+    /// to it in a secret manager, e.g. `vault://secret/data/ci#netrc`,
+    /// `op://ci/cache/netrc` or `cmd:<command>`.
+    ///
+    /// Defaults to `~/.netrc`.
+    #[arg(long)]
+    backend_netrc: Option<secrets::SecretSource>,
+
+    /// Send at most this many requests per second to the backend, shared
+    /// between serving and uploading.
+    #[arg(long)]
+    backend_rate_limit: Option<u32>,
+
+    /// Fail requests to the backend right away for a while after this many
+    /// in a row failed with a 429 or a 5xx, instead of adding to the load.
+    #[arg(long)]
+    circuit_breaker_threshold: Option<usize>,
+
+    /// How long requests fail once the circuit breaker opened. Defaults to
+    /// 60s.
+    #[arg(long, value_parser = util::parse_duration, requires = "circuit_breaker_threshold")]
+    circuit_breaker_duration: Option<std::time::Duration>,
+
+    /// Store the paths whose name matches a regex in another backend, e.g.
+    /// `-(doc|man)$=webdav:https://dav.example.com/cold`. Can be given
+    /// several times, the first matching route applies.
+    #[arg(long = "route", value_parser = routing::parse_rule)]
+    routes: Vec<(regex::Regex, Backend)>,
+
+    /// Also upload every path to this backend, e.g. `s3:nix-cache` to keep
+    /// a copy of the GitHub Actions Cache. Can be given several times. The
+    /// backend is only written to, and its failures don't fail uploads.
+    #[arg(long = "extra-backend")]
+    extra_backends: Vec<Backend>,
+
+    /// Answer requests that take longer than this with a 504, e.g. `nar=2m`
+    /// for a class of requests (`narinfo`, `nar`, `source`, `upload` or
+    /// `api`), or `30s` for all of them. `0s` disables the timeout. Reads
+    /// time out after a minute by default, uploads and the API never.
+    #[arg(long = "request-timeout", value_parser = timeout::parse_rule)]
+    request_timeouts: Vec<(Option<RouteClass>, std::time::Duration)>,
+
+    /// Serve at most this many connections at once. Defaults to 512.
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Disconnect clients that take longer than this to send the headers of
+    /// a request. Defaults to 30s.
+    #[arg(long, value_parser = util::parse_duration)]
+    header_read_timeout: Option<std::time::Duration>,
+
+    /// Fail requests whose body stalls for longer than this. Defaults to 60s.
+    #[arg(long, value_parser = util::parse_duration)]
+    body_read_timeout: Option<std::time::Duration>,
+
+    /// Keep the entries in the GitHub Actions Cache under this namespace,
+    /// e.g. the directory of the project in a monorepo or the workflow name,
+    /// so that independent projects sharing the repository's cache don't
+    /// evict or garbage-collect each other's entries. `gc` must be given the
+    /// same namespace.
+    #[arg(long, value_name = "NAME")]
+    cache_namespace: Option<String>,
+
+    /// A file with a secret to HMAC the names in cache keys with, so that
+    /// entry names don't reveal which store paths are cached. Every job
+    /// sharing the cache, including `gc`, must use the same secret. Also
+    /// accepts secret manager references like `--backend-netrc`.
+    #[arg(long)]
+    key_secret_file: Option<secrets::SecretSource>,
+
+    /// A file with a token that admin endpoints like `/nar/?list=1` require
+    /// as `Authorization: Bearer <token>`. They are disabled without it.
+    /// Also accepts secret manager references like `--backend-netrc`.
+    #[arg(long)]
+    admin_token_file: Option<secrets::SecretSource>,
+
+    #[command(flatten)]
+    github_app: github_app::GitHubAppFlags,
+
+    /// Whether to use the FlakeHub binary cache.
+    #[arg(long)]
+    use_flakehub: Option<Option<CacheTrinary>>,
+
+    /// URL to which to post startup notification.
+    #[arg(long)]
+    startup_notification_url: Option<reqwest::Url>,
+
+    /// File to write to when indicating startup.
+    #[arg(long)]
+    startup_notification_file: Option<PathBuf>,
+
+    /// Whether or not to diff the store before and after Magic Nix Cache runs
+    #[arg(long, default_value_t = false)]
+    diff_store: bool,
+
+    #[command(flatten)]
+    closure: gha::ClosureFlags,
+
+    /// Collect garbage in the Nix store when less than this much disk space is
+    /// free, e.g. `10G`. Paths that are waiting to be uploaded are kept.
+    #[arg(long, value_parser = util::parse_size)]
+    min_free_space: Option<u64>,
+
+    /// Accept at most this many bytes over `PUT` in total, e.g. `5G`, and
+    /// answer uploads beyond that with a 413.
+    #[arg(long, value_parser = util::parse_size)]
+    max_put_size: Option<u64>,
+
+    /// Upload at most this many bytes of compressed NARs from the queue,
+    /// e.g. `2G`, starting with the smallest paths.
+    #[arg(long, value_parser = util::parse_size)]
+    max_upload_size: Option<u64>,
+
+    /// Don't upload the paths whose uncompressed NAR is larger than this,
+    /// e.g. `1G`, so that one large toolchain can't evict the rest of the
+    /// cache.
+    #[arg(long, value_parser = util::parse_size)]
+    max_nar_size: Option<u64>,
+
+    /// Also upload the outputs of fixed-output derivations, like the sources
+    /// fetched by `fetchurl` or `fetchgit`. By default they are skipped,
+    /// since they are cheap to download again. Paths whose derivation isn't
+    /// in the store are always uploaded.
+    #[arg(long, default_value_t = false)]
+    include_fixed_output: bool,
+
+    /// Run this program with each store path as its argument before
+    /// uploading it, e.g. a secret scanner, and skip the paths for which it
+    /// exits with a non-zero status.
+    #[arg(long, value_name = "PROGRAM")]
+    scan_command: Option<PathBuf>,
+
+    /// Decide which paths are uploaded, skipped or stored in another backend
+    /// by the rules in this file, by their name, size, system, deriver and
+    /// the branch. See the README for the syntax.
+    #[arg(long, value_name = "FILE")]
+    upload_policy: Option<PathBuf>,
+
+    /// Keep an index of the paths in the cache in this SQLite database,
+    /// which is stored in the cache too, to skip paths the backend has
+    /// without asking it and to query what is cached.
+    #[arg(long, value_name = "FILE")]
+    narinfo_index: Option<PathBuf>,
+
+    /// Keep the paths that are waiting to be uploaded in this file, so that
+    /// `--resume` can upload them if the daemon is killed first.
+    #[arg(long, value_name = "FILE")]
+    queue_file: Option<PathBuf>,
+
+    /// Upload the paths that the previous daemon left in `--queue-file`,
+    /// e.g. in the next job of a workflow on the same runner.
+    #[arg(long, requires = "queue_file")]
+    resume: bool,
+
+    /// Store the paths whose NAR is smaller than this, e.g. `64K`, in bundles
+    /// of many paths instead of two cache entries each, to make fewer
+    /// requests to the backend.
+    #[arg(long, value_parser = util::parse_size)]
+    bundle_below: Option<u64>,
+
+    /// Upload up to this many paths at once. Defaults to 1.
+    #[arg(long)]
+    max_concurrent_uploads: Option<usize>,
+
+    /// Retry uploads that fail because the backend rate-limited them, failed
+    /// with a server error or timed out up to this many times, waiting
+    /// longer before each retry. Defaults to 3.
+    #[arg(long)]
+    upload_retries: Option<u32>,
+
+    /// The order to upload enqueued paths in: `toposort` uploads each path
+    /// after the paths it refers to, `smallest` the smallest paths first, to
+    /// upload as many as possible before the job ends, and `fifo` in the
+    /// order they were enqueued.
+    #[arg(long, value_enum, default_value_t = gha::UploadOrder::Toposort)]
+    upload_order: gha::UploadOrder,
+
+    /// Only upload the paths of enqueued closures that were built or added
+    /// in this job, assuming that the substituted ones are already in the
+    /// binary cache they came from.
+    #[arg(long, default_value_t = false)]
+    upload_built_only: bool,
+
+    #[command(flatten)]
+    name_filter: gha::NameFilter,
+
+    /// Directory for temporary files. Defaults to the system's temporary directory.
+    ///
+    /// Files are kept in a subdirectory that is removed on shutdown, or by the
+    /// next run if this one crashes.
+    #[arg(long)]
+    temp_dir: Option<PathBuf>,
+
+    /// Send the metrics to the StatsD (or DogStatsD) server at this address, e.g. `127.0.0.1:8125`.
+    #[arg(long)]
+    statsd_addr: Option<String>,
+
+    #[command(flatten)]
+    alerts: alerts::AlertFlags,
+
+    /// Abort on configuration problems instead of carrying on without the
+    /// affected cache, e.g. when FlakeHub can't be authenticated to or the
+    /// GitHub Actions Cache credentials are missing.
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+
+    /// Also store the whole closure of the job as a single archive, keyed by
+    /// `flake.lock` and the system, and restore it at the start of the next
+    /// run with the same key.
+    #[arg(long, default_value_t = false)]
+    closure_archive: bool,
+
+    /// When the workflow finishes, rebuild this many derivations of the
+    /// paths that were enqueued, picked at random, with `--check`, and
+    /// report the ones that aren't reproducible.
+    #[arg(long, value_name = "SAMPLES")]
+    check_reproducibility: Option<usize>,
+
+    /// Append a JSON line to this file for every object written to or
+    /// deleted from the cache, recording who changed what and when.
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Don't upload the paths whose name matches this regex, e.g. `-(doc|man)$`
+    /// or `-source$`, except in scheduled runs. They are recorded in
+    /// `--pending-file` instead. Can be given several times.
+    #[arg(long, requires = "pending_file")]
+    defer_until_idle: Vec<regex::Regex>,
+
+    /// Append the paths whose upload is deferred to this file.
+    #[arg(long)]
+    pending_file: Option<PathBuf>,
+
+    /// Sign the narinfos of uploaded paths with the secret key in this file,
+    /// as written by `nix key generate-secret`.
+    #[arg(long)]
+    signing_key_file: Option<PathBuf>,
+
+    /// Public keys that narinfos were signed with before the key in
+    /// `--signing-key-file`, like `cache.example.com-1:<base64>`, whose
+    /// signatures `/api/re-sign` replaces.
+    #[arg(long, value_delimiter = ' ', requires = "signing_key_file")]
+    old_public_keys: Vec<String>,
+
+    /// Coordinate with the other jobs of the workflow run, e.g. of a matrix,
+    /// through claims in the cache, so that only one of them uploads each path.
+    #[arg(long, default_value_t = false)]
+    elect_uploader: bool,
+
+    /// Experimental: also listen on this Unix socket, forwarding connections
+    /// to the Nix daemon and uploading the paths they build or add. Point
+    /// `NIX_REMOTE` at it to use it instead of a post-build hook.
+    #[arg(long)]
+    daemon_proxy_socket: Option<PathBuf>,
+
+    /// The Nix daemon socket that `--daemon-proxy-socket` forwards to.
+    #[arg(long, default_value = "/nix/var/nix/daemon-socket/socket")]
+    daemon_proxy_upstream: PathBuf,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Merge the metrics of all jobs of a workflow run, and print them as JSON.
+    Aggregate {
+        /// The workflow run. Defaults to the current one.
+        #[arg(long)]
+        run_id: Option<String>,
+    },
+
+    /// Compare the hit rates, upload volume and duration of the last workflow
+    /// runs, and print them as JSON.
+    Trend {
+        /// The number of runs to compare.
+        #[arg(long, default_value_t = 20)]
+        runs: usize,
+    },
+
+    /// Ask a running daemon to upload store paths and their closures.
+    Enqueue {
+        /// The daemon to connect to.
+        #[arg(short = 'l', long, default_value = "127.0.0.1:3000")]
+        server: SocketAddr,
+
+        /// Upload the given paths even if the cache already has them, replacing
+        /// the existing entries. Their closures are uploaded as usual.
+        #[arg(long)]
+        force: bool,
+
+        /// Also upload the paths in this pending file of `--defer-until-idle`
+        /// that exist in the local store.
+        #[arg(long)]
+        pending_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        closure: gha::ClosureFlags,
+
+        store_paths: Vec<String>,
+    },
+
+    /// Configure Nix in a running Docker container to use the cache, e.g. the
+    /// one the daemon serves with `--container-listen`.
+    Inject {
+        /// The ID or name of the container.
+        #[arg(long)]
+        container: String,
+
+        /// The substituter to use. Defaults to the one the daemon exported
+        /// as `MAGIC_NIX_CACHE_CONTAINER_SUBSTITUTER`.
+        #[arg(long)]
+        substituter: Option<String>,
+
+        /// A file with a scoped token from `/api/scoped-tokens` for pushing
+        /// to the substituter, which is written to a netrc file in the
+        /// container. Both the container listener and the scoped listener
+        /// accept it. Also accepts secret manager references like
+        /// `--backend-netrc`.
+        #[arg(long)]
+        token_file: Option<secrets::SecretSource>,
+    },
+
+    /// Delete the entries of old runs from the backend, according to retention rules.
+    Gc {
+        /// Keep the entries of runs on a branch for this long, e.g.
+        /// `main=30d`. Use `pr` for pull requests and `*` for any other run.
+        /// Entries of runs that no rule matches are kept.
+        #[arg(long = "retain", value_parser = retention::parse_rule)]
+        rules: Vec<(String, std::time::Duration)>,
+
+        /// Report what would be deleted without deleting anything.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum CacheTrinary {
+    NoPreference,
+    Enabled,
+    Disabled,
+}
+
+impl From<Option<Option<CacheTrinary>>> for CacheTrinary {
+    fn from(b: Option<Option<CacheTrinary>>) -> Self {
+        match b {
+            None => CacheTrinary::NoPreference,
+            Some(None) => CacheTrinary::Enabled,
+            Some(Some(v)) => v,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Dnixd {
+    Available,
+    Missing,
+}
+
+impl From<bool> for Dnixd {
+    fn from(b: bool) -> Self {
+        if b {
+            Dnixd::Available
+        } else {
+            Dnixd::Missing
+        }
+    }
+}
+
+impl Args {
+    fn validate(&self, environment: env::Environment) -> Result<(), error::Error> {
+        if environment.is_gitlab_ci()
+            && self.github_cache_preference() == CacheTrinary::Enabled
+            && self.backend(environment) == Backend::Gha
+        {
+            return Err(error::Error::Config(String::from(
+                "the --use-gha-cache flag should not be applied in GitLab CI with the gha backend",
+            )));
+        }
+
+        if self.strict {
+            self.validate_strict(environment)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reject settings that would otherwise be silently ignored.
+    fn validate_strict(&self, environment: env::Environment) -> Result<(), error::Error> {
+        if self.github_cache_preference() == CacheTrinary::Enabled
+            && self.backend(environment) == Backend::Gha
+            && !environment.has_actions_cache()
+        {
+            return Err(error::Error::Config(String::from(
+                "--use-gha-cache was given, but this is not running in GitHub, Forgejo or Gitea Actions",
+            )));
+        }
+
+        if let Some(netrc) = &self.flakehub_api_server_netrc {
+            if !netrc.exists() {
+                return Err(error::Error::Config(format!(
+                    "the --flakehub-api-server-netrc file {} does not exist",
+                    netrc.display()
+                )));
+            }
+        }
+
+        if let Some(secrets::SecretSource::File(netrc)) = &self.backend_netrc {
+            if !netrc.exists() {
+                return Err(error::Error::Config(format!(
+                    "the --backend-netrc file {} does not exist",
+                    netrc.display()
+                )));
+            }
+        }
+
+        if self.flakehub_preference() == CacheTrinary::Disabled
+            && self.flakehub_flake_name.is_some()
+        {
+            return Err(error::Error::Config(String::from(
+                "--flakehub-flake-name was given, but the FlakeHub cache is disabled",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The backend given by `--backend` or `--local-cache-dir`, or the one
+    /// for `environment`. Jobs in GitLab CI or on Buildkite that use FlakeHub
+    /// only use FlakeHub.
+    fn backend(&self, environment: env::Environment) -> Backend {
+        if let (None, Some(dir)) = (&self.backend, &self.local_cache_dir) {
+            return Backend::Local {
+                dir: dir.clone(),
+                max_size: self.local_cache_size,
+            };
+        }
+
+        match &self.backend {
+            Some(backend) => backend.clone(),
+            None if environment.is_gitlab_ci()
+                && self.flakehub_preference() != CacheTrinary::Enabled =>
+            {
+                Backend::GitLab(gitlab::DEFAULT_PACKAGE.to_owned())
+            }
+            None if environment.is_buildkite()
+                && self.flakehub_preference() != CacheTrinary::Enabled =>
+            {
+                Backend::Buildkite
+            }
+            None => Backend::Gha,
+        }
+    }
+
+    fn github_cache_preference(&self) -> CacheTrinary {
+        self.use_gha_cache.into()
+    }
+
+    fn flakehub_preference(&self) -> CacheTrinary {
+        self.use_flakehub.into()
+    }
+}
+
+fn default_nix_conf() -> PathBuf {
+    xdg::BaseDirectories::new()
+        .with_context(|| "identifying XDG base directories")
+        .expect(
+            "Could not identify your home directory. Try setting the HOME environment variable.",
+        )
+        .get_config_file("nix/nix.conf")
+}
+
+/// The global server state.
+struct StateInner {
+    /// State for uploading to the GHA cache.
+    gha_cache: Option<gha::GhaCache>,
+
+    /// The first upstream cache.
+    upstream: Option<String>,
+
+    /// The upstream caches, if narinfos are fetched from them instead of
+    /// redirected to, with `--pull-through`, `--trusted-public-keys` or
+    /// more than one `--upstream`.
+    upstream_client: Option<upstream::Upstream>,
+
+    /// Whether NARs from the upstream cache are stored in the cache.
+    pull_through: bool,
+
+    /// The keys that narinfos from the upstream cache must be signed with.
+    trusted_keys: Option<signing::TrustedKeys>,
+
+    /// The key that uploaded narinfos are signed with, and the ones it
+    /// replaced, if there is one.
+    signing_keys: Option<signing::SigningKeys>,
+
+    /// The token that admin endpoints require.
+    admin_token: Option<String>,
+
+    /// The tokens for the scoped listener, if there is one.
+    scoped_tokens: Option<scoped::ScopedTokens>,
+
+    /// The credentials of the upstream caches, for `/api/netrc`.
+    upstream_netrc: Vec<netrc::Entry>,
+
+    /// Whether the job was cancelled, which limits what is still uploaded.
+    cancellation: Arc<cancellation::Cancellation>,
+
+    /// The metrics of each workflow step.
+    steps: steps::Steps,
+
+    /// How the closures of enqueued paths are computed.
+    closure_flags: gha::ClosureFlags,
+
+    /// Which phase of its life the daemon is in.
+    lifecycle: lifecycle::Lifecycle,
+
+    /// The sender half of the oneshot channel to trigger a shutdown.
+    shutdown_sender: Mutex<Option<oneshot::Sender<()>>>,
+
+    /// Set of store path hashes that are not present in GHAC.
+    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+
+    /// Recently served narinfos, keyed by store path hash, used when the backend fails.
+    narinfo_cache: Mutex<binary_cache::NarinfoCache>,
+
+    /// Narinfos uploaded over `PUT` during this run, keyed by store path hash.
+    written_narinfos: RwLock<HashMap<String, axum::body::Bytes>>,
+
+    /// Metrics for sending to perf at shutdown
+    metrics: Arc<telemetry::TelemetryReport>,
+
+    /// Connection to the local Nix store.
+    store: Arc<NixStore>,
+
+    /// FlakeHub cache state.
+    flakehub_state: RwLock<Option<flakehub::State>>,
+
+    /// Where all of tracing will log to when GitHub Actions is run in debug mode
+    logfile: Option<PathBuf>,
+
+    /// The paths in the Nix store when Magic Nix Cache started, if store diffing is enabled.
+    original_paths: Option<Mutex<HashSet<PathBuf>>>,
+
+    /// The closure archive of this job, if closure archive mode is enabled.
+    closure_archive: Option<closure_archive::ClosureArchive>,
+
+    /// The derivations to rebuild, if reproducibility checks are enabled.
+    repro_check: Option<repro::ReproCheck>,
+
+    /// The audit log of changes to the cache, if one was requested.
+    audit_log: Option<Arc<audit::AuditLog>>,
+
+    /// Timeouts for handling requests.
+    timeouts: timeout::Timeouts,
+
+    /// The quota on the bytes uploaded over `PUT`, if there is one.
+    put_quota: Option<quota::PutQuota>,
+
+    /// Description of this daemon for `/api/info`.
+    info: info::Info,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum FlakeHubAuthSource {
+    DeterminateNixd,
+    Netrc(PathBuf),
+}
+
+impl FlakeHubAuthSource {
+    pub(crate) fn as_path_buf(&self) -> PathBuf {
+        match &self {
+            Self::Netrc(path) => path.clone(),
+            Self::DeterminateNixd => {
+                let mut path = PathBuf::from(DETERMINATE_STATE_DIR);
+                path.push("netrc");
+
+                path
+            }
+        }
+    }
+}
+
+async fn main_cli() -> Result<()> {
+    let guard = init_logging()?;
+    let _tracing_guard = guard.appender_guard;
+
+    let args = Args::parse();
+
+    if let Some(namespace) = &args.cache_namespace {
+        backend::init_gha_namespace(namespace)?;
+    }
+
+    if let Some(version) = &args.cache_version {
+        backend::init_gha_cache_version(version)?;
+    }
+
+    let key_secret = match &args.key_secret_file {
+        Some(source) => {
+            let mut secret = source
+                .resolve()
+                .await
+                .with_context(|| format!("Reading the key secret from {}", source))?;
+            while secret.last().is_some_and(u8::is_ascii_whitespace) {
+                secret.pop();
+            }
+            keys::init_secret(secret.clone())?;
+            Some(secret)
+        }
+        None => None,
+    };
+
+    // The server loads the GitHub App itself when it starts, so that its
+    // private key is only resolved once.
+    if matches!(
+        args.command,
+        Some(Command::Aggregate { .. } | Command::Trend { .. } | Command::Gc { .. })
+    ) {
+        if let Some(app) = args
+            .github_app
+            .load()
+            .await
+            .context("Authenticating as the GitHub App")?
+        {
+            github_app::init(app)?;
+        }
+    }
+
+    match &args.command {
+        Some(Command::Aggregate { run_id }) => {
+            let report = aggregate::aggregate(run_id.clone()).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+        Some(Command::Trend { runs }) => {
+            let report = aggregate::trend(*runs).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+        Some(Command::Enqueue {
+            server,
+            force,
+            pending_file,
+            closure,
+            store_paths,
+        }) => {
+            let mut store_paths = store_paths.clone();
+
+            if let Some(pending_file) = pending_file {
+                store_paths.extend(
+                    deferral::read_pending(pending_file)
+                        .await?
+                        .into_iter()
+                        .filter(|path| Path::new(path).exists()),
+                );
+            }
+
+            return pbh::enqueue(server, store_paths, *force, *closure).await;
+        }
+        Some(Command::Inject {
+            container,
+            substituter,
+            token_file,
+        }) => {
+            let token = match token_file {
+                Some(source) => Some(
+                    source
+                        .resolve_string()
+                        .await
+                        .with_context(|| format!("Reading the token from {}", source))?,
+                ),
+                None => None,
+            };
+
+            container::inject(
+                container,
+                substituter.as_deref(),
+                token.as_deref().map(str::trim),
+            )
+            .await?;
+            return Ok(());
+        }
+        Some(Command::Gc { rules, dry_run }) => {
+            let netrc = backend::read_netrc(args.backend_netrc.as_ref()).await?;
+            let report = retention::gc(
+                &args.backend(env::Environment::determine()),
+                netrc.as_deref(),
+                &retention::Policy(rules.clone()),
+                *dry_run,
+            )
+            .await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let environment = env::Environment::determine();
+    tracing::debug!("Running in {}", environment.to_string());
+    args.validate(environment)?;
+
+    let dnixd_uds_socket_dir: &Path = Path::new(&DETERMINATE_STATE_DIR);
+    let dnixd_uds_socket_path = dnixd_uds_socket_dir.join(DETERMINATE_NIXD_SOCKET_NAME);
+    let dnixd_available: Dnixd = dnixd_uds_socket_path.exists().into();
+
+    let nix_conf_path: PathBuf = args.nix_conf.clone();
+
+    // NOTE: we expect this to point to a user nix.conf
+    // we always open/append to it to be able to append the extra-substituter for github-actions cache
+    // but we don't write to it for initializing flakehub_cache unless dnixd is unavailable
+    if let Some(parent) = Path::new(&nix_conf_path).parent() {
+        create_dir_all(parent).with_context(|| "Creating parent directories of nix.conf")?;
+    }
+    let nix_conf = Arc::new(
+        nix_conf::Journal::recover(&nix_conf_path)
+            .with_context(|| "Removing the settings of an earlier run from nix.conf")?,
+    );
+    let _restore_nix_conf = nix_conf::RestoreOnDrop(nix_conf.clone());
+
+    // always enable fallback, first
+    nix_conf
+        .append(["fallback = true".to_owned()])
+        .with_context(|| "Setting fallback in nix.conf")?;
+
+    let flakehub_auth_method: Option<FlakeHubAuthSource> = match (
+        args.flakehub_preference(),
+        &args.flakehub_api_server_netrc,
+        dnixd_available,
+    ) {
+        // User has explicitly pyassed --use-flakehub=disabled, so just straight up don't
+        (CacheTrinary::Disabled, _, _) => {
+            tracing::info!("Disabling FlakeHub cache.");
+            None
+        }
+
+        // User has no preference, did not pass a netrc, and determinate-nixd is not available
+        (CacheTrinary::NoPreference, None, Dnixd::Missing) => None,
+
+        // Use it when determinate-nixd is available, and let the user know what's going on
+        (pref, user_netrc_path, Dnixd::Available) => {
+            if pref == CacheTrinary::NoPreference {
+                tracing::info!("Enabling FlakeHub cache because determinate-nixd is available.");
+            }
+
+            if user_netrc_path.is_some() {
+                tracing::info!("Ignoring the user-specified --flakehub-api-server-netrc, in favor of the determinate-nixd netrc");
+            }
+
+            Some(FlakeHubAuthSource::DeterminateNixd)
+        }
+
+        // When determinate-nixd is not available, but the user specified a netrc
+        (_, Some(path), Dnixd::Missing) => {
+            if path.exists() {
+                Some(FlakeHubAuthSource::Netrc(path.to_owned()))
+            } else {
+                tracing::debug!(path = %path.display(), "User-provided netrc does not exist");
+                None
+            }
+        }
+
+        // User explicitly turned on flakehub cache, but we have no netrc and determinate-nixd is not present
+        (CacheTrinary::Enabled, None, Dnixd::Missing) => {
+            return Err(anyhow!(
+                "--flakehub-api-server-netrc is required when determinate-nixd is unavailable"
+            ));
+        }
+    };
+
+    let mut builder = Server::builder()
+        .listen(args.listen)
+        .environment(environment)
+        .use_gha_cache(args.github_cache_preference())
+        .backend(args.backend(environment))
+        .diff_store(args.diff_store)
+        .closure_flags(args.closure)
+        .strict(args.strict)
+        .closure_archive(args.closure_archive)
+        .elect_uploader(args.elect_uploader)
+        .upload_upstream_paths(args.upload_upstream_paths)
+        .check_upstream_hashes(args.check_upstream_hashes)
+        .pull_through(args.pull_through)
+        .cancel_on_signals(true)
+        .request_timeouts(Timeouts::new(&args.request_timeouts));
+
+    if let Some(min_free_space) = args.min_free_space {
+        builder = builder.min_free_space(min_free_space);
+    }
+
+    if let Some(samples) = args.check_reproducibility {
+        builder = builder.check_reproducibility(samples);
+    }
+
+    if let Some(max_put_size) = args.max_put_size {
+        builder = builder.max_put_size(max_put_size);
+    }
+
+    if let Some(max_upload_size) = args.max_upload_size {
+        builder = builder.max_upload_size(max_upload_size);
+    }
+
+    if let Some(max_nar_size) = args.max_nar_size {
+        builder = builder.max_nar_size(max_nar_size);
+    }
+
+    if let Some(bundle_below) = args.bundle_below {
+        builder = builder.bundle_below(bundle_below);
+    }
+
+    if let Some(max_concurrent_uploads) = args.max_concurrent_uploads {
+        builder = builder.max_concurrent_uploads(max_concurrent_uploads);
+    }
+
+    if let Some(upload_retries) = args.upload_retries {
+        builder = builder.upload_retries(upload_retries);
+    }
+
+    builder = builder.include_fixed_output(args.include_fixed_output);
+
+    if let Some(scan_command) = &args.scan_command {
+        builder = builder.scan_command(scan_command.clone());
+    }
+
+    if let Some(upload_policy) = &args.upload_policy {
+        builder = builder.upload_policy(upload_policy.clone());
+    }
+
+    if let Some(narinfo_index) = &args.narinfo_index {
+        builder = builder.narinfo_index(narinfo_index.clone());
+    }
+
+    if let Some(queue_file) = &args.queue_file {
+        builder = builder.queue_file(queue_file.clone());
+    }
+
+    builder = builder.resume(args.resume);
+
+    builder = builder.upload_order(args.upload_order);
+    builder = builder.upload_built_only(args.upload_built_only);
+    builder = builder.name_filter(args.name_filter.clone());
+
+    if let Some(backend_rate_limit) = args.backend_rate_limit {
+        builder = builder.backend_rate_limit(backend_rate_limit);
+    }
+
+    if let Some(threshold) = args.circuit_breaker_threshold {
+        builder = builder.circuit_breaker(
+            threshold,
+            args.circuit_breaker_duration
+                .unwrap_or(std::time::Duration::from_secs(60)),
+        );
+    }
+
+    for (pattern, backend) in &args.routes {
+        builder = builder.route(pattern.clone(), backend.clone());
+    }
+
+    for backend in &args.extra_backends {
+        builder = builder.extra_backend(backend.clone());
+    }
+
+    for key in &args.trusted_public_keys {
+        builder = builder.trusted_public_key(key);
+    }
+
+    if let Some(namespace) = &args.cache_namespace {
+        builder = builder.cache_namespace(namespace);
+    }
+
+    if let Some(version) = &args.cache_version {
+        builder = builder.cache_version(version);
+    }
+
+    for version in &args.restore_cache_version {
+        builder = builder.restore_cache_version(version);
+    }
+
+    if let Some(key_secret) = key_secret {
+        builder = builder.key_secret(key_secret);
+    }
+
+    if let Some(source) = &args.admin_token_file {
+        let admin_token = source
+            .resolve_string()
+            .await
+            .with_context(|| format!("Reading the admin token from {}", source))?;
+        builder = builder.admin_token(admin_token.trim());
+    }
+
+    if let Some(scoped_listen) = args.scoped_listen {
+        builder = builder.scoped_listen(scoped_listen);
+    }
+
+    builder = builder.container_listen(args.container_listen);
+
+    if args.github_app.app_id.is_some() {
+        builder = builder.github_app(args.github_app.clone());
+    }
+
+    if let Some(max_connections) = args.max_connections {
+        builder = builder.max_connections(max_connections);
+    }
+
+    if let Some(header_read_timeout) = args.header_read_timeout {
+        builder = builder.header_read_timeout(header_read_timeout);
+    }
+
+    if let Some(body_read_timeout) = args.body_read_timeout {
+        builder = builder.body_read_timeout(body_read_timeout);
+    }
+
+    if let Some(statsd_addr) = &args.statsd_addr {
+        builder = builder.statsd(statsd_addr.clone());
+    }
+
+    if args.alerts.webhook.is_some() {
+        builder = builder.alerts(args.alerts.clone());
+    }
+
+    if let Some(temp_dir) = &args.temp_dir {
+        builder = builder.temp_dir(temp_dir.clone());
+    }
+
+    if let Some(audit_log) = &args.audit_log {
+        builder = builder.audit_log(audit_log.clone());
+    }
+
+    for pattern in &args.defer_until_idle {
+        builder = builder.defer_until_idle(pattern.clone());
+    }
+
+    if let Some(pending_file) = &args.pending_file {
+        builder = builder.pending_file(pending_file.clone());
+    }
+
+    if let Some(signing_key_file) = &args.signing_key_file {
+        builder = builder.signing_key_file(signing_key_file.clone());
+    }
+
+    for key in &args.old_public_keys {
+        builder = builder.old_public_key(key);
+    }
+
+    for upstream in &args.upstream {
+        builder = builder.upstream(upstream);
+    }
+
+    if let Some(backend_netrc) = &args.backend_netrc {
+        builder = builder.backend_netrc(backend_netrc.clone());
+    }
+
+    if let Some(auth_method) = &flakehub_auth_method {
+        builder.flakehub = Some(server::FlakeHubConfig {
+            api_server: args.flakehub_api_server.clone(),
+            cache_server: args.flakehub_cache_server.clone(),
+            flake_name: args.flakehub_flake_name.clone(),
+            auth_method: auth_method.clone(),
+        });
+    }
+
+    builder.logfile = guard.logfile;
+
+    let server = builder.start().await?;
+    let state = server.state().clone();
+
+    if let Some(container_addr) = server.container_addr() {
+        container::announce(container_addr);
+    }
+
+    // The settings that make Nix use the cache, which are checked for drift.
+    let mut nix_conf_settings = Vec::new();
+
+    if state.flakehub_state.read().await.is_some() {
+        if let Some(FlakeHubAuthSource::Netrc(ref path)) = flakehub_auth_method {
+            nix_conf_settings.push(nix_conf::Setting::Substituter(format!(
+                "{}?trusted=1",
+                &args.flakehub_cache_server
+            )));
+            nix_conf_settings.push(nix_conf::Setting::NetrcFile(path.clone()));
+        }
+    }
+
+    if state.gha_cache.is_some() {
+        nix_conf_settings.push(nix_conf::Setting::Substituter(format!(
+            "http://{}?trusted=1&compression=zstd&parallel-compression=true&priority=1",
+            server.local_addr()
+        )));
+    }
+
+    nix_conf
+        .append(nix_conf_settings.iter().map(ToString::to_string))
+        .with_context(|| "Writing to nix.conf")?;
+
+    match &args.diagnostic_endpoint {
+        telemetry::DiagnosticSink::Disabled => tracing::info!("Diagnostics disabled."),
+        telemetry::DiagnosticSink::File(path) => {
+            tracing::info!("Writing diagnostics to {}.", path.display())
+        }
+        telemetry::DiagnosticSink::Http(_) => {}
+    }
+
+    if let Some(socket) = &args.daemon_proxy_socket {
+        tracing::info!("Observing Nix daemon connections instead of using a post-build hook.");
+        crate::daemon_proxy::spawn(
+            socket.clone(),
+            args.daemon_proxy_upstream.clone(),
+            state.clone(),
+        )
+        .await?;
+    } else if dnixd_available == Dnixd::Available {
+        tracing::info!("Subscribing to Determinate Nixd build events.");
+        crate::pbh::subscribe_uds_post_build_hook(dnixd_uds_socket_path, state.clone()).await?;
+    } else {
+        tracing::info!("Patching nix.conf to use a post-build-hook.");
+        let hook = crate::pbh::setup_legacy_post_build_hook(&server.local_addr()).await?;
+        let setting = nix_conf::Setting::PostBuildHook(hook);
+        nix_conf
+            .append([setting.to_string()])
+            .with_context(|| "Writing to nix.conf")?;
+        nix_conf_settings.push(setting);
+    }
+
+    server.ready();
+
+    nix_conf::spawn(
+        nix_conf,
+        nix_conf_settings,
+        args.reapply_nix_conf,
+        state.metrics.clone(),
+    );
+
+    // Notify of startup via HTTP
+    if let Some(startup_notification_url) = args.startup_notification_url {
+        tracing::debug!("Startup notification via HTTP POST to {startup_notification_url}");
+
+        let response = reqwest::Client::new()
+            .post(startup_notification_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body("{}")
+            .send()
+            .await;
+        match response {
+            Ok(response) => {
+                if !response.status().is_success() {
+                    Err(anyhow!(
+                        "Startup notification returned an error: {}\n{}",
+                        response.status(),
+                        response
+                            .text()
+                            .await
+                            .unwrap_or_else(|_| "<no response text>".to_owned())
+                    ))?;
+                }
+            }
+            err @ Err(_) => {
+                err.with_context(|| "Startup notification failed")?;
+            }
+        }
+    }
+
+    // Notify of startup by writing "1" to the specified file
+    if let Some(startup_notification_file_path) = args.startup_notification_file {
+        let file_contents: &[u8] = b"1";
+
+        tracing::debug!("Startup notification via file at {startup_notification_file_path:?}");
+
+        if let Some(parent_dir) = startup_notification_file_path.parent() {
+            tokio::fs::create_dir_all(parent_dir)
+                .await
+                .with_context(|| {
+                    format!(
+                        "failed to create parent directory for startup notification file path: {}",
+                        startup_notification_file_path.display()
+                    )
+                })?;
+        }
+        let mut notification_file = File::create(&startup_notification_file_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to create startup notification file to path: {}",
+                    startup_notification_file_path.display()
+                )
+            })?;
+        notification_file
+            .write_all(file_contents)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to write startup notification file to path: {}",
+                    startup_notification_file_path.display()
+                )
+            })?;
+
+        tracing::debug!("Created startup notification file at {startup_notification_file_path:?}");
+    }
+
+    let ret = server.wait().await;
+
+    // Make this job's metrics available to `magic-nix-cache aggregate`.
+    if environment.is_github_actions() && state.gha_cache.is_some() {
+        state.metrics.update_elapsed();
+        if let Err(err) = aggregate::push_snapshot(&state.metrics).await {
+            tracing::warn!("Failed to store the metrics snapshot: {}", err);
+        }
+    }
+
+    // Notify diagnostics endpoint
+    state.metrics.send(&args.diagnostic_endpoint).await;
+
+    ret?;
+
+    Ok(())
+}
+
+/// Run the `magic-nix-cache` command line interface.
+///
+/// When invoked as a Nix post-build hook, this sends the built paths to
+/// the running daemon instead.
+pub async fn run() -> Result<()> {
+    match std::env::var("OUT_PATHS") {
+        Ok(out_paths) => pbh::handle_legacy_post_build_hook(&out_paths).await,
+        Err(_) => main_cli().await,
+    }
+}
+
+pub(crate) fn debug_logfile() -> PathBuf {
+    std::env::temp_dir().join("magic-nix-cache-tracing.log")
+}
+
+struct LogGuard {
+    appender_guard: Option<tracing_appender::non_blocking::WorkerGuard>,
+    logfile: Option<PathBuf>,
+}
+
+fn init_logging() -> Result<LogGuard> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        #[cfg(debug_assertions)]
+        return EnvFilter::new("info")
+            .add_directive(
+                "magic_nix_cache=debug"
+                    .parse()
+                    .expect("failed to parse magix_nix_cache directive"),
+            )
+            .add_directive(
+                "gha_cache=debug"
+                    .parse()
+                    .expect("failed to parse gha_cahce directive"),
+            );
+
+        #[cfg(not(debug_assertions))]
+        return EnvFilter::new("info");
+    });
+
+    let stderr_layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .pretty();
+
+    let (guard, file_layer) = match std::env::var("RUNNER_DEBUG") {
+        Ok(val) if val == "1" => {
+            let logfile = debug_logfile();
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&logfile)?;
+            let (nonblocking, guard) = tracing_appender::non_blocking(file);
+            let file_layer = tracing_subscriber::fmt::layer()
+                .with_writer(nonblocking)
+                .pretty();
+
+            (
+                LogGuard {
+                    appender_guard: Some(guard),
+                    logfile: Some(logfile),
+                },
+                Some(file_layer),
+            )
+        }
+        _ => (
+            LogGuard {
+                appender_guard: None,
+                logfile: None,
+            },
+            None,
+        ),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stderr_layer)
+        .with(file_layer)
+        .init();
+
+    Ok(guard)
+}
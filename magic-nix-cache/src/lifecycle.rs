@@ -0,0 +1,65 @@
+//! The phases of the daemon's life.
+//!
+//! The daemon is configuring while it starts its server and sets up Nix to
+//! use it, serving once Nix does, draining while it waits for the last
+//! uploads after `/api/workflow-finish` or a cancellation, and stopped once
+//! it no longer accepts work. The phase is reported at `/api/info`.
+//!
+//! Phases only ever advance, so a repeated transition, e.g. a second
+//! shutdown request while the first one still waits for the uploads, does
+//! nothing, and callers can wait for the phase that the other one reaches.
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Configuring,
+    Serving,
+    Draining,
+    Stopped,
+}
+
+pub struct Lifecycle {
+    phase: watch::Sender<Phase>,
+}
+
+impl Lifecycle {
+    pub fn new() -> Lifecycle {
+        Lifecycle {
+            phase: watch::Sender::new(Phase::Configuring),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        *self.phase.borrow()
+    }
+
+    /// Advance to `phase`, returning whether this call did, rather than an
+    /// earlier one.
+    pub fn advance(&self, phase: Phase) -> bool {
+        let advanced = self.phase.send_if_modified(|current| {
+            if *current < phase {
+                *current = phase;
+                true
+            } else {
+                false
+            }
+        });
+
+        if advanced {
+            tracing::debug!("The daemon is {:?} now", phase);
+        }
+
+        advanced
+    }
+
+    /// Wait until the daemon has reached `phase`.
+    pub async fn reached(&self, phase: Phase) {
+        let mut receiver = self.phase.subscribe();
+
+        // The sender lives as long as `self`, so this can't fail.
+        let _ = receiver.wait_for(|current| *current >= phase).await;
+    }
+}
@@ -0,0 +1,155 @@
+//! Serving HTTP connections.
+//!
+//! Connections are served with hyper directly rather than `axum::serve`, so
+//! that a misbehaving client process on a shared runner can't exhaust the
+//! file descriptors of the daemon: the number of concurrent connections is
+//! limited, and clients that send headers or request bodies too slowly are
+//! disconnected.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use futures::StreamExt as _;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Semaphore};
+
+use crate::error::Error;
+
+/// Limits on the connections to the daemon.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// How many connections are served at once. Further connections wait
+    /// in the listen backlog.
+    pub max_connections: usize,
+
+    /// How long a client may take to send the headers of a request.
+    pub header_read_timeout: Duration,
+
+    /// How long a client may pause while sending the body of a request.
+    pub body_read_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: 512,
+            header_read_timeout: Duration::from_secs(30),
+            body_read_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Serve `app` on `listener` until `shutdown` completes, then wait for the
+/// open connections to finish their requests.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    limits: Limits,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let connections = Arc::new(Semaphore::new(limits.max_connections));
+    let (shutdown_sender, shutdown_receiver) = watch::channel(());
+
+    tokio::pin!(shutdown);
+
+    loop {
+        if connections.available_permits() == 0 {
+            tracing::warn!(
+                "Serving the maximum of {} connections, waiting for one to close",
+                limits.max_connections
+            );
+        }
+
+        let permit = tokio::select! {
+            permit = connections.clone().acquire_owned() => {
+                permit.expect("the connection semaphore is never closed")
+            }
+            _ = &mut shutdown => break,
+        };
+
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    // E.g. running out of file descriptors, which may resolve itself.
+                    tracing::warn!("Failed to accept a connection: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let service = TowerToHyperService::new(app.clone());
+        let header_read_timeout = limits.header_read_timeout;
+        let mut shutdown_receiver = shutdown_receiver.clone();
+
+        tokio::task::spawn(async move {
+            let _permit = permit;
+
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .header_read_timeout(header_read_timeout);
+
+            let connection = builder.serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown_receiver.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+
+            if let Err(err) = result {
+                tracing::debug!("Connection failed: {}", err);
+            }
+        });
+    }
+
+    drop(shutdown_receiver);
+    shutdown_sender.send_replace(());
+
+    let _ = connections
+        .acquire_many(limits.max_connections as u32)
+        .await;
+
+    Ok(())
+}
+
+/// Fail requests whose body stalls for longer than `timeout`.
+pub async fn limit_body(request: Request, next: Next, timeout: Duration) -> Response {
+    let request = request.map(|body| {
+        Body::from_stream(futures::stream::unfold(
+            Some(body.into_data_stream()),
+            move |stream| async move {
+                let mut stream = stream?;
+
+                match tokio::time::timeout(timeout, stream.next()).await {
+                    Ok(Some(chunk)) => Some((chunk, Some(stream))),
+                    Ok(None) => None,
+                    Err(_) => {
+                        tracing::warn!("Request body stalled for {:?}", timeout);
+                        Some((Err(axum::Error::new(Error::Timeout(timeout))), None))
+                    }
+                }
+            },
+        ))
+    });
+
+    next.run(request).await.into_response()
+}
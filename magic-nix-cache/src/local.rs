@@ -0,0 +1,503 @@
+//! A storage backend in a directory on the runner's disk.
+//!
+//! Self-hosted runners with persistent disks can keep the binary cache
+//! locally with `--local-cache-dir`, which makes restores about as fast as
+//! the disk. Objects are stored as files under their keys. When the files
+//! together exceed `--local-cache-size`, the least recently used ones are
+//! removed; their modification time records when they were last used, so
+//! that the order survives restarts. A narinfo is removed together with its
+//! NAR, and the manifests, bundle catalogs and narinfo indexes are kept.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use opendal::raw::{
+    oio, Access, AccessorInfo, BytesRange, OpRead, OpStat, OpWrite, RpRead, RpStat, RpWrite,
+};
+use opendal::{Buffer, Capability, EntryMode, ErrorKind, Metadata, Scheme};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::error::{Error, Result};
+use crate::keys;
+
+/// The subdirectory with the objects that are being written.
+const INCOMPLETE_DIR: &str = ".incomplete";
+
+/// Incomplete objects older than this were left behind by a daemon that
+/// crashed, and are removed on startup.
+const STALE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Size of the chunks that are read from files.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct LocalBackend {
+    dir: PathBuf,
+    info: Arc<AccessorInfo>,
+    usage: Arc<Mutex<Usage>>,
+
+    /// The number of the next incomplete object.
+    next_incomplete: AtomicU64,
+}
+
+/// The size of the stored objects, and the order in which they were used.
+#[derive(Debug)]
+struct Usage {
+    max_size: u64,
+    total: u64,
+
+    /// The objects, with the tick at which they were last used and their size.
+    entries: HashMap<String, (u64, u64)>,
+
+    /// Keys ordered by the tick at which they were last used.
+    recency: BTreeMap<u64, String>,
+
+    tick: u64,
+}
+
+impl Usage {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+
+        if let Some((used, _)) = self.entries.get_mut(key) {
+            if let Some(key) = self.recency.remove(used) {
+                self.recency.insert(self.tick, key);
+            }
+            *used = self.tick;
+        }
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        self.tick += 1;
+
+        if let Some((used, old_size)) = self.entries.insert(key.clone(), (self.tick, size)) {
+            self.recency.remove(&used);
+            self.total -= old_size;
+        }
+        self.recency.insert(self.tick, key);
+        self.total += size;
+    }
+
+    /// Remove the least recently used objects in `dir` until the rest fit,
+    /// returning their keys.
+    ///
+    /// NARs aren't evicted on their own while there is anything else to
+    /// evict, but together with the narinfo that refers to them, so that no
+    /// narinfo is left whose NAR is gone. Only NARs that no narinfo refers
+    /// to any more are evicted after that.
+    fn evict(&mut self, dir: &Path) -> Vec<String> {
+        let mut excess = self.total.saturating_sub(self.max_size);
+        let mut evicted = Vec::new();
+        let mut seen = HashSet::new();
+        let nars = keys::nars();
+
+        for evicting_nars in [false, true] {
+            for key in self.recency.values() {
+                if excess == 0 {
+                    break;
+                }
+
+                if is_pinned(key) || key.starts_with(&nars) != evicting_nars {
+                    continue;
+                }
+
+                let nar = key
+                    .ends_with(".narinfo")
+                    .then(|| nar_of(dir, key))
+                    .flatten();
+
+                for key in std::iter::once(key.clone()).chain(nar) {
+                    if let Some((_, size)) = self.entries.get(&key) {
+                        if seen.insert(key.clone()) {
+                            excess = excess.saturating_sub(*size);
+                            evicted.push(key);
+                        }
+                    }
+                }
+            }
+        }
+
+        for key in &evicted {
+            if let Some((used, size)) = self.entries.remove(key) {
+                self.recency.remove(&used);
+                self.total -= size;
+            }
+        }
+
+        evicted
+    }
+}
+
+/// Whether the object under `key` is never evicted. Manifests, bundle
+/// catalogs and narinfo indexes are small, and describe the whole cache.
+fn is_pinned(key: &str) -> bool {
+    [
+        keys::manifests(),
+        keys::bundle_catalogs(),
+        keys::narinfo_indexes(),
+    ]
+    .iter()
+    .any(|prefix| key.starts_with(prefix.as_str()))
+}
+
+/// The key of the NAR that the narinfo stored in `dir` under `key` refers to.
+fn nar_of(dir: &Path, key: &str) -> Option<String> {
+    let narinfo = std::fs::read_to_string(dir.join(key)).ok()?;
+
+    narinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("URL: "))?
+        .strip_prefix("nar/")
+        .map(keys::nar)
+}
+
+impl LocalBackend {
+    pub fn new(dir: &Path, max_size: u64) -> Result<Self> {
+        let incomplete = dir.join(INCOMPLETE_DIR);
+        std::fs::create_dir_all(&incomplete)
+            .map_err(|e| Error::Io(e, format!("Creating {}", incomplete.display())))?;
+
+        remove_stale(&incomplete);
+
+        // Objects in the order they were last used, for the initial recency.
+        let mut objects = Vec::new();
+        scan(dir, dir, &mut objects)
+            .map_err(|e| Error::Io(e, format!("Listing {}", dir.display())))?;
+        objects.sort_by_key(|(_, _, used)| *used);
+
+        let mut usage = Usage {
+            max_size,
+            total: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        };
+        for (key, size, _) in objects {
+            usage.insert(key, size);
+        }
+
+        tracing::debug!(
+            "The local cache in {} holds {} objects, {} bytes",
+            dir.display(),
+            usage.entries.len(),
+            usage.total
+        );
+
+        let info = AccessorInfo::default();
+        info.set_scheme(Scheme::Custom("local"))
+            .set_root("/")
+            .set_native_capability(Capability {
+                stat: true,
+                read: true,
+                write: true,
+                ..Default::default()
+            });
+
+        let backend = LocalBackend {
+            dir: dir.to_owned(),
+            info: Arc::new(info),
+            usage: Arc::new(Mutex::new(usage)),
+            next_incomplete: AtomicU64::new(0),
+        };
+
+        let evicted = backend.usage.lock().expect("usage lock").evict(dir);
+        remove_evicted(&backend.dir, evicted);
+
+        Ok(backend)
+    }
+
+    /// The file that holds the object at `path`.
+    fn file_path(&self, path: &str) -> opendal::Result<(String, PathBuf)> {
+        let key = path.trim_start_matches('/');
+
+        if key.is_empty()
+            || key.starts_with(INCOMPLETE_DIR)
+            || !Path::new(key)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(opendal::Error::new(
+                ErrorKind::Unexpected,
+                format!("invalid key '{}' for the local cache", key),
+            ));
+        }
+
+        Ok((key.to_owned(), self.dir.join(key)))
+    }
+}
+
+impl Access for LocalBackend {
+    type Reader = FileReader;
+    type Writer = LocalWriter;
+    type Lister = ();
+    type Deleter = ();
+    type BlockingReader = ();
+    type BlockingWriter = ();
+    type BlockingLister = ();
+    type BlockingDeleter = ();
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        self.info.clone()
+    }
+
+    async fn stat(&self, path: &str, _args: OpStat) -> opendal::Result<RpStat> {
+        let (_, file_path) = self.file_path(path)?;
+
+        let metadata = tokio::fs::metadata(&file_path)
+            .await
+            .map_err(|e| io_error("reading the metadata of", &file_path, e))?;
+
+        Ok(RpStat::new(
+            Metadata::new(EntryMode::FILE).with_content_length(metadata.len()),
+        ))
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        let (key, file_path) = self.file_path(path)?;
+
+        let file = tokio::fs::File::open(&file_path)
+            .await
+            .map_err(|e| io_error("opening", &file_path, e))?;
+
+        self.usage.lock().expect("usage lock").touch(&key);
+
+        // Best-effort, since it only affects what is evicted after a restart.
+        if let Ok(clone) = file.try_clone().await {
+            let _ = clone.into_std().await.set_modified(SystemTime::now());
+        }
+
+        Ok((RpRead::new(), FileReader::new(file, args.range()).await?))
+    }
+
+    async fn write(&self, path: &str, _args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        let (key, file_path) = self.file_path(path)?;
+
+        let incomplete = self.dir.join(INCOMPLETE_DIR).join(format!(
+            "{}-{}",
+            std::process::id(),
+            self.next_incomplete.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let file = tokio::fs::File::create(&incomplete)
+            .await
+            .map_err(|e| io_error("creating", &incomplete, e))?;
+
+        Ok((
+            RpWrite::new(),
+            LocalWriter {
+                key,
+                dir: self.dir.clone(),
+                file_path,
+                incomplete,
+                file: Some(file),
+                size: 0,
+                usage: self.usage.clone(),
+            },
+        ))
+    }
+}
+
+/// Reads a range of an object from a file. Shared with the other backends
+/// that download objects to files.
+pub struct FileReader {
+    file: tokio::fs::File,
+
+    /// How many bytes of the range are still to be read, if it doesn't
+    /// extend to the end of the file.
+    remaining: Option<u64>,
+}
+
+impl FileReader {
+    pub async fn new(mut file: tokio::fs::File, range: BytesRange) -> opendal::Result<FileReader> {
+        if range.offset() > 0 {
+            file.seek(SeekFrom::Start(range.offset()))
+                .await
+                .map_err(|e| {
+                    opendal::Error::new(ErrorKind::Unexpected, "seeking in a cached file")
+                        .set_source(e)
+                })?;
+        }
+
+        Ok(FileReader {
+            file,
+            remaining: range.size(),
+        })
+    }
+}
+
+impl oio::Read for FileReader {
+    async fn read(&mut self) -> opendal::Result<Buffer> {
+        let len = match self.remaining {
+            Some(remaining) => remaining.min(READ_CHUNK_SIZE as u64),
+            None => READ_CHUNK_SIZE as u64,
+        };
+        if len == 0 {
+            return Ok(Buffer::new());
+        }
+
+        let mut buf = Vec::with_capacity(len as usize);
+
+        (&mut self.file)
+            .take(len)
+            .read_buf(&mut buf)
+            .await
+            .map_err(|e| {
+                opendal::Error::new(ErrorKind::Unexpected, "reading a cached file").set_source(e)
+            })?;
+
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= buf.len() as u64;
+        }
+
+        Ok(Buffer::from(buf))
+    }
+}
+
+/// Writes an object to an incomplete file, which is moved into place when
+/// it is complete, so that readers never see a partial object.
+pub struct LocalWriter {
+    key: String,
+    dir: PathBuf,
+    file_path: PathBuf,
+    incomplete: PathBuf,
+    file: Option<tokio::fs::File>,
+    size: u64,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl oio::Write for LocalWriter {
+    async fn write(&mut self, bs: Buffer) -> opendal::Result<()> {
+        let file = self.file.as_mut().ok_or_else(|| {
+            opendal::Error::new(ErrorKind::Unexpected, "writing to a closed local writer")
+        })?;
+
+        file.write_all(&bs.to_bytes())
+            .await
+            .map_err(|e| io_error("writing", &self.incomplete, e))?;
+
+        self.size += bs.len() as u64;
+
+        Ok(())
+    }
+
+    async fn close(&mut self) -> opendal::Result<Metadata> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()
+                .await
+                .map_err(|e| io_error("writing", &self.incomplete, e))?;
+        }
+
+        let max_size = self.usage.lock().expect("usage lock").max_size;
+        if self.size > max_size {
+            let _ = tokio::fs::remove_file(&self.incomplete).await;
+
+            return Err(opendal::Error::new(
+                ErrorKind::Unexpected,
+                format!(
+                    "'{}' is {} bytes, more than the local cache holds",
+                    self.key, self.size
+                ),
+            ));
+        }
+
+        if let Some(parent) = self.file_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| io_error("creating", parent, e))?;
+        }
+
+        tokio::fs::rename(&self.incomplete, &self.file_path)
+            .await
+            .map_err(|e| io_error("moving into place", &self.file_path, e))?;
+
+        let evicted = {
+            let mut usage = self.usage.lock().expect("usage lock");
+            usage.insert(self.key.clone(), self.size);
+            usage.evict(&self.dir)
+        };
+        remove_evicted(&self.dir, evicted);
+
+        Ok(Metadata::new(EntryMode::FILE).with_content_length(self.size))
+    }
+
+    async fn abort(&mut self) -> opendal::Result<()> {
+        drop(self.file.take());
+
+        match tokio::fs::remove_file(&self.incomplete).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(io_error("removing", &self.incomplete, err)),
+        }
+    }
+}
+
+/// Collect the key, size and modification time of the objects below `dir`.
+fn scan(
+    root: &Path,
+    dir: &Path,
+    objects: &mut Vec<(String, u64, SystemTime)>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+
+        if metadata.is_dir() {
+            if path != root.join(INCOMPLETE_DIR) {
+                scan(root, &path, objects)?;
+            }
+        } else if let Some(key) = path.strip_prefix(root).ok().and_then(|key| key.to_str()) {
+            objects.push((
+                key.to_owned(),
+                metadata.len(),
+                metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_stale(incomplete: &Path) {
+    let Ok(entries) = std::fs::read_dir(incomplete) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > STALE_AGE);
+
+        if stale {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+fn remove_evicted(dir: &Path, evicted: Vec<String>) {
+    for key in evicted {
+        tracing::debug!("Evicting '{}' from the local cache", key);
+
+        if let Err(err) = std::fs::remove_file(dir.join(&key)) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Failed to evict '{}' from the local cache: {}", key, err);
+            }
+        }
+    }
+}
+
+fn io_error(action: &str, path: &Path, err: std::io::Error) -> opendal::Error {
+    let kind = match err.kind() {
+        std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+        _ => ErrorKind::Unexpected,
+    };
+
+    opendal::Error::new(kind, format!("{} {}", action, path.display())).set_source(err)
+}
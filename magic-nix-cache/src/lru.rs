@@ -0,0 +1,73 @@
+//! A small least-recently-used cache.
+
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+pub struct LruCache<K, V> {
+    capacity: usize,
+
+    /// The entries, together with the tick at which they were last used.
+    entries: HashMap<K, (u64, V)>,
+
+    /// Keys ordered by the tick at which they were last used.
+    recency: BTreeMap<u64, K>,
+
+    tick: u64,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Return a copy of the entry for `key`, marking it as recently used.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let (used, value) = self.entries.get_mut(key)?;
+        if let Some(key) = self.recency.remove(used) {
+            self.recency.insert(tick, key);
+        }
+        *used = tick;
+
+        Some(value.clone())
+    }
+
+    /// Insert an entry, evicting the least recently used one if the cache is
+    /// full. Returns the entry that was replaced or evicted, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        self.tick += 1;
+
+        let removed = if let Some((used, replaced)) = self.entries.remove(&key) {
+            self.recency.remove(&used);
+            Some((key.clone(), replaced))
+        } else if self.entries.len() >= self.capacity {
+            self.recency.pop_first().and_then(|(_, evicted)| {
+                let (_, value) = self.entries.remove(&evicted)?;
+                Some((evicted, value))
+            })
+        } else {
+            None
+        };
+
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, value));
+
+        removed
+    }
+}
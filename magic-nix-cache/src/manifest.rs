@@ -0,0 +1,173 @@
+//! A manifest of the paths a repository has uploaded to the cache.
+//!
+//! The manifest is stored in the cache under a fixed key. It is read when the
+//! daemon starts, so that paths uploaded by earlier workflow runs can be
+//! skipped without asking the backend about each of them, and written back,
+//! merged with any concurrent updates, when it shuts down.
+//!
+//! Each line records the hash of a store path, its NAR hash and when it was
+//! uploaded. Entries expire after [`MAX_AGE`], so that paths that were
+//! evicted from the cache are eventually uploaded again.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opendal::{ErrorKind, Operator};
+
+use crate::audit::AuditLog;
+use crate::{gha, keys};
+
+/// How long an entry is trusted. The GitHub Actions Cache evicts entries that
+/// haven't been accessed for a week.
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+struct Entry {
+    nar_hash: String,
+    uploaded_at: u64,
+}
+
+pub struct Manifest {
+    key: String,
+
+    /// Entries by store path hash.
+    entries: HashMap<String, Entry>,
+
+    /// Whether any entries were added since the manifest was loaded.
+    dirty: bool,
+}
+
+impl Manifest {
+    /// Fetch the manifest of the current repository, or start an empty one
+    /// if it doesn't exist or can't be read.
+    pub async fn load(api: &Operator) -> Manifest {
+        let repository = std::env::var("GITHUB_REPOSITORY").unwrap_or_else(|_| "local".to_owned());
+        let key = keys::manifest(&repository);
+
+        let entries = match read(api, &key).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::warn!("Failed to read the upload manifest '{}': {}", key, err);
+                HashMap::new()
+            }
+        };
+
+        tracing::debug!("Upload manifest lists {} paths", entries.len());
+
+        Manifest {
+            key,
+            entries,
+            dirty: false,
+        }
+    }
+
+    /// Whether the path with `store_path_hash` was uploaded with this NAR hash.
+    pub fn contains(&self, store_path_hash: &str, nar_hash: &str) -> bool {
+        self.entries
+            .get(store_path_hash)
+            .is_some_and(|entry| entry.nar_hash == nar_hash)
+    }
+
+    /// Whether the path with `store_path_hash` was uploaded, whatever its NAR
+    /// hash was.
+    pub fn has_path(&self, store_path_hash: &str) -> bool {
+        self.entries.contains_key(store_path_hash)
+    }
+
+    /// Record that a path was uploaded.
+    pub fn insert(&mut self, store_path_hash: String, nar_hash: String) {
+        self.entries.insert(
+            store_path_hash,
+            Entry {
+                nar_hash,
+                uploaded_at: now(),
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Write the manifest back to the cache, merging in entries that other
+    /// jobs added in the meantime.
+    pub async fn save(&mut self, api: &Operator, audit_log: Option<&AuditLog>) {
+        if !self.dirty {
+            return;
+        }
+
+        let existing = match read(api, &self.key).await {
+            Ok(existing) => existing,
+            Err(err) => {
+                tracing::warn!("Failed to read the upload manifest '{}': {}", self.key, err);
+                HashMap::new()
+            }
+        };
+
+        for (hash, entry) in &existing {
+            match self.entries.get(hash) {
+                Some(ours) if ours.uploaded_at >= entry.uploaded_at => {}
+                _ => {
+                    self.entries.insert(hash.clone(), entry.clone());
+                }
+            }
+        }
+
+        let mut content = String::new();
+        for (hash, entry) in &self.entries {
+            content.push_str(&format!(
+                "{} {} {}\n",
+                hash, entry.nar_hash, entry.uploaded_at
+            ));
+        }
+
+        match gha::replace(api, audit_log, &self.key, !existing.is_empty(), content).await {
+            Ok(_) => {
+                tracing::debug!("Stored an upload manifest of {} paths", self.entries.len());
+                self.dirty = false;
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to store the upload manifest '{}': {}",
+                    self.key,
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Read the unexpired entries of the manifest stored under `key`.
+async fn read(api: &Operator, key: &str) -> opendal::Result<HashMap<String, Entry>> {
+    let content = match api.read(key).await {
+        Ok(content) => content.to_vec(),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err),
+    };
+
+    let oldest = now().saturating_sub(MAX_AGE.as_secs());
+
+    Ok(String::from_utf8_lossy(&content)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let hash = fields.next()?;
+            let nar_hash = fields.next()?;
+            let uploaded_at = fields.next()?.parse().ok()?;
+
+            (uploaded_at >= oldest).then(|| {
+                (
+                    hash.to_owned(),
+                    Entry {
+                        nar_hash: nar_hash.to_owned(),
+                        uploaded_at,
+                    },
+                )
+            })
+        })
+        .collect())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
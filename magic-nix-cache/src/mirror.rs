@@ -0,0 +1,257 @@
+//! Pull-through caching of objects from the upstream cache.
+//!
+//! With `--pull-through`, the daemon fetches the narinfos and NARs that the
+//! cache doesn't have from the upstream cache itself, instead of redirecting
+//! clients there. A NAR is written into the cache while it is streamed to the
+//! client, and once it is complete, so is the narinfo that refers to it, so
+//! that later jobs get the path from the cache instead of from the CDN of the
+//! upstream cache. NARs are only persisted if their narinfo was served
+//! recently, since they would be unreachable without it.
+//!
+//! With `--trusted-public-keys`, narinfos are fetched the same way, so that
+//! their signatures can be checked, see [`crate::signing`].
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt as _;
+use opendal::Operator;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::audit::{self, Action};
+use crate::binary_cache::{NAR_CACHE_CONTROL, NAR_CONTENT_TYPE};
+use crate::error::{Error, Result};
+use crate::upload::Upload;
+use crate::upstream::Upstream;
+use crate::{keys, routing, State};
+
+/// Number of chunks buffered between the upstream download and the client.
+const STREAM_DEPTH: usize = 16;
+
+type Chunk = std::result::Result<Bytes, std::io::Error>;
+
+/// Fetch the narinfo of `store_path_hash` from the upstream cache, or `None`
+/// if it doesn't have it or it isn't signed by a trusted key.
+pub async fn narinfo(
+    state: &State,
+    upstream: &Upstream,
+    store_path_hash: &str,
+) -> Result<Option<Bytes>> {
+    let Some(response) = upstream.narinfo(store_path_hash).await? else {
+        return Ok(None);
+    };
+
+    let content = response.bytes().await?;
+
+    if let Some(trusted_keys) = &state.trusted_keys {
+        if !trusted_keys.verify(&String::from_utf8_lossy(&content)) {
+            tracing::warn!(
+                "Rejecting '{}.narinfo' from the upstream cache, which isn't signed by a trusted key",
+                store_path_hash
+            );
+            state.metrics.narinfos_rejected_unsigned.incr();
+            return Ok(None);
+        }
+    }
+
+    // Remembered so that the NAR can be persisted along with it.
+    state
+        .narinfo_cache
+        .lock()
+        .await
+        .insert(store_path_hash.to_owned(), content.clone());
+    state.metrics.narinfos_pulled_through.incr();
+
+    Ok(Some(content))
+}
+
+/// Stream the NAR at `nar/<path>` from the upstream cache, or `None` if it
+/// doesn't have it, writing it into the cache along with the narinfo of
+/// `store_path_hash` if that is known.
+pub async fn nar(
+    state: &State,
+    upstream: &Upstream,
+    path: &str,
+    store_path_hash: Option<String>,
+) -> Result<Option<Response>> {
+    let Some(response) = upstream.nar(path, store_path_hash.as_deref()).await? else {
+        return Ok(None);
+    };
+
+    let content_length = response.content_length();
+
+    let narinfo = match &store_path_hash {
+        Some(store_path_hash) => state.narinfo_cache.lock().await.get(store_path_hash),
+        None => None,
+    };
+
+    let (tx, rx) = mpsc::channel::<Chunk>(STREAM_DEPTH);
+
+    let target = match (&state.gha_cache, store_path_hash.zip(narinfo)) {
+        (Some(gha_cache), Some((store_path_hash, narinfo))) => {
+            let api = match routing::narinfo_name(&String::from_utf8_lossy(&narinfo)) {
+                Some(name) => gha_cache.api_for(name).clone(),
+                None => (*gha_cache.api).clone(),
+            };
+
+            Some((api, store_path_hash, narinfo))
+        }
+        _ => None,
+    };
+
+    let state = state.clone();
+    let path = path.to_owned();
+
+    tokio::task::spawn(async move {
+        let Some((api, store_path_hash, narinfo)) = target else {
+            forward(response, &tx).await;
+            return;
+        };
+
+        let key = keys::nar(&path);
+
+        match tee(&api, &key, response, content_length, &tx).await {
+            Ok(size) => {
+                if let Err(err) = persist_narinfo(&state, &api, &store_path_hash, narinfo).await {
+                    tracing::warn!("Storing the narinfo of '{}' failed: {}", path, err);
+                    return;
+                }
+
+                state.metrics.nars_pulled_through.incr();
+
+                audit::record(
+                    state.audit_log.as_deref(),
+                    Action::Upload,
+                    audit::Entry {
+                        key: &key,
+                        size: Some(size),
+                        ..Default::default()
+                    },
+                )
+                .await;
+
+                tracing::debug!("Stored '{}' from the upstream cache", path);
+            }
+            Err(err) => {
+                tracing::warn!("Storing '{}' from the upstream cache failed: {}", path, err)
+            }
+        }
+    });
+
+    let mut response = Body::from_stream(ReceiverStream::new(rx)).into_response();
+    let headers = response.headers_mut();
+
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(NAR_CONTENT_TYPE),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(NAR_CACHE_CONTROL),
+    );
+    if let Some(content_length) = content_length {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+    }
+
+    Ok(Some(response))
+}
+
+/// Send the body of `response` to the client.
+async fn forward(response: reqwest::Response, tx: &mpsc::Sender<Chunk>) {
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(std::io::Error::other);
+        let failed = chunk.is_err();
+
+        if tx.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// Send the body of `response` to the client while writing it to `key`,
+/// returning its size. The client going away doesn't stop the write.
+async fn tee(
+    api: &Operator,
+    key: &str,
+    response: reqwest::Response,
+    content_length: Option<u64>,
+    tx: &mpsc::Sender<Chunk>,
+) -> Result<u64> {
+    // Dropping the upload on errors aborts it.
+    let mut upload = Upload::new(api.writer(key).await?, key);
+    let mut stream = response.bytes_stream();
+    let mut size = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                let _ = tx.send(Err(std::io::Error::other(err.to_string()))).await;
+                return Err(err.into());
+            }
+        };
+
+        size += chunk.len() as u64;
+        let _ = tx.send(Ok(chunk.clone())).await;
+
+        if let Err(err) = upload.write(chunk).await {
+            // Finish serving the client without the cache.
+            drop(upload);
+            while let Some(chunk) = stream.next().await {
+                if tx.send(chunk.map_err(std::io::Error::other)).await.is_err() {
+                    break;
+                }
+            }
+            return Err(err);
+        }
+    }
+
+    // Don't persist a truncated download.
+    if content_length.is_some_and(|content_length| content_length != size) {
+        return Err(Error::Internal(format!(
+            "the upstream cache sent {} bytes instead of {:?}",
+            size, content_length
+        )));
+    }
+
+    upload.close().await?;
+
+    Ok(size)
+}
+
+/// Write the narinfo of a NAR that was just stored.
+async fn persist_narinfo(
+    state: &State,
+    api: &Operator,
+    store_path_hash: &str,
+    narinfo: Bytes,
+) -> Result<()> {
+    let key = keys::narinfo(store_path_hash);
+    let size = narinfo.len() as u64;
+
+    api.write(&key, narinfo).await?;
+
+    audit::record(
+        state.audit_log.as_deref(),
+        Action::Upload,
+        audit::Entry {
+            key: &key,
+            size: Some(size),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    state
+        .narinfo_negative_cache
+        .write()
+        .await
+        .remove(store_path_hash);
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! The netrc that Nix needs to substitute next to the daemon.
+//!
+//! Nix substitutes from the FlakeHub cache directly, and fetches from the
+//! upstream caches that the daemon redirects it to, so it needs their
+//! credentials too. `GET /api/netrc` renders them as a netrc, so that the
+//! wrapper action and containers don't have to template one: the entry of
+//! the FlakeHub cache server, from the FlakeHub netrc as it is refreshed, and
+//! the entries of the upstream caches in `--backend-netrc`. It reveals
+//! credentials, so it is only served to requests carrying the admin token.
+
+use std::fmt;
+
+use reqwest::Url;
+
+use crate::backend::netrc_credentials;
+use crate::error::Result;
+
+/// The credentials of one host.
+#[derive(Clone)]
+pub struct Entry {
+    pub host: String,
+    pub login: String,
+    pub password: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "machine {} login {} password {}",
+            self.host, self.login, self.password
+        )
+    }
+}
+
+/// The entries of `netrc` for the hosts of the upstream caches at `urls`.
+pub fn upstream_entries(netrc: Option<&str>, urls: &[String]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::<Entry>::new();
+
+    for url in urls {
+        let Ok(url) = Url::parse(url) else {
+            continue;
+        };
+
+        let Some(host) = url.host_str() else {
+            continue;
+        };
+
+        if entries.iter().any(|entry| entry.host == host) {
+            continue;
+        }
+
+        if let Some((login, password)) = netrc_credentials(netrc, &url)? {
+            entries.push(Entry {
+                host: host.to_owned(),
+                login,
+                password,
+            });
+        }
+    }
+
+    Ok(entries)
+}
@@ -0,0 +1,389 @@
+//! Detecting changes to the Nix configuration that bypass the cache.
+//!
+//! The daemon appends its substituters and post-build hook to `nix.conf` on
+//! startup. A later step that rewrites `nix.conf`, e.g. another action that
+//! installs or configures Nix, drops them, and the rest of the job neither
+//! substitutes from the cache nor uploads to it, without any error. The
+//! daemon periodically checks that its settings are still in effect,
+//! according to `nix show-config`, and warns when they aren't. With
+//! `--reapply-nix-conf`, it also appends the missing ones to `nix.conf` again.
+//!
+//! The lines that the daemon appends are recorded in a journal next to
+//! `nix.conf`, and removed again when it exits. If a daemon dies without
+//! removing them, e.g. because it was killed, the next one removes them on
+//! startup, so that Nix doesn't keep using a cache and a post-build hook that
+//! are gone.
+
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+use crate::telemetry::TelemetryReport;
+
+/// How often the effective configuration is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A setting that the daemon added to `nix.conf`.
+pub enum Setting {
+    Substituter(String),
+    NetrcFile(PathBuf),
+    PostBuildHook(PathBuf),
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Setting::Substituter(url) => write!(f, "extra-substituters = {}", url),
+            Setting::NetrcFile(path) => write!(f, "netrc-file = {}", path.display()),
+            Setting::PostBuildHook(path) => write!(f, "post-build-hook = {}", path.display()),
+        }
+    }
+}
+
+impl Setting {
+    /// Whether the setting is in effect in `config`, as printed by
+    /// `nix show-config --json`.
+    fn is_effective(&self, config: &serde_json::Value) -> bool {
+        let value = |name: &str| config.get(name).and_then(|setting| setting.get("value"));
+        let is_path = |name: &str, path: &Path| {
+            value(name).and_then(serde_json::Value::as_str) == path.to_str()
+        };
+
+        match self {
+            Setting::Substituter(url) => value("substituters")
+                .and_then(serde_json::Value::as_array)
+                .is_some_and(|urls| urls.iter().any(|u| u.as_str() == Some(url))),
+            Setting::NetrcFile(path) => is_path("netrc-file", path),
+            Setting::PostBuildHook(path) => is_path("post-build-hook", path),
+        }
+    }
+}
+
+/// The lines that this daemon appended to `nix.conf`.
+pub struct Journal {
+    nix_conf: PathBuf,
+
+    /// Where the lines are recorded.
+    path: PathBuf,
+
+    /// The appended lines, or `None` once they are removed again.
+    lines: Mutex<Option<Vec<String>>>,
+}
+
+/// The content of a journal.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    pid: u32,
+    lines: Vec<String>,
+}
+
+impl Journal {
+    /// Remove the lines that daemons which are no longer running left in
+    /// `nix_conf`, and start the journal of this one.
+    pub fn recover(nix_conf: &Path) -> Result<Journal> {
+        for (path, record) in journals(nix_conf) {
+            if record.pid != std::process::id() && is_running(record.pid) {
+                continue;
+            }
+
+            remove_lines(nix_conf, &record.lines)?;
+            remove_journal(&path)?;
+
+            tracing::warn!(
+                "Removed the settings that an earlier run, which didn't shut down, left in {}",
+                nix_conf.display()
+            );
+        }
+
+        Ok(Journal {
+            nix_conf: nix_conf.to_owned(),
+            path: journal_path(nix_conf, std::process::id()),
+            lines: Mutex::new(Some(Vec::new())),
+        })
+    }
+
+    pub fn nix_conf(&self) -> &Path {
+        &self.nix_conf
+    }
+
+    /// Whether the lines were removed from `nix.conf` again, after which
+    /// nothing is appended anymore.
+    pub fn is_restored(&self) -> bool {
+        self.lines.lock().expect("nix.conf journal lock").is_none()
+    }
+
+    /// Append `lines` to `nix.conf`.
+    pub fn append(&self, lines: impl IntoIterator<Item = String>) -> Result<()> {
+        let lines = lines.into_iter().collect::<Vec<_>>();
+        let mut recorded = self.lines.lock().expect("nix.conf journal lock");
+        let Some(recorded) = recorded.as_mut() else {
+            return Ok(());
+        };
+        recorded.extend(lines.iter().cloned());
+
+        // Record the lines first, so that a daemon that dies while appending
+        // them doesn't leave them behind.
+        let record = Record {
+            pid: std::process::id(),
+            lines: recorded.clone(),
+        };
+        let content = serde_json::to_vec(&record)
+            .map_err(|e| Error::Internal(format!("Serializing the nix.conf journal: {}", e)))?;
+        std::fs::write(&self.path, content)
+            .map_err(|e| Error::Io(e, format!("Writing {}", self.path.display())))?;
+
+        let mut contents = String::new();
+        for line in &lines {
+            contents.push_str(&format!("{}\n", line));
+        }
+
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.nix_conf)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .map_err(|e| Error::Io(e, format!("Appending to {}", self.nix_conf.display())))
+    }
+
+    /// Remove the lines that this daemon appended to `nix.conf`. Repeated
+    /// calls do nothing.
+    pub fn restore(&self) -> Result<()> {
+        let mut lines = self.lines.lock().expect("nix.conf journal lock");
+
+        let Some(recorded) = lines.as_ref() else {
+            return Ok(());
+        };
+
+        remove_lines(&self.nix_conf, recorded)?;
+        remove_journal(&self.path)?;
+        *lines = None;
+
+        tracing::debug!(
+            "Removed the settings for the cache from {}",
+            self.nix_conf.display()
+        );
+
+        Ok(())
+    }
+}
+
+/// Restores `nix.conf` when dropped, however the daemon exits.
+pub struct RestoreOnDrop(pub Arc<Journal>);
+
+impl Drop for RestoreOnDrop {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.restore() {
+            tracing::warn!("Failed to restore {}: {}", self.0.nix_conf.display(), err);
+        }
+    }
+}
+
+/// Where the daemon with `pid` records the lines it appends to `nix_conf`.
+fn journal_path(nix_conf: &Path, pid: u32) -> PathBuf {
+    let mut file_name = nix_conf.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(".magic-nix-cache.{}", pid));
+    nix_conf.with_file_name(file_name)
+}
+
+/// The journals next to `nix_conf`.
+fn journals(nix_conf: &Path) -> Vec<(PathBuf, Record)> {
+    let Some(dir) = nix_conf.parent() else {
+        return Vec::new();
+    };
+
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let prefix = format!(
+        "{}.magic-nix-cache.",
+        nix_conf.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .filter_map(|entry| {
+            let path = entry.path();
+            let record = std::fs::read(&path)
+                .ok()
+                .and_then(|content| serde_json::from_slice::<Record>(&content).ok());
+
+            match record {
+                Some(record) => Some((path, record)),
+                None => {
+                    tracing::debug!("Ignoring the unreadable journal {}", path.display());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Remove the last occurrence of each of `lines` from `nix_conf`, leaving
+/// the lines that others wrote alone.
+fn remove_lines(nix_conf: &Path, lines: &[String]) -> Result<()> {
+    let contents = match std::fs::read_to_string(nix_conf) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(Error::Io(err, format!("Reading {}", nix_conf.display()))),
+    };
+
+    let mut kept = contents.lines().collect::<Vec<_>>();
+    let before = kept.len();
+
+    for line in lines {
+        if let Some(i) = kept.iter().rposition(|kept| kept.trim() == line.trim()) {
+            kept.remove(i);
+        }
+    }
+
+    if kept.len() == before {
+        return Ok(());
+    }
+
+    let mut contents = kept.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+
+    std::fs::write(nix_conf, contents)
+        .map_err(|e| Error::Io(e, format!("Writing {}", nix_conf.display())))
+}
+
+fn remove_journal(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(Error::Io(err, format!("Removing {}", path.display()))),
+    }
+}
+
+/// Whether the process with `pid` is still running.
+fn is_running(pid: u32) -> bool {
+    if Path::new("/proc/self").exists() {
+        return Path::new("/proc").join(pid.to_string()).exists();
+    }
+
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Spawn a task that checks that `settings` are still in effect, and
+/// appends them to `nix.conf` again if `reapply` is set.
+pub fn spawn(
+    journal: Arc<Journal>,
+    settings: Vec<Setting>,
+    reapply: bool,
+    metrics: Arc<TelemetryReport>,
+) {
+    if settings.is_empty() {
+        return;
+    }
+
+    tokio::task::spawn(async move {
+        // Only warn once until the settings are back in effect.
+        let mut drifted = false;
+
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+
+            // The daemon is exiting.
+            if journal.is_restored() {
+                break;
+            }
+
+            match check(&journal, &settings, reapply, drifted, &metrics).await {
+                Ok(now_drifted) => drifted = now_drifted,
+                Err(err) => tracing::debug!("Checking the Nix configuration failed: {}", err),
+            }
+        }
+    });
+}
+
+/// Check the settings, returning whether some are still missing.
+async fn check(
+    journal: &Journal,
+    settings: &[Setting],
+    reapply: bool,
+    drifted: bool,
+    metrics: &TelemetryReport,
+) -> Result<bool> {
+    let nix_conf = journal.nix_conf();
+    let config = effective_config().await?;
+
+    let missing = settings
+        .iter()
+        .filter(|setting| !setting.is_effective(&config))
+        .collect::<Vec<_>>();
+
+    if missing.is_empty() {
+        if drifted {
+            tracing::info!("The Nix configuration uses the cache again");
+        }
+
+        return Ok(false);
+    }
+
+    if !drifted {
+        metrics.nix_conf_drifts.incr();
+
+        let missing = missing
+            .iter()
+            .map(|setting| format!("'{}'", setting))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        tracing::warn!(
+            "The Nix configuration no longer has {}, probably because another step rewrote {}, so Nix bypasses the cache",
+            missing,
+            nix_conf.display()
+        );
+    }
+
+    if !reapply {
+        return Ok(true);
+    }
+
+    journal.append(missing.iter().map(ToString::to_string))?;
+
+    tracing::info!(
+        "Appended the missing settings to {} again",
+        nix_conf.display()
+    );
+
+    Ok(false)
+}
+
+/// The effective Nix configuration, as JSON.
+async fn effective_config() -> Result<serde_json::Value> {
+    let output = Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command"])
+        .args(["show-config", "--json"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix show-config".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Internal(format!(
+            "nix show-config failed: {}",
+            output.status
+        )));
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::Internal(format!("bad output of nix show-config: {}", e)))
+}
@@ -15,6 +15,7 @@ use tempfile::NamedTempFile;
 use tokio::net::UnixStream;
 use tokio::process::Command;
 
+use crate::gha::ClosureFlags;
 use crate::BuiltPathResponseEventV1;
 use crate::State;
 
@@ -107,7 +108,7 @@ pub async fn subscribe_uds_post_build_hook(
                 };
 
                 tracing::debug!("about to enqueue paths: {:?}", store_paths);
-                if let Err(e) = crate::api::enqueue_paths(&state, store_paths).await {
+                if let Err(e) = crate::api::enqueue_paths(&state, store_paths, false).await {
                     tracing::error!(
                         "built-paths: failed to enqueue paths for drv ({}): {}",
                         event.drv.display(),
@@ -122,10 +123,9 @@ pub async fn subscribe_uds_post_build_hook(
     Ok(())
 }
 
-pub async fn setup_legacy_post_build_hook(
-    listen: &SocketAddr,
-    nix_conf: &mut std::fs::File,
-) -> Result<()> {
+/// Write the post-build hook for the daemon on `listen`, returning its path
+/// for `nix.conf`.
+pub async fn setup_legacy_post_build_hook(listen: &SocketAddr) -> Result<PathBuf> {
     /* Write the post-build hook script. Note that the shell script
      * ignores errors, to avoid the Nix build from failing. */
     let post_build_hook_script = {
@@ -184,12 +184,7 @@ pub async fn setup_legacy_post_build_hook(
         }
     };
 
-    /* Update nix.conf. */
-    nix_conf
-        .write_all(format!("post-build-hook = {}\n", post_build_hook_script.display()).as_bytes())
-        .with_context(|| "Writing to nix.conf")?;
-
-    Ok(())
+    Ok(post_build_hook_script)
 }
 
 pub async fn handle_legacy_post_build_hook(out_paths: &str) -> Result<()> {
@@ -207,10 +202,24 @@ pub async fn handle_legacy_post_build_hook(out_paths: &str) -> Result<()> {
         .map(|s| s.trim().to_owned())
         .collect();
 
-    let request = crate::api::EnqueuePathsRequest { store_paths };
+    enqueue(&args.server, store_paths, false).await
+}
+
+/// Ask the daemon at `server` to upload `store_paths`.
+pub async fn enqueue(
+    server: &SocketAddr,
+    store_paths: Vec<String>,
+    force: bool,
+    closure: ClosureFlags,
+) -> Result<()> {
+    let request = crate::api::EnqueuePathsRequest {
+        store_paths,
+        force,
+        closure,
+    };
 
     let response = reqwest::Client::new()
-        .post(format!("http://{}/api/enqueue-paths", &args.server))
+        .post(format!("http://{}/api/enqueue-paths", server))
         .header(reqwest::header::CONTENT_TYPE, "application/json")
         .body(
             serde_json::to_string(&request)
@@ -0,0 +1,268 @@
+//! Upload decisions by rules.
+//!
+//! `--upload-policy <file>` reads rules that decide for each path whether it
+//! is uploaded, skipped or stored in another backend, instead of combining
+//! flags. Each line holds an action, optionally followed by `when` and
+//! conditions joined with `and`, and the first rule whose conditions all hold
+//! decides:
+//!
+//! ```text
+//! # Documentation goes to a cheap cold store.
+//! route webdav:https://dav.example.com/cold when name ~ -(doc|man)$
+//! skip when size > 2G
+//! skip when branch !~ ^(main|release-.*)$ and system = aarch64-darwin
+//! upload when name ~ -source$
+//! ```
+//!
+//! The actions are `upload`, `skip` and `route <backend>`. Conditions compare
+//! the `name`, `system`, `deriver` or `branch` of a path with `=` or `!=`, or
+//! match them against a regex with `~` or `!~`, and compare its NAR `size`
+//! with `<`, `<=`, `>` or `>=`. Values are single words, which store path
+//! names always are. A condition on a system or deriver that isn't known,
+//! e.g. because the derivation isn't in the store, doesn't hold.
+//!
+//! A path that a rule uploads or routes is uploaded whatever `--max-nar-size`
+//! and `--include-fixed-output` say, which only apply to the paths that no
+//! rule matches.
+
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::backend::Backend;
+use crate::error::{Error, Result};
+use crate::util;
+
+/// What happens to a path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Upload,
+    Skip,
+
+    /// Store the path in the backend with this index in [`Policy::backends`].
+    Route(usize),
+}
+
+struct Rule {
+    action: Action,
+    conditions: Vec<Condition>,
+
+    /// The rule as written, for logging.
+    text: String,
+}
+
+enum Field {
+    Name,
+    System,
+    Deriver,
+    Branch,
+}
+
+enum Condition {
+    Equals(Field, String, bool),
+    Matches(Field, Regex, bool),
+    Size(SizeComparison, u64),
+}
+
+enum SizeComparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// What the rules are evaluated against.
+pub struct Subject<'a> {
+    pub name: &'a str,
+    pub nar_size: u64,
+    pub system: Option<&'a str>,
+    pub deriver: Option<&'a str>,
+}
+
+pub struct Policy {
+    rules: Vec<Rule>,
+
+    /// The backends that rules route paths to.
+    backends: Vec<Backend>,
+
+    branch: Option<String>,
+}
+
+impl Policy {
+    /// Read the rules in `path`.
+    pub fn load(path: &Path) -> Result<Policy> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Io(e, format!("Reading the upload policy {}", path.display())))?;
+
+        Policy::parse(&text)
+            .map_err(|e| Error::Config(format!("Bad upload policy {}: {}", path.display(), e)))
+    }
+
+    fn parse(text: &str) -> std::result::Result<Policy, String> {
+        let mut policy = Policy {
+            rules: Vec::new(),
+            backends: Vec::new(),
+            branch: std::env::var("GITHUB_HEAD_REF")
+                .ok()
+                .filter(|branch| !branch.is_empty())
+                .or_else(|| std::env::var("GITHUB_REF_NAME").ok()),
+        };
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let rule = policy
+                .parse_rule(line)
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+            policy.rules.push(rule);
+        }
+
+        Ok(policy)
+    }
+
+    fn parse_rule(&mut self, line: &str) -> std::result::Result<Rule, String> {
+        let mut words = line.split_whitespace();
+
+        let action = match words.next() {
+            Some("upload") => Action::Upload,
+            Some("skip") => Action::Skip,
+            Some("route") => {
+                let backend = words
+                    .next()
+                    .ok_or("expected a backend after 'route'")?
+                    .parse::<Backend>()
+                    .map_err(|e| e.to_string())?;
+                self.backends.push(backend);
+                Action::Route(self.backends.len() - 1)
+            }
+            Some(action) => {
+                return Err(format!(
+                    "unknown action '{}', expected 'upload', 'skip' or 'route'",
+                    action
+                ))
+            }
+            None => unreachable!("empty lines are skipped"),
+        };
+
+        let mut conditions = Vec::new();
+
+        match words.next() {
+            None => {}
+            Some("when") => loop {
+                let (Some(field), Some(operator), Some(value)) =
+                    (words.next(), words.next(), words.next())
+                else {
+                    return Err("expected '<field> <operator> <value>'".to_owned());
+                };
+
+                conditions.push(parse_condition(field, operator, value)?);
+
+                match words.next() {
+                    None => break,
+                    Some("and") => {}
+                    Some(word) => return Err(format!("expected 'and', found '{}'", word)),
+                }
+            },
+            Some(word) => return Err(format!("expected 'when', found '{}'", word)),
+        }
+
+        Ok(Rule {
+            action,
+            conditions,
+            text: line.to_owned(),
+        })
+    }
+
+    /// The backends that rules route paths to.
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+
+    /// Whether the rules look at the derivation of paths, which takes
+    /// querying the store.
+    pub fn needs_derivation(&self) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.conditions.iter().any(|condition| match condition {
+                Condition::Equals(field, ..) | Condition::Matches(field, ..) => {
+                    matches!(field, Field::System | Field::Deriver)
+                }
+                Condition::Size(..) => false,
+            })
+        })
+    }
+
+    /// The action of the first rule that matches `subject`, if one does.
+    pub fn decide(&self, subject: &Subject) -> Option<Action> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.conditions.iter().all(|c| self.holds(c, subject)))?;
+
+        tracing::debug!("'{}' matches the rule '{}'", subject.name, rule.text);
+
+        Some(rule.action)
+    }
+
+    fn holds(&self, condition: &Condition, subject: &Subject) -> bool {
+        let value = |field: &Field| match field {
+            Field::Name => Some(subject.name),
+            Field::System => subject.system,
+            Field::Deriver => subject.deriver,
+            Field::Branch => self.branch.as_deref(),
+        };
+
+        match condition {
+            Condition::Equals(field, expected, negated) => {
+                value(field).is_some_and(|value| (value == expected) != *negated)
+            }
+            Condition::Matches(field, pattern, negated) => {
+                value(field).is_some_and(|value| pattern.is_match(value) != *negated)
+            }
+            Condition::Size(comparison, size) => match comparison {
+                SizeComparison::Less => subject.nar_size < *size,
+                SizeComparison::LessOrEqual => subject.nar_size <= *size,
+                SizeComparison::Greater => subject.nar_size > *size,
+                SizeComparison::GreaterOrEqual => subject.nar_size >= *size,
+            },
+        }
+    }
+}
+
+fn parse_condition(
+    field: &str,
+    operator: &str,
+    value: &str,
+) -> std::result::Result<Condition, String> {
+    if field == "size" {
+        let comparison = match operator {
+            "<" => SizeComparison::Less,
+            "<=" => SizeComparison::LessOrEqual,
+            ">" => SizeComparison::Greater,
+            ">=" => SizeComparison::GreaterOrEqual,
+            _ => return Err(format!("'size' can't be compared with '{}'", operator)),
+        };
+
+        return Ok(Condition::Size(comparison, util::parse_size(value)?));
+    }
+
+    let field = match field {
+        "name" => Field::Name,
+        "system" => Field::System,
+        "deriver" => Field::Deriver,
+        "branch" => Field::Branch,
+        _ => return Err(format!("unknown field '{}'", field)),
+    };
+
+    let regex = || Regex::new(value).map_err(|e| format!("bad regex '{}': {}", value, e));
+
+    match operator {
+        "=" => Ok(Condition::Equals(field, value.to_owned(), false)),
+        "!=" => Ok(Condition::Equals(field, value.to_owned(), true)),
+        "~" => Ok(Condition::Matches(field, regex()?, false)),
+        "!~" => Ok(Condition::Matches(field, regex()?, true)),
+        _ => Err(format!("unknown operator '{}'", operator)),
+    }
+}
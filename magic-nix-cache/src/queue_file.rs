@@ -0,0 +1,168 @@
+//! Persisting the upload queue across daemons.
+//!
+//! A daemon that is killed, e.g. because the job was cancelled, loses the
+//! paths that were still waiting to be uploaded. With `--queue-file <file>`,
+//! the daemon keeps them in that file, one store path per line, rewritten
+//! every few seconds while the queue changes. The next daemon started with
+//! `--resume` and the same file, e.g. in the next job of the workflow on a
+//! self-hosted runner, enqueues the paths that are still in its store. The
+//! file has the format of the pending file of `--defer-until-idle`, so it can
+//! also be passed to `enqueue --pending-file`.
+//!
+//! Once the job is cancelled, paths stay in the file even if they are
+//! skipped or uploaded, since the daemon may be killed at any moment.
+//! Uploading them again is cheap, as they are then found in the manifest.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cancellation::Cancellation;
+use crate::error::{Error, Result};
+
+/// How often the file is rewritten while the queue changes.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct QueueFile {
+    path: PathBuf,
+
+    /// How often each queued path was enqueued and not yet dequeued.
+    paths: Mutex<HashMap<PathBuf, usize>>,
+
+    /// Whether the queue changed since the file was written.
+    dirty: AtomicBool,
+
+    cancellation: Arc<Cancellation>,
+}
+
+impl QueueFile {
+    /// Keep the queue in the file at `path`, rewriting it periodically.
+    pub fn spawn(path: PathBuf, cancellation: Arc<Cancellation>) -> Arc<QueueFile> {
+        let queue_file = Arc::new(QueueFile {
+            path,
+            paths: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+            cancellation,
+        });
+
+        let weak = Arc::downgrade(&queue_file);
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(FLUSH_INTERVAL).await;
+
+                let Some(queue_file) = weak.upgrade() else {
+                    break;
+                };
+                queue_file.flush().await;
+            }
+        });
+
+        queue_file
+    }
+
+    /// Record that the store paths at `full_paths` were enqueued.
+    pub fn add(&self, full_paths: impl IntoIterator<Item = PathBuf>) {
+        let mut paths = self.paths.lock().expect("queue file lock");
+        for full_path in full_paths {
+            *paths.entry(full_path).or_default() += 1;
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Dequeue the store path at `full_path` once the returned guard is
+    /// dropped, i.e. once the worker is done with it.
+    pub fn dequeue_on_drop(self: &Arc<Self>, full_path: PathBuf) -> Dequeue {
+        Dequeue {
+            queue_file: self.clone(),
+            full_path,
+            keep: false,
+        }
+    }
+
+    fn remove(&self, full_path: &Path) {
+        // The daemon may be killed at any moment now.
+        if self.cancellation.is_cancelled() {
+            return;
+        }
+
+        let mut paths = self.paths.lock().expect("queue file lock");
+        if let Some(count) = paths.get_mut(full_path) {
+            *count -= 1;
+            if *count == 0 {
+                paths.remove(full_path);
+            }
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Rewrite the file if the queue changed.
+    pub async fn flush(&self) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let mut content = Vec::new();
+        for full_path in self.paths.lock().expect("queue file lock").keys() {
+            content.extend_from_slice(full_path.as_os_str().as_encoded_bytes());
+            content.push(b'\n');
+        }
+
+        // Replace the file at once, so that a daemon killed while writing it
+        // doesn't leave half of it.
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+
+        let result = async {
+            tokio::fs::write(&temp_path, &content).await?;
+            tokio::fs::rename(&temp_path, &self.path).await
+        }
+        .await;
+
+        if let Err(err) = result {
+            self.dirty.store(true, Ordering::Relaxed);
+            tracing::warn!(
+                "Failed to write the queue file {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Dequeues a path from the queue file when dropped.
+pub struct Dequeue {
+    queue_file: Arc<QueueFile>,
+    full_path: PathBuf,
+    keep: bool,
+}
+
+impl Dequeue {
+    /// Keep the path in the queue file, e.g. because uploading it failed in
+    /// a way that the next daemon may not run into.
+    pub fn keep(mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for Dequeue {
+    fn drop(&mut self) {
+        if !self.keep {
+            self.queue_file.remove(&self.full_path);
+        }
+    }
+}
+
+/// Read the paths that the daemon that used the queue file at `path` didn't
+/// upload, if it exists.
+pub async fn read(path: &Path) -> Result<Vec<String>> {
+    match tokio::fs::try_exists(path).await {
+        Ok(true) => crate::deferral::read_pending(path).await,
+        Ok(false) => Ok(Vec::new()),
+        Err(e) => Err(Error::Io(
+            e,
+            format!("Reading the queue file {}", path.display()),
+        )),
+    }
+}
@@ -0,0 +1,126 @@
+//! A quota on the bytes uploaded to the daemon.
+//!
+//! With `--max-put-size`, the bodies of all `PUT` requests together may be at
+//! most that many bytes, so that e.g. a runaway `nix copy --all` pointed at
+//! the daemon can't use up the GitHub Actions Cache quota. Requests that
+//! would exceed it are answered with a 413, up front if they have a
+//! `Content-Length`, or as soon as the quota runs out while reading them.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Extension, Request},
+    http::{header, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt as _;
+
+use crate::error::Error;
+use crate::State;
+
+#[derive(Debug)]
+pub struct PutQuota {
+    limit: u64,
+
+    /// The bytes accepted so far.
+    accepted: AtomicU64,
+}
+
+impl PutQuota {
+    pub fn new(limit: u64) -> PutQuota {
+        PutQuota {
+            limit,
+            accepted: AtomicU64::new(0),
+        }
+    }
+
+    /// Account for `len` more bytes, returning whether they fit in the quota.
+    /// Bytes that don't fit aren't counted, so a rejected upload leaves the
+    /// rest of the quota to the others.
+    fn accept(&self, len: u64) -> bool {
+        self.accepted
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |accepted| {
+                accepted
+                    .checked_add(len)
+                    .filter(|accepted| *accepted <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn remaining(&self) -> u64 {
+        self.limit
+            .saturating_sub(self.accepted.load(Ordering::Relaxed))
+    }
+}
+
+pub async fn middleware(
+    Extension(state): Extension<State>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(quota) = state
+        .put_quota
+        .as_ref()
+        .filter(|_| request.method() == Method::PUT)
+    else {
+        return next.run(request).await;
+    };
+
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if content_length.is_some_and(|content_length| content_length > quota.remaining()) {
+        return reject(&state, request.uri().path());
+    }
+
+    let exceeded = Arc::new(AtomicBool::new(false));
+
+    let request = {
+        let state = state.clone();
+        let exceeded = exceeded.clone();
+
+        request.map(|body| {
+            Body::from_stream(body.into_data_stream().map(move |chunk| {
+                let chunk = chunk?;
+                let quota = state.put_quota.as_ref().expect("the quota is set");
+
+                if quota.accept(chunk.len() as u64) {
+                    state.metrics.put_bytes_accepted.add(chunk.len());
+                    Ok(chunk)
+                } else {
+                    exceeded.store(true, Ordering::Relaxed);
+                    Err(axum::Error::new(Error::PayloadTooLarge(quota.limit)))
+                }
+            }))
+        })
+    };
+
+    let path = request.uri().path().to_owned();
+    let response = next.run(request).await;
+
+    // The handler only saw a failing body, so it didn't answer with a 413.
+    if exceeded.load(Ordering::Relaxed) {
+        return reject(&state, &path);
+    }
+
+    response
+}
+
+fn reject(state: &State, path: &str) -> Response {
+    let quota = state.put_quota.as_ref().expect("the quota is set");
+
+    tracing::warn!(
+        "Rejecting the upload of '{}', which exceeds the upload quota of {} bytes",
+        path,
+        quota.limit
+    );
+    state.metrics.puts_rejected_quota.incr();
+
+    Error::PayloadTooLarge(quota.limit).into_response()
+}
@@ -0,0 +1,228 @@
+//! Checking that built paths are reproducible.
+//!
+//! The cache serves whatever a job built, so a build that isn't
+//! deterministic goes unnoticed until two jobs disagree. With
+//! `--check-reproducibility <n>`, the daemon remembers the paths it is asked
+//! to upload, and when the workflow finishes, it rebuilds the derivations of
+//! up to `n` of them, picked at random, with `nix-store --realise --check`,
+//! which compares the new outputs with the ones in the store. The results
+//! are logged and listed in the summary of the GitHub Actions job, and the
+//! derivations whose outputs differ are counted in the
+//! `derivations_not_reproducible` metric.
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use attic::nix_store::{NixStore, StorePath};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+use crate::telemetry::TelemetryReport;
+
+/// How many paths are remembered to pick the samples from.
+const MAX_CANDIDATES: usize = 10_000;
+
+/// The exit status of `nix-store --realise --check` when outputs differ.
+const NOT_DETERMINISTIC_STATUS: i32 = 104;
+
+pub struct ReproCheck {
+    /// How many derivations are rebuilt.
+    samples: usize,
+
+    /// The paths enqueued during this job.
+    candidates: Mutex<HashSet<StorePath>>,
+}
+
+/// The result of rebuilding a derivation.
+enum Outcome {
+    Reproducible,
+    Differs,
+    Failed(String),
+}
+
+impl ReproCheck {
+    pub fn new(samples: usize) -> ReproCheck {
+        ReproCheck {
+            samples,
+            candidates: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Remember `store_paths` as candidates for rebuilding.
+    pub async fn record(&self, store_paths: &[StorePath]) {
+        let mut candidates = self.candidates.lock().await;
+
+        for path in store_paths {
+            if candidates.len() >= MAX_CANDIDATES {
+                break;
+            }
+            candidates.insert(path.clone());
+        }
+    }
+
+    /// Rebuild the derivations of a random sample of the candidates, and
+    /// report the results, in the job summary at `summary` if given.
+    pub async fn run(&self, store: &NixStore, metrics: &TelemetryReport, summary: Option<&Path>) {
+        let mut candidates = self
+            .candidates
+            .lock()
+            .await
+            .iter()
+            .map(|path| store.get_full_path(path))
+            .collect::<Vec<_>>();
+
+        shuffle(&mut candidates);
+
+        let mut results = Vec::new();
+        let mut derivers = HashSet::new();
+
+        for path in candidates {
+            if results.len() >= self.samples {
+                break;
+            }
+
+            // Only the derivations that are in the store can be rebuilt, and
+            // each of them once, whichever of its outputs was picked.
+            let Some(deriver) = deriver(&path).await else {
+                continue;
+            };
+
+            if !derivers.insert(deriver.clone()) {
+                continue;
+            }
+
+            tracing::info!(
+                "Rebuilding {} to check that it is reproducible",
+                deriver.display()
+            );
+
+            let outcome = rebuild(&deriver).await;
+            metrics.derivations_rechecked.incr();
+
+            match &outcome {
+                Outcome::Reproducible => {
+                    tracing::info!("{} is reproducible", deriver.display());
+                }
+                Outcome::Differs => {
+                    metrics.derivations_not_reproducible.incr();
+                    tracing::warn!(
+                        "{} isn't reproducible: rebuilding it produced different outputs",
+                        deriver.display()
+                    );
+                }
+                Outcome::Failed(err) => {
+                    tracing::warn!("Rebuilding {} failed: {}", deriver.display(), err);
+                }
+            }
+
+            results.push((deriver, outcome));
+        }
+
+        if !results.is_empty() {
+            write_summary(&results, summary);
+        }
+    }
+}
+
+/// The derivation of `path`, if it is in the store.
+async fn deriver(path: &Path) -> Option<PathBuf> {
+    let output = Command::new("nix-store")
+        .arg("--query")
+        .arg("--deriver")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .ok()?;
+
+    // Prints `unknown-deriver` for paths without one.
+    let deriver = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if !output.status.success() || !deriver.ends_with(".drv") {
+        return None;
+    }
+
+    // The deriver is often not in the store, e.g. for substituted paths.
+    tokio::fs::metadata(&deriver).await.ok()?;
+
+    Some(PathBuf::from(deriver))
+}
+
+/// Rebuild `deriver` and compare its outputs with the ones in the store.
+async fn rebuild(deriver: &Path) -> Outcome {
+    let output = match Command::new("nix-store")
+        .arg("--realise")
+        .arg("--check")
+        .arg(deriver)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output()
+        .await
+    {
+        Ok(output) => output,
+        Err(err) => return Outcome::Failed(format!("running nix-store: {}", err)),
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    if output.status.success() {
+        Outcome::Reproducible
+    } else if output.status.code() == Some(NOT_DETERMINISTIC_STATUS)
+        || stderr.contains("may not be deterministic")
+    {
+        Outcome::Differs
+    } else {
+        Outcome::Failed(
+            stderr
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .unwrap_or("nix-store failed")
+                .trim()
+                .to_owned(),
+        )
+    }
+}
+
+/// Add the results to `summary`, or to the summary of the GitHub Actions
+/// step that started the daemon.
+fn write_summary(results: &[(PathBuf, Outcome)], summary: Option<&Path>) {
+    let Some(summary) = summary
+        .map(Path::to_owned)
+        .or_else(|| std::env::var_os("GITHUB_STEP_SUMMARY").map(PathBuf::from))
+    else {
+        return;
+    };
+
+    let mut contents =
+        String::from("### Reproducibility check\n\n| Derivation | Result |\n| --- | --- |\n");
+
+    for (deriver, outcome) in results {
+        let result = match outcome {
+            Outcome::Reproducible => "reproducible".to_owned(),
+            Outcome::Differs => "**not reproducible**".to_owned(),
+            Outcome::Failed(err) => format!("rebuild failed: {}", err.replace('|', "\\|")),
+        };
+
+        contents.push_str(&format!("| `{}` | {} |\n", deriver.display(), result));
+    }
+
+    let result = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&summary)
+        .and_then(|mut file| file.write_all(contents.as_bytes()));
+
+    if let Err(err) = result {
+        tracing::warn!("Writing the job summary failed: {}", err);
+    }
+}
+
+/// Shuffle `items` in place, with the randomness of version 4 UUIDs.
+fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let j = (uuid::Uuid::new_v4().as_u128() % (i as u128 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
@@ -0,0 +1,290 @@
+//! Retention of cache entries by provenance.
+//!
+//! When it shuts down, each daemon writes a retention index listing the keys
+//! it uploaded or relied on, along with the workflow, branch and time. The
+//! `gc` subcommand reads these indexes and deletes the entries of runs that
+//! are older than the retention policy for their branch, e.g. keeping
+//! artifacts of `main` for 30 days but those of pull requests for 3.
+//!
+//! An entry is only deleted if no retained run listed it.
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opendal::Operator;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::Backend;
+use crate::error::{Error, Result};
+use crate::keys;
+
+/// The retention class of runs triggered by pull requests.
+pub const PULL_REQUEST_CLASS: &str = "pr";
+
+/// The retention class that applies when no other class matches.
+pub const DEFAULT_CLASS: &str = "*";
+
+/// The provenance of the entries used by a run.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RetentionIndex {
+    pub workflow: Option<String>,
+    pub branch: Option<String>,
+    pub pull_request: bool,
+
+    /// When the run finished, in seconds since the epoch.
+    pub created_at: u64,
+
+    pub keys: HashSet<String>,
+}
+
+impl RetentionIndex {
+    /// Start an index for the current run.
+    pub fn new() -> RetentionIndex {
+        let event = std::env::var("GITHUB_EVENT_NAME").unwrap_or_default();
+
+        RetentionIndex {
+            workflow: std::env::var("GITHUB_WORKFLOW").ok(),
+            branch: std::env::var("GITHUB_HEAD_REF")
+                .ok()
+                .filter(|branch| !branch.is_empty())
+                .or_else(|| std::env::var("GITHUB_REF_NAME").ok()),
+            pull_request: event.starts_with("pull_request"),
+            created_at: 0,
+            keys: HashSet::new(),
+        }
+    }
+
+    /// Record that this run uploaded or relied on the entry under `key`.
+    pub fn record(&mut self, key: String) {
+        self.keys.insert(key);
+    }
+
+    /// Store the index in the cache, if any entries were recorded.
+    pub async fn save(&mut self, api: &Operator) {
+        if self.keys.is_empty() {
+            return;
+        }
+
+        self.created_at = now();
+
+        let key = keys::retention_index(&uuid::Uuid::now_v7().to_string());
+
+        let content = match serde_json::to_vec(self) {
+            Ok(content) => content,
+            Err(err) => {
+                tracing::warn!("Failed to serialize the retention index: {}", err);
+                return;
+            }
+        };
+
+        match api.write(&key, content).await {
+            Ok(_) => tracing::debug!("Stored the retention index '{}'", key),
+            Err(err) => tracing::warn!("Failed to store the retention index '{}': {}", key, err),
+        }
+    }
+
+    /// The retention class of this run: its branch, or `pr` for pull requests.
+    fn class(&self) -> &str {
+        if self.pull_request {
+            PULL_REQUEST_CLASS
+        } else {
+            self.branch.as_deref().unwrap_or(DEFAULT_CLASS)
+        }
+    }
+}
+
+/// How long the entries of each class of runs are kept.
+#[derive(Debug, Clone)]
+pub struct Policy(pub Vec<(String, Duration)>);
+
+impl Policy {
+    /// How long to keep the entries of runs in `class`, or `None` to keep them forever.
+    fn max_age(&self, class: &str) -> Option<Duration> {
+        self.find(class).or_else(|| self.find(DEFAULT_CLASS))
+    }
+
+    fn find(&self, class: &str) -> Option<Duration> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == class)
+            .map(|(_, age)| *age)
+    }
+}
+
+/// Parse a retention rule like `main=30d`.
+pub fn parse_rule(s: &str) -> std::result::Result<(String, Duration), String> {
+    let (class, age) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid retention rule '{}', expected e.g. 'main=30d'", s))?;
+
+    Ok((class.to_owned(), crate::util::parse_duration(age)?))
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct GcReport {
+    pub runs: usize,
+    pub runs_expired: usize,
+    pub keys_deleted: usize,
+    pub keys_retained: usize,
+    pub dry_run: bool,
+}
+
+/// The storage the garbage collector works on.
+enum Store {
+    /// The GitHub Actions Cache, which is listed and deleted through the REST API.
+    Gha(gha_cache::Api),
+    Operator(Operator),
+}
+
+impl Store {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        match self {
+            Store::Gha(api) => Ok(api
+                .list(prefix)
+                .await?
+                .into_iter()
+                .map(|entry| crate::backend::gha_key(&entry.key))
+                .collect()),
+            Store::Operator(api) => Ok(api
+                .list(prefix)
+                .await?
+                .into_iter()
+                .filter(|entry| entry.metadata().is_file())
+                .map(|entry| entry.path().to_owned())
+                .collect()),
+        }
+    }
+
+    async fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let api = match self {
+            Store::Gha(api) => api.operator(),
+            Store::Operator(api) => api,
+        };
+
+        match api.read(key).await {
+            Ok(content) => Ok(Some(content.to_vec())),
+            Err(err) if err.kind() == opendal::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match self {
+            Store::Gha(api) => Ok(api.delete(key).await?),
+            Store::Operator(api) => Ok(api.delete(key).await?),
+        }
+    }
+}
+
+/// Delete the entries of runs that are older than `policy` allows.
+pub async fn gc(
+    backend: &Backend,
+    netrc: Option<&str>,
+    policy: &Policy,
+    dry_run: bool,
+) -> Result<GcReport> {
+    let store = match backend {
+        Backend::Gha => Store::Gha(crate::backend::gha_rest_api().await?),
+        backend => {
+            let api = backend.operator(netrc)?;
+
+            if !api.info().full_capability().list {
+                return Err(Error::Config(format!(
+                    "the {} doesn't support listing, so it can't be garbage-collected",
+                    backend
+                )));
+            }
+
+            Store::Operator(api)
+        }
+    };
+
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    let now = now();
+    let mut retained = HashSet::new();
+    let mut expired = Vec::new();
+
+    for index_key in store.list(&keys::retention_indexes()).await? {
+        let Some(content) = store.read(&index_key).await? else {
+            continue;
+        };
+
+        let index: RetentionIndex = match serde_json::from_slice(&content) {
+            Ok(index) => index,
+            Err(err) => {
+                tracing::warn!(
+                    "Ignoring unreadable retention index '{}': {}",
+                    index_key,
+                    err
+                );
+                continue;
+            }
+        };
+
+        report.runs += 1;
+
+        let age = Duration::from_secs(now.saturating_sub(index.created_at));
+        match policy.max_age(index.class()) {
+            Some(max_age) if age > max_age => expired.push((index_key, index)),
+            _ => retained.extend(index.keys),
+        }
+    }
+
+    report.runs_expired = expired.len();
+    report.keys_retained = retained.len();
+
+    let mut deleted = HashSet::new();
+
+    for (index_key, index) in expired {
+        tracing::info!(
+            "Run of {} on {} from {} has expired",
+            index.workflow.as_deref().unwrap_or("an unknown workflow"),
+            index.class(),
+            index.created_at
+        );
+
+        for key in index.keys {
+            if retained.contains(&key) || deleted.contains(&key) {
+                continue;
+            }
+
+            if !dry_run {
+                store.delete(&key).await?;
+            }
+
+            tracing::debug!("Deleted '{}'", key);
+            deleted.insert(key);
+        }
+
+        if !dry_run {
+            store.delete(&index_key).await?;
+        }
+    }
+
+    report.keys_deleted = deleted.len();
+
+    // The upload manifest would otherwise keep claiming that deleted paths exist.
+    if !deleted.is_empty() && !dry_run {
+        let repository = std::env::var("GITHUB_REPOSITORY").unwrap_or_else(|_| "local".to_owned());
+        if let Err(err) = store.delete(&keys::manifest(&repository)).await {
+            tracing::warn!("Failed to delete the upload manifest: {}", err);
+        }
+
+        if let Err(err) = store.delete(&keys::bundle_catalog(&repository)).await {
+            tracing::warn!("Failed to delete the bundle catalog: {}", err);
+        }
+    }
+
+    Ok(report)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
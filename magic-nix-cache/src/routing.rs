@@ -0,0 +1,122 @@
+//! Routing of store paths to backends by name.
+//!
+//! Rules like `-(doc|man)$=webdav:https://…` send the store paths whose name
+//! matches a regex to another backend than the default one, e.g. to keep
+//! documentation in a cheap cold store while compilers stay in the GitHub
+//! Actions Cache. The worker uploads each path to the backend of the first
+//! matching rule, and narinfos uploaded over `PUT` are routed by their
+//! `StorePath`. `nix copy` uploads a NAR before its narinfo, so NARs are
+//! stored in the default backend and moved to the routed one along with the
+//! narinfo. Requests for NARs and narinfos only carry hashes, so they are
+//! looked up in the default backend and then in the routed ones.
+//!
+//! The backends that rules of the upload policy route paths to are looked
+//! up the same way, but only the worker stores paths in them, as the policy
+//! decides.
+
+use std::sync::Arc;
+
+use opendal::Operator;
+use regex::Regex;
+
+use crate::backend::Backend;
+use crate::error::Result;
+use crate::scheduler::{Scheduler, SchedulerLayer};
+use crate::telemetry;
+
+pub struct Route {
+    pattern: Regex,
+
+    /// The backend that paths matching `pattern` are stored in.
+    pub api: Arc<Operator>,
+}
+
+#[derive(Default)]
+pub struct Routes {
+    routes: Vec<Route>,
+
+    /// The backends of the upload policy, by their index in [`Policy::backends`].
+    ///
+    /// [`Policy::backends`]: crate::policy::Policy::backends
+    policy: Vec<Arc<Operator>>,
+}
+
+impl Routes {
+    /// Create the operators for the backends of `rules`, as parsed by
+    /// [`parse_rule`], and of `policy_backends`.
+    pub fn new(
+        rules: &[(Regex, Backend)],
+        policy_backends: &[Backend],
+        netrc: Option<&str>,
+        metrics: &Arc<telemetry::TelemetryReport>,
+    ) -> Result<Routes> {
+        // Each backend has its own limits, so it gets its own scheduler.
+        let operator = |backend: &Backend| -> Result<Arc<Operator>> {
+            let scheduler = Arc::new(Scheduler::new(None, metrics.clone()));
+            Ok(Arc::new(
+                backend.operator(netrc)?.layer(SchedulerLayer(scheduler)),
+            ))
+        };
+
+        Ok(Routes {
+            routes: rules
+                .iter()
+                .map(|(pattern, backend)| {
+                    Ok(Route {
+                        pattern: pattern.clone(),
+                        api: operator(backend)?,
+                    })
+                })
+                .collect::<Result<_>>()?,
+            policy: policy_backends
+                .iter()
+                .map(operator)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// The route for the store path named `name`, or `None` for the default backend.
+    pub fn select(&self, name: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| route.pattern.is_match(name))
+    }
+
+    /// The backend of the upload policy with `index`.
+    pub fn policy_backend(&self, index: usize) -> Option<&Arc<Operator>> {
+        self.policy.get(index)
+    }
+
+    /// The backends of all routes.
+    pub fn apis(&self) -> impl Iterator<Item = &Operator> {
+        self.routes
+            .iter()
+            .map(|route| &*route.api)
+            .chain(self.policy.iter().map(|api| &**api))
+    }
+}
+
+/// Parse a routing rule like `-(doc|man)$=webdav:https://dav.example.com/cold`.
+pub fn parse_rule(s: &str) -> std::result::Result<(Regex, Backend), String> {
+    let (pattern, backend) = s
+        .split_once('=')
+        .ok_or_else(|| format!("bad route '{}', expected '<regex>=<backend>'", s))?;
+
+    let pattern =
+        Regex::new(pattern).map_err(|e| format!("bad regex '{}' in route: {}", pattern, e))?;
+    let backend = backend.parse::<Backend>().map_err(|e| e.to_string())?;
+
+    Ok((pattern, backend))
+}
+
+/// The name of the store path of a narinfo, e.g. `hello-2.12` for
+/// `StorePath: /nix/store/<hash>-hello-2.12`.
+pub fn narinfo_name(narinfo: &str) -> Option<&str> {
+    let store_path = narinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("StorePath: "))?
+        .trim();
+
+    let (_, name) = store_path.rsplit('/').next()?.split_once('-')?;
+    Some(name)
+}
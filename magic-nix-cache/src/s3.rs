@@ -0,0 +1,140 @@
+//! S3 storage, including presets for S3-compatible providers.
+//!
+//! Credentials are read from the usual `AWS_*` environment variables. The
+//! presets for Cloudflare R2 and Backblaze B2 also accept the provider's own
+//! variables, and set the endpoint, region and retry policy those providers
+//! need.
+
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use opendal::layers::RetryLayer;
+use opendal::Operator;
+
+use crate::error::{Error, Result};
+
+/// An S3-compatible storage provider.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Provider {
+    /// Amazon S3, or any endpoint configured through `AWS_ENDPOINT_URL`.
+    Aws,
+
+    /// Cloudflare R2, for the given account ID.
+    R2 { account_id: String },
+
+    /// Backblaze B2, in the given region (e.g. `us-west-004`).
+    B2 { region: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct S3Config {
+    pub provider: Provider,
+    pub bucket: String,
+}
+
+impl S3Config {
+    /// Parse the part of a backend specification after the provider:
+    /// `<bucket>` for S3, `<account-id>/<bucket>` for R2 and
+    /// `<region>/<bucket>` for B2.
+    pub fn parse(provider: &str, spec: &str) -> Result<Self> {
+        let qualified = |what: &str| {
+            spec.split_once('/')
+                .filter(|(qualifier, bucket)| !qualifier.is_empty() && !bucket.is_empty())
+                .map(|(qualifier, bucket)| (qualifier.to_owned(), bucket.to_owned()))
+                .ok_or_else(|| {
+                    Error::Config(format!(
+                        "expected '{}:<{}>/<bucket>', got '{}:{}'",
+                        provider, what, provider, spec
+                    ))
+                })
+        };
+
+        let (provider, bucket) = match provider {
+            "s3" if !spec.is_empty() => (Provider::Aws, spec.to_owned()),
+            "r2" => {
+                let (account_id, bucket) = qualified("account-id")?;
+                (Provider::R2 { account_id }, bucket)
+            }
+            "b2" => {
+                let (region, bucket) = qualified("region")?;
+                (Provider::B2 { region }, bucket)
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "expected '{}:<bucket>', got '{}:{}'",
+                    provider, provider, spec
+                )))
+            }
+        };
+
+        Ok(S3Config { provider, bucket })
+    }
+
+    pub fn operator(&self) -> Result<Operator> {
+        let mut builder = opendal::services::S3::default().bucket(&self.bucket);
+
+        let retry = RetryLayer::new().with_jitter();
+
+        let retry = match &self.provider {
+            Provider::Aws => {
+                // Self-hosted stores like MinIO are reached through their own endpoint.
+                if let Ok(endpoint) = std::env::var("AWS_ENDPOINT_URL") {
+                    builder = builder.endpoint(&endpoint);
+                }
+
+                retry
+            }
+            Provider::R2 { account_id } => {
+                builder = builder
+                    .endpoint(&format!("https://{}.r2.cloudflarestorage.com", account_id))
+                    .region("auto")
+                    // R2 rejects the `response-*` overrides opendal sends with HEAD requests.
+                    .disable_stat_with_override()
+                    .disable_ec2_metadata();
+                builder = with_credentials(builder, "R2_ACCESS_KEY_ID", "R2_SECRET_ACCESS_KEY");
+
+                retry.with_max_times(5)
+            }
+            Provider::B2 { region } => {
+                builder = builder
+                    .endpoint(&format!("https://s3.{}.backblazeb2.com", region))
+                    .region(region)
+                    .disable_ec2_metadata();
+                builder = with_credentials(builder, "B2_APPLICATION_KEY_ID", "B2_APPLICATION_KEY");
+
+                // B2 answers bursts with 503s and expects clients to back off
+                // for longer than the default retry policy does.
+                retry
+                    .with_max_times(8)
+                    .with_min_delay(Duration::from_millis(500))
+                    .with_max_delay(Duration::from_secs(30))
+            }
+        };
+
+        Ok(Operator::new(builder)?.layer(retry).finish())
+    }
+}
+
+/// Use the credentials in the given environment variables, if they are set.
+fn with_credentials(
+    builder: opendal::services::S3,
+    key_id_var: &str,
+    secret_var: &str,
+) -> opendal::services::S3 {
+    match (std::env::var(key_id_var), std::env::var(secret_var)) {
+        (Ok(key_id), Ok(secret)) => builder.access_key_id(&key_id).secret_access_key(&secret),
+        _ => builder,
+    }
+}
+
+impl Display for S3Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.provider {
+            Provider::Aws => write!(f, "S3 bucket {}", self.bucket),
+            Provider::R2 { .. } => write!(f, "Cloudflare R2 bucket {}", self.bucket),
+            Provider::B2 { region } => {
+                write!(f, "Backblaze B2 bucket {} ({})", self.bucket, region)
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Scanning store paths before they are uploaded.
+//!
+//! Every job of the repository substitutes from the cache, so a path that
+//! contains a secret, e.g. a token that a build baked into a wrapper, or
+//! malware, spreads to all of them. With `--scan-command <program>`, the
+//! worker runs `program <store path>` before uploading each path, e.g. a
+//! secret or virus scanner, and only uploads the path if it exits
+//! successfully. The paths that aren't uploaded are logged with the last line
+//! of the program's output, counted in the `uploads_skipped_scan` metric and
+//! listed at `/api/stats`.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::process::Command;
+
+/// How long a scan may take before the path is rejected.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How many rejected paths are listed.
+const MAX_REJECTIONS: usize = 1000;
+
+pub struct Scanner {
+    program: PathBuf,
+
+    rejections: Mutex<Vec<Rejection>>,
+}
+
+/// A path that wasn't uploaded, and why.
+#[derive(Clone, Debug, Serialize)]
+pub struct Rejection {
+    pub store_path: PathBuf,
+    pub reason: String,
+}
+
+impl Scanner {
+    pub fn new(program: PathBuf) -> Scanner {
+        Scanner {
+            program,
+            rejections: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Scan the store path at `path`, returning why it must not be uploaded,
+    /// if it must not. A scan that can't be run rejects the path too.
+    pub async fn scan(&self, path: &Path) -> Option<String> {
+        let output = Command::new(&self.program)
+            .arg(path)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+
+        let reason = match tokio::time::timeout(SCAN_TIMEOUT, output).await {
+            Ok(Ok(output)) if output.status.success() => return None,
+            Ok(Ok(output)) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+
+                match stderr
+                    .lines()
+                    .chain(stdout.lines())
+                    .rev()
+                    .find(|line| !line.trim().is_empty())
+                {
+                    Some(line) => format!("{} ({})", line.trim(), output.status),
+                    None => format!("the scan failed with {}", output.status),
+                }
+            }
+            Ok(Err(err)) => format!("running {}: {}", self.program.display(), err),
+            Err(_) => format!("the scan took longer than {:?}", SCAN_TIMEOUT),
+        };
+
+        let mut rejections = self.rejections.lock().expect("scan rejections lock");
+        if rejections.len() < MAX_REJECTIONS {
+            rejections.push(Rejection {
+                store_path: path.to_owned(),
+                reason: reason.clone(),
+            });
+        }
+
+        Some(reason)
+    }
+
+    /// The paths that were rejected so far.
+    pub fn rejections(&self) -> Vec<Rejection> {
+        self.rejections
+            .lock()
+            .expect("scan rejections lock")
+            .clone()
+    }
+}
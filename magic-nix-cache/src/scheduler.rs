@@ -0,0 +1,365 @@
+//! Scheduling of backend requests.
+//!
+//! The binary cache routes and the upload worker share one operator, which
+//! is wrapped in a [`SchedulerLayer`]. Every request waits for its turn in
+//! the [`Scheduler`], which spaces requests out to respect a configured rate
+//! limit, and pauses all of them when the backend asks the daemon to slow
+//! down. A 429 hit by the worker thus also slows down serving, instead of
+//! the two independently hammering the API.
+//!
+//! The scheduler also reports the outcome of every request to the health of
+//! the backend, and fails requests right away while its circuit is open.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use opendal::raw::{
+    oio, Access, AccessorInfo, Layer, LayeredAccess, OpList, OpRead, OpStat, OpWrite, RpDelete,
+    RpList, RpRead, RpStat, RpWrite,
+};
+use opendal::{Buffer, ErrorKind, Metadata};
+use tokio::time::Instant;
+
+use crate::health::BackendHealth;
+use crate::telemetry;
+
+/// How long to pause after the first rate-limited request without a `Retry-After`.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest pause, whatever the backend asks for.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Scheduler {
+    /// The minimum interval between requests, if they are rate-limited.
+    interval: Option<Duration>,
+
+    state: Mutex<State>,
+
+    metrics: Arc<telemetry::TelemetryReport>,
+
+    /// The health of the backend, whose circuit breaker watches the requests.
+    health: Option<Arc<BackendHealth>>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// When the next request may start.
+    next_slot: Instant,
+
+    /// Until when all requests are paused, after the backend rate-limited us.
+    paused_until: Instant,
+
+    /// The pause after the next rate-limited request without a `Retry-After`.
+    backoff: Duration,
+}
+
+impl Scheduler {
+    /// Create a scheduler allowing at most `max_requests_per_second`, or any
+    /// number if it is `None`.
+    pub fn new(
+        max_requests_per_second: Option<u32>,
+        metrics: Arc<telemetry::TelemetryReport>,
+    ) -> Scheduler {
+        let now = Instant::now();
+
+        Scheduler {
+            interval: max_requests_per_second
+                .filter(|rate| *rate > 0)
+                .map(|rate| Duration::from_secs(1) / rate),
+            state: Mutex::new(State {
+                next_slot: now,
+                paused_until: now,
+                backoff: INITIAL_BACKOFF,
+            }),
+            metrics,
+            health: None,
+        }
+    }
+
+    /// Report the outcome of requests to `health`, and fail them while its
+    /// circuit is open.
+    pub fn with_health(mut self, health: Arc<BackendHealth>) -> Scheduler {
+        self.health = Some(health);
+        self
+    }
+
+    /// Wait until a request may be sent, or fail if the circuit is open.
+    pub async fn acquire(&self) -> opendal::Result<()> {
+        if self
+            .health
+            .as_ref()
+            .is_some_and(|health| health.is_circuit_open())
+        {
+            return Err(opendal::Error::new(
+                ErrorKind::Unexpected,
+                "the circuit breaker is open because the backend is overloaded",
+            )
+            .set_temporary());
+        }
+
+        let start = {
+            let mut state = self.state.lock().unwrap();
+            let start = Instant::now().max(state.next_slot).max(state.paused_until);
+
+            if let Some(interval) = self.interval {
+                state.next_slot = start + interval;
+            }
+
+            start
+        };
+
+        tokio::time::sleep_until(start).await;
+
+        Ok(())
+    }
+
+    /// Pause all requests, for `retry_after` if the backend said how long.
+    pub fn back_off(&self, retry_after: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+
+        let pause = match retry_after {
+            Some(retry_after) => retry_after.min(MAX_BACKOFF),
+            None => {
+                let pause = state.backoff;
+                state.backoff = (state.backoff * 2).min(MAX_BACKOFF);
+                pause
+            }
+        };
+
+        let until = Instant::now() + pause;
+        if until > state.paused_until {
+            tracing::warn!(
+                "The backend is rate-limiting requests, pausing them for {:?}",
+                pause
+            );
+            state.paused_until = until;
+        }
+
+        self.metrics.backend_rate_limited.incr();
+        self.metrics
+            .tripped_429
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Update the schedule after a request finished with `result`.
+    fn observe<T>(&self, result: &opendal::Result<T>) {
+        self.record(result.as_ref().is_err_and(is_overloaded));
+
+        match result {
+            Ok(_) => self.state.lock().unwrap().backoff = INITIAL_BACKOFF,
+            Err(err) if err.kind() == ErrorKind::RateLimited => self.back_off(retry_after(err)),
+            Err(_) => {}
+        }
+    }
+
+    /// Report to the circuit breaker whether a request failed because the
+    /// backend is overloaded.
+    fn record(&self, overloaded: bool) {
+        if let Some(health) = &self.health {
+            if health.record_request(overloaded) {
+                self.metrics.backend_circuit_opened.incr();
+            }
+        }
+    }
+
+    /// Update the schedule after a request to the backend outside of the
+    /// operator, e.g. to the Artifactory REST API, got `response`.
+    pub fn observe_response(&self, response: &reqwest::Response) {
+        let status = response.status();
+        self.record(status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error());
+
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs);
+
+            self.back_off(retry_after);
+        } else if response.status().is_success() {
+            self.state.lock().unwrap().backoff = INITIAL_BACKOFF;
+        }
+    }
+}
+
+/// Whether `err` means that the backend is overloaded or otherwise failing,
+/// rather than that the request was wrong.
+pub fn is_overloaded(err: &opendal::Error) -> bool {
+    err.is_temporary()
+        || err.kind() == ErrorKind::RateLimited
+        // The `ghac` service reports server errors as unexpected errors, with
+        // the response in their context.
+        || (err.kind() == ErrorKind::Unexpected
+            && ["status: 500", "status: 502", "status: 503", "status: 504"]
+                .iter()
+                .any(|status| err.to_string().contains(status)))
+}
+
+/// The delay requested by a `Retry-After` header, as found in the context of `err`.
+///
+/// Services report the response headers that came with an error in its
+/// context, which is only exposed through its `Display` implementation.
+fn retry_after(err: &opendal::Error) -> Option<Duration> {
+    let message = err.to_string().to_ascii_lowercase();
+    let rest = &message[message.find("retry-after")? + "retry-after".len()..];
+
+    let seconds = rest
+        .trim_start_matches(|c: char| !c.is_ascii_digit() && c != ',' && c != '}')
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()?;
+
+    Some(Duration::from_secs(seconds))
+}
+
+/// Sends all requests of an operator through a [`Scheduler`].
+pub struct SchedulerLayer(pub Arc<Scheduler>);
+
+impl<A: Access> Layer<A> for SchedulerLayer {
+    type LayeredAccess = ScheduledAccess<A>;
+
+    fn layer(&self, inner: A) -> Self::LayeredAccess {
+        ScheduledAccess {
+            inner,
+            scheduler: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ScheduledAccess<A> {
+    inner: A,
+    scheduler: Arc<Scheduler>,
+}
+
+impl<A: Access> LayeredAccess for ScheduledAccess<A> {
+    type Inner = A;
+    type Reader = Scheduled<A::Reader>;
+    type Writer = Scheduled<A::Writer>;
+    type Lister = A::Lister;
+    type Deleter = A::Deleter;
+    type BlockingReader = A::BlockingReader;
+    type BlockingWriter = A::BlockingWriter;
+    type BlockingLister = A::BlockingLister;
+    type BlockingDeleter = A::BlockingDeleter;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        self.inner.info()
+    }
+
+    async fn stat(&self, path: &str, args: OpStat) -> opendal::Result<RpStat> {
+        self.scheduler.acquire().await?;
+        let result = self.inner.stat(path, args).await;
+        self.scheduler.observe(&result);
+        result
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> opendal::Result<(RpRead, Self::Reader)> {
+        self.scheduler.acquire().await?;
+        let result = self.inner.read(path, args).await;
+        self.scheduler.observe(&result);
+
+        let (rp, inner) = result?;
+        Ok((rp, Scheduled::new(inner, &self.scheduler)))
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> opendal::Result<(RpWrite, Self::Writer)> {
+        self.scheduler.acquire().await?;
+        let result = self.inner.write(path, args).await;
+        self.scheduler.observe(&result);
+
+        let (rp, inner) = result?;
+        Ok((rp, Scheduled::new(inner, &self.scheduler)))
+    }
+
+    async fn delete(&self) -> opendal::Result<(RpDelete, Self::Deleter)> {
+        self.scheduler.acquire().await?;
+        let result = self.inner.delete().await;
+        self.scheduler.observe(&result);
+        result
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> opendal::Result<(RpList, Self::Lister)> {
+        self.scheduler.acquire().await?;
+        let result = self.inner.list(path, args).await;
+        self.scheduler.observe(&result);
+        result
+    }
+
+    fn blocking_read(
+        &self,
+        path: &str,
+        args: OpRead,
+    ) -> opendal::Result<(RpRead, Self::BlockingReader)> {
+        self.inner.blocking_read(path, args)
+    }
+
+    fn blocking_write(
+        &self,
+        path: &str,
+        args: OpWrite,
+    ) -> opendal::Result<(RpWrite, Self::BlockingWriter)> {
+        self.inner.blocking_write(path, args)
+    }
+
+    fn blocking_list(
+        &self,
+        path: &str,
+        args: OpList,
+    ) -> opendal::Result<(RpList, Self::BlockingLister)> {
+        self.inner.blocking_list(path, args)
+    }
+
+    fn blocking_delete(&self) -> opendal::Result<(RpDelete, Self::BlockingDeleter)> {
+        self.inner.blocking_delete()
+    }
+}
+
+/// A reader or writer whose requests are reported to the scheduler, since
+/// services like the GitHub Actions Cache only upload when it is closed.
+pub struct Scheduled<T> {
+    inner: T,
+    scheduler: Arc<Scheduler>,
+}
+
+impl<T> Scheduled<T> {
+    fn new(inner: T, scheduler: &Arc<Scheduler>) -> Self {
+        Scheduled {
+            inner,
+            scheduler: scheduler.clone(),
+        }
+    }
+}
+
+impl<R: oio::Read> oio::Read for Scheduled<R> {
+    async fn read(&mut self) -> opendal::Result<Buffer> {
+        let result = self.inner.read().await;
+        self.scheduler.observe(&result);
+        result
+    }
+}
+
+impl<W: oio::Write> oio::Write for Scheduled<W> {
+    async fn write(&mut self, bs: Buffer) -> opendal::Result<()> {
+        let result = self.inner.write(bs).await;
+        self.scheduler.observe(&result);
+        result
+    }
+
+    async fn close(&mut self) -> opendal::Result<Metadata> {
+        let result = self.inner.close().await;
+        self.scheduler.observe(&result);
+        result
+    }
+
+    async fn abort(&mut self) -> opendal::Result<()> {
+        self.inner.abort().await
+    }
+}
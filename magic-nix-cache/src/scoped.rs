@@ -0,0 +1,170 @@
+//! Short-lived tokens that only allow pushing specific paths.
+//!
+//! The daemon's endpoint isn't authenticated, so everything in the job that
+//! can reach it can push any path, use up the quota or shut the daemon down.
+//! To let a sub-process like a container build step push its outputs
+//! without that, start the daemon with `--scoped-listen`, e.g. on the
+//! address of the Docker bridge, and mint a token for the paths with
+//! `POST /api/scoped-tokens`. The scoped listener only serves the binary
+//! cache, only to requests carrying an unexpired token, as
+//! `Authorization: Bearer <token>` or as the password of a netrc entry, and
+//! only accepts the narinfos of the token's paths. NARs are accepted with any
+//! token, since they are uploaded before their narinfo and are only reachable
+//! through one, but only if they aren't in the cache yet: a NAR that is
+//! already there is answered with a 409 instead of being replaced.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Extension, Request},
+    http::{header, HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::binary_cache::narinfo_hash;
+use crate::error::{Error, Result};
+use crate::State;
+
+/// How long tokens are valid unless the request asks otherwise.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How long tokens may be valid at most, the longest a job runs.
+pub const MAX_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Marks the requests that were authorized by a scoped token, whose pushes
+/// may not replace objects that are already in the cache.
+#[derive(Clone, Copy)]
+pub struct Scoped;
+
+pub struct ScopedTokens {
+    /// The URL of the scoped listener.
+    pub url: String,
+
+    /// The store path hashes that each token may push, by the digest of the
+    /// token, and when the token expires.
+    tokens: Mutex<HashMap<Vec<u8>, (HashSet<String>, Instant)>>,
+}
+
+impl ScopedTokens {
+    pub fn new(addr: SocketAddr) -> ScopedTokens {
+        ScopedTokens {
+            url: format!("http://{}", addr),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Mint a token that may push the paths with `store_path_hashes` for `ttl`.
+    pub fn mint(&self, store_path_hashes: HashSet<String>, ttl: Duration) -> String {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let now = Instant::now();
+
+        let mut tokens = self.tokens.lock().expect("scoped tokens lock");
+        tokens.retain(|_, (_, expires)| *expires > now);
+        tokens.insert(digest(&token), (store_path_hashes, now + ttl));
+
+        token
+    }
+
+    /// Check that `token` may make a request with `method` for `path`.
+    fn authorize(&self, token: &str, method: &Method, path: &str) -> Result<()> {
+        let tokens = self.tokens.lock().expect("scoped tokens lock");
+
+        let (store_path_hashes, _) = tokens
+            .get(&digest(token))
+            .filter(|(_, expires)| *expires > Instant::now())
+            .ok_or(Error::Unauthorized)?;
+
+        if method != Method::PUT || path.starts_with("/nar/") {
+            return Ok(());
+        }
+
+        match path.strip_prefix('/').and_then(narinfo_hash) {
+            Some(hash) if store_path_hashes.contains(hash) => Ok(()),
+            _ => {
+                tracing::warn!("Rejecting '{}', which the scoped token doesn't cover", path);
+                Err(Error::Forbidden)
+            }
+        }
+    }
+}
+
+/// Only pass on the requests that carry a token allowing them.
+pub async fn middleware(
+    Extension(state): Extension<State>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(scoped_tokens) = &state.scoped_tokens else {
+        return Error::NotFound.into_response();
+    };
+
+    let result = token(request.headers())
+        .ok_or(Error::Unauthorized)
+        .and_then(|token| scoped_tokens.authorize(&token, request.method(), request.uri().path()));
+
+    match result {
+        Ok(()) => {
+            request.extensions_mut().insert(Scoped);
+            next.run(request).await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Pass on the requests that read from the cache, and only the pushes that
+/// carry a token allowing them, for listeners that anyone on the host can
+/// reach, like the one on the Docker bridge.
+pub async fn push_middleware(
+    Extension(state): Extension<State>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if request.method() == Method::GET || request.method() == Method::HEAD {
+        return next.run(request).await;
+    }
+
+    // Tokens can only be minted with a scoped listener.
+    let Some(scoped_tokens) = &state.scoped_tokens else {
+        return Error::Forbidden.into_response();
+    };
+
+    let result = token(request.headers())
+        .ok_or(Error::Unauthorized)
+        .and_then(|token| scoped_tokens.authorize(&token, request.method(), request.uri().path()));
+
+    match result {
+        Ok(()) => {
+            request.extensions_mut().insert(Scoped);
+            next.run(request).await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// The token in the `Authorization` header, as a bearer token or as the
+/// password of basic authentication, which is what Nix sends from a netrc.
+fn token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return Some(token.trim().to_owned());
+    }
+
+    let credentials = STANDARD.decode(value.strip_prefix("Basic ")?.trim()).ok()?;
+    let (_, password) = std::str::from_utf8(&credentials).ok()?.split_once(':')?;
+
+    Some(password.to_owned())
+}
+
+/// Tokens are kept by their digest, so that looking one up doesn't reveal
+/// how much of it was right.
+fn digest(token: &str) -> Vec<u8> {
+    Sha256::digest(token).to_vec()
+}
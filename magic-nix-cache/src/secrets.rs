@@ -0,0 +1,162 @@
+//! Resolution of secrets given in the configuration.
+//!
+//! Options that take a secret, like `--backend-netrc`, accept either the path
+//! of a file containing it or a reference to a secret manager, which is
+//! resolved once at startup and only kept in memory:
+//!
+//! - `vault://<path>#<field>` reads a field of a secret from HashiCorp Vault,
+//!   using `VAULT_ADDR`, `VAULT_TOKEN` and optionally `VAULT_NAMESPACE`. The
+//!   path is the API path, e.g. `secret/data/ci/cache` for a KV v2 secret.
+//! - `op://<vault>/<item>/<field>` reads a field with the 1Password CLI.
+//! - `cmd:<command>` runs a shell command, e.g. another secret manager's CLI,
+//!   and uses its output.
+
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde_json::Value;
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretSource {
+    /// A file containing the secret.
+    File(PathBuf),
+
+    /// A field of a secret in Vault.
+    Vault { path: String, field: String },
+
+    /// A 1Password secret reference, including the `op://` prefix.
+    OnePassword(String),
+
+    /// A shell command that prints the secret.
+    Command(String),
+}
+
+impl SecretSource {
+    /// Read the secret.
+    pub async fn resolve(&self) -> Result<Vec<u8>> {
+        match self {
+            SecretSource::File(path) => tokio::fs::read(path)
+                .await
+                .map_err(|e| Error::Io(e, format!("Reading {}", path.display()))),
+            SecretSource::Vault { path, field } => read_vault(path, field).await,
+            SecretSource::OnePassword(reference) => {
+                run(Command::new("op")
+                    .arg("read")
+                    .arg("--no-newline")
+                    .arg(reference))
+                .await
+            }
+            SecretSource::Command(command) => run(Command::new("sh").arg("-c").arg(command)).await,
+        }
+    }
+
+    /// Read the secret as text.
+    pub async fn resolve_string(&self) -> Result<String> {
+        String::from_utf8(self.resolve().await?)
+            .map_err(|_| Error::Config(format!("The secret from {} isn't valid UTF-8", self)))
+    }
+}
+
+impl From<PathBuf> for SecretSource {
+    fn from(path: PathBuf) -> Self {
+        SecretSource::File(path)
+    }
+}
+
+impl FromStr for SecretSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(reference) = s.strip_prefix("vault://") {
+            let (path, field) = reference
+                .split_once('#')
+                .filter(|(path, field)| !path.is_empty() && !field.is_empty())
+                .ok_or_else(|| {
+                    Error::Config(format!(
+                        "bad Vault reference '{}', expected 'vault://<path>#<field>'",
+                        s
+                    ))
+                })?;
+
+            Ok(SecretSource::Vault {
+                path: path.trim_matches('/').to_owned(),
+                field: field.to_owned(),
+            })
+        } else if s.starts_with("op://") {
+            Ok(SecretSource::OnePassword(s.to_owned()))
+        } else if let Some(command) = s.strip_prefix("cmd:") {
+            Ok(SecretSource::Command(command.to_owned()))
+        } else {
+            Ok(SecretSource::File(s.into()))
+        }
+    }
+}
+
+impl Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SecretSource::File(path) => write!(f, "{}", path.display()),
+            SecretSource::Vault { path, field } => write!(f, "vault://{}#{}", path, field),
+            SecretSource::OnePassword(reference) => write!(f, "{}", reference),
+            SecretSource::Command(_) => write!(f, "a command"),
+        }
+    }
+}
+
+/// Read `field` of the Vault secret at the API path `path`.
+async fn read_vault(path: &str, field: &str) -> Result<Vec<u8>> {
+    let addr = std::env::var("VAULT_ADDR")
+        .map_err(|_| Error::Config("VAULT_ADDR must be set to read secrets from Vault".into()))?;
+    let token = std::env::var("VAULT_TOKEN")
+        .map_err(|_| Error::Config("VAULT_TOKEN must be set to read secrets from Vault".into()))?;
+
+    let mut request = reqwest::Client::new()
+        .get(format!("{}/v1/{}", addr.trim_end_matches('/'), path))
+        .header("X-Vault-Token", token);
+
+    if let Ok(namespace) = std::env::var("VAULT_NAMESPACE") {
+        request = request.header("X-Vault-Namespace", namespace);
+    }
+
+    let response: Value = request.send().await?.error_for_status()?.json().await?;
+
+    // KV v2 secrets nest the fields in another `data` object.
+    let data = &response["data"];
+    let value = data["data"]
+        .get(field)
+        .or_else(|| data.get(field))
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "the Vault secret '{}' has no field '{}'",
+                path, field
+            ))
+        })?;
+
+    match value {
+        Value::String(value) => Ok(value.clone().into_bytes()),
+        value => Ok(value.to_string().into_bytes()),
+    }
+}
+
+/// Run a command that prints a secret.
+async fn run(command: &mut Command) -> Result<Vec<u8>> {
+    let output = command
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running a secret manager command".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Config(format!(
+            "the secret manager command failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(output.stdout)
+}
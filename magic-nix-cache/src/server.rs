@@ -0,0 +1,1269 @@
+//! Embedding the cache in another program.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use magic_nix_cache::{CacheTrinary, Server};
+//!
+//! let server = Server::builder()
+//!     .use_gha_cache(CacheTrinary::Enabled)
+//!     .upstream("https://cache.nixos.org")
+//!     .start()
+//!     .await?;
+//!
+//! println!("substituter: http://{}", server.local_addr());
+//!
+//! // ... run builds ...
+//!
+//! server.stop().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use attic::nix_store::NixStore;
+use axum::{extract::Extension, routing::get, Router};
+use regex::Regex;
+use reqwest::Url;
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::task::JoinHandle;
+
+use crate::alerts::{AlertFlags, Alerts};
+use crate::audit::AuditLog;
+use crate::cancellation::{self, Cancellation};
+use crate::closure_archive::ClosureArchive;
+use crate::deferral::{self, Deferral};
+use crate::election::Election;
+use crate::error::{Error, Result};
+use crate::github_app::{self, GitHubAppFlags};
+use crate::index::Index;
+use crate::lifecycle::{Lifecycle, Phase};
+use crate::policy::Policy;
+use crate::queue_file::{self, QueueFile};
+use crate::repro::ReproCheck;
+use crate::scoped::{self, ScopedTokens};
+use crate::secrets::SecretSource;
+use crate::statsd::StatsdSink;
+use crate::timeout::{self, Timeouts};
+use crate::upstream::Upstream;
+use crate::{
+    api, backend::Backend, binary_cache, container, disk_guard, env, flakehub, gha, info, keys,
+    listener, netrc, quota, request_id, signing, source, telemetry, temp, CacheTrinary,
+    FlakeHubAuthSource, State, StateInner, NARINFO_CACHE_CAPACITY,
+};
+
+/// Configuration for a [`Server`].
+pub struct ServerBuilder {
+    listen: SocketAddr,
+    environment: env::Environment,
+    upstreams: Vec<String>,
+    upload_upstream_paths: bool,
+    check_upstream_hashes: bool,
+    pull_through: bool,
+    trusted_public_keys: Vec<String>,
+    cancel_on_signals: bool,
+    use_gha_cache: CacheTrinary,
+    backend: Backend,
+    backend_netrc: Option<SecretSource>,
+    backend_rate_limit: Option<u32>,
+    circuit_breaker: Option<(usize, Duration)>,
+    routes: Vec<(Regex, Backend)>,
+    extra_backends: Vec<Backend>,
+    cache_namespace: Option<String>,
+    cache_version: Option<String>,
+    restore_cache_versions: Vec<String>,
+    key_secret: Option<Vec<u8>>,
+    admin_token: Option<String>,
+    scoped_listen: Option<SocketAddr>,
+    container_listen: bool,
+    github_app: GitHubAppFlags,
+    request_timeouts: Timeouts,
+    connection_limits: listener::Limits,
+    diff_store: bool,
+    closure_flags: gha::ClosureFlags,
+    min_free_space: Option<u64>,
+    max_put_size: Option<u64>,
+    max_upload_size: Option<u64>,
+    max_nar_size: Option<u64>,
+    include_fixed_output: bool,
+    scan_command: Option<PathBuf>,
+    upload_policy: Option<PathBuf>,
+    narinfo_index: Option<PathBuf>,
+    queue_file: Option<PathBuf>,
+    resume: bool,
+    bundle_below: Option<u64>,
+    max_concurrent_uploads: usize,
+    upload_retries: u32,
+    upload_order: gha::UploadOrder,
+    upload_built_only: bool,
+    name_filter: gha::NameFilter,
+    temp_dir: Option<PathBuf>,
+    statsd_addr: Option<String>,
+    alerts: Option<AlertFlags>,
+    strict: bool,
+    closure_archive: bool,
+    check_reproducibility: Option<usize>,
+    audit_log: Option<PathBuf>,
+    defer_until_idle: Vec<Regex>,
+    pending_file: Option<PathBuf>,
+    signing_key_file: Option<PathBuf>,
+    old_public_keys: Vec<String>,
+    elect_uploader: bool,
+    pub(crate) flakehub: Option<FlakeHubConfig>,
+    pub(crate) logfile: Option<PathBuf>,
+}
+
+pub(crate) struct FlakeHubConfig {
+    pub api_server: Url,
+    pub cache_server: Url,
+    pub flake_name: Option<String>,
+    pub auth_method: FlakeHubAuthSource,
+}
+
+/// A running binary cache server.
+pub struct Server {
+    local_addr: SocketAddr,
+    container_addr: Option<SocketAddr>,
+    state: State,
+    serve: JoinHandle<std::io::Result<()>>,
+    statsd: Option<Arc<StatsdSink>>,
+    alerts: Option<Arc<Alerts>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            environment: env::Environment::determine(),
+            upstreams: Vec::new(),
+            upload_upstream_paths: false,
+            check_upstream_hashes: false,
+            pull_through: false,
+            trusted_public_keys: Vec::new(),
+            cancel_on_signals: false,
+            use_gha_cache: CacheTrinary::NoPreference,
+            backend: Backend::default(),
+            backend_netrc: None,
+            backend_rate_limit: None,
+            circuit_breaker: None,
+            routes: Vec::new(),
+            extra_backends: Vec::new(),
+            cache_namespace: None,
+            cache_version: None,
+            restore_cache_versions: Vec::new(),
+            key_secret: None,
+            admin_token: None,
+            scoped_listen: None,
+            container_listen: false,
+            github_app: GitHubAppFlags::default(),
+            request_timeouts: Timeouts::default(),
+            connection_limits: listener::Limits::default(),
+            diff_store: false,
+            closure_flags: gha::ClosureFlags::default(),
+            min_free_space: None,
+            max_put_size: None,
+            max_upload_size: None,
+            max_nar_size: None,
+            include_fixed_output: false,
+            scan_command: None,
+            upload_policy: None,
+            narinfo_index: None,
+            queue_file: None,
+            resume: false,
+            bundle_below: None,
+            max_concurrent_uploads: 1,
+            upload_retries: 3,
+            upload_order: gha::UploadOrder::default(),
+            upload_built_only: false,
+            name_filter: gha::NameFilter::default(),
+            temp_dir: None,
+            statsd_addr: None,
+            alerts: None,
+            strict: false,
+            closure_archive: false,
+            check_reproducibility: None,
+            audit_log: None,
+            defer_until_idle: Vec::new(),
+            pending_file: None,
+            signing_key_file: None,
+            old_public_keys: Vec::new(),
+            elect_uploader: false,
+            flakehub: None,
+            logfile: None,
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Set the address to listen on. Defaults to a random port on localhost.
+    pub fn listen(mut self, listen: SocketAddr) -> Self {
+        self.listen = listen;
+        self
+    }
+
+    pub(crate) fn environment(mut self, environment: env::Environment) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    /// Add a cache that requests for unknown paths are redirected to. The
+    /// caches are tried in the order they were added.
+    pub fn upstream(mut self, upstream: impl Into<String>) -> Self {
+        self.upstreams.push(upstream.into());
+        self
+    }
+
+    /// Also upload the paths that the upstream cache has, instead of
+    /// checking for each path whether it does.
+    pub fn upload_upstream_paths(mut self, upload_upstream_paths: bool) -> Self {
+        self.upload_upstream_paths = upload_upstream_paths;
+        self
+    }
+
+    /// Compare the NAR hashes of the paths that the upstream cache has with
+    /// its narinfos, and warn about the ones that differ.
+    pub fn check_upstream_hashes(mut self, check_upstream_hashes: bool) -> Self {
+        self.check_upstream_hashes = check_upstream_hashes;
+        self
+    }
+
+    /// Fetch the paths that the cache doesn't have from the upstream cache,
+    /// and store them in the cache, instead of redirecting to it.
+    pub fn pull_through(mut self, pull_through: bool) -> Self {
+        self.pull_through = pull_through;
+        self
+    }
+
+    /// Only serve narinfos from the upstream cache that are signed by `key`
+    /// or another trusted key.
+    pub fn trusted_public_key(mut self, key: impl Into<String>) -> Self {
+        self.trusted_public_keys.push(key.into());
+        self
+    }
+
+    /// Treat `SIGTERM` and `SIGINT` as the job being cancelled: finish
+    /// uploading small paths only, then shut down.
+    pub fn cancel_on_signals(mut self, cancel_on_signals: bool) -> Self {
+        self.cancel_on_signals = cancel_on_signals;
+        self
+    }
+
+    /// Whether to use the GitHub Actions Cache.
+    ///
+    /// With no preference, it is used unless the FlakeHub cache is available.
+    pub fn use_gha_cache(mut self, use_gha_cache: CacheTrinary) -> Self {
+        self.use_gha_cache = use_gha_cache;
+        self
+    }
+
+    /// Set where the binary cache stores its objects. Defaults to the GitHub Actions Cache.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Set the `netrc` file with the credentials for the backend, or a
+    /// reference to it in a secret manager, see [`crate::secrets`].
+    ///
+    /// Defaults to `~/.netrc`, if it exists.
+    pub fn backend_netrc(mut self, netrc: impl Into<SecretSource>) -> Self {
+        self.backend_netrc = Some(netrc.into());
+        self
+    }
+
+    /// Send at most `requests_per_second` requests to the backend. Requests
+    /// are also paused whenever the backend rate-limits them.
+    pub fn backend_rate_limit(mut self, requests_per_second: u32) -> Self {
+        self.backend_rate_limit = Some(requests_per_second);
+        self
+    }
+
+    /// Fail requests to the backend right away for `open_for` after
+    /// `threshold` of them in a row failed with a 429 or a 5xx.
+    pub fn circuit_breaker(mut self, threshold: usize, open_for: Duration) -> Self {
+        self.circuit_breaker = Some((threshold, open_for)).filter(|_| threshold > 0);
+        self
+    }
+
+    /// Store the paths whose name matches `pattern` in `backend` instead of
+    /// the default backend. The first matching route applies.
+    pub fn route(mut self, pattern: Regex, backend: Backend) -> Self {
+        self.routes.push((pattern, backend));
+        self
+    }
+
+    /// Also upload every path to `backend`, besides the default backend or
+    /// the backend of its route.
+    pub fn extra_backend(mut self, backend: Backend) -> Self {
+        self.extra_backends.push(backend);
+        self
+    }
+
+    /// Set how long requests may take before they are answered with a 504.
+    pub fn request_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.request_timeouts = timeouts;
+        self
+    }
+
+    /// Set how many connections are served at once.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.connection_limits.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Set how long clients may take to send the headers of a request.
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_limits.header_read_timeout = timeout;
+        self
+    }
+
+    /// Set how long clients may pause while sending the body of a request.
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_limits.body_read_timeout = timeout;
+        self
+    }
+
+    /// Keep the entries in the GitHub Actions Cache apart from those of other
+    /// projects in the same repository, under `namespace`.
+    pub fn cache_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.cache_namespace = Some(namespace.into());
+        self
+    }
+
+    /// Write the entries in the GitHub Actions Cache with `version`, e.g. one
+    /// per branch, keeping them apart from those of other versions.
+    pub fn cache_version(mut self, version: impl Into<String>) -> Self {
+        self.cache_version = Some(version.into());
+        self
+    }
+
+    /// Also look up the paths that the cache doesn't have in the entries of
+    /// the GitHub Actions Cache with `version`, without writing to them.
+    /// Versions are tried in the order they were added, and `""` is the
+    /// version of entries written without a cache version.
+    pub fn restore_cache_version(mut self, version: impl Into<String>) -> Self {
+        self.restore_cache_versions.push(version.into());
+        self
+    }
+
+    /// Replace the names in cache keys by their HMAC with `secret`, so that
+    /// they don't reveal which paths are cached. Every daemon sharing the
+    /// cache must use the same secret.
+    pub fn key_secret(mut self, secret: Vec<u8>) -> Self {
+        self.key_secret = Some(secret);
+        self
+    }
+
+    /// Serve admin endpoints like `/nar/?list=1` to requests carrying `token`.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Use the GitHub REST API as the GitHub App in `flags`, instead of with
+    /// `GITHUB_TOKEN`.
+    pub fn github_app(mut self, flags: GitHubAppFlags) -> Self {
+        self.github_app = flags;
+        self
+    }
+
+    /// Also listen on `addr` for requests with the tokens minted at
+    /// `/api/scoped-tokens`, which may only push the paths they were minted
+    /// for.
+    pub fn scoped_listen(mut self, addr: SocketAddr) -> Self {
+        self.scoped_listen = Some(addr);
+        self
+    }
+
+    /// Also serve the binary cache on the address of the Docker bridge, so
+    /// that containers can use it.
+    pub fn container_listen(mut self, enable: bool) -> Self {
+        self.container_listen = enable;
+        self
+    }
+
+    /// Use the FlakeHub cache, authenticating with the given `netrc` file.
+    pub fn flakehub(mut self, api_server: Url, cache_server: Url, netrc: PathBuf) -> Self {
+        self.flakehub = Some(FlakeHubConfig {
+            api_server,
+            cache_server,
+            flake_name: None,
+            auth_method: FlakeHubAuthSource::Netrc(netrc),
+        });
+        self
+    }
+
+    /// Whether to diff the store when a workflow starts and finishes.
+    pub fn diff_store(mut self, diff_store: bool) -> Self {
+        self.diff_store = diff_store;
+        self
+    }
+
+    /// Compute the closures of enqueued paths with `closure_flags`, e.g. to
+    /// upload build-time closures.
+    pub fn closure_flags(mut self, closure_flags: gha::ClosureFlags) -> Self {
+        self.closure_flags = closure_flags;
+        self
+    }
+
+    /// Collect garbage in the Nix store whenever less than `min_free_space`
+    /// bytes are free. Paths that are waiting to be uploaded are kept.
+    pub fn min_free_space(mut self, min_free_space: u64) -> Self {
+        self.min_free_space = Some(min_free_space);
+        self
+    }
+
+    /// Accept at most `max_put_size` bytes over `PUT` in total.
+    pub fn max_put_size(mut self, max_put_size: u64) -> Self {
+        self.max_put_size = Some(max_put_size);
+        self
+    }
+
+    /// Upload at most `max_upload_size` bytes of compressed NARs from the
+    /// queue, starting with the smallest paths.
+    pub fn max_upload_size(mut self, max_upload_size: u64) -> Self {
+        self.max_upload_size = Some(max_upload_size);
+        self
+    }
+
+    /// Don't upload the paths whose NAR is larger than `max_nar_size` bytes,
+    /// unless they are enqueued with `force`.
+    pub fn max_nar_size(mut self, max_nar_size: u64) -> Self {
+        self.max_nar_size = Some(max_nar_size);
+        self
+    }
+
+    /// Also upload the outputs of fixed-output derivations, like the sources
+    /// fetched by `fetchurl`, which are skipped by default since they are
+    /// cheap to download again.
+    pub fn include_fixed_output(mut self, include_fixed_output: bool) -> Self {
+        self.include_fixed_output = include_fixed_output;
+        self
+    }
+
+    /// Run `program` with each store path before uploading it, and skip the
+    /// paths for which it fails, e.g. because a secret scanner found a
+    /// token in them.
+    pub fn scan_command(mut self, program: PathBuf) -> Self {
+        self.scan_command = Some(program);
+        self
+    }
+
+    /// Decide which paths are uploaded, skipped or stored in another backend
+    /// by the rules in the file at `path`, the first matching one applying.
+    pub fn upload_policy(mut self, path: PathBuf) -> Self {
+        self.upload_policy = Some(path);
+        self
+    }
+
+    /// Keep an index of the paths in the cache in the SQLite database at
+    /// `path`, which is also stored in the cache.
+    pub fn narinfo_index(mut self, path: PathBuf) -> Self {
+        self.narinfo_index = Some(path);
+        self
+    }
+
+    /// Keep the paths that are waiting to be uploaded in the file at `path`.
+    pub fn queue_file(mut self, path: PathBuf) -> Self {
+        self.queue_file = Some(path);
+        self
+    }
+
+    /// Enqueue the paths that the previous daemon left in the
+    /// [`queue_file`](Self::queue_file) when starting.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Store the paths whose NAR is smaller than `bundle_below` bytes in
+    /// bundles instead of entries of their own.
+    pub fn bundle_below(mut self, bundle_below: u64) -> Self {
+        self.bundle_below = Some(bundle_below);
+        self
+    }
+
+    /// Upload up to `max_concurrent_uploads` paths at once. Defaults to 1.
+    pub fn max_concurrent_uploads(mut self, max_concurrent_uploads: usize) -> Self {
+        self.max_concurrent_uploads = max_concurrent_uploads.max(1);
+        self
+    }
+
+    /// Retry uploads that fail with a rate limit, a server error or a
+    /// timeout up to `upload_retries` times, with exponential backoff.
+    /// Defaults to 3.
+    pub fn upload_retries(mut self, upload_retries: u32) -> Self {
+        self.upload_retries = upload_retries;
+        self
+    }
+
+    /// Upload the enqueued paths in `order`. Defaults to
+    /// [`gha::UploadOrder::Toposort`].
+    pub fn upload_order(mut self, order: gha::UploadOrder) -> Self {
+        self.upload_order = order;
+        self
+    }
+
+    /// Only upload the paths of enqueued closures that were built or added
+    /// locally, not the ones that were substituted.
+    pub fn upload_built_only(mut self, built_only: bool) -> Self {
+        self.upload_built_only = built_only;
+        self
+    }
+
+    /// Only upload the paths of enqueued closures whose name `name_filter`
+    /// allows. Paths enqueued with `force` are always uploaded.
+    pub fn name_filter(mut self, name_filter: gha::NameFilter) -> Self {
+        self.name_filter = name_filter;
+        self
+    }
+
+    /// Set the directory below which temporary files are kept. Defaults to
+    /// the system's temporary directory.
+    pub fn temp_dir(mut self, temp_dir: PathBuf) -> Self {
+        self.temp_dir = Some(temp_dir);
+        self
+    }
+
+    /// Send the metrics to the StatsD server at `addr`.
+    pub fn statsd(mut self, addr: impl Into<String>) -> Self {
+        self.statsd_addr = Some(addr.into());
+        self
+    }
+
+    /// Send webhook alerts when the thresholds in `flags` are crossed.
+    pub fn alerts(mut self, flags: AlertFlags) -> Self {
+        self.alerts = Some(flags);
+        self
+    }
+
+    /// Fail to start if a requested cache can't be set up, instead of
+    /// continuing without it.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Also store the closure of everything that is enqueued as a single
+    /// archive, and restore it on startup. Requires a `flake.lock` in the
+    /// workspace.
+    pub fn closure_archive(mut self, closure_archive: bool) -> Self {
+        self.closure_archive = closure_archive;
+        self
+    }
+
+    /// When the workflow finishes, rebuild the derivations of `samples`
+    /// random enqueued paths and report the ones that aren't reproducible.
+    pub fn check_reproducibility(mut self, samples: usize) -> Self {
+        self.check_reproducibility = Some(samples).filter(|samples| *samples > 0);
+        self
+    }
+
+    /// Append a JSON line to the file at `path` for every object written to
+    /// or deleted from the cache.
+    pub fn audit_log(mut self, path: PathBuf) -> Self {
+        self.audit_log = Some(path);
+        self
+    }
+
+    /// Don't upload the paths whose name matches `pattern` except in
+    /// scheduled runs, and record them in the [`pending_file`](Self::pending_file).
+    pub fn defer_until_idle(mut self, pattern: Regex) -> Self {
+        self.defer_until_idle.push(pattern);
+        self
+    }
+
+    /// Append the paths whose upload is deferred to the file at `path`.
+    pub fn pending_file(mut self, path: PathBuf) -> Self {
+        self.pending_file = Some(path);
+        self
+    }
+
+    /// Sign the narinfos of uploaded paths with the secret key in the file
+    /// at `path`.
+    pub fn signing_key_file(mut self, path: PathBuf) -> Self {
+        self.signing_key_file = Some(path);
+        self
+    }
+
+    /// Replace the signatures by the public key `key` with `/api/re-sign`,
+    /// since the signing key replaced it.
+    pub fn old_public_key(mut self, key: impl Into<String>) -> Self {
+        self.old_public_keys.push(key.into());
+        self
+    }
+
+    /// Coordinate with the other jobs of the workflow run, so that only one
+    /// of them uploads each path.
+    pub fn elect_uploader(mut self, elect_uploader: bool) -> Self {
+        self.elect_uploader = elect_uploader;
+        self
+    }
+
+    /// Initialize the backends and start serving.
+    pub async fn start(self) -> Result<Server> {
+        temp::init(self.temp_dir.as_deref()).await?;
+
+        if let Some(namespace) = &self.cache_namespace {
+            crate::backend::init_gha_namespace(namespace)?;
+        }
+
+        if let Some(version) = &self.cache_version {
+            crate::backend::init_gha_cache_version(version)?;
+        }
+
+        if !self.restore_cache_versions.is_empty() && self.backend != Backend::Gha {
+            return Err(Error::Config(
+                "Restoring from other cache versions requires the GitHub Actions Cache backend"
+                    .to_owned(),
+            ));
+        }
+
+        let upload_policy = self
+            .upload_policy
+            .as_deref()
+            .map(Policy::load)
+            .transpose()?
+            .map(Arc::new);
+
+        if let Some(secret) = &self.key_secret {
+            // A remote binary cache is served directly, under the real names.
+            if std::iter::once(&self.backend)
+                .chain(self.routes.iter().map(|(_, backend)| backend))
+                .chain(upload_policy.iter().flat_map(|policy| policy.backends()))
+                .chain(&self.extra_backends)
+                .any(|backend| matches!(backend, Backend::Http(_)))
+            {
+                return Err(Error::Config(
+                    "A key secret can't be used with an HTTP backend".to_owned(),
+                ));
+            }
+
+            keys::init_secret(secret.clone())?;
+        }
+
+        if self.pull_through && self.upstreams.is_empty() {
+            return Err(Error::Config(
+                "Pull-through caching requires an upstream cache".to_owned(),
+            ));
+        }
+
+        if self.check_upstream_hashes && (self.upstreams.is_empty() || self.upload_upstream_paths) {
+            return Err(Error::Config(
+                "Checking upstream hashes requires an upstream cache, whose paths aren't uploaded"
+                    .to_owned(),
+            ));
+        }
+
+        let trusted_keys = if self.trusted_public_keys.is_empty() {
+            None
+        } else if self.upstreams.is_empty() {
+            return Err(Error::Config(
+                "Trusted public keys require an upstream cache".to_owned(),
+            ));
+        } else {
+            Some(signing::TrustedKeys::new(&self.trusted_public_keys)?)
+        };
+
+        if self.admin_token.as_deref().is_some_and(str::is_empty) {
+            return Err(Error::Config("The admin token is empty".to_owned()));
+        }
+
+        if let Some(app) = self.github_app.load().await? {
+            github_app::init(app)?;
+        }
+
+        let metrics = Arc::new(telemetry::TelemetryReport::new());
+
+        let statsd = match &self.statsd_addr {
+            Some(addr) => Some(StatsdSink::spawn(addr, metrics.clone()).await?),
+            None => None,
+        };
+
+        let alerts = match &self.alerts {
+            Some(flags) => Some(Alerts::spawn(
+                flags.clone(),
+                metrics.clone(),
+                self.max_put_size,
+                self.max_upload_size,
+            )?),
+            None => None,
+        };
+
+        let store = Arc::new(NixStore::connect()?);
+
+        let narinfo_negative_cache = Arc::new(RwLock::new(HashSet::new()));
+
+        let audit_log = match &self.audit_log {
+            Some(path) => Some(Arc::new(
+                AuditLog::open(path, self.backend.redacted().to_string()).await?,
+            )),
+            None => None,
+        };
+
+        let deferral = match &self.pending_file {
+            _ if self.defer_until_idle.is_empty() => None,
+            None => {
+                return Err(Error::Config(
+                    "Deferring uploads requires a pending file".to_owned(),
+                ))
+            }
+            Some(_) if deferral::is_maintenance_run() => {
+                tracing::info!("Uploading deferred paths, since this is a scheduled run");
+                None
+            }
+            Some(path) => Some(Arc::new(
+                Deferral::open(path, self.defer_until_idle.clone()).await?,
+            )),
+        };
+
+        let election = if self.elect_uploader {
+            let election = Election::from_env();
+            if election.is_none() {
+                tracing::warn!("Not electing an uploader, since there is no workflow run");
+            }
+            election.map(Arc::new)
+        } else {
+            None
+        };
+
+        let cancellation = Arc::new(Cancellation::default());
+
+        // Read before the file is rewritten with this daemon's queue.
+        let resumed = match &self.queue_file {
+            Some(path) if self.resume => match queue_file::read(path).await {
+                Ok(resumed) => resumed,
+                Err(err) => {
+                    tracing::warn!("Not resuming the previous upload queue: {}", err);
+                    Vec::new()
+                }
+            },
+            _ => Vec::new(),
+        };
+
+        let queue_file = self
+            .queue_file
+            .clone()
+            .map(|path| QueueFile::spawn(path, cancellation.clone()));
+
+        let signing_key = match &self.signing_key_file {
+            Some(path) => {
+                let signing_key = signing::load(path).await?;
+                tracing::info!(
+                    "Signing narinfos with the key {}",
+                    signing_key.export_public_key()
+                );
+                Some(Arc::new(signing_key))
+            }
+            None if !self.old_public_keys.is_empty() => {
+                return Err(Error::Config(
+                    "Old public keys require a signing key".to_owned(),
+                ));
+            }
+            None => None,
+        };
+        let signing_keys = signing_key
+            .clone()
+            .map(|signing_key| signing::SigningKeys::new(signing_key, &self.old_public_keys))
+            .transpose()?;
+
+        let flakehub_state = if let Some(flakehub) = &self.flakehub {
+            match flakehub::init_cache(
+                self.environment,
+                &flakehub.api_server,
+                &flakehub.cache_server,
+                &flakehub.flake_name,
+                store.clone(),
+                &flakehub.auth_method,
+            )
+            .await
+            {
+                Ok(state) => {
+                    tracing::info!("FlakeHub cache is enabled.");
+                    Some(state)
+                }
+                Err(err) if self.strict => {
+                    return Err(Error::Config(format!(
+                        "FlakeHub cache initialization failed: {}",
+                        err
+                    )));
+                }
+                Err(err) => {
+                    tracing::error!("FlakeHub cache initialization failed: {}. Unable to authenticate to FlakeHub. Individuals must register at FlakeHub.com; Organizations must create an organization at FlakeHub.com.", err);
+                    println!("::error title={{FlakeHub: Unauthenticated}}::{{Unable to authenticate to FlakeHub. Individuals must register at FlakeHub.com; Organizations must create an organization at FlakeHub.com.}}");
+                    None
+                }
+            }
+        } else {
+            tracing::info!("FlakeHub cache is disabled.");
+            None
+        };
+
+        // The upstream caches are only used along with the backend.
+        let mut upstream_netrc = Vec::new();
+
+        let narinfo_index = match &self.narinfo_index {
+            Some(path) => Some(Arc::new(Index::open(path).await?)),
+            None => None,
+        };
+
+        // An explicitly configured backend is used even if FlakeHub is available.
+        let gha_cache = if (self.use_gha_cache == CacheTrinary::Enabled)
+            || (self.use_gha_cache == CacheTrinary::NoPreference
+                && (flakehub_state.is_none() || self.backend != Backend::Gha))
+        {
+            if self.strict && self.backend == Backend::Gha {
+                gha_cache::Credentials::load_from_env()?;
+            }
+
+            let backend_netrc = crate::backend::read_netrc(self.backend_netrc.as_ref()).await?;
+            upstream_netrc = netrc::upstream_entries(backend_netrc.as_deref(), &self.upstreams)?;
+
+            let uploads = gha::UploadConfig {
+                max_upload_size: self.max_upload_size,
+                max_nar_size: self.max_nar_size,
+                include_fixed_output: self.include_fixed_output,
+                scan_command: self.scan_command.clone(),
+                upload_policy,
+                narinfo_index,
+                queue_file,
+                bundle_below: self.bundle_below,
+                max_concurrent_uploads: self.max_concurrent_uploads,
+                upload_retries: self.upload_retries,
+                upload_order: self.upload_order,
+                built_only: self.upload_built_only,
+                name_filter: self.name_filter.clone(),
+            };
+
+            let gha_cache = gha::GhaCache::new(
+                store.clone(),
+                metrics.clone(),
+                narinfo_negative_cache.clone(),
+                gha::GhaCacheConfig {
+                    backend: &self.backend,
+                    backend_netrc: backend_netrc.as_deref(),
+                    rate_limit: self.backend_rate_limit,
+                    circuit_breaker: self.circuit_breaker,
+                    routes: &self.routes,
+                    extra_backends: &self.extra_backends,
+                    restore_versions: &self.restore_cache_versions,
+                    audit_log: audit_log.clone(),
+                    deferral,
+                    election: election.clone(),
+                    upstream: Some(
+                        Upstream::new(&self.upstreams).check_hashes(self.check_upstream_hashes),
+                    )
+                    .filter(|_| !self.upstreams.is_empty() && !self.upload_upstream_paths),
+                    signing_key: signing_key.clone(),
+                    cancellation: cancellation.clone(),
+                    uploads,
+                },
+            )?;
+
+            if self.backend == Backend::Gha {
+                tracing::info!("Native GitHub Action cache is enabled.");
+            } else {
+                tracing::info!("Binary cache is enabled, using the {}.", self.backend);
+            }
+            Some(gha_cache)
+        } else {
+            if self.environment.has_actions_cache() {
+                tracing::info!("Native GitHub Action cache is disabled.");
+            }
+
+            None
+        };
+
+        let closure_archive = match &gha_cache {
+            Some(gha_cache) if self.closure_archive => {
+                let closure_archive = ClosureArchive::new().await;
+
+                if let Some(closure_archive) = &closure_archive {
+                    if let Err(err) = closure_archive.restore(&gha_cache.api, &store).await {
+                        tracing::warn!("Restoring the closure archive failed: {}", err);
+                    }
+                }
+
+                closure_archive
+            }
+            _ => None,
+        };
+
+        let info = info::Info {
+            version: env!("CARGO_PKG_VERSION"),
+            backends: info::Backends {
+                gha: gha_cache
+                    .as_ref()
+                    .map(|_| self.backend.redacted().to_string()),
+                flakehub: flakehub_state.as_ref().and_then(|_| {
+                    let flakehub = self.flakehub.as_ref()?;
+                    Some(info::redact_url(&flakehub.cache_server).to_string())
+                }),
+            },
+            config: info::Config {
+                listen: self.listen,
+                scoped_listen: self.scoped_listen,
+                container_listen: self.container_listen,
+                upstreams: self
+                    .upstreams
+                    .iter()
+                    .map(|upstream| info::redact(upstream))
+                    .collect(),
+                upload_upstream_paths: self.upload_upstream_paths,
+                check_upstream_hashes: self.check_upstream_hashes,
+                pull_through: self.pull_through,
+                trusted_public_keys: self.trusted_public_keys.clone(),
+                backend: self.backend.redacted().to_string(),
+                backend_netrc: self.backend_netrc.is_some(),
+                backend_rate_limit: self.backend_rate_limit,
+                circuit_breaker_threshold: self.circuit_breaker.map(|(threshold, _)| threshold),
+                circuit_breaker_duration: self
+                    .circuit_breaker
+                    .map(|(_, open_for)| open_for.as_secs()),
+                routes: self
+                    .routes
+                    .iter()
+                    .map(|(pattern, backend)| format!("{} -> {}", pattern, backend.redacted()))
+                    .collect(),
+                extra_backends: self
+                    .extra_backends
+                    .iter()
+                    .map(|backend| backend.redacted().to_string())
+                    .collect(),
+                cache_namespace: self.cache_namespace.clone(),
+                cache_version: self.cache_version.clone(),
+                restore_cache_versions: self.restore_cache_versions.clone(),
+                key_secret: self.key_secret.is_some(),
+                admin_token: self.admin_token.is_some(),
+                github_app_id: self.github_app.app_id,
+                diff_store: self.diff_store,
+                closure: self.closure_flags,
+                min_free_space: self.min_free_space,
+                max_put_size: self.max_put_size,
+                max_upload_size: self.max_upload_size,
+                max_nar_size: self.max_nar_size,
+                include_fixed_output: self.include_fixed_output,
+                narinfo_index: self
+                    .narinfo_index
+                    .as_ref()
+                    .map(|path| path.display().to_string()),
+                queue_file: self
+                    .queue_file
+                    .as_ref()
+                    .map(|path| path.display().to_string()),
+                resume: self.resume,
+                upload_policy: self
+                    .upload_policy
+                    .as_ref()
+                    .map(|path| path.display().to_string()),
+                scan_command: self
+                    .scan_command
+                    .as_ref()
+                    .map(|program| program.display().to_string()),
+                bundle_below: self.bundle_below,
+                max_concurrent_uploads: self.max_concurrent_uploads,
+                upload_retries: self.upload_retries,
+                upload_order: self.upload_order,
+                upload_built_only: self.upload_built_only,
+                include_regex: self
+                    .name_filter
+                    .include
+                    .iter()
+                    .map(|pattern| pattern.to_string())
+                    .collect(),
+                exclude_regex: self
+                    .name_filter
+                    .exclude
+                    .iter()
+                    .map(|pattern| pattern.to_string())
+                    .collect(),
+                temp_dir: temp::dir().to_owned(),
+                statsd_addr: self.statsd_addr.clone(),
+                alerts: self.alerts.clone(),
+                strict: self.strict,
+                closure_archive: closure_archive.is_some(),
+                check_reproducibility: self.check_reproducibility,
+                audit_log: audit_log.is_some(),
+                defer_until_idle: self
+                    .defer_until_idle
+                    .iter()
+                    .map(|pattern| pattern.to_string())
+                    .collect(),
+                elect_uploader: election.is_some(),
+                signing_key: signing_key.as_ref().map(|key| key.export_public_key()),
+                old_public_keys: self.old_public_keys.clone(),
+                flakehub: self.flakehub.as_ref().map(|flakehub| info::FlakeHubConfig {
+                    api_server: info::redact_url(&flakehub.api_server).to_string(),
+                    cache_server: info::redact_url(&flakehub.cache_server).to_string(),
+                    flake_name: flakehub.flake_name.clone(),
+                }),
+            },
+            features: info::features(self.statsd_addr.is_some(), self.min_free_space.is_some()),
+            environment: info::Environment::detect(self.environment).await,
+        };
+
+        if let Some(min_free_space) = self.min_free_space {
+            disk_guard::spawn(store.store_dir().to_owned(), min_free_space);
+        }
+
+        let bridge_ip = if self.container_listen {
+            Some(container::bridge_address().await?)
+        } else {
+            None
+        };
+
+        let scoped_listener = match self.scoped_listen {
+            Some(addr) => Some(
+                tokio::net::TcpListener::bind(addr)
+                    .await
+                    .map_err(|e| Error::Io(e, format!("Listening on {}", addr)))?,
+            ),
+            None => None,
+        };
+        let scoped_tokens = scoped_listener
+            .as_ref()
+            .map(|listener| listener.local_addr().map(ScopedTokens::new))
+            .transpose()
+            .map_err(|e| Error::Io(e, "Getting the scoped listening address".to_owned()))?;
+
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
+        let original_paths = self.diff_store.then_some(Mutex::new(HashSet::new()));
+        let state = Arc::new(StateInner {
+            gha_cache,
+            upstream_client: Some(Upstream::new(&self.upstreams)).filter(|_| {
+                !self.upstreams.is_empty()
+                    && (self.pull_through || trusted_keys.is_some() || self.upstreams.len() > 1)
+            }),
+            pull_through: self.pull_through,
+            trusted_keys,
+            signing_keys,
+            upstream: self.upstreams.into_iter().next(),
+            admin_token: self.admin_token,
+            scoped_tokens,
+            upstream_netrc,
+            cancellation,
+            steps: Default::default(),
+            closure_flags: self.closure_flags,
+            lifecycle: Lifecycle::new(),
+            shutdown_sender: Mutex::new(Some(shutdown_sender)),
+            narinfo_negative_cache,
+            narinfo_cache: Mutex::new(binary_cache::NarinfoCache::new(NARINFO_CACHE_CAPACITY)),
+            written_narinfos: RwLock::new(HashMap::new()),
+            metrics,
+            store,
+            flakehub_state: RwLock::new(flakehub_state),
+            logfile: self.logfile,
+            original_paths,
+            closure_archive,
+            repro_check: self.check_reproducibility.map(ReproCheck::new),
+            audit_log,
+            timeouts: self.request_timeouts,
+            put_quota: self.max_put_size.map(quota::PutQuota::new),
+            info,
+        });
+
+        if self.cancel_on_signals {
+            cancellation::spawn(state.clone());
+        }
+
+        // The paths may have been garbage-collected since.
+        let resumed = resumed
+            .iter()
+            .filter(|path| Path::new(path).exists())
+            .filter_map(|path| state.store.follow_store_path(path).ok())
+            .collect::<Vec<_>>();
+
+        if !resumed.is_empty() && state.gha_cache.is_some() {
+            tracing::info!(
+                "Resuming the upload of {} paths from the previous queue",
+                resumed.len()
+            );
+
+            let state = state.clone();
+            tokio::task::spawn(async move {
+                if let Err(err) = api::enqueue_paths(&state, resumed, false).await {
+                    tracing::warn!("Failed to resume the previous upload queue: {}", err);
+                }
+            });
+        }
+
+        let app = Router::new()
+            .route("/", get(root))
+            .merge(api::get_router())
+            .merge(binary_cache::get_router())
+            .merge(source::get_router());
+
+        #[cfg(debug_assertions)]
+        let app = app
+            .layer(tower_http::trace::TraceLayer::new_for_http())
+            .layer(axum::middleware::from_fn(dump_api_stats));
+
+        let body_read_timeout = self.connection_limits.body_read_timeout;
+        let app = with_middleware(app, &state, body_read_timeout);
+
+        // The other listeners stop with the main one.
+        let mut other_serves = Vec::new();
+
+        if let Some(listener) = scoped_listener {
+            tracing::info!(
+                "Listening for scoped tokens on {}",
+                state
+                    .scoped_tokens
+                    .as_ref()
+                    .map_or("", |tokens| tokens.url.as_str())
+            );
+
+            let app =
+                binary_cache::get_router().layer(axum::middleware::from_fn(scoped::middleware));
+            let app = with_middleware(app, &state, body_read_timeout);
+
+            other_serves.push(tokio::task::spawn(listener::serve(
+                listener,
+                app,
+                self.connection_limits.clone(),
+                std::future::pending(),
+            )));
+        }
+
+        let listener = tokio::net::TcpListener::bind(&self.listen)
+            .await
+            .map_err(|e| Error::Io(e, format!("Listening on {}", self.listen)))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| Error::Io(e, "Getting the listening address".to_owned()))?;
+
+        tracing::info!("Listening on {}", local_addr);
+
+        // Containers reach the bridge at the port they'd expect.
+        let container_addr = match bridge_ip {
+            Some(bridge_ip) => {
+                let addr = SocketAddr::new(bridge_ip, local_addr.port());
+                let listener = tokio::net::TcpListener::bind(addr)
+                    .await
+                    .map_err(|e| Error::Io(e, format!("Listening on {}", addr)))?;
+
+                tracing::info!("Listening for containers on {}", addr);
+
+                let app = binary_cache::get_router()
+                    .layer(axum::middleware::from_fn(scoped::push_middleware));
+                let app = with_middleware(app, &state, body_read_timeout);
+                other_serves.push(tokio::task::spawn(listener::serve(
+                    listener,
+                    app,
+                    self.connection_limits.clone(),
+                    std::future::pending(),
+                )));
+
+                Some(addr)
+            }
+            None => None,
+        };
+
+        let serve = tokio::task::spawn(async move {
+            let result = listener::serve(listener, app, self.connection_limits, async move {
+                shutdown_receiver.await.ok();
+                tracing::info!("Shutting down");
+            })
+            .await;
+
+            for other_serve in other_serves {
+                other_serve.abort();
+            }
+
+            result
+        });
+
+        Ok(Server {
+            local_addr,
+            container_addr,
+            state,
+            serve,
+            statsd,
+            alerts,
+        })
+    }
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address the server is listening on for containers, if it does.
+    pub fn container_addr(&self) -> Option<SocketAddr> {
+        self.container_addr
+    }
+
+    pub(crate) fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Report that Nix is set up to use the server. Until then, it is
+    /// configuring.
+    pub fn ready(&self) {
+        self.state.lifecycle.advance(Phase::Serving);
+    }
+
+    /// Wait for pending uploads to finish, then stop the server.
+    pub async fn stop(self) -> Result<()> {
+        api::shutdown(&self.state).await?;
+        self.wait().await
+    }
+
+    /// Wait until the server stops, e.g. after a `/api/workflow-finish` request.
+    pub async fn wait(self) -> Result<()> {
+        let result = self
+            .serve
+            .await
+            .map_err(|e| Error::Internal(format!("Server task failed: {}", e)))?
+            .map_err(|e| Error::Io(e, "Serving".to_owned()));
+
+        if let Some(statsd) = &self.statsd {
+            statsd.flush().await;
+        }
+
+        if let Some(alerts) = &self.alerts {
+            alerts.check(true).await;
+        }
+
+        temp::cleanup().await;
+
+        result
+    }
+}
+
+/// Add the middleware that the routes of every listener go through.
+fn with_middleware(app: Router, state: &State, body_read_timeout: Duration) -> Router {
+    app.layer(axum::middleware::from_fn(
+        move |request: axum::extract::Request, next: axum::middleware::Next| {
+            listener::limit_body(request, next, body_read_timeout)
+        },
+    ))
+    .layer(axum::middleware::from_fn(quota::middleware))
+    .layer(axum::middleware::from_fn(timeout::middleware))
+    .layer(axum::middleware::from_fn(request_id::middleware))
+    .layer(Extension(state.clone()))
+}
+
+#[cfg(debug_assertions)]
+async fn dump_api_stats(
+    Extension(state): Extension<State>,
+    request: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    next.run(request).await
+}
+
+async fn root() -> &'static str {
+    "cache the world 🚀"
+}
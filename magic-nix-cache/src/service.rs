@@ -0,0 +1,79 @@
+//! Storage in any service that opendal supports, e.g. Google Cloud Storage
+//! or Azure Blob Storage.
+//!
+//! The backend is given as `opendal:<scheme>?<key>=<value>&…`, with the
+//! options of the opendal service, like
+//! `opendal:gcs?bucket=nix-cache&root=/ci`. Credentials are best left to the
+//! environment variables that the services read, since the backend ends up
+//! in logs and `/api/info`; the values of options that look like secrets are
+//! hidden there anyway.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use opendal::layers::RetryLayer;
+use opendal::{Operator, Scheme};
+
+use crate::error::{Error, Result};
+
+/// Parts of option keys that mark their values as secret.
+const SECRET_KEYS: &[&str] = &["key", "secret", "password", "token", "credential", "sas"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceConfig {
+    pub scheme: Scheme,
+    pub options: Vec<(String, String)>,
+}
+
+impl ServiceConfig {
+    /// Parse the part of a backend specification after `opendal:`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (scheme, options) = spec.split_once('?').unwrap_or((spec, ""));
+
+        let scheme = Scheme::from_str(scheme)
+            .map_err(|e| Error::Config(format!("unknown opendal service '{}': {}", scheme, e)))?;
+
+        let options = options
+            .split('&')
+            .filter(|option| !option.is_empty())
+            .map(|option| {
+                option
+                    .split_once('=')
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .ok_or_else(|| {
+                        Error::Config(format!(
+                            "bad option '{}' for the opendal service {}, expected '<key>=<value>'",
+                            option, scheme
+                        ))
+                    })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(ServiceConfig { scheme, options })
+    }
+
+    pub fn operator(&self) -> Result<Operator> {
+        Ok(Operator::via_iter(self.scheme, self.options.clone())?
+            .layer(RetryLayer::new().with_jitter()))
+    }
+}
+
+impl Display for ServiceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "opendal {} service", self.scheme)?;
+
+        for (i, (key, value)) in self.options.iter().enumerate() {
+            let secret = SECRET_KEYS.iter().any(|part| key.contains(part));
+
+            write!(
+                f,
+                "{}{}={}",
+                if i == 0 { " with " } else { ", " },
+                key,
+                if secret { "***" } else { value }
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+//! Signing of uploaded narinfos, and verification of upstream ones.
+//!
+//! With `--signing-key-file`, the narinfos of the paths that the daemon
+//! uploads carry a `Sig:` field made with that key, so that the cache can be
+//! used with `require-sigs` enabled. Narinfos uploaded over `PUT` are stored
+//! as they are, since `nix copy` signs them itself when given a key.
+//!
+//! To rotate the key, pass the public keys it replaces with
+//! `--old-public-keys`. `POST /api/re-sign` then signs the narinfos in the
+//! cache with the new key, dropping the signatures by the old ones.
+//!
+//! With `--trusted-public-keys`, narinfos from the upstream cache are fetched
+//! by the daemon instead of redirected to, and only served if they are signed
+//! by one of the keys.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use attic::signing::{NixKeypair, NixPublicKey};
+use opendal::Operator;
+use serde::Serialize;
+
+use crate::audit::AuditLog;
+use crate::error::{Error, Result};
+use crate::keys;
+
+/// Read the secret key in the file at `path`, as written by
+/// `nix key generate-secret`, e.g. `cache.example.com-1:<base64>`.
+pub async fn load(path: &Path) -> Result<NixKeypair> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| Error::Io(e, format!("Reading the signing key {}", path.display())))?;
+
+    NixKeypair::from_str(content.trim()).map_err(|e| {
+        Error::Config(format!(
+            "The signing key in {} isn't valid: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// The key that narinfos are signed with, and the keys that signed them
+/// before it.
+pub struct SigningKeys {
+    primary: Arc<NixKeypair>,
+    old: Vec<(String, NixPublicKey)>,
+}
+
+impl SigningKeys {
+    /// Sign with `primary`, which replaces the public keys `old`, like
+    /// `cache.example.com-1:<base64>`.
+    pub fn new(primary: Arc<NixKeypair>, old: &[String]) -> Result<SigningKeys> {
+        let old = old
+            .iter()
+            .map(|key| {
+                let public_key = NixPublicKey::from_str(key).map_err(|e| {
+                    Error::Config(format!("The old public key '{}' isn't valid: {}", key, e))
+                })?;
+                Ok((key.clone(), public_key))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(SigningKeys { primary, old })
+    }
+
+    /// The public keys of the primary key and the old keys, which the
+    /// narinfos in the cache may be signed with until they are re-signed.
+    pub fn public_keys(&self) -> Vec<String> {
+        std::iter::once(self.primary.export_public_key())
+            .chain(self.old.iter().map(|(key, _)| key.clone()))
+            .collect()
+    }
+
+    /// `narinfo` signed with the primary key instead of the old keys, or
+    /// `None` if the primary key already signed it.
+    pub fn re_sign(&self, narinfo: &str) -> Option<String> {
+        let fingerprint = fingerprint(narinfo)?;
+        let signed_by_primary = narinfo
+            .lines()
+            .filter_map(|line| line.strip_prefix("Sig: "))
+            .any(|signature| {
+                self.primary
+                    .verify(fingerprint.as_bytes(), signature.trim())
+                    .is_ok()
+            });
+
+        if signed_by_primary {
+            return None;
+        }
+
+        // Signatures by other keys, e.g. from `nix copy`, are kept.
+        let mut lines = narinfo
+            .lines()
+            .filter(|line| match line.strip_prefix("Sig: ") {
+                Some(signature) => !self
+                    .old
+                    .iter()
+                    .any(|(_, key)| key.verify(fingerprint.as_bytes(), signature.trim()).is_ok()),
+                None => true,
+            })
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        lines.push(format!(
+            "Sig: {}",
+            self.primary.sign(fingerprint.as_bytes())
+        ));
+
+        Some(lines.join("\n") + "\n")
+    }
+}
+
+/// What `/api/re-sign` did.
+#[derive(Debug, Default, Serialize)]
+pub struct ReSignReport {
+    pub narinfos: usize,
+    pub re_signed: usize,
+    pub failed: usize,
+}
+
+/// Sign the narinfos in the backend `api` with the primary key of
+/// `signing_keys`, unless it already signed them.
+pub async fn re_sign_all(
+    api: &Operator,
+    signing_keys: &SigningKeys,
+    audit_log: Option<&AuditLog>,
+    report: &mut ReSignReport,
+) -> Result<()> {
+    let prefix = keys::narinfos();
+
+    let entries = if api.info().scheme() == opendal::Scheme::Ghac {
+        // The cache service can't list entries, but the REST API can.
+        crate::backend::gha_rest_api()
+            .await?
+            .list(&prefix)
+            .await?
+            .into_iter()
+            .map(|entry| crate::backend::gha_key(&entry.key))
+            .collect::<Vec<_>>()
+    } else if api.info().full_capability().list {
+        api.list(&prefix)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.metadata().is_file())
+            .map(|entry| entry.path().to_owned())
+            .collect()
+    } else {
+        tracing::warn!(
+            "Not re-signing the narinfos in the {} backend, which can't list entries",
+            api.info().scheme()
+        );
+        return Ok(());
+    };
+
+    for key in entries.iter().filter(|key| key.ends_with(".narinfo")) {
+        report.narinfos += 1;
+
+        match re_sign_entry(api, signing_keys, audit_log, key).await {
+            Ok(true) => report.re_signed += 1,
+            Ok(false) => {}
+            Err(err) => {
+                tracing::warn!("Re-signing '{}' failed: {}", key, err);
+                report.failed += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Re-sign the narinfo under `key`, returning whether it was rewritten.
+async fn re_sign_entry(
+    api: &Operator,
+    signing_keys: &SigningKeys,
+    audit_log: Option<&AuditLog>,
+    key: &str,
+) -> Result<bool> {
+    let narinfo = api.read(key).await?.to_vec();
+    let Some(narinfo) = signing_keys.re_sign(&String::from_utf8_lossy(&narinfo)) else {
+        return Ok(false);
+    };
+
+    // Not every backend can overwrite entries.
+    crate::gha::remove_existing(api, audit_log, key).await?;
+    api.write(key, narinfo).await?;
+
+    Ok(true)
+}
+
+/// The keys that narinfos from the upstream cache must be signed with.
+pub struct TrustedKeys(Vec<NixPublicKey>);
+
+impl TrustedKeys {
+    /// Parse keys like `cache.nixos.org-1:<base64>`.
+    pub fn new(keys: &[String]) -> Result<TrustedKeys> {
+        keys.iter()
+            .map(|key| {
+                NixPublicKey::from_str(key).map_err(|e| {
+                    Error::Config(format!(
+                        "The trusted public key '{}' isn't valid: {}",
+                        key, e
+                    ))
+                })
+            })
+            .collect::<Result<_>>()
+            .map(TrustedKeys)
+    }
+
+    /// Whether `narinfo` has a valid signature by one of the keys.
+    pub fn verify(&self, narinfo: &str) -> bool {
+        let Some(fingerprint) = fingerprint(narinfo) else {
+            return false;
+        };
+
+        narinfo
+            .lines()
+            .filter_map(|line| line.strip_prefix("Sig: "))
+            .any(|signature| {
+                self.0
+                    .iter()
+                    .any(|key| key.verify(fingerprint.as_bytes(), signature.trim()).is_ok())
+            })
+    }
+}
+
+/// The message that the signatures of `narinfo` sign, like
+/// `1;<store path>;<NAR hash>;<NAR size>;<references>`.
+fn fingerprint(narinfo: &str) -> Option<String> {
+    let field = |name| {
+        narinfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(str::trim)
+    };
+
+    let store_path = field("StorePath: ")?;
+    let (store_dir, _) = store_path.rsplit_once('/')?;
+
+    let references = field("References: ")
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|reference| format!("{}/{}", store_dir, reference))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    Some(format!(
+        "1;{};{};{};{}",
+        store_path,
+        field("NarHash: ")?,
+        field("NarSize: ")?,
+        references
+    ))
+}
@@ -0,0 +1,167 @@
+//! Cache for source trees, e.g. flake inputs.
+//!
+//! A source tree is uploaded as a NAR to `/source/<hash>`, where `<hash>` is
+//! the base-32 SHA-256 NAR hash of the tree (the `narHash` of a flake input).
+//! Matrix jobs that check out the same inputs can then fetch them from the
+//! cache instead of from GitHub.
+
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use attic::hash::Hash;
+use axum::{
+    body::Body,
+    extract::{Extension, Path},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use futures::StreamExt as _;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt as _, BufReader};
+use tokio_util::{
+    compat::FuturesAsyncReadCompatExt,
+    io::{ReaderStream, StreamReader},
+};
+
+use super::State;
+use crate::audit::{self, Action};
+use crate::error::{Error, Result};
+use crate::upload::Upload;
+use crate::{keys, temp};
+
+/// Characters used by Nix's base-32 encoding.
+const NIX32_CHARS: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+pub fn get_router() -> Router {
+    Router::new().route("/source/:hash", get(get_source).put(put_source))
+}
+
+async fn get_source(
+    Extension(state): Extension<State>,
+    Path(hash): Path<String>,
+) -> Result<impl IntoResponse> {
+    validate_hash(&hash)?;
+
+    let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
+
+    if !gha_cache.health.is_available() {
+        return Err(Error::BackendUnavailable);
+    }
+
+    let key = keys::source(&hash);
+
+    // Fail with a 404 now rather than in the middle of the response.
+    gha_cache.api.stat(&key).await?;
+
+    let reader = gha_cache
+        .api
+        .reader(&key)
+        .await?
+        .into_futures_async_read(..)
+        .await?;
+
+    state.metrics.sources_served.incr();
+
+    let nar = ZstdDecoder::new(BufReader::new(reader.compat()));
+
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, "application/x-nix-nar")],
+        Body::from_stream(ReaderStream::new(nar)),
+    ))
+}
+
+async fn put_source(
+    Extension(state): Extension<State>,
+    Path(hash): Path<String>,
+    body: Body,
+) -> Result<()> {
+    validate_hash(&hash)?;
+
+    let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
+
+    if !gha_cache.health.is_available() {
+        return Err(Error::BackendUnavailable);
+    }
+
+    // Spool the NAR to disk, so that it can be checked against the hash
+    // before anything is uploaded. Concurrent PUTs of the same tree each
+    // get their own file.
+    let spool_path = temp::dir().join(format!("source-{}-{}.nar", hash, uuid::Uuid::new_v4()));
+    let mut spool = tokio::fs::OpenOptions::new()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(&spool_path)
+        .await
+        .map_err(|e| Error::Io(e, format!("Creating {}", spool_path.display())))?;
+
+    let result = async {
+        let mut body =
+            StreamReader::new(body.into_data_stream().map(|r| {
+                r.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+            }));
+
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+
+        loop {
+            let n = body.read(&mut buf).await?;
+
+            if n == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..n]);
+            spool.write_all(&buf[..n]).await?;
+        }
+
+        if Hash::Sha256(hasher.finalize().into()).to_base32() != hash {
+            tracing::warn!("Rejecting source '{}' whose NAR hash doesn't match", hash);
+            return Err(Error::BadRequest);
+        }
+
+        spool.flush().await?;
+        spool.rewind().await?;
+
+        let mut compressor = ZstdEncoder::new(BufReader::new(&mut spool));
+
+        let key = keys::source(&hash);
+        let mut upload = Upload::new(gha_cache.api.writer(&key).await?, &key);
+
+        let size = upload.copy(&mut compressor).await?;
+
+        upload.close().await?;
+
+        Ok(size)
+    }
+    .await;
+
+    if let Err(err) = tokio::fs::remove_file(&spool_path).await {
+        tracing::debug!("Failed to remove {}: {}", spool_path.display(), err);
+    }
+
+    let size = result?;
+
+    state.metrics.sources_uploaded.incr();
+
+    audit::record(
+        state.audit_log.as_deref(),
+        Action::Upload,
+        audit::Entry {
+            key: &keys::source(&hash),
+            size: Some(size),
+            nar_hash: Some(&hash),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    Ok(())
+}
+
+fn validate_hash(hash: &str) -> Result<()> {
+    if hash.len() == 52 && hash.chars().all(|c| NIX32_CHARS.contains(c)) {
+        Ok(())
+    } else {
+        Err(Error::BadRequest)
+    }
+}
@@ -0,0 +1,113 @@
+//! Exporting the telemetry counters to StatsD.
+//!
+//! Counters are sent as the increase since the previous flush, and gauges
+//! such as `elapsed_seconds` as their current value. Tags are added in the
+//! DogStatsD format, which plain StatsD servers ignore.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::telemetry::TelemetryReport;
+
+/// How often the counters are sent.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+const PREFIX: &str = "magic_nix_cache";
+
+/// Metrics that are sent as gauges rather than counters.
+const GAUGES: &[&str] = &[
+    "elapsed_seconds",
+    "num_original_paths",
+    "num_final_paths",
+    "num_new_paths",
+];
+
+/// Environment variables whose values are added as tags.
+const TAGS: &[(&str, &str)] = &[
+    ("repository", "GITHUB_REPOSITORY"),
+    ("workflow", "GITHUB_WORKFLOW"),
+    ("job", "GITHUB_JOB"),
+];
+
+pub struct StatsdSink {
+    socket: UdpSocket,
+    metrics: Arc<TelemetryReport>,
+    tags: String,
+
+    /// The counter values at the previous flush.
+    sent: Mutex<HashMap<String, u64>>,
+}
+
+impl StatsdSink {
+    /// Start sending `metrics` to the StatsD server at `addr`.
+    pub async fn spawn(addr: &str, metrics: Arc<TelemetryReport>) -> Result<Arc<StatsdSink>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .map_err(|e| Error::Io(e, "Creating the StatsD socket".to_owned()))?;
+        socket
+            .connect(addr)
+            .await
+            .map_err(|e| Error::Io(e, format!("Connecting to StatsD at {}", addr)))?;
+
+        let tags = TAGS
+            .iter()
+            .filter_map(|(tag, var)| Some(format!("{}:{}", tag, std::env::var(var).ok()?)))
+            .collect::<Vec<_>>();
+        let tags = if tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", tags.join(","))
+        };
+
+        let sink = Arc::new(StatsdSink {
+            socket,
+            metrics,
+            tags,
+            sent: Mutex::new(HashMap::new()),
+        });
+
+        let sink2 = sink.clone();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(FLUSH_INTERVAL).await;
+                sink2.flush().await;
+            }
+        });
+
+        Ok(sink)
+    }
+
+    /// Send the metrics that changed since the previous flush.
+    pub async fn flush(&self) {
+        self.metrics.update_elapsed();
+
+        let mut sent = self.sent.lock().await;
+
+        for (name, value) in self.metrics.counters() {
+            let line = if GAUGES.contains(&name.as_str()) {
+                format!("{}.{}:{}|g{}", PREFIX, name, value, self.tags)
+            } else {
+                let previous = sent.get(&name).copied().unwrap_or(0);
+                if value <= previous {
+                    continue;
+                }
+                format!("{}.{}:{}|c{}", PREFIX, name, value - previous, self.tags)
+            };
+
+            // StatsD is best-effort; a failed send is retried with the next flush.
+            match self.socket.send(line.as_bytes()).await {
+                Ok(_) => {
+                    sent.insert(name, value);
+                }
+                Err(err) => {
+                    tracing::debug!("Sending to StatsD failed: {}", err);
+                }
+            }
+        }
+    }
+}
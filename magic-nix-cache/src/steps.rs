@@ -0,0 +1,107 @@
+//! Accounting of cache traffic per workflow step.
+//!
+//! A workflow can bracket its steps with `/api/step-start` and
+//! `/api/step-end`, and the daemon attributes the change in its metrics in
+//! between, like the NAR bytes served and uploaded, to the step. Uploads run
+//! in the background, so they count for the step during which they finish.
+//! Finished steps are logged and reported by `/api/stats`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::telemetry::TelemetryReport;
+
+/// Counters that aren't traffic, so they aren't attributed to steps.
+const IGNORED_COUNTERS: &[&str] = &["elapsed_seconds"];
+
+#[derive(Default)]
+pub struct Steps {
+    running: Mutex<Option<RunningStep>>,
+    finished: Mutex<Vec<Step>>,
+}
+
+struct RunningStep {
+    name: String,
+    started: Instant,
+    counters: HashMap<String, u64>,
+}
+
+/// A finished step and the metrics it changed.
+#[derive(Clone, Debug, Serialize)]
+pub struct Step {
+    pub name: String,
+    pub duration_secs: f64,
+    pub metrics: BTreeMap<String, u64>,
+}
+
+impl Steps {
+    /// Start the step `name`, ending the one that is running, if any.
+    pub async fn start(&self, name: String, metrics: &TelemetryReport) {
+        let mut running = self.running.lock().await;
+
+        if let Some(step) = running.take() {
+            tracing::debug!("Step '{}' started before '{}' ended", name, step.name);
+            self.finish(step, metrics).await;
+        }
+
+        tracing::info!("Step '{}' started", name);
+
+        *running = Some(RunningStep {
+            name,
+            started: Instant::now(),
+            counters: metrics.counters().into_iter().collect(),
+        });
+    }
+
+    /// End the running step, which must be `name` if that is given.
+    pub async fn end(&self, name: Option<&str>, metrics: &TelemetryReport) -> Result<Step> {
+        let mut running = self.running.lock().await;
+
+        let step = running.take().ok_or(Error::BadRequest)?;
+
+        if name.is_some_and(|name| name != step.name) {
+            *running = Some(step);
+            return Err(Error::BadRequest);
+        }
+
+        Ok(self.finish(step, metrics).await)
+    }
+
+    /// The steps that have ended, in order.
+    pub async fn finished(&self) -> Vec<Step> {
+        self.finished.lock().await.clone()
+    }
+
+    async fn finish(&self, step: RunningStep, metrics: &TelemetryReport) -> Step {
+        let changed = metrics
+            .counters()
+            .into_iter()
+            .filter(|(name, _)| !IGNORED_COUNTERS.contains(&name.as_str()))
+            .filter_map(|(name, value)| {
+                let delta = value.saturating_sub(step.counters.get(&name).copied().unwrap_or(0));
+                (delta > 0).then_some((name, delta))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let step = Step {
+            name: step.name,
+            duration_secs: step.started.elapsed().as_secs_f64(),
+            metrics: changed,
+        };
+
+        tracing::info!(
+            "Step '{}' ended after {:.1}s: {:?}",
+            step.name,
+            step.duration_secs,
+            step.metrics
+        );
+
+        self.finished.lock().await.push(step.clone());
+
+        step
+    }
+}
@@ -1,8 +1,41 @@
+use std::collections::BTreeMap;
 use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::SystemTime;
 
 use sha2::{Digest, Sha256};
 
+/// Where the telemetry report is sent at the end of the job.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiagnosticSink {
+    /// Posted as JSON to an HTTP endpoint.
+    Http(String),
+
+    /// Written as JSON to a local file, without any network calls.
+    File(PathBuf),
+
+    Disabled,
+}
+
+impl FromStr for DiagnosticSink {
+    type Err = String;
+
+    /// Parse an HTTP(S) URL, `file:<path>`, or the empty string or `none`
+    /// to disable reporting.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s.is_empty() || s == "none" => Ok(DiagnosticSink::Disabled),
+            Some(("http" | "https", _)) => Ok(DiagnosticSink::Http(s.to_owned())),
+            Some(("file", path)) if !path.is_empty() => Ok(DiagnosticSink::File(path.into())),
+            _ => Err(format!(
+                "invalid diagnostic endpoint '{}', expected an HTTP(S) URL, 'file:<path>' or ''",
+                s
+            )),
+        }
+    }
+}
+
 /// A telemetry report to measure the effectiveness of the Magic Nix Cache
 #[derive(Debug, Default, serde::Serialize)]
 pub struct TelemetryReport {
@@ -16,22 +49,87 @@ pub struct TelemetryReport {
     elapsed_seconds: Metric,
 
     pub narinfos_served: Metric,
+    pub narinfos_served_stale: Metric,
     pub narinfos_sent_upstream: Metric,
+    pub narinfos_pulled_through: Metric,
+    pub narinfos_rejected_unsigned: Metric,
     pub narinfos_negative_cache_hits: Metric,
     pub narinfos_negative_cache_misses: Metric,
     pub narinfos_uploaded: Metric,
 
     pub nars_served: Metric,
+    pub nar_bytes_served: Metric,
     pub nars_sent_upstream: Metric,
+    pub nars_pulled_through: Metric,
     pub nars_uploaded: Metric,
+    pub nar_bytes_uploaded: Metric,
+    pub nars_checksum_deployed: Metric,
+    pub nars_corrupt: Metric,
+    pub paths_bundled: Metric,
+
+    pub sources_served: Metric,
+    pub sources_uploaded: Metric,
+
+    pub uploads_skipped_unhealthy: Metric,
+    pub uploads_skipped_manifest: Metric,
+    pub uploads_skipped_substituted: Metric,
+    pub uploads_skipped_not_built: Metric,
+    pub uploads_excluded: Metric,
+    pub uploads_skipped_claimed: Metric,
+    pub uploads_skipped_upstream: Metric,
+    pub upstream_hash_mismatches: Metric,
+    pub uploads_deduplicated: Metric,
+    pub nar_dedup_hits: Metric,
+    pub uploads_deferred: Metric,
+    pub uploads_skipped_cancelled: Metric,
+    pub uploads_skipped_budget: Metric,
+    pub uploads_skipped_too_large: Metric,
+    pub uploads_skipped_fixed_output: Metric,
+    pub uploads_skipped_scan: Metric,
+    pub uploads_skipped_policy: Metric,
+    pub uploads_retried: Metric,
+    pub uploads_failed: Metric,
+
+    pub backend_rate_limited: Metric,
+    pub backend_circuit_opened: Metric,
+    pub requests_timed_out: Metric,
+    pub put_bytes_accepted: Metric,
+    pub puts_rejected_quota: Metric,
+    pub nix_conf_drifts: Metric,
+
+    pub outputs_built: Metric,
+    pub outputs_substituted: Metric,
+
+    pub derivations_rechecked: Metric,
+    pub derivations_not_reproducible: Metric,
 
     pub num_original_paths: Metric,
     pub num_final_paths: Metric,
     pub num_new_paths: Metric,
 
+    /// Uploads to each `--extra-backend`, by backend.
+    #[serde(skip_serializing_if = "extra_backends_unused")]
+    pub extra_backends: std::sync::Mutex<BTreeMap<String, BackendUploads>>,
+
     pub tripped_429: std::sync::atomic::AtomicBool,
 }
 
+/// The uploads to one of several backends.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct BackendUploads {
+    pub nars_uploaded: u64,
+    pub nar_bytes_uploaded: u64,
+    pub narinfos_uploaded: u64,
+    pub uploads_failed: u64,
+}
+
+fn extra_backends_unused(backends: &std::sync::Mutex<BTreeMap<String, BackendUploads>>) -> bool {
+    match backends.lock() {
+        Ok(backends) => backends.is_empty(),
+        Err(_) => true,
+    }
+}
+
 #[derive(Debug, Default, serde::Serialize)]
 pub struct Metric(std::sync::atomic::AtomicUsize);
 impl Metric {
@@ -39,6 +137,10 @@ impl Metric {
         self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
 
+    pub fn add(&self, val: usize) {
+        self.0.fetch_add(val, std::sync::atomic::Ordering::Relaxed);
+    }
+
     pub fn set(&self, val: usize) {
         self.0.store(val, std::sync::atomic::Ordering::Relaxed);
     }
@@ -60,7 +162,8 @@ impl TelemetryReport {
         }
     }
 
-    pub async fn send(&self, endpoint: &str) {
+    /// Record how long the daemon has been running.
+    pub fn update_elapsed(&self) {
         if let Some(start_time) = self.start_time {
             self.elapsed_seconds.set(
                 SystemTime::now()
@@ -71,15 +174,54 @@ impl TelemetryReport {
                     .unwrap_or(usize::MAX),
             );
         }
+    }
+
+    /// Update the uploads counted for the extra backend `name`.
+    pub fn record_extra(&self, name: &str, update: impl FnOnce(&mut BackendUploads)) {
+        if let Ok(mut backends) = self.extra_backends.lock() {
+            update(backends.entry(name.to_owned()).or_default());
+        }
+    }
+
+    /// The current value of every numeric metric, by name.
+    pub fn counters(&self) -> Vec<(String, u64)> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, value.as_u64()?)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
 
-        if let Ok(serialized) = serde_json::to_string_pretty(&self) {
-            let _ = reqwest::Client::new()
-                .post(endpoint)
-                .body(serialized)
-                .header("Content-Type", "application/json")
-                .timeout(std::time::Duration::from_millis(3000))
-                .send()
-                .await;
+    /// Send the report to `sink`.
+    pub async fn send(&self, sink: &DiagnosticSink) {
+        self.update_elapsed();
+
+        let Ok(serialized) = serde_json::to_string_pretty(&self) else {
+            return;
+        };
+
+        match sink {
+            DiagnosticSink::Http(endpoint) => {
+                let _ = reqwest::Client::new()
+                    .post(endpoint)
+                    .body(serialized)
+                    .header("Content-Type", "application/json")
+                    .timeout(std::time::Duration::from_millis(3000))
+                    .send()
+                    .await;
+            }
+            DiagnosticSink::File(path) => {
+                if let Err(err) = tokio::fs::write(path, serialized).await {
+                    tracing::warn!(
+                        "Failed to write the diagnostics to {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+            }
+            DiagnosticSink::Disabled => {}
         }
     }
 }
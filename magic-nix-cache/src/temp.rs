@@ -0,0 +1,111 @@
+//! The directory for temporary files.
+//!
+//! Each daemon uses its own `magic-nix-cache-<pid>` directory below the
+//! configured base directory, which is removed on shutdown. Directories left
+//! behind by daemons that crashed are removed when the next one starts.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::error::{Error, Result};
+
+const DIR_PREFIX: &str = "magic-nix-cache-";
+
+static TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// The directory for temporary files of this process.
+pub fn dir() -> &'static Path {
+    TEMP_DIR.get_or_init(|| dir_in(&std::env::temp_dir()))
+}
+
+fn dir_in(base: &Path) -> PathBuf {
+    base.join(format!("{}{}", DIR_PREFIX, std::process::id()))
+}
+
+/// Create the temporary directory below `base` (or the system's temporary
+/// directory), after removing those left behind by dead daemons.
+pub async fn init(base: Option<&Path>) -> Result<()> {
+    let base = base.map_or_else(std::env::temp_dir, Path::to_path_buf);
+
+    if TEMP_DIR.set(dir_in(&base)).is_err() && dir() != dir_in(&base) {
+        return Err(Error::Config(format!(
+            "Already using {} for temporary files",
+            dir().display()
+        )));
+    }
+
+    tokio::fs::create_dir_all(&base)
+        .await
+        .map_err(|e| Error::Io(e, format!("Creating {}", base.display())))?;
+
+    remove_stale(&base).await;
+
+    tokio::fs::create_dir_all(dir())
+        .await
+        .map_err(|e| Error::Io(e, format!("Creating {}", dir().display())))?;
+
+    tracing::debug!("Using {} for temporary files", dir().display());
+
+    Ok(())
+}
+
+/// Remove the temporary directory of this process.
+pub async fn cleanup() {
+    match tokio::fs::remove_dir_all(dir()).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => tracing::warn!(
+            "Failed to remove the temporary directory {}: {}",
+            dir().display(),
+            err
+        ),
+    }
+}
+
+async fn remove_stale(base: &Path) {
+    let mut entries = match tokio::fs::read_dir(base).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            tracing::warn!("Failed to list {}: {}", base.display(), err);
+            return;
+        }
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(DIR_PREFIX))
+            .and_then(|pid| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+
+        if pid == std::process::id() || is_running(pid).await {
+            continue;
+        }
+
+        tracing::info!(
+            "Removing {}, left behind by a previous run",
+            entry.path().display()
+        );
+
+        if let Err(err) = tokio::fs::remove_dir_all(entry.path()).await {
+            tracing::warn!("Failed to remove {}: {}", entry.path().display(), err);
+        }
+    }
+}
+
+async fn is_running(pid: u32) -> bool {
+    // `kill -0` also fails for processes of other users, whose files we
+    // couldn't remove anyway.
+    tokio::process::Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+        .map(|status| status.success())
+        .unwrap_or(true)
+}
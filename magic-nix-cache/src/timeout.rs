@@ -0,0 +1,189 @@
+//! Timeouts for requests to the daemon.
+//!
+//! A request that isn't answered within the timeout for its class gets a
+//! 504, so that a wedged backend read doesn't hold the connection of the Nix
+//! client open indefinitely. The timeout covers producing the response, not
+//! streaming its body, so slow downloads of large NARs aren't cut off.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::{
+    extract::{Extension, Request},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::Error;
+use crate::State;
+
+/// The timeout of reads when none is configured.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a request is for, which determines its timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    /// Fetching narinfos and `nix-cache-info`.
+    Narinfo,
+
+    /// Fetching NARs.
+    Nar,
+
+    /// Fetching source trees.
+    Source,
+
+    /// Uploading narinfos, NARs and source trees.
+    Upload,
+
+    /// The `/api/` routes. `/api/workflow-finish` waits for pending uploads.
+    Api,
+}
+
+const ROUTE_CLASSES: [RouteClass; 5] = [
+    RouteClass::Narinfo,
+    RouteClass::Nar,
+    RouteClass::Source,
+    RouteClass::Upload,
+    RouteClass::Api,
+];
+
+impl RouteClass {
+    fn of(request: &Request) -> RouteClass {
+        let path = request.uri().path();
+
+        if path.starts_with("/api/") {
+            return RouteClass::Api;
+        }
+
+        if request.method() == Method::PUT {
+            return RouteClass::Upload;
+        }
+
+        // The per-system view serves the same routes below `/system/<system>`.
+        let path = match path.strip_prefix("/system/") {
+            Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => path,
+        };
+
+        if path.starts_with("/nar/") {
+            RouteClass::Nar
+        } else if path.starts_with("/source/") {
+            RouteClass::Source
+        } else {
+            RouteClass::Narinfo
+        }
+    }
+
+    /// The timeout when none is configured. Uploads and the API can take
+    /// arbitrarily long, so they don't time out by default.
+    fn default_timeout(self) -> Option<Duration> {
+        match self {
+            RouteClass::Narinfo | RouteClass::Nar | RouteClass::Source => {
+                Some(DEFAULT_READ_TIMEOUT)
+            }
+            RouteClass::Upload | RouteClass::Api => None,
+        }
+    }
+}
+
+impl FromStr for RouteClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ROUTE_CLASSES
+            .into_iter()
+            .find(|class| class.to_string() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown route class '{}', expected 'narinfo', 'nar', 'source', 'upload' or 'api'",
+                    s
+                )
+            })
+    }
+}
+
+impl Display for RouteClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouteClass::Narinfo => write!(f, "narinfo"),
+            RouteClass::Nar => write!(f, "nar"),
+            RouteClass::Source => write!(f, "source"),
+            RouteClass::Upload => write!(f, "upload"),
+            RouteClass::Api => write!(f, "api"),
+        }
+    }
+}
+
+/// The timeout of each class of requests.
+#[derive(Debug, Clone)]
+pub struct Timeouts(HashMap<RouteClass, Duration>);
+
+impl Timeouts {
+    /// Apply `rules`, as parsed by [`parse_rule`], to the default timeouts.
+    /// A rule without a class applies to every class without its own rule,
+    /// and a zero duration disables the timeout.
+    pub fn new(rules: &[(Option<RouteClass>, Duration)]) -> Timeouts {
+        let find = |class| {
+            rules
+                .iter()
+                .rev()
+                .find(|(c, _)| *c == class)
+                .map(|(_, timeout)| *timeout)
+        };
+
+        Timeouts(
+            ROUTE_CLASSES
+                .into_iter()
+                .filter_map(|class| {
+                    let timeout = find(Some(class))
+                        .or_else(|| find(None))
+                        .or_else(|| class.default_timeout())?;
+                    (!timeout.is_zero()).then_some((class, timeout))
+                })
+                .collect(),
+        )
+    }
+
+    fn get(&self, class: RouteClass) -> Option<Duration> {
+        self.0.get(&class).copied()
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts::new(&[])
+    }
+}
+
+/// Parse a timeout rule like `nar=5m`, or `30s` for all classes.
+pub fn parse_rule(s: &str) -> std::result::Result<(Option<RouteClass>, Duration), String> {
+    match s.split_once('=') {
+        Some((class, timeout)) => Ok((Some(class.parse()?), crate::util::parse_duration(timeout)?)),
+        None => Ok((None, crate::util::parse_duration(s)?)),
+    }
+}
+
+pub async fn middleware(
+    Extension(state): Extension<State>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let class = RouteClass::of(&request);
+
+    let Some(timeout) = state.timeouts.get(class) else {
+        return next.run(request).await;
+    };
+
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!("Timed out handling a {} request after {:?}", class, timeout);
+            state.metrics.requests_timed_out.incr();
+
+            Error::Timeout(timeout).into_response()
+        }
+    }
+}
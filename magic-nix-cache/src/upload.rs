@@ -0,0 +1,140 @@
+//! Writes of objects to the backend that are aborted unless they complete.
+//!
+//! An upload can stop halfway, because the client went away, the request
+//! timed out, the job was cancelled or reading the data failed. Dropping an
+//! opendal writer leaves a multipart upload or a partial object behind,
+//! which uses up quota and, with some backends, is served as a corrupt
+//! entry. An [`Upload`] that is dropped before [`Upload::close`] aborts the
+//! write instead.
+
+use futures::future::join_all;
+use opendal::{Buffer, Writer};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::Result;
+
+/// Size of the chunks that are read from the source of an upload.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+pub struct Upload {
+    key: String,
+    writer: Option<Writer>,
+}
+
+impl Upload {
+    pub fn new(writer: Writer, key: &str) -> Upload {
+        Upload {
+            key: key.to_owned(),
+            writer: Some(writer),
+        }
+    }
+
+    /// Write all of `reader` to the object, returning the number of bytes.
+    pub async fn copy(&mut self, reader: &mut (impl AsyncRead + Unpin)) -> Result<u64> {
+        let mut size = 0;
+
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            if reader.read_buf(&mut chunk).await? == 0 {
+                return Ok(size);
+            }
+
+            size += chunk.len() as u64;
+            self.write(chunk).await?;
+        }
+    }
+
+    pub async fn write(&mut self, bs: impl Into<Buffer>) -> Result<()> {
+        Ok(self.writer().write(bs).await?)
+    }
+
+    /// Complete the object.
+    pub async fn close(mut self) -> Result<()> {
+        let mut writer = self.writer.take().expect("the upload is open");
+
+        if let Err(err) = writer.close().await {
+            let _ = writer.abort().await;
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    fn writer(&mut self) -> &mut Writer {
+        self.writer.as_mut().expect("the upload is open")
+    }
+}
+
+/// Write all of `reader` to each of `uploads` at once and complete them,
+/// returning the number of bytes and the result of each upload. An upload
+/// that fails is aborted while the others go on; only failing to read fails
+/// them all.
+pub async fn copy_all(
+    uploads: Vec<Upload>,
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<(u64, Vec<Result<()>>)> {
+    let mut uploads = uploads.into_iter().map(Ok).collect::<Vec<Result<Upload>>>();
+    let mut size = 0;
+
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        if reader.read_buf(&mut chunk).await? == 0 {
+            break;
+        }
+
+        size += chunk.len() as u64;
+        let chunk = Buffer::from(chunk);
+
+        join_all(uploads.iter_mut().map(|upload| {
+            let chunk = chunk.clone();
+            async move {
+                let result = match upload {
+                    Ok(open) => open.write(chunk).await,
+                    Err(_) => return,
+                };
+
+                // Dropping the upload aborts it.
+                if let Err(err) = result {
+                    *upload = Err(err);
+                }
+            }
+        }))
+        .await;
+    }
+
+    let results = join_all(
+        uploads
+            .into_iter()
+            .map(|upload| async move { upload?.close().await }),
+    )
+    .await;
+
+    Ok((size, results))
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let Some(mut writer) = self.writer.take() else {
+            return;
+        };
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let key = std::mem::take(&mut self.key);
+
+        runtime.spawn(async move {
+            match writer.abort().await {
+                Ok(()) => tracing::debug!("Aborted the incomplete upload of '{}'", key),
+                Err(err) => {
+                    tracing::warn!(
+                        "Aborting the incomplete upload of '{}' failed: {}",
+                        key,
+                        err
+                    )
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,240 @@
+//! Requests to the upstream caches.
+//!
+//! Most of a closure usually comes straight from the upstream cache, e.g.
+//! `cache.nixos.org`, so uploading it again only costs time and quota. The
+//! worker asks the upstream caches for the narinfo of each path before
+//! uploading it, and skips the paths one of them has, along with their
+//! references, which it has too. With pull-through caching, objects are also
+//! fetched from them, see [`crate::mirror`].
+//!
+//! With more than one `--upstream`, they are tried in the order they were
+//! given. A redirect can't fall back to the next cache, so narinfos are then
+//! always fetched by the daemon, and NARs come from the cache that served
+//! their narinfo.
+//!
+//! With `--check-upstream-hashes`, the worker fetches the narinfos instead,
+//! and compares their NAR hash with the one of the local path. A different
+//! hash means that the path was built differently here than upstream, so
+//! either the build isn't reproducible, or one of the two was tampered with.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::join_all;
+use reqwest::StatusCode;
+
+use crate::lru::LruCache;
+
+/// How long to wait for the upstream cache to say whether it has a path,
+/// before uploading the path anyway.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many paths the upstream cache that served their narinfo is
+/// remembered for.
+const SOURCES_CAPACITY: usize = 4096;
+
+pub struct Upstream {
+    client: reqwest::Client,
+
+    /// The upstream caches, in the order they are tried.
+    urls: Vec<String>,
+
+    /// The index of the upstream cache that served the narinfo of each
+    /// recently fetched store path hash.
+    sources: Mutex<LruCache<String, usize>>,
+
+    /// Whether the NAR hashes of the paths are compared with upstream.
+    check_hashes: bool,
+}
+
+impl Upstream {
+    pub fn new(urls: &[String]) -> Upstream {
+        Upstream {
+            client: reqwest::Client::new(),
+            urls: urls
+                .iter()
+                .map(|url| url.trim_end_matches('/').to_owned())
+                .collect(),
+            sources: Mutex::new(LruCache::new(SOURCES_CAPACITY)),
+            check_hashes: false,
+        }
+    }
+
+    /// Compare the NAR hashes of the paths with upstream, see [`Upstream::nar_hashes`].
+    pub fn check_hashes(mut self, check_hashes: bool) -> Upstream {
+        self.check_hashes = check_hashes;
+        self
+    }
+
+    /// Whether the NAR hashes of the paths are compared with upstream.
+    pub fn checks_hashes(&self) -> bool {
+        self.check_hashes
+    }
+
+    /// Whether there is more than one upstream cache.
+    pub fn is_chain(&self) -> bool {
+        self.urls.len() > 1
+    }
+
+    /// Whether an upstream cache has the path with `store_path_hash`. The
+    /// caches are asked at once. If they can't be asked, the path is assumed
+    /// to be missing, so that it gets uploaded.
+    pub async fn has(&self, store_path_hash: &str) -> bool {
+        join_all(self.urls.iter().map(|url| async move {
+            let url = format!("{}/{}.narinfo", url, store_path_hash);
+
+            match self.client.head(&url).timeout(TIMEOUT).send().await {
+                Ok(response) => response.status().is_success(),
+                Err(err) => {
+                    tracing::debug!("Checking {} failed: {}", url, err);
+                    false
+                }
+            }
+        }))
+        .await
+        .into_iter()
+        .any(|has| has)
+    }
+
+    /// The NAR hashes, in base32 without the `sha256:` prefix, that the
+    /// upstream caches which have the path with `store_path_hash` list for
+    /// it, along with the URLs of those caches. Like [`Upstream::has`], the
+    /// caches that can't be asked are assumed not to have the path.
+    pub async fn nar_hashes(&self, store_path_hash: &str) -> Vec<(&str, String)> {
+        join_all(self.urls.iter().map(|url| async move {
+            let narinfo_url = format!("{}/{}.narinfo", url, store_path_hash);
+
+            let result = self
+                .client
+                .get(&narinfo_url)
+                .timeout(TIMEOUT)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            let narinfo = match result {
+                Ok(response) => response.text().await,
+                Err(err) => Err(err),
+            };
+
+            match narinfo {
+                Ok(narinfo) => nar_hash(&narinfo).map(|hash| (url.as_str(), hash.to_owned())),
+                Err(err) => {
+                    if !err.status().is_some_and(is_missing) {
+                        tracing::debug!("Fetching {} failed: {}", narinfo_url, err);
+                    }
+                    None
+                }
+            }
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Fetch the narinfo of `store_path_hash` from the first upstream cache
+    /// that has it, or `None` if none does.
+    pub async fn narinfo(
+        &self,
+        store_path_hash: &str,
+    ) -> reqwest::Result<Option<reqwest::Response>> {
+        let path = format!("{}.narinfo", store_path_hash);
+        let response = self.get_first(&path, 0..self.urls.len()).await?;
+
+        if let Some((index, _)) = &response {
+            self.sources
+                .lock()
+                .expect("sources lock")
+                .insert(store_path_hash.to_owned(), *index);
+        }
+
+        Ok(response.map(|(_, response)| response))
+    }
+
+    /// Fetch the NAR at `nar/<path>`, or `None` if no upstream cache has it,
+    /// trying the cache that served the narinfo of `store_path_hash` first.
+    pub async fn nar(
+        &self,
+        path: &str,
+        store_path_hash: Option<&str>,
+    ) -> reqwest::Result<Option<reqwest::Response>> {
+        let source = self.source(store_path_hash);
+        let order = std::iter::once(source).chain((0..self.urls.len()).filter(|i| *i != source));
+
+        Ok(self
+            .get_first(&format!("nar/{}", path), order)
+            .await?
+            .map(|(_, response)| response))
+    }
+
+    /// The URL of the upstream cache to redirect requests for the path with
+    /// `store_path_hash` to: the one that served its narinfo, or the first.
+    pub fn url_for(&self, store_path_hash: Option<&str>) -> &str {
+        &self.urls[self.source(store_path_hash)]
+    }
+
+    fn source(&self, store_path_hash: Option<&str>) -> usize {
+        store_path_hash
+            .and_then(|store_path_hash| {
+                self.sources
+                    .lock()
+                    .expect("sources lock")
+                    .get(store_path_hash)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Fetch `path` from the upstream caches in `order`, returning the first
+    /// successful response and the index of its cache. If none has it and
+    /// some failed, the first error is returned.
+    async fn get_first(
+        &self,
+        path: &str,
+        order: impl IntoIterator<Item = usize>,
+    ) -> reqwest::Result<Option<(usize, reqwest::Response)>> {
+        let mut first_err = None;
+
+        for index in order {
+            let url = format!("{}/{}", self.urls[index], path);
+
+            let result =
+                self.client
+                    .get(&url)
+                    .send()
+                    .await
+                    .and_then(|response| match response.status() {
+                        status if is_missing(status) => Ok(None),
+                        _ => response.error_for_status().map(Some),
+                    });
+
+            match result {
+                Ok(Some(response)) => return Ok(Some((index, response))),
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::debug!("Fetching {} failed: {}", url, err);
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The NAR hash in `narinfo`, without its `sha256:` prefix.
+fn nar_hash(narinfo: &str) -> Option<&str> {
+    narinfo.lines().find_map(|line| {
+        let hash = line.strip_prefix("NarHash:")?.trim();
+        Some(hash.strip_prefix("sha256:").unwrap_or(hash))
+    })
+}
+
+/// Whether a status of the upstream cache means that it doesn't have an object.
+/// S3-backed caches answer 403 for missing objects.
+fn is_missing(status: StatusCode) -> bool {
+    status == StatusCode::NOT_FOUND || status == StatusCode::FORBIDDEN
+}
@@ -2,6 +2,7 @@
 
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use attic::nix_store::NixStore;
 
@@ -50,3 +51,52 @@ pub async fn get_store_paths(store: &NixStore) -> Result<HashSet<PathBuf>> {
     }
     Ok(paths)
 }
+
+/// Parse a size in bytes, with an optional binary `K`, `M`, `G` or `T` suffix.
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let trimmed = s.trim();
+
+    let (digits, shift) = match trimmed.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&trimmed[..i], 10),
+        Some((i, 'M' | 'm')) => (&trimmed[..i], 20),
+        Some((i, 'G' | 'g')) => (&trimmed[..i], 30),
+        Some((i, 'T' | 't')) => (&trimmed[..i], 40),
+        _ => (trimmed, 0),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+/// Parse a percentage from 0 to 100, with an optional `%` suffix.
+pub fn parse_percent(s: &str) -> std::result::Result<f64, String> {
+    s.trim()
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| format!("invalid percentage '{}', expected 0 to 100", s))
+}
+
+/// Parse a duration with an `s`, `m`, `h` or `d` suffix, e.g. `30d`.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let trimmed = s.trim();
+
+    let (digits, unit) = match trimmed.char_indices().last() {
+        Some((i, 's')) => (&trimmed[..i], 1),
+        Some((i, 'm')) => (&trimmed[..i], 60),
+        Some((i, 'h')) => (&trimmed[..i], 60 * 60),
+        Some((i, 'd')) => (&trimmed[..i], 24 * 60 * 60),
+        _ => return Err(format!("invalid duration '{}', expected e.g. '30d'", s)),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration '{}'", s))
+}